@@ -0,0 +1,154 @@
+/// Encrypted configuration values, so that configs with secrets could be committed
+/// to git or stored in plaintext-only KV stores.
+///
+/// A string value prefixed with `enc:` is decrypted with AES-256-GCM when the
+/// configuration is loaded from any source (see [parse_config]). The key
+/// is the base64 of 32 bytes in environment `CONFIG_ENCRYPTION_KEY`, loading fails
+/// if an `enc:` value is present but the key is not configured.
+///
+/// ```rust,ignore
+/// // generate the value to be put into the configuration
+/// let value = encrypt(&key, "redis-password")?; // enc:...
+/// ```
+///
+/// [parse_config]: crate::utils::parse_config
+use crate::config::env::optional_some;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::Value;
+use thiserror::Error;
+
+pub const ENC_PREFIX: &str = "enc:";
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("{field} is encrypted but environment CONFIG_ENCRYPTION_KEY is not set")]
+    MissingKey { field: String },
+    #[error("encryption key must be the base64 of 32 bytes")]
+    InvalidKey,
+    #[error("cannot encrypt the value")]
+    Encrypt,
+    #[error("cannot decrypt {field}: {reason}")]
+    Decrypt { field: String, reason: String },
+}
+
+/// Encrypt the plaintext into an `enc:` value with a 32 bytes key
+pub fn encrypt(key: &[u8], plaintext: &str) -> Result<String, EncryptionError> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| EncryptionError::InvalidKey)?;
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| EncryptionError::Encrypt)?;
+    let mut payload = nonce.to_vec();
+    payload.extend(ciphertext);
+    Ok(format!("{}{}", ENC_PREFIX, STANDARD.encode(payload)))
+}
+
+/// Decrypt an `enc:` value with a 32 bytes key, `field` is used in errors
+pub fn decrypt(key: &[u8], value: &str, field: &str) -> Result<String, EncryptionError> {
+    let err = |reason: &str| EncryptionError::Decrypt {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    };
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| EncryptionError::InvalidKey)?;
+    let payload = STANDARD
+        .decode(value.strip_prefix(ENC_PREFIX).unwrap_or(value))
+        .map_err(|_| err("not base64"))?;
+    if payload.len() < NONCE_LEN {
+        return Err(err("too short"));
+    }
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| err("wrong key or tampered value"))?;
+    String::from_utf8(plaintext).map_err(|_| err("not utf-8"))
+}
+
+/// Decrypt all the `enc:` values in place with the key in `CONFIG_ENCRYPTION_KEY`,
+/// the key is only read when an encrypted value is found.
+pub(crate) fn decrypt_value(value: &mut Value) -> Result<(), EncryptionError> {
+    let mut key = None;
+    decrypt_tree(value, "", &mut || {
+        if key.is_none() {
+            key = optional_some("CONFIG_ENCRYPTION_KEY");
+        }
+        key.clone()
+    })
+}
+
+fn decrypt_tree(
+    value: &mut Value,
+    field: &str,
+    key: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), EncryptionError> {
+    let join = |name: &str| match field {
+        "" => name.to_string(),
+        _ => format!("{}.{}", field, name),
+    };
+    match value {
+        Value::String(s) if s.starts_with(ENC_PREFIX) => {
+            let field = if field.is_empty() { "." } else { field };
+            let key = key().ok_or_else(|| EncryptionError::MissingKey {
+                field: field.to_string(),
+            })?;
+            let key = STANDARD
+                .decode(key.trim())
+                .map_err(|_| EncryptionError::InvalidKey)?;
+            *s = decrypt(&key, s, field)?;
+        }
+        Value::Array(values) => {
+            for (idx, value) in values.iter_mut().enumerate() {
+                decrypt_tree(value, &join(&idx.to_string()), key)?;
+            }
+        }
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                decrypt_tree(value, &join(name), key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::encryption::{decrypt, decrypt_tree, encrypt, EncryptionError};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde_json::json;
+
+    #[test]
+    fn test_round_trip() {
+        let key = [7u8; 32];
+        let value = encrypt(&key, "redis-password").unwrap();
+        assert!(value.starts_with("enc:"));
+        assert_eq!(decrypt(&key, &value, "dsn").unwrap(), "redis-password");
+        assert!(matches!(
+            decrypt(&[8u8; 32], &value, "dsn"),
+            Err(EncryptionError::Decrypt { .. })
+        ));
+
+        let mut config = json!({
+            "redis": {"password": value, "db": 1},
+            "tags": ["plain"],
+        });
+        let err = decrypt_tree(&mut config.clone(), "", &mut || None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "redis.password is encrypted but environment CONFIG_ENCRYPTION_KEY is not set"
+        );
+        decrypt_tree(&mut config, "", &mut || Some(STANDARD.encode(key))).unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "redis": {"password": "redis-password", "db": 1},
+                "tags": ["plain"],
+            })
+        );
+    }
+}
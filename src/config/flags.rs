@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Feature flags in configuration, a map of flag name to its rule.
+///
+/// ```yaml
+/// flags:
+///   new_checkout: true                  # on/off
+///   fast_search: { percentage: 20 }     # 20% of keys
+///   beta_ui: { allowlist: [uid1, uid2] }
+/// ```
+///
+/// Flags flip without redeploy when the configuration is hot reloaded by
+/// [ConfigWatcher], e.p. `conf.borrow().flags.is_enabled("beta_ui", &ctx)`.
+///
+/// [ConfigWatcher]: crate::utils::reload::ConfigWatcher
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FeatureFlags(HashMap<String, FlagRule>);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FlagRule {
+    Switch(bool),
+    /// Enabled for the keys whose stable hash falls in the percentage (0 ~ 100),
+    /// a key always gets the same result as long as the percentage is unchanged
+    Rollout {
+        percentage: u8,
+    },
+    Allowlist {
+        allowlist: HashSet<String>,
+    },
+}
+
+/// The context a flag is evaluated in
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlagContext<'a> {
+    /// The key of rollout and allowlist, e.p. uid. A context without key only
+    /// passes the switch and the 100% rollout
+    pub key: Option<&'a str>,
+}
+
+impl<'a> FlagContext<'a> {
+    pub fn new(key: &'a str) -> Self {
+        Self { key: Some(key) }
+    }
+}
+
+/// FNV-1a, stable across processes and rust versions unlike `DefaultHasher`
+fn stable_hash(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl FlagRule {
+    fn evaluate(&self, flag: &str, ctx: &FlagContext) -> bool {
+        match self {
+            FlagRule::Switch(on) => *on,
+            FlagRule::Rollout { percentage } if *percentage >= 100 => true,
+            FlagRule::Rollout { percentage } => ctx.key.is_some_and(|key| {
+                // hash with the flag name, so flags roll out to different keys
+                let bytes = flag.bytes().chain([b':']).chain(key.bytes());
+                stable_hash(bytes) % 100 < *percentage as u64
+            }),
+            FlagRule::Allowlist { allowlist } => ctx.key.is_some_and(|key| allowlist.contains(key)),
+        }
+    }
+}
+
+impl FeatureFlags {
+    pub fn new(flags: HashMap<String, FlagRule>) -> Self {
+        Self(flags)
+    }
+
+    /// Whether the flag is enabled in the context, an unknown flag is disabled
+    pub fn is_enabled(&self, flag: &str, ctx: &FlagContext) -> bool {
+        self.0
+            .get(flag)
+            .is_some_and(|rule| rule.evaluate(flag, ctx))
+    }
+
+    pub fn rule(&self, flag: &str) -> Option<&FlagRule> {
+        self.0.get(flag)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::flags::{FeatureFlags, FlagContext};
+
+    #[test]
+    fn test_feature_flags() {
+        let flags: FeatureFlags = serde_json::from_str(
+            r#"{
+                "on": true,
+                "off": false,
+                "rollout": { "percentage": 20 },
+                "beta": { "allowlist": ["uid1"] }
+            }"#,
+        )
+        .unwrap();
+        let anonymous = FlagContext::default();
+        assert!(flags.is_enabled("on", &anonymous));
+        assert!(!flags.is_enabled("off", &anonymous));
+        assert!(!flags.is_enabled("unknown", &anonymous));
+        assert!(!flags.is_enabled("rollout", &anonymous));
+
+        assert!(flags.is_enabled("beta", &FlagContext::new("uid1")));
+        assert!(!flags.is_enabled("beta", &FlagContext::new("uid2")));
+
+        let keys = (0..1000).map(|i| format!("uid{}", i)).collect::<Vec<_>>();
+        let enabled = keys
+            .iter()
+            .filter(|key| flags.is_enabled("rollout", &FlagContext::new(key)))
+            .collect::<Vec<_>>();
+        assert!((150..250).contains(&enabled.len()));
+        // deterministic
+        assert!(enabled
+            .iter()
+            .all(|key| flags.is_enabled("rollout", &FlagContext::new(key))));
+    }
+}
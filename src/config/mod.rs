@@ -2,9 +2,13 @@ use once_cell::sync::OnceCell;
 use std::sync::Arc;
 use tracing::info;
 
+pub mod encryption;
+pub mod flags;
 pub mod layer;
 pub mod middleware;
+pub mod secret;
 pub mod service;
+pub mod tls;
 
 // Root config type
 pub struct Config;
@@ -18,15 +22,78 @@ impl<T> ConfigType for T where T: Clone + for<'de> serde::de::Deserialize<'de> +
 pub mod env {
 
     use super::*;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::cell::RefCell;
+    use std::panic::AssertUnwindSafe;
+    use tracing::warn;
+
+    /// An environment read by the defaults of a config, see [describe]
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+    pub struct EnvVarDoc {
+        pub key: String,
+        pub required: bool,
+        pub default: Option<String>,
+    }
+
+    thread_local! {
+        static DESCRIBING: RefCell<Option<Vec<EnvVarDoc>>> = const { RefCell::new(None) };
+    }
+
+    /// Run `f` and collect the environments it reads instead of reading them,
+    /// meanwhile `require` returns an empty string and `optional` the default.
+    /// A panic in `f` (e.p. parsing the empty string) stops the collecting.
+    pub fn describe(f: impl FnOnce()) -> Vec<EnvVarDoc> {
+        let outer = DESCRIBING.with(|docs| docs.replace(Some(Vec::new())));
+        let _ = std::panic::catch_unwind(AssertUnwindSafe(f));
+        let docs = DESCRIBING
+            .with(|docs| docs.replace(outer))
+            .unwrap_or_default();
+        // a nested describing is also collected by the outer one
+        for doc in &docs {
+            described(&doc.key, doc.required, doc.default.clone());
+        }
+        docs
+    }
+
+    /// Whether in [describe], used by [define_config](crate::define_config)
+    #[doc(hidden)]
+    pub fn describing() -> bool {
+        DESCRIBING.with(|docs| docs.borrow().is_some())
+    }
+
+    /// Record the environment if describing
+    fn described(env_key: &str, required: bool, default: Option<String>) -> bool {
+        DESCRIBING.with(|docs| match docs.borrow_mut().as_mut() {
+            Some(docs) => {
+                if !docs.iter().any(|doc| doc.key == env_key) {
+                    docs.push(EnvVarDoc {
+                        key: env_key.to_string(),
+                        required,
+                        default,
+                    });
+                }
+                true
+            }
+            None => false,
+        })
+    }
 
     pub fn require(env_key: impl AsRef<str>) -> String {
+        if described(env_key.as_ref(), true, None) {
+            return String::new();
+        }
         std::env::var(env_key.as_ref())
             .unwrap_or_else(|_| panic!("require an environment {}", env_key.as_ref()))
     }
 
     pub fn optional(env_key: impl AsRef<str>, default: impl ToString) -> String {
+        if described(env_key.as_ref(), false, Some(default.to_string())) {
+            return default.to_string();
+        }
         std::env::var(env_key.as_ref()).unwrap_or_else(|_| {
             let ret = default.to_string();
+            crate::utils::startup::record_env_fallback(env_key.as_ref(), &ret);
             info!(
                 "cannot found environment {}, use '{}' as default",
                 env_key.as_ref(),
@@ -37,7 +104,11 @@ pub mod env {
     }
 
     pub fn optional_some(env_key: impl AsRef<str>) -> Option<String> {
-        std::env::var(env_key.as_ref()).ok().or({
+        if described(env_key.as_ref(), false, None) {
+            return None;
+        }
+        std::env::var(env_key.as_ref()).ok().or_else(|| {
+            crate::utils::startup::record_env_fallback(env_key.as_ref(), "None");
             info!(
                 "cannot found environment {}, use None as default",
                 env_key.as_ref(),
@@ -45,6 +116,94 @@ pub mod env {
             None
         })
     }
+
+    /// Parse a structured value (array, map, etc.) from a JSON environment,
+    /// e.p. `CONSUL_META='{"version": "v1"}'`
+    pub fn require_json<T: DeserializeOwned>(env_key: impl AsRef<str>) -> T {
+        if described(env_key.as_ref(), true, None) {
+            panic!("stop describing at environment {}", env_key.as_ref());
+        }
+        let value = require(env_key.as_ref());
+        serde_json::from_str(&value).unwrap_or_else(|err| {
+            panic!(
+                "environment {} is not a valid JSON value, err: {}",
+                env_key.as_ref(),
+                err
+            )
+        })
+    }
+
+    /// Same as [require_json] but use the default value when the environment
+    /// is not found or cannot be parsed.
+    pub fn optional_json<T: DeserializeOwned + Serialize>(
+        env_key: impl AsRef<str>,
+        default: T,
+    ) -> T {
+        if described(
+            env_key.as_ref(),
+            false,
+            serde_json::to_string(&default).ok(),
+        ) {
+            return default;
+        }
+        match std::env::var(env_key.as_ref()) {
+            Ok(value) => serde_json::from_str(&value).unwrap_or_else(|err| {
+                warn!(
+                    "environment {} is not a valid JSON value, use default, err: {}",
+                    env_key.as_ref(),
+                    err
+                );
+                default
+            }),
+            Err(_) => {
+                crate::utils::startup::record_env_fallback(env_key.as_ref(), "default");
+                info!("cannot found environment {}, use default", env_key.as_ref());
+                default
+            }
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_optional_json() {
+        use std::collections::HashMap;
+
+        std::env::set_var("TEST_OPTIONAL_JSON", r#"{"v1": 10, "v2": 90}"#);
+        let weights: HashMap<String, i32> = optional_json("TEST_OPTIONAL_JSON", HashMap::new());
+        assert_eq!(weights["v2"], 90);
+
+        std::env::set_var("TEST_OPTIONAL_JSON_BAD", "[1, 2");
+        let tags: Vec<i32> = optional_json("TEST_OPTIONAL_JSON_BAD", vec![3]);
+        assert_eq!(tags, vec![3]);
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_describe() {
+        std::env::set_var("TEST_DESCRIBE_REQUIRED", "set");
+        let docs = describe(|| {
+            assert_eq!(require("TEST_DESCRIBE_REQUIRED"), "");
+            optional("TEST_DESCRIBE_OPTIONAL", "v1");
+            let _: u64 = optional_json("TEST_DESCRIBE_JSON", 10);
+            require("TEST_DESCRIBE_NUMBER").parse::<u64>().unwrap();
+            optional_some("TEST_DESCRIBE_UNREACHED");
+        });
+        let keys = docs.iter().map(|doc| doc.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                "TEST_DESCRIBE_REQUIRED",
+                "TEST_DESCRIBE_OPTIONAL",
+                "TEST_DESCRIBE_JSON",
+                "TEST_DESCRIBE_NUMBER"
+            ]
+        );
+        assert_eq!(docs[1].default.as_deref(), Some("v1"));
+        assert_eq!(docs[2].default.as_deref(), Some("10"));
+        assert!(docs[3].required);
+        // not describing
+        assert_eq!(require("TEST_DESCRIBE_REQUIRED"), "set");
+    }
 }
 
 pub mod register {
@@ -127,8 +286,32 @@ macro_rules! define_config {
             $(fn $ff() -> $typ $dft)?
         )*
 
+        impl $conf {
+            /// The environments read by the defaults of this config
+            #[allow(dead_code)]
+            pub fn describe_env() -> Vec<$crate::config::env::EnvVarDoc> {
+                let mut docs = Vec::new();
+                $($(
+                    docs.extend($crate::config::env::describe(|| {
+                        let _ = <$dtyp as Default>::default();
+                    }));
+                )*)?
+                $($(
+                    docs.extend($crate::config::env::describe(|| {
+                        let _ = $ff();
+                    }));
+                )*)?
+                docs
+            }
+        }
+
         impl Default for $conf {
             fn default() -> Self {
+                // a nested config is described field by field, so that a panic
+                // of one field does not stop describing the others
+                if $crate::config::env::describing() {
+                    let _ = Self::describe_env();
+                }
                 Self {
                     $($(
                         $dfname: Default::default(),
@@ -3,6 +3,9 @@ use super::*;
 use crate::define_config;
 use names::Generator;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
 
 pub trait ServiceConfig {
     type RestService: ConfigType;
@@ -18,6 +21,8 @@ define_config! {
             let mut generator = Generator::default();
             optional("SERVICE_NAME", generator.next().unwrap())
         },
+        // `host:port`, or a bare host (e.p. `0.0.0.0`) to bind the port of the discover
+        // address, or empty to bind the discover address itself, see `bind_addr`
         #[default_listen_addr = "default_listen_addr"]
         pub listen_addr -> String {
             optional("LISTEN_ADDR", "0.0.0.0:3000")
@@ -26,6 +31,27 @@ define_config! {
         pub discover_addr -> String {
             optional("DISCOVER_ADDR", "http://127.0.0.1:3000")
         },
+        // the registered address if it differs from the discover address, e.p. behind NAT
+        #[default_advertise_addr = "default_advertise_addr"]
+        pub advertise_addr -> Option<String> {
+            optional_some("ADVERTISE_ADDR")
+        },
+        // an invalid port is warned and not advertised
+        #[default_advertise_port = "default_advertise_port"]
+        pub advertise_port -> Option<u16> {
+            optional_json("ADVERTISE_PORT", None)
+        },
+        // registered with the instance, the `meta` of registry options wins on conflicts
+        #[default_meta = "default_meta"]
+        pub meta -> HashMap<String, String> {
+            optional_json("SERVICE_META", HashMap::new())
+        },
+        // consul weights, e.p. `{"Passing": 10, "Warning": 1}`, unless the registry
+        // options set `weights`
+        #[default_weights = "default_weights"]
+        pub weights -> Option<HashMap<String, i32>> {
+            optional_json("SERVICE_WEIGHTS", None)
+        },
         #[default_timeout = "default_timeout"]
         pub timeout -> u64 {
             30
@@ -37,10 +63,84 @@ define_config! {
         #[default_load_shed = "default_load_shed"]
         pub load_shed -> bool {
             false
+        },
+        #[default_tls_cert = "default_tls_cert"]
+        pub tls_cert -> Option<String> {
+            optional_some("TLS_CERT")
+        },
+        #[default_tls_key = "default_tls_key"]
+        pub tls_key -> Option<String> {
+            optional_some("TLS_KEY")
         }
     }
 }
 
+#[derive(Debug, Error)]
+pub enum BindAddrError {
+    #[error("invalid listen addr {0}, expect an ip with an optional port")]
+    Listen(String),
+    #[error("invalid discover addr {addr}: {reason}")]
+    Discover { addr: String, reason: String },
+    #[error("port 0 is not served at a known port and cannot be registered")]
+    ZeroPort,
+    #[error("listen port {listen} differs from the registered port {registered}, set ADVERTISE_PORT if the port is mapped")]
+    PortMismatch { listen: u16, registered: u16 },
+}
+
+impl ServiceConf {
+    /// The local address to serve at, derived from `listen_addr` and `discover_addr`
+    /// so that the served port is the registered one:
+    /// `0.0.0.0:3000` => bound as is
+    /// `0.0.0.0` => the port of `discover_addr`
+    /// empty => the host and port of `discover_addr`, which must be an ip
+    ///
+    /// Unless `advertise_port` is set (e.p. the port is mapped), the port must be the
+    /// same as the one of `discover_addr`, which is registered.
+    pub fn bind_addr(&self) -> Result<SocketAddr, BindAddrError> {
+        let discover =
+            url::Url::parse(&self.discover_addr).map_err(|err| BindAddrError::Discover {
+                addr: self.discover_addr.clone(),
+                reason: err.to_string(),
+            })?;
+        let discover_err = |reason: &str| BindAddrError::Discover {
+            addr: self.discover_addr.clone(),
+            reason: reason.to_string(),
+        };
+        let discover_port = discover
+            .port_or_known_default()
+            .ok_or_else(|| discover_err("no port"))?;
+        let listen = self.listen_addr.trim();
+        let addr = if listen.is_empty() {
+            let ip = match discover.host() {
+                Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+                Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+                _ => return Err(discover_err("not an ip, set LISTEN_ADDR to bind")),
+            };
+            SocketAddr::new(ip, discover_port)
+        } else if let Ok(addr) = listen.parse::<SocketAddr>() {
+            addr
+        } else {
+            let host = listen.trim_start_matches('[').trim_end_matches(']');
+            let ip = host
+                .parse::<IpAddr>()
+                .map_err(|_| BindAddrError::Listen(listen.to_string()))?;
+            SocketAddr::new(ip, discover_port)
+        };
+        if addr.port() == 0 {
+            return Err(BindAddrError::ZeroPort);
+        }
+        if self.advertise_port.is_none() && addr.port() != discover_port {
+            return Err(BindAddrError::PortMismatch {
+                listen: addr.port(),
+                registered: discover_port,
+            });
+        }
+        Ok(addr)
+    }
+}
+
+// `cert_file` and `key_file` are deprecated, `tls_cert` and `tls_key` of the service
+// take precedence, see `RestServiceConf::tls_files`
 define_config! {
     #[derive(Serialize, Debug)]
     pub RestServiceConf (
@@ -63,3 +163,64 @@ impl ServiceConfig for Config {
     type GrpcService = GrpcServiceConf;
     type Service = ServiceConf;
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::service::{BindAddrError, ServiceConf};
+
+    #[test]
+    fn test_bind_addr() {
+        let conf = |listen: &str, discover: &str| ServiceConf {
+            listen_addr: listen.to_string(),
+            discover_addr: discover.to_string(),
+            advertise_port: None,
+            ..Default::default()
+        };
+        let bind = |conf: ServiceConf| conf.bind_addr().map(|addr| addr.to_string());
+
+        // the defaults
+        assert_eq!(
+            bind(conf("0.0.0.0:3000", "http://127.0.0.1:3000")).unwrap(),
+            "0.0.0.0:3000"
+        );
+        // the port of the discover addr
+        assert_eq!(
+            bind(conf("0.0.0.0", "http://10.0.0.1:8080")).unwrap(),
+            "0.0.0.0:8080"
+        );
+        assert_eq!(
+            bind(conf("[::]", "http://10.0.0.1:8080")).unwrap(),
+            "[::]:8080"
+        );
+        assert_eq!(
+            bind(conf("", "http://10.0.0.1:8080")).unwrap(),
+            "10.0.0.1:8080"
+        );
+
+        assert!(matches!(
+            conf("", "http://book.svc:8080").bind_addr(),
+            Err(BindAddrError::Discover { .. })
+        ));
+        assert!(matches!(
+            conf("localhost:3000", "http://127.0.0.1:3000").bind_addr(),
+            Err(BindAddrError::Listen(_))
+        ));
+        assert!(matches!(
+            conf("0.0.0.0:0", "http://127.0.0.1:3000").bind_addr(),
+            Err(BindAddrError::ZeroPort)
+        ));
+        assert!(matches!(
+            conf("0.0.0.0:3001", "http://127.0.0.1:3000").bind_addr(),
+            Err(BindAddrError::PortMismatch {
+                listen: 3001,
+                registered: 3000
+            })
+        ));
+        // mapped port
+        let mapped = ServiceConf {
+            advertise_port: Some(443),
+            ..conf("0.0.0.0:3001", "http://127.0.0.1:3000")
+        };
+        assert_eq!(bind(mapped).unwrap(), "0.0.0.0:3001");
+    }
+}
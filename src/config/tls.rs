@@ -0,0 +1,280 @@
+/// Serving TLS with the certificate and private key files in [ServiceConf],
+/// the files could be reloaded in background for zero-downtime rotation.
+///
+/// `cert_file` and `key_file` of [RestServiceConf] are deprecated, they are only
+/// used by [RestServiceConf::tls_files] when `tls_cert` and `tls_key` of its service
+/// are not configured.
+///
+/// ```rust,no_run
+/// use common::config::service::ServiceConf;
+/// use std::time::Duration;
+/// use tokio_util::sync::CancellationToken;
+///
+/// let conf = ServiceConf::default();
+/// if let Some((server_config, cert)) = conf.reloadable_tls_config().unwrap() {
+///     cert.watch(Duration::from_secs(60), CancellationToken::new());
+///     // serve with `server_config`
+/// }
+/// ```
+use crate::config::service::{RestServiceConf, ServiceConf};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("the certificate and private key must be configured together")]
+    Incomplete,
+    #[error("cannot read {path}, err: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("unsupported private key in {0}")]
+    UnsupportedKey(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+impl ServiceConf {
+    /// The paths of certificate and private key, `None` if TLS is not configured
+    pub fn tls_files(&self) -> Result<Option<(&str, &str)>, TlsError> {
+        paired(&self.tls_cert, &self.tls_key)
+    }
+
+    /// Build the server TLS config, `None` if TLS is not configured
+    pub fn tls_config(&self) -> Result<Option<ServerConfig>, TlsError> {
+        self.tls_files()?.map(tls_config).transpose()
+    }
+
+    /// Same as [ServiceConf::tls_config] but the certificate could be reloaded
+    /// by the returned [ReloadableCert].
+    pub fn reloadable_tls_config(
+        &self,
+    ) -> Result<Option<(ServerConfig, Arc<ReloadableCert>)>, TlsError> {
+        self.tls_files()?.map(reloadable_tls_config).transpose()
+    }
+}
+
+impl RestServiceConf {
+    /// Same as [ServiceConf::tls_files], `tls_cert` and `tls_key` of the service take
+    /// precedence over the deprecated `cert_file` and `key_file`
+    pub fn tls_files(&self) -> Result<Option<(&str, &str)>, TlsError> {
+        if let Some(files) = self.service.tls_files()? {
+            return Ok(Some(files));
+        }
+        let files = paired(&self.cert_file, &self.key_file)?;
+        if files.is_some() {
+            warn!("cert_file and key_file are deprecated, use tls_cert and tls_key of service");
+        }
+        Ok(files)
+    }
+
+    /// Same as [ServiceConf::tls_config] with the files of [RestServiceConf::tls_files]
+    pub fn tls_config(&self) -> Result<Option<ServerConfig>, TlsError> {
+        self.tls_files()?.map(tls_config).transpose()
+    }
+
+    /// Same as [ServiceConf::reloadable_tls_config] with the files of
+    /// [RestServiceConf::tls_files]
+    pub fn reloadable_tls_config(
+        &self,
+    ) -> Result<Option<(ServerConfig, Arc<ReloadableCert>)>, TlsError> {
+        self.tls_files()?.map(reloadable_tls_config).transpose()
+    }
+}
+
+fn paired<'a>(
+    cert: &'a Option<String>,
+    key: &'a Option<String>,
+) -> Result<Option<(&'a str, &'a str)>, TlsError> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some((cert, key))),
+        (None, None) => Ok(None),
+        _ => Err(TlsError::Incomplete),
+    }
+}
+
+fn tls_config((cert, key): (&str, &str)) -> Result<ServerConfig, TlsError> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_private_key(key)?)?;
+    Ok(config)
+}
+
+fn reloadable_tls_config(
+    (cert, key): (&str, &str),
+) -> Result<(ServerConfig, Arc<ReloadableCert>), TlsError> {
+    let cert = Arc::new(ReloadableCert::new(cert, key)?);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(cert.clone());
+    Ok((config, cert))
+}
+
+fn read_file(path: &str) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| TlsError::Io {
+            path: path.to_string(),
+            source,
+        })
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut read_file(path)?).map_err(|source| TlsError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_string()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, TlsError> {
+    let mut reader = read_file(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader).map_err(|source| TlsError::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        match item {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(TlsError::NoPrivateKey(path.to_string())),
+        }
+    }
+}
+
+fn load_certified_key(cert: &str, key: &str) -> Result<CertifiedKey, TlsError> {
+    let certs = load_certs(cert)?;
+    let signing_key = rustls::sign::any_supported_type(&load_private_key(key)?)
+        .map_err(|_| TlsError::UnsupportedKey(key.to_string()))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// A certificate resolver serving the latest loaded certificate
+pub struct ReloadableCert {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    pub fn new(cert_path: impl ToString, key_path: impl ToString) -> Result<Self, TlsError> {
+        let cert_path = cert_path.to_string();
+        let key_path = key_path.to_string();
+        let current = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Load the files again, the current certificate is kept on error.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    /// Check the modification time of files every interval and reload on change,
+    /// until the shutdown token is cancelled.
+    pub fn watch(
+        self: Arc<Self>,
+        interval: Duration,
+        shutdown: CancellationToken,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut last = (modified(&self.cert_path), modified(&self.key_path));
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    _ = ticker.tick() => {}
+                }
+                let now = (modified(&self.cert_path), modified(&self.key_path));
+                if now == last {
+                    continue;
+                }
+                // files may be written one by one, retry on the next tick if failed
+                match self.reload() {
+                    Ok(_) => {
+                        info!("tls certificate {} reloaded", self.cert_path);
+                        last = now;
+                    }
+                    Err(err) => warn!("cannot reload tls certificate, err: {}", err),
+                }
+            }
+        })
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::service::{RestServiceConf, ServiceConf};
+    use crate::config::tls::TlsError;
+
+    #[test]
+    fn test_tls_files() {
+        let mut conf = ServiceConf {
+            tls_cert: None,
+            tls_key: None,
+            ..Default::default()
+        };
+        assert!(conf.tls_config().unwrap().is_none());
+
+        conf.tls_cert = Some("cert.pem".to_string());
+        assert!(matches!(conf.tls_files(), Err(TlsError::Incomplete)));
+
+        conf.tls_key = Some("not_found.pem".to_string());
+        assert!(matches!(conf.tls_config(), Err(TlsError::Io { .. })));
+
+        // the files of service take precedence over the deprecated ones
+        let mut rest = RestServiceConf {
+            service: conf,
+            cert_file: Some("legacy.pem".to_string()),
+            key_file: Some("legacy.key".to_string()),
+        };
+        assert_eq!(
+            rest.tls_files().unwrap(),
+            Some(("cert.pem", "not_found.pem"))
+        );
+        rest.service.tls_cert = None;
+        rest.service.tls_key = None;
+        assert_eq!(
+            rest.tls_files().unwrap(),
+            Some(("legacy.pem", "legacy.key"))
+        );
+    }
+}
@@ -1,7 +1,13 @@
 mod cqrs;
 mod id;
+mod readiness;
 mod resolver;
+#[cfg(feature = "unique-service-key")]
+mod service_key;
 
 pub use cqrs::*;
 pub use id::*;
+pub use readiness::*;
 pub use resolver::*;
+#[cfg(feature = "unique-service-key")]
+pub use service_key::*;
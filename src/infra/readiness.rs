@@ -0,0 +1,265 @@
+use crate::infra::Resolver;
+use crate::middleware::HealthCheck;
+use futures::future::{join_all, BoxFuture};
+use http::header::CONTENT_TYPE;
+use http::{Response, StatusCode};
+use serde::Serialize;
+use std::fmt::Display;
+use std::time::Duration;
+use tracing::info;
+
+/// The readiness of a service, it is ready only if all checks are ready.
+/// By default [Resolver::ready] pings the clients of [Resolver::health_checks],
+/// override it to express custom readiness, e.p.
+///
+/// ```rust,ignore
+/// async fn ready(&self) -> Readiness {
+///     Readiness::new()
+///         .ping_all(self.health_checks())
+///         .await
+///         .check("migration", self.migrated().then_some(()).ok_or("pending"))
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<ReadyCheck>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ReadyCheck {
+    pub name: String,
+    pub ready: bool,
+    /// The reason of not ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Readiness {
+    /// Ready without any check
+    pub fn new() -> Self {
+        Self {
+            ready: true,
+            checks: vec![],
+        }
+    }
+
+    /// Add a sub-check
+    pub fn check<E: Display>(mut self, name: impl Into<String>, result: Result<(), E>) -> Self {
+        let detail = result.err().map(|err| err.to_string());
+        self.ready &= detail.is_none();
+        self.checks.push(ReadyCheck {
+            name: name.into(),
+            ready: detail.is_none(),
+            detail,
+        });
+        self
+    }
+
+    /// Add a sub-check pinging a middleware client
+    pub async fn ping<H: HealthCheck + Sync>(self, name: impl Into<String>, client: &H) -> Self {
+        let result = client.ping().await;
+        self.check(name, result)
+    }
+
+    /// Add the sub-checks pinging middleware clients concurrently
+    pub async fn ping_all(mut self, pings: Vec<HealthPing>) -> Self {
+        let names: Vec<_> = pings.iter().map(|ping| ping.name.clone()).collect();
+        let results = join_all(pings.into_iter().map(|ping| ping.ping)).await;
+        for (name, result) in names.into_iter().zip(results) {
+            self = self.check(name, result);
+        }
+        self
+    }
+}
+
+/// A middleware client to be pinged, see [Resolver::health_checks]
+pub struct HealthPing {
+    name: String,
+    ping: BoxFuture<'static, Result<(), String>>,
+}
+
+impl HealthPing {
+    pub fn new<H>(name: impl Into<String>, client: H) -> Self
+    where
+        H: HealthCheck + Send + Sync + 'static,
+    {
+        Self {
+            name: name.into(),
+            ping: Box::pin(async move { client.ping().await.map_err(|err| err.to_string()) }),
+        }
+    }
+}
+
+/// Wait until the resolver is ready, the readiness is checked every `interval`
+/// and the not ready checks are logged, e.p. to defer the registration with
+/// [register_when_ready].
+///
+/// [register_when_ready]: crate::registry::ServiceRegister::register_when_ready
+pub async fn wait_ready<R: Resolver + Sync>(resolver: &R, interval: Duration) {
+    loop {
+        let readiness = resolver.ready().await;
+        if readiness.ready {
+            return;
+        }
+        for check in readiness.checks.iter().filter(|check| !check.ready) {
+            info!(
+                "{} is not ready yet, reason: {}",
+                check.name,
+                check.detail.as_deref().unwrap_or("unknown")
+            );
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// A handler to be mounted as `/readyz`, responds the readiness of resolver in JSON,
+/// `200 OK` if it is ready, otherwise `503 Service Unavailable`.
+pub async fn readyz_handler<R, ResBody>(resolver: &R) -> Response<ResBody>
+where
+    R: Resolver + Sync,
+    ResBody: From<String>,
+{
+    let readiness = resolver.ready().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(ResBody::from(serde_json::to_string(&readiness).unwrap()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::infra::{wait_ready, HealthPing, Readiness, Resolver, Target};
+    use crate::middleware::HealthCheck;
+    use crate::registry::ServiceRegister;
+    use async_trait::async_trait;
+    use serde::Deserialize;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_readiness() {
+        let readiness = Readiness::new().check("cache", Ok::<_, String>(()));
+        assert!(readiness.ready);
+
+        let readiness = readiness.check("migration", Err("pending"));
+        assert!(!readiness.ready);
+        assert_eq!(
+            serde_json::to_string(&readiness).unwrap(),
+            r#"{"ready":false,"checks":[{"name":"cache","ready":true},{"name":"migration","ready":false,"detail":"pending"}]}"#
+        );
+    }
+
+    #[derive(Clone, Default, Deserialize)]
+    struct MyConfig {}
+
+    #[derive(Default)]
+    struct MyResolver {
+        conf: MyConfig,
+        migrated: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Resolver for MyResolver {
+        const TARGET: Target = Target::GRPC;
+        const DOMAIN: &'static str = "sys";
+        type Config = MyConfig;
+
+        fn conf(&self) -> &Self::Config {
+            &self.conf
+        }
+
+        async fn ready(&self) -> Readiness {
+            let migrated = self.migrated.load(Ordering::SeqCst);
+            Readiness::new().check("migration", migrated.then_some(()).ok_or("pending"))
+        }
+    }
+
+    struct Pinged(Result<(), &'static str>);
+
+    #[async_trait]
+    impl HealthCheck for Pinged {
+        type Error = &'static str;
+
+        async fn ping(&self) -> Result<(), Self::Error> {
+            self.0
+        }
+    }
+
+    struct PingResolver(MyConfig);
+
+    impl Resolver for PingResolver {
+        const TARGET: Target = Target::GRPC;
+        const DOMAIN: &'static str = "sys";
+        type Config = MyConfig;
+
+        fn conf(&self) -> &Self::Config {
+            &self.0
+        }
+
+        fn health_checks(&self) -> Vec<HealthPing> {
+            vec![
+                HealthPing::new("redis", Pinged(Ok(()))),
+                HealthPing::new("etcd", Pinged(Err("unavailable"))),
+            ]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_ready() {
+        let readiness = PingResolver(MyConfig::default()).ready().await;
+        assert!(!readiness.ready);
+        assert_eq!(
+            serde_json::to_string(&readiness).unwrap(),
+            r#"{"ready":false,"checks":[{"name":"redis","ready":true},{"name":"etcd","ready":false,"detail":"unavailable"}]}"#
+        );
+    }
+
+    #[derive(Default)]
+    struct MyRegistry(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl ServiceRegister for MyRegistry {
+        type Error = ();
+
+        async fn register_service(&self, service_key: &str) -> Result<(), Self::Error> {
+            self.0.lock().unwrap().push(service_key.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_when_ready() {
+        let resolver = MyResolver::default();
+        let migrated = resolver.migrated.clone();
+        let registry = Arc::new(MyRegistry::default());
+        let task = {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                let ready = wait_ready(&resolver, Duration::from_millis(10));
+                registry
+                    .register_when_ready(&MyResolver::service_key(), ready)
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(registry.0.lock().unwrap().is_empty());
+
+        migrated.store(true, Ordering::SeqCst);
+        task.await.unwrap().unwrap();
+        assert_eq!(*registry.0.lock().unwrap(), vec!["sys-grpc"]);
+    }
+}
@@ -1,6 +1,9 @@
 use crate::config::register::Register;
 use crate::config::ConfigType;
+use crate::infra::{HealthPing, Readiness};
+use async_trait::async_trait;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 /// The target service type to be resolved by the resolver.
 pub enum Target {
@@ -19,7 +22,31 @@ impl Display for Target {
     }
 }
 
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rest" => Ok(Target::REST),
+            "grpc" => Ok(Target::GRPC),
+            "graphql" => Ok(Target::GRAPHQL),
+            _ => Err(format!("unknown target {}", s)),
+        }
+    }
+}
+
+/// Parse a service key generated by [Resolver::service_key] back into domain
+/// and target. The domain could contain `-`, only the final segment is the target.
+pub fn parse_service_key(key: &str) -> Option<(String, Target)> {
+    let (domain, target) = key.rsplit_once('-')?;
+    if domain.is_empty() {
+        return None;
+    }
+    Some((domain.to_string(), target.parse().ok()?))
+}
+
 /// Basic abstraction of Resolver
+#[async_trait]
 pub trait Resolver {
     /// The target to be resolved by the resolver.
     const TARGET: Target;
@@ -47,6 +74,19 @@ pub trait Resolver {
     fn resolve<T>(&self, register: &Register<Self::Config, T>) -> T {
         register.register(self.conf())
     }
+
+    /// The middleware clients pinged by the default [Resolver::ready],
+    /// e.p. `vec![HealthPing::new("redis", self.redis())]`. None by default.
+    fn health_checks(&self) -> Vec<HealthPing> {
+        vec![]
+    }
+
+    /// The readiness of the service, pings [Resolver::health_checks] by default.
+    /// Override it for the readiness beyond connecting to dependencies, e.p.
+    /// migrations completed, caches warmed or leadership acquired, see [Readiness].
+    async fn ready(&self) -> Readiness {
+        Readiness::new().ping_all(self.health_checks()).await
+    }
 }
 
 #[cfg(test)]
@@ -55,7 +95,7 @@ mod test {
     use crate::config::register::Register;
     use crate::config::service::ServiceConfig;
     use crate::config::Config;
-    use crate::infra::{Resolver, Target};
+    use crate::infra::{parse_service_key, Resolver, Target};
     use serde::{Deserialize, Serialize};
 
     type MyRegister<T> = Register<MyConfig, T>;
@@ -102,4 +142,19 @@ mod test {
         let client = resolver.redis();
         println!("{:?}", client);
     }
+
+    #[test]
+    fn test_parse_service_key() {
+        let (domain, target) = parse_service_key(&MyResolver::service_key()).unwrap();
+        assert_eq!(domain, "sys");
+        assert_eq!(target.to_string(), "grpc");
+
+        let (domain, target) = parse_service_key("user-profile-rest").unwrap();
+        assert_eq!(domain, "user-profile");
+        assert_eq!(target.to_string(), "rest");
+
+        assert!(parse_service_key("sys").is_none());
+        assert!(parse_service_key("-grpc").is_none());
+        assert!(parse_service_key("sys-http").is_none());
+    }
 }
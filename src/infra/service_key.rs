@@ -0,0 +1,80 @@
+/// Detect the resolvers sharing a service key, which would collide silently in
+/// the registry. Each resolver is registered with [register_resolver], and the
+/// keys of all resolvers linked in the binary are checked at startup by
+/// [parse_config] (or [assert_unique_service_keys]).
+///
+/// ```rust,ignore
+/// common::register_resolver!(UserResolver);
+/// ```
+///
+/// [register_resolver]: crate::register_resolver
+/// [parse_config]: crate::utils::parse_config
+use std::collections::BTreeMap;
+
+#[doc(hidden)]
+pub use inventory;
+
+/// A registered resolver, see [register_resolver](crate::register_resolver)
+pub struct ResolverEntry {
+    pub type_name: &'static str,
+    pub service_key: fn() -> String,
+}
+
+inventory::collect!(ResolverEntry);
+
+/// Register a resolver to check the uniqueness of its service key
+#[macro_export]
+macro_rules! register_resolver {
+    ($resolver:ty) => {
+        $crate::infra::inventory::submit! {
+            $crate::infra::ResolverEntry {
+                type_name: stringify!($resolver),
+                service_key: <$resolver as $crate::infra::Resolver>::service_key,
+            }
+        }
+    };
+}
+
+/// The conflicts in `(service key, resolver type)`, e.p.
+/// `service key user-grpc is shared by UserResolver, AccountResolver`
+fn conflicts<'a>(entries: impl IntoIterator<Item = (String, &'a str)>) -> Vec<String> {
+    let mut keys: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (key, type_name) in entries {
+        keys.entry(key).or_default().push(type_name);
+    }
+    keys.into_iter()
+        .filter(|(_, types)| types.len() > 1)
+        .map(|(key, types)| format!("service key {} is shared by {}", key, types.join(", ")))
+        .collect()
+}
+
+/// Panic if any service key is shared by the registered resolvers
+pub fn assert_unique_service_keys() {
+    let conflicts = conflicts(
+        inventory::iter::<ResolverEntry>
+            .into_iter()
+            .map(|entry| ((entry.service_key)(), entry.type_name)),
+    );
+    if !conflicts.is_empty() {
+        panic!("duplicated service keys: {}", conflicts.join("; "));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::infra::service_key::conflicts;
+
+    #[test]
+    fn test_conflicts() {
+        let entries = [
+            ("user-grpc".to_string(), "UserResolver"),
+            ("user-rest".to_string(), "UserRestResolver"),
+            ("user-grpc".to_string(), "AccountResolver"),
+        ];
+        assert_eq!(
+            conflicts(entries),
+            vec!["service key user-grpc is shared by UserResolver, AccountResolver"]
+        );
+        assert!(conflicts([("user-grpc".to_string(), "UserResolver")]).is_empty());
+    }
+}
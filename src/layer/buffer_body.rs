@@ -0,0 +1,261 @@
+/// Buffer the request body into memory, so that layers behind it could read or
+/// re-send the body (retry, idempotency, etc.) with [BufferedBody] or [clone_request].
+///
+/// It is opt-in since buffering costs memory, a body over the limit is rejected
+/// with `413 Payload Too Large` before it is fully read, and a body failed to be
+/// read is rejected with `400 Bad Request`.
+use crate::status::error_body::{ErrorBody, ErrorResponseBody, RequestId};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use http::header::CONTENT_LENGTH;
+use http::{Request, Response, StatusCode};
+use http_body::{Body, Full};
+use std::fmt::Display;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::warn;
+
+const DEFAULT_BUFFER_LIMIT: usize = 2 * 1024 * 1024;
+
+/// The buffered request body in request extensions, cheap to clone
+#[derive(Clone, Debug)]
+pub struct BufferedBody(pub Bytes);
+
+/// Clone a buffered request for re-sending, the extensions other than [BufferedBody]
+/// are not cloned. Returns `None` if the request is not buffered.
+pub fn clone_request<B: From<Bytes>>(req: &Request<B>) -> Option<Request<B>> {
+    let body = req.extensions().get::<BufferedBody>()?.clone();
+    let mut cloned = Request::new(B::from(body.0.clone()));
+    *cloned.method_mut() = req.method().clone();
+    *cloned.uri_mut() = req.uri().clone();
+    *cloned.version_mut() = req.version();
+    *cloned.headers_mut() = req.headers().clone();
+    cloned.extensions_mut().insert(body);
+    Some(cloned)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BufferBodyLayer {
+    limit: usize,
+}
+
+impl Default for BufferBodyLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BufferBodyLayer {
+    /// Buffer bodies up to 2 MiB
+    pub fn new() -> Self {
+        Self {
+            limit: DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    /// The max bytes of a buffered body
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+}
+
+impl<S> Layer<S> for BufferBodyLayer {
+    type Service = BufferBody<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BufferBody {
+            inner,
+            limit: self.limit,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BufferBody<S> {
+    inner: S,
+    limit: usize,
+}
+
+enum Buffering<E> {
+    TooLarge,
+    Failed(E),
+}
+
+/// Read the whole body into memory, stop reading once it is over the limit
+async fn to_bytes_limited<B: Body>(body: B, limit: usize) -> Result<Bytes, Buffering<B::Error>> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(Buffering::TooLarge);
+    }
+    tokio::pin!(body);
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(Buffering::Failed)?;
+        if buf.len() + chunk.remaining() > limit {
+            return Err(Buffering::TooLarge);
+        }
+        // a chunk may be made of several segments
+        buf.put(chunk);
+    }
+    Ok(buf.freeze())
+}
+
+fn status_response<B: ErrorResponseBody>(
+    request_id: Option<&RequestId>,
+    status: StatusCode,
+) -> Response<B> {
+    ErrorBody {
+        request_id: request_id.map(|id| id.0.clone()),
+        ..ErrorBody::from_status(status)
+    }
+    .into_response(status)
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for BufferBody<S>
+where
+    S: Service<Request<Full<Bytes>>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Body + Send + 'static,
+    ReqBody::Data: Send,
+    ReqBody::Error: Display + Send,
+    ResBody: ErrorResponseBody,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let limit = self.limit;
+        let declared = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if matches!(declared, Some(len) if len > limit as u64) {
+            let request_id = req.extensions().get::<RequestId>().cloned();
+            return Box::pin(async move {
+                Ok(status_response(
+                    request_id.as_ref(),
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                ))
+            });
+        }
+
+        // take the service which is ready
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let body = match to_bytes_limited(body, limit).await {
+                Ok(body) => body,
+                Err(Buffering::TooLarge) => {
+                    return Ok(status_response(
+                        parts.extensions.get(),
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    ))
+                }
+                Err(Buffering::Failed(err)) => {
+                    warn!("cannot read request body, err: {}", err);
+                    return Ok(status_response(
+                        parts.extensions.get(),
+                        StatusCode::BAD_REQUEST,
+                    ));
+                }
+            };
+            parts.extensions.insert(BufferedBody(body.clone()));
+            inner
+                .call(Request::from_parts(parts, Full::new(body)))
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::buffer_body::{clone_request, to_bytes_limited, BufferBodyLayer};
+    use bytes::{Buf, Bytes};
+    use http::{HeaderMap, Request, Response, StatusCode};
+    use http_body::{Body, Full};
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tower::{service_fn, Layer, ServiceExt};
+
+    // a streaming body without size hint
+    struct Chunks(VecDeque<Bytes>);
+
+    impl Body for Chunks {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffer_body() {
+        let svc = BufferBodyLayer::new().limit(8).layer(service_fn(
+            |req: Request<Full<Bytes>>| async move {
+                let cloned = clone_request(&req).unwrap();
+                let body = read_body(cloned.into_body()).await;
+                Ok::<_, Infallible>(Response::new(Full::new(body)))
+            },
+        ));
+        let chunks = |chunks: &[&'static str]| {
+            Chunks(chunks.iter().map(|chunk| Bytes::from(*chunk)).collect())
+        };
+
+        let res = svc
+            .clone()
+            .oneshot(Request::post("/").body(chunks(&["1234", "5678"])).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(read_body(res.into_body()).await, "12345678");
+
+        // over the limit while streaming
+        let res = svc
+            .clone()
+            .oneshot(Request::post("/").body(chunks(&["1234", "56789"])).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // over the limit by content-length
+        let res = svc
+            .oneshot(
+                Request::post("/")
+                    .header("content-length", "9")
+                    .body(chunks(&[]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // a chunk made of several segments
+        let segmented = Full::new(Bytes::from("1234").chain(Bytes::from("5678")));
+        let body = to_bytes_limited(segmented, 8).await.ok().unwrap();
+        assert_eq!(body, "12345678");
+    }
+
+    async fn read_body(body: Full<Bytes>) -> Bytes {
+        crate::layer::to_bytes(body).await.unwrap()
+    }
+}
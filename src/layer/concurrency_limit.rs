@@ -0,0 +1,309 @@
+/// Cap the number of in-flight requests to protect a service from overload. When
+/// the limit is reached, a request is either shed with `503 Service Unavailable`
+/// at once, or queued for a bounded wait and shed if no slot is freed in time.
+///
+/// The slot is acquired when the service is polled ready, and held until the
+/// response body is fully streamed or dropped.
+///
+/// ```rust,ignore
+/// let layer = ConcurrencyLimitLayer::new(128).queue(Duration::from_millis(100));
+/// // export `layer.in_flight()` as a gauge
+/// ```
+use crate::status::error_body::{error_response, ErrorResponseBody};
+use futures::future::BoxFuture;
+use futures::ready;
+use http::{HeaderMap, Request, Response, StatusCode};
+use http_body::{Body, SizeHint};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
+use tokio_util::sync::PollSemaphore;
+use tower::{Layer, Service};
+
+/// What to do with a request when the limit is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overload {
+    /// Respond `503` immediately
+    Shed,
+    /// Wait for a slot at most the duration, then respond `503`
+    Queue(Duration),
+}
+
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimitLayer {
+    semaphore: Arc<Semaphore>,
+    limit: usize,
+    overload: Overload,
+}
+
+impl ConcurrencyLimitLayer {
+    /// Shed the requests over the limit by default
+    pub fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit,
+            overload: Overload::Shed,
+        }
+    }
+
+    pub fn shed(mut self) -> Self {
+        self.overload = Overload::Shed;
+        self
+    }
+
+    pub fn queue(mut self, timeout: Duration) -> Self {
+        self.overload = Overload::Queue(timeout);
+        self
+    }
+
+    /// The number of in-flight requests of all the services made by this layer
+    pub fn in_flight(&self) -> usize {
+        self.limit - self.semaphore.available_permits()
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConcurrencyLimit {
+            inner,
+            semaphore: PollSemaphore::new(self.semaphore.clone()),
+            overload: self.overload,
+            permit: None,
+            queued: None,
+            shed: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConcurrencyLimit<S> {
+    inner: S,
+    semaphore: PollSemaphore,
+    overload: Overload,
+    /// The slot acquired by [Service::poll_ready] for the next request
+    permit: Option<OwnedSemaphorePermit>,
+    /// The deadline of waiting for a slot in queue
+    queued: Option<Pin<Box<Sleep>>>,
+    /// No slot is acquired in time, the next request is shed
+    shed: bool,
+}
+
+impl<S: Clone> Clone for ConcurrencyLimit<S> {
+    // the acquired slot is not shared with the clones
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            semaphore: self.semaphore.clone(),
+            overload: self.overload,
+            permit: None,
+            queued: None,
+            shed: false,
+        }
+    }
+}
+
+impl<S> ConcurrencyLimit<S> {
+    fn poll_permit(&mut self, cx: &mut Context<'_>) -> Poll<Option<OwnedSemaphorePermit>> {
+        let timeout = match self.overload {
+            Overload::Shed => {
+                return Poll::Ready(self.semaphore.clone_inner().try_acquire_owned().ok())
+            }
+            Overload::Queue(timeout) => timeout,
+        };
+        let queued = self
+            .queued
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        if let Poll::Ready(permit) = self.semaphore.poll_acquire(cx) {
+            self.queued = None;
+            return Poll::Ready(permit);
+        }
+        ready!(queued.as_mut().poll(cx));
+        self.queued = None;
+        Poll::Ready(None)
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ConcurrencyLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: ErrorResponseBody + Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = Response<ConcurrencyLimitBody<ResBody>>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.permit.is_none() && !self.shed {
+            self.permit = ready!(self.poll_permit(cx));
+            self.shed = self.permit.is_none();
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        self.shed = false;
+        let permit = match self.permit.take() {
+            Some(permit) => permit,
+            None => {
+                let res = error_response(&req, StatusCode::SERVICE_UNAVAILABLE).map(|inner| {
+                    ConcurrencyLimitBody {
+                        inner,
+                        permit: None,
+                    }
+                });
+                return Box::pin(futures::future::ready(Ok(res)));
+            }
+        };
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(res.map(|inner| ConcurrencyLimitBody {
+                inner,
+                permit: Some(permit),
+            }))
+        })
+    }
+}
+
+pin_project! {
+    /// A body holding the slot of its request until it is fully streamed or dropped
+    pub struct ConcurrencyLimitBody<B> {
+        #[pin]
+        inner: B,
+        permit: Option<OwnedSemaphorePermit>,
+    }
+}
+
+impl<B: Default> Default for ConcurrencyLimitBody<B> {
+    fn default() -> Self {
+        Self {
+            inner: B::default(),
+            permit: None,
+        }
+    }
+}
+
+impl<B: ErrorResponseBody> ErrorResponseBody for ConcurrencyLimitBody<B> {
+    fn from_json(json: Vec<u8>) -> Option<Self> {
+        B::from_json(json).map(|inner| Self {
+            inner,
+            permit: None,
+        })
+    }
+}
+
+impl<B: Body> Body for ConcurrencyLimitBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let data = ready!(this.inner.poll_data(cx));
+        if data.is_none() {
+            this.permit.take();
+        }
+        Poll::Ready(data)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.project();
+        let trailers = ready!(this.inner.poll_trailers(cx));
+        this.permit.take();
+        Poll::Ready(trailers)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::{to_bytes, ConcurrencyLimitLayer};
+    use bytes::Bytes;
+    use http::{Request, Response, StatusCode};
+    use http_body::Full;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        // requests are blocked until the gate is opened
+        let gate = Arc::new(Semaphore::new(0));
+        let inner = {
+            let gate = gate.clone();
+            service_fn(move |_req: Request<()>| {
+                let gate = gate.clone();
+                async move {
+                    gate.acquire().await.unwrap().forget();
+                    Ok::<_, Infallible>(Response::new(()))
+                }
+            })
+        };
+        let call = |layer: &ConcurrencyLimitLayer| {
+            let svc = layer.layer(inner.clone());
+            tokio::spawn(async move {
+                let req = Request::get("/").body(()).unwrap();
+                svc.oneshot(req).await.unwrap().status()
+            })
+        };
+
+        let shed = ConcurrencyLimitLayer::new(2);
+        let blocked = [call(&shed), call(&shed)];
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(shed.in_flight(), 2);
+        assert_eq!(call(&shed).await.unwrap(), StatusCode::SERVICE_UNAVAILABLE);
+        gate.add_permits(2);
+        for res in blocked {
+            assert_eq!(res.await.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(shed.in_flight(), 0);
+
+        let queue = ConcurrencyLimitLayer::new(1).queue(Duration::from_millis(100));
+        let blocked = call(&queue);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // times out in the queue
+        assert_eq!(call(&queue).await.unwrap(), StatusCode::SERVICE_UNAVAILABLE);
+        // served once the slot is freed
+        let queued = call(&queue);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        gate.add_permits(2);
+        assert_eq!(blocked.await.unwrap(), StatusCode::OK);
+        assert_eq!(queued.await.unwrap(), StatusCode::OK);
+
+        // the slot is held until the body is streamed
+        let streaming = ConcurrencyLimitLayer::new(1);
+        let svc = streaming.layer(service_fn(|_req: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("hello"))))
+        }));
+        let request = || Request::get("/").body(()).unwrap();
+        let res = svc.clone().oneshot(request()).await.unwrap();
+        assert_eq!(streaming.in_flight(), 1);
+        let shed = svc.clone().oneshot(request()).await.unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "hello");
+        assert_eq!(streaming.in_flight(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+/// Reject requests whose `Content-Type` is not allowed with `415 Unsupported Media Type`,
+/// so that handlers do not have to check it defensively.
+///
+/// Only requests carrying a body with `POST`, `PUT` or `PATCH` are checked. The allowed
+/// set could be overridden per route by inserting [AllowedContentTypes] into request
+/// extensions before this layer.
+use crate::status::error_body::{error_response, ErrorResponseBody};
+use futures::future::{ready, Either, Ready};
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use http_body::Body;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// Allowed media types of a route, e.p. `["multipart/form-data"]` for an upload route
+#[derive(Clone, Debug)]
+pub struct AllowedContentTypes(pub Vec<String>);
+
+impl AllowedContentTypes {
+    /// Whether the content type (with or without parameters) is allowed,
+    /// `type/*` allows all subtypes.
+    fn allows(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        self.0
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(typ) => essence
+                    .split_once('/')
+                    .map(|(other, _)| other.eq_ignore_ascii_case(typ))
+                    .unwrap_or(false),
+                None => allowed.eq_ignore_ascii_case(essence),
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ContentTypeLayer {
+    allowed: Arc<AllowedContentTypes>,
+}
+
+impl ContentTypeLayer {
+    pub fn new<T: ToString>(allowed: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            allowed: Arc::new(AllowedContentTypes(
+                allowed.into_iter().map(|typ| typ.to_string()).collect(),
+            )),
+        }
+    }
+
+    /// Only `application/json` is allowed
+    pub fn json() -> Self {
+        Self::new(["application/json"])
+    }
+}
+
+impl<S> Layer<S> for ContentTypeLayer {
+    type Service = ContentType<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ContentType {
+            inner,
+            allowed: self.allowed.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ContentType<S> {
+    inner: S,
+    allowed: Arc<AllowedContentTypes>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ContentType<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ReqBody: Body,
+    ResBody: ErrorResponseBody,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<S::Response, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let has_body = matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH)
+            && !req.body().is_end_stream();
+        if has_body {
+            let allowed = req
+                .extensions()
+                .get::<AllowedContentTypes>()
+                .unwrap_or(&*self.allowed);
+            let content_type = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok());
+            if !content_type
+                .map(|content_type| allowed.allows(content_type))
+                .unwrap_or(false)
+            {
+                let res = error_response(&req, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                return Either::Left(ready(Ok(res)));
+            }
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::content_type::{AllowedContentTypes, ContentTypeLayer};
+    use bytes::Bytes;
+    use http::{Request, Response, StatusCode};
+    use http_body::Full;
+    use std::convert::Infallible;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_content_type() {
+        let svc = ContentTypeLayer::json().layer(service_fn(|_req: Request<Full<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::<Bytes>::default()))
+        }));
+        let status = |req: Request<Full<Bytes>>| {
+            let svc = svc.clone();
+            async move { svc.oneshot(req).await.unwrap().status() }
+        };
+        let body = || Full::new(Bytes::from("{}"));
+
+        // allowed
+        let req = Request::post("/")
+            .header("content-type", "application/json; charset=utf-8")
+            .body(body())
+            .unwrap();
+        assert_eq!(status(req).await, StatusCode::OK);
+
+        // disallowed
+        let req = Request::post("/")
+            .header("content-type", "text/plain")
+            .body(body())
+            .unwrap();
+        assert_eq!(status(req).await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // missing
+        let req = Request::put("/").body(body()).unwrap();
+        assert_eq!(status(req).await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // bodiless
+        let req = Request::get("/").body(body()).unwrap();
+        assert_eq!(status(req).await, StatusCode::OK);
+        let req = Request::post("/").body(Full::default()).unwrap();
+        assert_eq!(status(req).await, StatusCode::OK);
+
+        // overridden by route
+        let mut req = Request::post("/upload")
+            .header("content-type", "image/png")
+            .body(body())
+            .unwrap();
+        req.extensions_mut()
+            .insert(AllowedContentTypes(vec!["image/*".to_string()]));
+        assert_eq!(status(req).await, StatusCode::OK);
+    }
+}
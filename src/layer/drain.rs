@@ -0,0 +1,217 @@
+/// Count the in-flight requests so that a graceful shutdown could tell whether it
+/// drained cleanly or was forced with requests still pending.
+///
+/// ```rust,ignore
+/// let drain = DrainLayer::new();
+/// let server = Server::builder().layer(drain.clone()) /* ... */;
+/// // on shutdown signal, stop accepting and then
+/// drain_in_flight(&drain, Duration::from_secs(30)).await;
+/// ```
+///
+/// A [ConcurrencyLimitLayer] tracks its in-flight requests already, it could be
+/// drained without this layer.
+///
+/// [ConcurrencyLimitLayer]: crate::layer::ConcurrencyLimitLayer
+use crate::layer::ConcurrencyLimitLayer;
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tower::{Layer, Service};
+use tracing::{info, warn};
+
+/// Anything counting the in-flight requests
+pub trait InFlight {
+    fn in_flight(&self) -> usize;
+}
+
+impl InFlight for ConcurrencyLimitLayer {
+    fn in_flight(&self) -> usize {
+        ConcurrencyLimitLayer::in_flight(self)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DrainLayer {
+    counter: Arc<Counter>,
+}
+
+impl DrainLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl InFlight for DrainLayer {
+    fn in_flight(&self) -> usize {
+        self.counter.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+impl<S> Layer<S> for DrainLayer {
+    type Service = Drain<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Drain {
+            inner,
+            counter: self.counter.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Drain<S> {
+    inner: S,
+    counter: Arc<Counter>,
+}
+
+/// Decrease the counter once the request completes or is dropped
+struct Guard(Arc<Counter>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl<S, Req> Service<Req> for Drain<S>
+where
+    S: Service<Req>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.counter.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = Guard(self.counter.clone());
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let _guard = guard;
+            fut.await
+        })
+    }
+}
+
+/// The result of draining
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Requests completed during the grace period
+    pub drained: usize,
+    /// Requests still in flight when the grace period expired
+    pub forced: usize,
+    pub elapsed: Duration,
+}
+
+/// Wait until no request is in flight or `grace` expires, the final counts are
+/// logged as structured fields.
+pub async fn drain_in_flight(tracker: &impl InFlight, grace: Duration) -> DrainReport {
+    const POLL_INTERVAL: Duration = Duration::from_millis(20);
+    let start = Instant::now();
+    let pending = tracker.in_flight();
+    while tracker.in_flight() > 0 && start.elapsed() < grace {
+        let left = grace.saturating_sub(start.elapsed());
+        tokio::time::sleep(left.min(POLL_INTERVAL)).await;
+    }
+    report(pending, tracker.in_flight(), start)
+}
+
+fn report(pending: usize, forced: usize, start: Instant) -> DrainReport {
+    let report = DrainReport {
+        drained: pending.saturating_sub(forced),
+        forced,
+        elapsed: start.elapsed(),
+    };
+    if forced == 0 {
+        info!(
+            drained = report.drained,
+            forced = 0,
+            elapsed_ms = report.elapsed.as_millis() as u64,
+            "in-flight requests are drained"
+        );
+    } else {
+        warn!(
+            drained = report.drained,
+            forced = report.forced,
+            elapsed_ms = report.elapsed.as_millis() as u64,
+            "grace period expired with {} requests in flight",
+            report.forced
+        );
+    }
+    report
+}
+
+impl DrainLayer {
+    /// Same as [drain_in_flight] but return as soon as the last request completes
+    pub async fn drain(&self, grace: Duration) -> DrainReport {
+        let start = Instant::now();
+        let pending = self.in_flight();
+        let counter = &self.counter;
+        let idle = async {
+            loop {
+                let notified = counter.idle.notified();
+                if counter.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(grace, idle).await;
+        report(pending, self.in_flight(), start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::{drain_in_flight, DrainLayer, InFlight};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = DrainLayer::new();
+        let svc = drain.layer(service_fn(|rx: oneshot::Receiver<()>| async move {
+            let _ = rx.await;
+            Ok::<_, Infallible>(())
+        }));
+        let (finish, rx) = oneshot::channel();
+        let (_stuck, stuck_rx) = oneshot::channel();
+        let finished = tokio::spawn(svc.clone().oneshot(rx));
+        let stuck = tokio::spawn(svc.oneshot(stuck_rx));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(drain.in_flight(), 2);
+
+        // one completes during the grace period
+        let report = tokio::spawn({
+            let drain = drain.clone();
+            async move { drain.drain(Duration::from_millis(50)).await }
+        });
+        finish.send(()).unwrap();
+        finished.await.unwrap().unwrap();
+        let report = report.await.unwrap();
+        assert_eq!((report.drained, report.forced), (1, 1));
+
+        stuck.abort();
+        let _ = stuck.await;
+        assert_eq!(drain.in_flight(), 0);
+        let report = drain_in_flight(&drain, Duration::from_millis(50)).await;
+        assert_eq!((report.drained, report.forced), (0, 0));
+    }
+}
@@ -0,0 +1,370 @@
+/// Verify signed requests of server-to-server APIs (webhooks, internal APIs, etc.)
+/// with a shared secret per key id, instead of a JWT.
+///
+/// The signature is a HMAC over the canonical request:
+/// ```text
+/// {METHOD}\n{path and query}\n{timestamp}\n{hex(sha256(body))}
+/// ```
+/// carried by headers `x-key-id`, `x-timestamp` (unix seconds) and `x-signature`
+/// (hex by default). A mismatched signature is rejected with `401 Unauthorized`
+/// and a timestamp out of the replay window with `400 Bad Request`.
+///
+/// The body is read from [BufferedBody], so deploy it behind [BufferBodyLayer].
+/// On success, the [KeyId] is inserted into request extensions as the identity,
+/// e.p. `RoleMappingLayer<KeyId, E>`.
+///
+/// [BufferBodyLayer]: crate::layer::BufferBodyLayer
+use crate::layer::BufferedBody;
+use crate::status::error_body::{error_response, ErrorResponseBody};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::future::{ready, Either, Ready};
+use hmac::{Hmac, KeyInit, Mac};
+use http::header::HeaderName;
+use http::{HeaderValue, Request, Response, StatusCode};
+use http_body::Body;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// The key id of a verified request in request extensions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyId(pub String);
+
+impl AsRef<str> for KeyId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The HMAC algorithm
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HmacScheme {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+/// How the signature is encoded in the header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+#[derive(Clone, Debug)]
+struct HmacHeaders {
+    key_id: HeaderName,
+    timestamp: HeaderName,
+    signature: HeaderName,
+}
+
+#[derive(Clone)]
+pub struct HmacAuthLayer {
+    keys: Arc<HashMap<String, Vec<u8>>>,
+    headers: HmacHeaders,
+    scheme: HmacScheme,
+    encoding: SignatureEncoding,
+    window: Duration,
+}
+
+impl Default for HmacAuthLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HmacAuthLayer {
+    /// HMAC-SHA256 with hex signatures and a 5 minutes replay window
+    pub fn new() -> Self {
+        Self {
+            keys: Arc::new(HashMap::new()),
+            headers: HmacHeaders {
+                key_id: HeaderName::from_static("x-key-id"),
+                timestamp: HeaderName::from_static("x-timestamp"),
+                signature: HeaderName::from_static("x-signature"),
+            },
+            scheme: HmacScheme::default(),
+            encoding: SignatureEncoding::default(),
+            window: Duration::from_secs(300),
+        }
+    }
+
+    /// Add the shared secret of a key id
+    pub fn key(mut self, key_id: impl ToString, secret: impl AsRef<[u8]>) -> Self {
+        Arc::make_mut(&mut self.keys).insert(key_id.to_string(), secret.as_ref().to_vec());
+        self
+    }
+
+    pub fn key_id_header(mut self, name: HeaderName) -> Self {
+        self.headers.key_id = name;
+        self
+    }
+
+    pub fn timestamp_header(mut self, name: HeaderName) -> Self {
+        self.headers.timestamp = name;
+        self
+    }
+
+    pub fn signature_header(mut self, name: HeaderName) -> Self {
+        self.headers.signature = name;
+        self
+    }
+
+    pub fn scheme(mut self, scheme: HmacScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: SignatureEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// The max difference between the timestamp and now
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sign the request with the secret of key id, used by the callers.
+    /// Returns `false` if the key id is unknown.
+    pub fn sign<B>(&self, key_id: &str, req: &mut Request<B>, body: &[u8]) -> bool {
+        let secret = match self.keys.get(key_id) {
+            Some(secret) => secret,
+            None => return false,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        let canonical = canonical_request(req, &timestamp, body);
+        let signature = match self.encoding {
+            SignatureEncoding::Hex => to_hex(&self.scheme.sign(secret, &canonical)),
+            SignatureEncoding::Base64 => STANDARD.encode(self.scheme.sign(secret, &canonical)),
+        };
+        let headers = req.headers_mut();
+        for (name, value) in [
+            (&self.headers.key_id, key_id.to_string()),
+            (&self.headers.timestamp, timestamp),
+            (&self.headers.signature, signature),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name.clone(), value);
+            }
+        }
+        true
+    }
+}
+
+impl HmacScheme {
+    fn sign(&self, secret: &[u8], canonical: &str) -> Vec<u8> {
+        match self {
+            HmacScheme::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length");
+                mac.update(canonical.as_bytes());
+                mac.finalize().into_bytes().to_vec()
+            }
+            HmacScheme::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("any key length");
+                mac.update(canonical.as_bytes());
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Compare in constant time
+    fn verify(&self, secret: &[u8], canonical: &str, signature: &[u8]) -> bool {
+        match self {
+            HmacScheme::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length");
+                mac.update(canonical.as_bytes());
+                mac.verify_slice(signature).is_ok()
+            }
+            HmacScheme::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(secret).expect("any key length");
+                mac.update(canonical.as_bytes());
+                mac.verify_slice(signature).is_ok()
+            }
+        }
+    }
+}
+
+fn canonical_request<B>(req: &Request<B>, timestamp: &str, body: &[u8]) -> String {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    format!(
+        "{}\n{}\n{}\n{}",
+        req.method(),
+        path,
+        timestamp,
+        to_hex(&Sha256::digest(body))
+    )
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+impl<S> Layer<S> for HmacAuthLayer {
+    type Service = HmacAuth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HmacAuth {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HmacAuth<S> {
+    inner: S,
+    layer: HmacAuthLayer,
+}
+
+impl<S> HmacAuth<S> {
+    /// Verify the request and return the key id, or the rejected status
+    fn verify<B: Body>(&self, req: &Request<B>) -> Result<KeyId, StatusCode> {
+        let layer = &self.layer;
+        let header = |name: &HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or(StatusCode::UNAUTHORIZED)
+        };
+        let key_id = header(&layer.headers.key_id)?;
+        let timestamp = header(&layer.headers.timestamp)?;
+        let signature = header(&layer.headers.signature)?;
+
+        let signed_at = timestamp
+            .parse::<u64>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(signed_at) > layer.window.as_secs() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let body = match req.extensions().get::<BufferedBody>() {
+            Some(body) => body.0.as_ref(),
+            None if req.body().is_end_stream() => &[],
+            None => {
+                warn!("request body is not buffered, deploy HmacAuthLayer behind BufferBodyLayer");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let secret = layer.keys.get(key_id).ok_or(StatusCode::UNAUTHORIZED)?;
+        let signature = match layer.encoding {
+            SignatureEncoding::Hex => from_hex(signature),
+            SignatureEncoding::Base64 => STANDARD.decode(signature).ok(),
+        }
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+        let canonical = canonical_request(req, timestamp, body);
+        if !layer.scheme.verify(secret, &canonical, &signature) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(KeyId(key_id.to_string()))
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for HmacAuth<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ReqBody: Body,
+    ResBody: ErrorResponseBody,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<S::Response, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        match self.verify(&req) {
+            Ok(key_id) => {
+                req.extensions_mut().insert(key_id);
+                Either::Right(self.inner.call(req))
+            }
+            Err(status) => {
+                let res = error_response(&req, status);
+                Either::Left(ready(Ok(res)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::hmac_auth::{HmacAuthLayer, KeyId};
+    use crate::layer::BufferBodyLayer;
+    use bytes::Bytes;
+    use http::{Request, Response, StatusCode};
+    use http_body::Full;
+    use std::convert::Infallible;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_hmac_auth() {
+        let hmac = HmacAuthLayer::new().key("billing", "secret");
+        let svc = BufferBodyLayer::new().layer(hmac.clone().layer(service_fn(
+            |req: Request<Full<Bytes>>| async move {
+                assert_eq!(req.extensions().get::<KeyId>().unwrap().0, "billing");
+                Ok::<_, Infallible>(Response::new(Full::<Bytes>::default()))
+            },
+        )));
+        let status = |req: Request<Full<Bytes>>| {
+            let svc = svc.clone();
+            async move { svc.oneshot(req).await.unwrap().status() }
+        };
+        let signed = |body: &'static str| {
+            let mut req = Request::post("/hook?id=1")
+                .body(Full::new(Bytes::from(body)))
+                .unwrap();
+            assert!(hmac.sign("billing", &mut req, body.as_bytes()));
+            req
+        };
+
+        assert_eq!(status(signed("{}")).await, StatusCode::OK);
+
+        // tampered body
+        let mut req = signed("{}");
+        *req.body_mut() = Full::new(Bytes::from("{\"amount\":1}"));
+        assert_eq!(status(req).await, StatusCode::UNAUTHORIZED);
+
+        // stale timestamp
+        let mut req = signed("{}");
+        req.headers_mut()
+            .insert("x-timestamp", "1".parse().unwrap());
+        assert_eq!(status(req).await, StatusCode::BAD_REQUEST);
+
+        // unsigned
+        let req = Request::post("/hook").body(Full::default()).unwrap();
+        assert_eq!(status(req).await, StatusCode::UNAUTHORIZED);
+    }
+}
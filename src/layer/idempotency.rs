@@ -0,0 +1,681 @@
+/// Idempotency-key deduplication layer for write endpoints.
+///
+/// A request carrying an `Idempotency-Key` header is executed at most once
+/// within the ttl, the response is cached by an [IdempotencyStore] and replayed
+/// for following requests with the same key. A concurrent request with the
+/// same key of an in-flight one is rejected with `409 Conflict`.
+///
+/// Keys are scoped by the subject of request (the identity extension set by
+/// [IdempotencyLayer::identity], or the `Authorization` header), so a response is
+/// never replayed to another user sending the same key.
+///
+/// Responses with server errors (5xx) are not cached, so clients could retry them.
+/// A corrupt cached response is treated as a miss, the request is executed again.
+/// A cancelled request releases its key, and an in-flight key is only held for a
+/// short lease in case the release is lost (e.p. the instance crashed).
+use crate::layer::hmac_auth::to_hex;
+use crate::layer::{is_websocket_upgrade, to_bytes};
+use crate::status::error_body::{ErrorBody, RequestId};
+use crate::utils::clock::{system_clock, Clock, SharedClock};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::header::HeaderName;
+use http::response::Parts;
+use http::{header, Extensions, HeaderValue, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{BoxError, Layer, Service};
+use tracing::warn;
+
+pub const DEFAULT_IDEMPOTENCY_HEADER: &str = "idempotency-key";
+const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60); // 1 day
+const DEFAULT_IDEMPOTENCY_LEASE: Duration = Duration::from_secs(60);
+const MEMORY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A response recorded by [IdempotencyStore]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    pub(crate) fn new(parts: &Parts, body: &Bytes) -> Self {
+        Self {
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: body.to_vec(),
+        }
+    }
+
+    /// Fail if the recorded status or headers are corrupt
+    pub(crate) fn into_response<B: From<Bytes>>(self) -> Result<Response<B>, http::Error> {
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+        builder.body(B::from(Bytes::from(self.body)))
+    }
+}
+
+/// The state of a key after [IdempotencyStore::begin]
+#[derive(Clone, Debug)]
+pub enum Begin {
+    /// The key is first seen, caller should execute the request
+    Started,
+    /// Another request with the same key is being executed
+    InFlight,
+    /// The request has been executed before
+    Done(CachedResponse),
+}
+
+#[async_trait]
+pub trait IdempotencyStore {
+    /// Atomically mark the key as in-flight for the lease if it is not seen before.
+    async fn begin(&self, key: &str, lease: Duration) -> Result<Begin, BoxError>;
+
+    /// Record the response of the key.
+    async fn complete(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Result<(), BoxError>;
+
+    /// Release the in-flight key without recording any response.
+    async fn abort(&self, key: &str) -> Result<(), BoxError>;
+}
+
+enum MemoryEntry {
+    InFlight,
+    Done(CachedResponse),
+}
+
+#[derive(Default)]
+struct MemoryEntries {
+    entries: HashMap<String, (Instant, MemoryEntry)>,
+    sweep_at: Option<Instant>,
+}
+
+/// In-memory [IdempotencyStore], keys are not shared across instances.
+/// Expired keys are dropped when they are looked up, and swept once a minute.
+#[derive(Clone)]
+pub struct MemoryIdempotencyStore {
+    entries: Arc<Mutex<MemoryEntries>>,
+    clock: SharedClock,
+}
+
+impl Default for MemoryIdempotencyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new() -> Self {
+        Self {
+            entries: Default::default(),
+            clock: system_clock(),
+        }
+    }
+
+    /// The clock of the key expiry, e.p. a [MockClock] in tests
+    ///
+    /// [MockClock]: crate::utils::clock::MockClock
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn begin(&self, key: &str, lease: Duration) -> Result<Begin, BoxError> {
+        let now = self.clock.now();
+        let mut memory = self.entries.lock().unwrap();
+        match memory.sweep_at {
+            Some(sweep_at) if sweep_at > now => {}
+            _ => {
+                memory.entries.retain(|_, (expire, _)| *expire > now);
+                memory.sweep_at = Some(now + MEMORY_SWEEP_INTERVAL);
+            }
+        }
+        match memory.entries.get(key) {
+            Some((expire, MemoryEntry::InFlight)) if *expire > now => Ok(Begin::InFlight),
+            Some((expire, MemoryEntry::Done(response))) if *expire > now => {
+                Ok(Begin::Done(response.clone()))
+            }
+            _ => {
+                memory
+                    .entries
+                    .insert(key.to_string(), (now + lease, MemoryEntry::InFlight));
+                Ok(Begin::Started)
+            }
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        self.entries.lock().unwrap().entries.insert(
+            key.to_string(),
+            (self.clock.now() + ttl, MemoryEntry::Done(response)),
+        );
+        Ok(())
+    }
+
+    async fn abort(&self, key: &str) -> Result<(), BoxError> {
+        self.entries.lock().unwrap().entries.remove(key);
+        Ok(())
+    }
+}
+
+/// Redis backed [IdempotencyStore], keys are shared across instances.
+#[derive(Clone)]
+pub struct RedisIdempotencyStore {
+    conn: redis::aio::MultiplexedConnection,
+    prefix: String,
+}
+
+const REDIS_IN_FLIGHT: &str = "in-flight";
+
+impl RedisIdempotencyStore {
+    pub fn new(conn: redis::aio::MultiplexedConnection) -> Self {
+        Self {
+            conn,
+            prefix: "idempotency:".to_string(),
+        }
+    }
+
+    /// The prefix of redis keys, default is `idempotency:`
+    pub fn prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for RedisIdempotencyStore {
+    async fn begin(&self, key: &str, lease: Duration) -> Result<Begin, BoxError> {
+        let key = self.key(key);
+        let mut conn = self.conn.clone();
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(REDIS_IN_FLIGHT)
+            .arg("NX")
+            .arg("PX")
+            .arg(lease.as_millis() as u64)
+            .query_async(&mut conn)
+            .await?;
+        if set.is_some() {
+            return Ok(Begin::Started);
+        }
+        let value: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(&mut conn).await?;
+        match value {
+            // expired between SET and GET, treat it as in-flight and let client retry
+            None => Ok(Begin::InFlight),
+            Some(value) if value == REDIS_IN_FLIGHT.as_bytes() => Ok(Begin::InFlight),
+            Some(value) => Ok(Begin::Done(serde_json::from_slice(&value)?)),
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Result<(), BoxError> {
+        let mut conn = self.conn.clone();
+        redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(serde_json::to_vec(&response)?)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn abort(&self, key: &str) -> Result<(), BoxError> {
+        let mut conn = self.conn.clone();
+        redis::cmd("DEL")
+            .arg(self.key(key))
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Read the subject of a request from its extensions
+type SubjectOf = fn(&Extensions) -> Option<&str>;
+
+fn identity_of<I: AsRef<str> + Send + Sync + 'static>(extensions: &Extensions) -> Option<&str> {
+    extensions.get::<I>().map(AsRef::as_ref)
+}
+
+#[derive(Clone)]
+pub struct IdempotencyLayer<St = MemoryIdempotencyStore> {
+    store: St,
+    ttl: Duration,
+    lease: Duration,
+    header: HeaderName,
+    identity: Option<SubjectOf>,
+}
+
+impl Default for IdempotencyLayer {
+    fn default() -> Self {
+        Self::new(MemoryIdempotencyStore::new())
+    }
+}
+
+impl<St> IdempotencyLayer<St> {
+    pub fn new(store: St) -> Self {
+        Self {
+            store,
+            ttl: DEFAULT_IDEMPOTENCY_TTL,
+            lease: DEFAULT_IDEMPOTENCY_LEASE,
+            header: HeaderName::from_static(DEFAULT_IDEMPOTENCY_HEADER),
+            identity: None,
+        }
+    }
+
+    /// How long a key is remembered, default is 1 day
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long a key is held in-flight if its request is never finished nor
+    /// released, it should be longer than the slowest request. Default is 1 minute.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Scope keys by the identity extension `I` (e.p. the user id inserted by the
+    /// auth layer) instead of the `Authorization` header
+    pub fn identity<I: AsRef<str> + Send + Sync + 'static>(mut self) -> Self {
+        self.identity = Some(identity_of::<I>);
+        self
+    }
+
+    /// The header carrying idempotency key, default is `idempotency-key`
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+}
+
+impl<S, St: Clone> Layer<S> for IdempotencyLayer<St> {
+    type Service = Idempotency<S, St>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Idempotency {
+            inner,
+            store: self.store.clone(),
+            ttl: self.ttl,
+            lease: self.lease,
+            header: self.header.clone(),
+            identity: self.identity,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Idempotency<S, St> {
+    inner: S,
+    store: St,
+    ttl: Duration,
+    lease: Duration,
+    header: HeaderName,
+    identity: Option<SubjectOf>,
+}
+
+/// The subject of request hashed, the raw credential never goes to the store
+fn subject_hash<B>(req: &Request<B>, identity: Option<SubjectOf>) -> String {
+    let subject = identity
+        .and_then(|identity| identity(req.extensions()))
+        .map(str::as_bytes)
+        .or_else(|| {
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .map(HeaderValue::as_bytes)
+        })
+        .unwrap_or_default();
+    to_hex(&Sha256::digest(subject))
+}
+
+/// Releases the in-flight key if the request is dropped before it is recorded
+struct InFlight<St: IdempotencyStore + Clone + Send + Sync + 'static> {
+    store: St,
+    key: String,
+    settled: bool,
+}
+
+impl<St: IdempotencyStore + Clone + Send + Sync + 'static> InFlight<St> {
+    async fn abort(mut self) {
+        self.settled = true;
+        if let Err(err) = self.store.abort(&self.key).await {
+            warn!("cannot abort idempotency key {}, err: {}", self.key, err);
+        }
+    }
+
+    async fn complete(mut self, cached: CachedResponse, ttl: Duration) {
+        self.settled = true;
+        if let Err(err) = self.store.complete(&self.key, cached, ttl).await {
+            warn!("cannot record idempotency key {}, err: {}", self.key, err);
+        }
+    }
+}
+
+impl<St: IdempotencyStore + Clone + Send + Sync + 'static> Drop for InFlight<St> {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let store = self.store.clone();
+        let key = std::mem::take(&mut self.key);
+        runtime.spawn(async move {
+            if let Err(err) = store.abort(&key).await {
+                warn!(
+                    "cannot release cancelled idempotency key {}, err: {}",
+                    key, err
+                );
+            }
+        });
+    }
+}
+
+fn status_response<B: From<Bytes>>(
+    request_id: Option<&RequestId>,
+    status: StatusCode,
+) -> Response<B> {
+    let body = ErrorBody {
+        request_id: request_id.map(|id| id.0.clone()),
+        ..ErrorBody::from_status(status)
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(B::from(Bytes::from(body.to_json())))
+        .unwrap()
+}
+
+impl<S, St, ReqBody, ResBody> Service<Request<ReqBody>> for Idempotency<S, St>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    St: IdempotencyStore + Clone + Send + Sync + 'static,
+    ReqBody: Send + 'static,
+    ResBody: http_body::Body + From<Bytes> + Send + 'static,
+    ResBody::Data: Send,
+    ResBody::Error: Display + Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let key = match req
+            .headers()
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(key) if !is_websocket_upgrade(&req) => format!(
+                "{}:{}:{}:{}",
+                req.method(),
+                req.uri().path(),
+                subject_hash(&req, self.identity),
+                key
+            ),
+            _ => return Box::pin(self.inner.call(req)),
+        };
+
+        // take the service which is ready
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let store = self.store.clone();
+        let ttl = self.ttl;
+        let lease = self.lease;
+        let request_id = req.extensions().get::<RequestId>().cloned();
+
+        Box::pin(async move {
+            match store.begin(&key, lease).await {
+                Ok(Begin::Started) => {}
+                Ok(Begin::InFlight) => {
+                    return Ok(status_response(request_id.as_ref(), StatusCode::CONFLICT))
+                }
+                Ok(Begin::Done(cached)) => match cached.into_response::<ResBody>() {
+                    Ok(mut res) => {
+                        res.headers_mut().insert(
+                            HeaderName::from_static("idempotent-replayed"),
+                            HeaderValue::from_static("true"),
+                        );
+                        return Ok(res);
+                    }
+                    // the entry is overridden by the response executed again
+                    Err(err) => warn!("corrupt cached response of key {}, err: {}", key, err),
+                },
+                Err(err) => {
+                    warn!("idempotency store is working abnormally, err: {}", err);
+                    return inner.call(req).await;
+                }
+            }
+
+            // released if this future is dropped from now on
+            let in_flight = InFlight {
+                store,
+                key,
+                settled: false,
+            };
+            let res = match inner.call(req).await {
+                Ok(res) => res,
+                Err(err) => {
+                    in_flight.abort().await;
+                    return Err(err);
+                }
+            };
+
+            if res.status().is_server_error() {
+                in_flight.abort().await;
+                return Ok(res);
+            }
+
+            let (parts, body) = res.into_parts();
+            let body = match to_bytes(body).await {
+                Ok(body) => body,
+                Err(err) => {
+                    warn!("cannot read response body, err: {}", err);
+                    in_flight.abort().await;
+                    return Ok(status_response(
+                        request_id.as_ref(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ));
+                }
+            };
+            let cached = CachedResponse::new(&parts, &body);
+            in_flight.complete(cached, ttl).await;
+            Ok(Response::from_parts(parts, ResBody::from(body)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::hmac_auth::to_hex;
+    use crate::layer::idempotency::{
+        Begin, CachedResponse, IdempotencyLayer, IdempotencyStore, MemoryIdempotencyStore,
+    };
+    use crate::layer::to_bytes;
+    use crate::status::error_body::{ErrorBody, RequestId};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use http::{Request, Response, StatusCode};
+    use http_body::Full;
+    use sha2::{Digest, Sha256};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use tower::{service_fn, BoxError, Layer, ServiceExt};
+
+    /// Notifies when a key is released
+    #[derive(Clone, Default)]
+    struct NotifyStore {
+        inner: MemoryIdempotencyStore,
+        aborted: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl IdempotencyStore for NotifyStore {
+        async fn begin(&self, key: &str, lease: Duration) -> Result<Begin, BoxError> {
+            self.inner.begin(key, lease).await
+        }
+
+        async fn complete(
+            &self,
+            key: &str,
+            response: CachedResponse,
+            ttl: Duration,
+        ) -> Result<(), BoxError> {
+            self.inner.complete(key, response, ttl).await
+        }
+
+        async fn abort(&self, key: &str) -> Result<(), BoxError> {
+            self.inner.abort(key).await?;
+            self.aborted.notify_one();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let svc = IdempotencyLayer::default().layer(service_fn(move |_: Request<()>| {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            async move { Ok::<_, BoxError>(Response::new(Full::<Bytes>::from(n.to_string()))) }
+        }));
+        let request = || {
+            Request::post("/pay")
+                .header("idempotency-key", "114514")
+                .body(())
+                .unwrap()
+        };
+
+        let first = svc.clone().oneshot(request()).await.unwrap();
+        let second = svc.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.headers()["idempotent-replayed"], "true");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // another user sending the same key
+        let mut other = request();
+        other
+            .headers_mut()
+            .insert("authorization", "Bearer other".parse().unwrap());
+        let res = svc.clone().oneshot(other).await.unwrap();
+        assert!(res.headers().get("idempotent-replayed").is_none());
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        svc.oneshot(Request::post("/pay").body(()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // a corrupt cached response is a miss
+        let store = MemoryIdempotencyStore::new();
+        let corrupt = CachedResponse {
+            status: 1000,
+            headers: vec![],
+            body: vec![],
+        };
+        let key = format!("POST:/pay:{}:114514", to_hex(&Sha256::digest(b"")));
+        store
+            .complete(&key, corrupt, Duration::from_secs(60))
+            .await
+            .unwrap();
+        let svc = IdempotencyLayer::new(store).layer(service_fn(|_: Request<()>| async {
+            Ok::<_, BoxError>(Response::new(Full::<Bytes>::from("executed")))
+        }));
+        let res = svc.clone().oneshot(request()).await.unwrap();
+        assert!(res.headers().get("idempotent-replayed").is_none());
+        let res = svc.oneshot(request()).await.unwrap();
+        assert_eq!(res.headers()["idempotent-replayed"], "true");
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "executed");
+    }
+
+    #[tokio::test]
+    async fn test_cancelled() {
+        let store = NotifyStore::default();
+        let aborted = store.aborted.clone();
+        let entered = Arc::new(Notify::new());
+        let notify = entered.clone();
+        let svc = IdempotencyLayer::new(store).layer(service_fn(move |_: Request<()>| {
+            let entered = notify.clone();
+            async move {
+                entered.notify_one();
+                futures::future::pending::<()>().await;
+                Ok::<_, BoxError>(Response::new(Full::<Bytes>::default()))
+            }
+        }));
+        let request = || {
+            Request::post("/slow")
+                .header("idempotency-key", "114514")
+                .body(())
+                .unwrap()
+        };
+
+        let cancelled = tokio::spawn(svc.clone().oneshot(request()));
+        entered.notified().await;
+        cancelled.abort();
+        assert!(cancelled.await.unwrap_err().is_cancelled());
+        // the release is spawned on drop
+        aborted.notified().await;
+
+        // executed again rather than rejected with 409
+        let held = tokio::spawn(svc.clone().oneshot(request()));
+        entered.notified().await;
+        let mut conflict = request();
+        conflict
+            .extensions_mut()
+            .insert(RequestId("req-1".to_string()));
+        let res = svc.oneshot(conflict).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body = to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<ErrorBody>(&body).unwrap(),
+            ErrorBody {
+                request_id: Some("req-1".to_string()),
+                ..ErrorBody::from_status(StatusCode::CONFLICT)
+            }
+        );
+        held.abort();
+    }
+}
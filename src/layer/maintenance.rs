@@ -0,0 +1,210 @@
+/// Take a service out of rotation for maintenance without killing it. While the
+/// switch is on, requests are responded with `503 Service Unavailable` and a
+/// `Retry-After` header, except the allowlisted paths (`/healthz` and `/readyz`
+/// by default).
+///
+/// ```rust,ignore
+/// let switch = MaintenanceSwitch::default();
+/// let layer = MaintenanceLayer::new(switch.clone()).allow("/admin/*");
+/// // mount `maintenance_handler(&switch, req)` as `/admin/maintenance`, or
+/// switch.enable();
+/// ```
+use crate::layer::to_bytes;
+use crate::status::error_body::{error_response, ErrorResponseBody};
+use futures::future::{ready, Either, Ready};
+use http::header::RETRY_AFTER;
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+use tracing::info;
+
+/// The maintenance flag shared by layers and the admin endpoint, switchable at runtime
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceSwitch(Arc<AtomicBool>);
+
+impl MaintenanceSwitch {
+    pub fn set(&self, on: bool) {
+        if self.0.swap(on, Ordering::SeqCst) != on {
+            info!(
+                "maintenance mode is turned {}",
+                if on { "on" } else { "off" }
+            );
+        }
+    }
+
+    pub fn enable(&self) {
+        self.set(true)
+    }
+
+    pub fn disable(&self) {
+        self.set(false)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MaintenanceLayer {
+    switch: MaintenanceSwitch,
+    allowlist: Arc<Vec<String>>,
+    retry_after: HeaderValue,
+}
+
+impl MaintenanceLayer {
+    pub fn new(switch: MaintenanceSwitch) -> Self {
+        Self {
+            switch,
+            allowlist: Arc::new(vec!["/healthz".to_string(), "/readyz".to_string()]),
+            retry_after: HeaderValue::from(60),
+        }
+    }
+
+    /// Serve the path during maintenance, `/prefix/*` allows all paths under the prefix
+    pub fn allow(mut self, path: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.allowlist).push(path.into());
+        self
+    }
+
+    /// The `Retry-After` of responses, 60 seconds by default
+    pub fn retry_after(mut self, after: Duration) -> Self {
+        self.retry_after = HeaderValue::from(after.as_secs());
+        self
+    }
+}
+
+impl<S> Layer<S> for MaintenanceLayer {
+    type Service = Maintenance<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Maintenance {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Maintenance<S> {
+    inner: S,
+    layer: MaintenanceLayer,
+}
+
+fn allows(allowlist: &[String], path: &str) -> bool {
+    allowlist
+        .iter()
+        .any(|allowed| match allowed.strip_suffix("/*") {
+            Some(prefix) => path == prefix || path.starts_with(&format!("{}/", prefix)),
+            None => allowed == path,
+        })
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Maintenance<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: ErrorResponseBody,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<S::Response, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        if self.layer.switch.is_enabled() && !allows(&self.layer.allowlist, req.uri().path()) {
+            let mut res = error_response(&req, StatusCode::SERVICE_UNAVAILABLE);
+            res.headers_mut()
+                .insert(RETRY_AFTER, self.layer.retry_after.clone());
+            return Either::Left(ready(Ok(res)));
+        }
+        Either::Right(self.inner.call(req))
+    }
+}
+
+/// A handler to be mounted as an admin route (e.p. `/admin/maintenance`), remember
+/// to allowlist it. `GET` returns `on` or `off`, `PUT`/`POST` switches with the body `on` or `off`.
+pub async fn maintenance_handler<B, ResBody>(
+    switch: &MaintenanceSwitch,
+    req: Request<B>,
+) -> Response<ResBody>
+where
+    B: http_body::Body,
+    B::Error: std::fmt::Display,
+    ResBody: From<String>,
+{
+    let respond = |status: StatusCode, body: String| {
+        Response::builder()
+            .status(status)
+            .body(ResBody::from(body))
+            .unwrap()
+    };
+    let state = |on: bool| if on { "on" } else { "off" }.to_string();
+    match *req.method() {
+        Method::GET => respond(StatusCode::OK, state(switch.is_enabled())),
+        Method::PUT | Method::POST => {
+            let body = match to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(err) => return respond(StatusCode::BAD_REQUEST, err.to_string()),
+            };
+            match String::from_utf8_lossy(&body).trim() {
+                "on" => switch.enable(),
+                "off" => switch.disable(),
+                other => {
+                    return respond(
+                        StatusCode::BAD_REQUEST,
+                        format!("expect on or off, got '{}'", other),
+                    )
+                }
+            }
+            respond(StatusCode::OK, state(switch.is_enabled()))
+        }
+        _ => respond(StatusCode::METHOD_NOT_ALLOWED, String::new()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::{MaintenanceLayer, MaintenanceSwitch};
+    use http::{Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let switch = MaintenanceSwitch::default();
+        let svc = MaintenanceLayer::new(switch.clone())
+            .allow("/admin/*")
+            .layer(service_fn(|_req: Request<()>| async {
+                Ok::<_, Infallible>(Response::new(()))
+            }));
+        let call = |path: &'static str| {
+            let svc = svc.clone();
+            async move {
+                let req = Request::get(path).body(()).unwrap();
+                svc.oneshot(req).await.unwrap()
+            }
+        };
+
+        assert_eq!(call("/users").await.status(), StatusCode::OK);
+
+        switch.enable();
+        let res = call("/users").await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["retry-after"], "60");
+        assert_eq!(call("/healthz").await.status(), StatusCode::OK);
+        assert_eq!(call("/admin/maintenance").await.status(), StatusCode::OK);
+        assert_eq!(
+            call("/administrator").await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        switch.disable();
+        assert_eq!(call("/users").await.status(), StatusCode::OK);
+    }
+}
@@ -1,6 +1,97 @@
 /// tower layers
+pub mod buffer_body;
+pub mod concurrency_limit;
+pub mod content_type;
+pub mod drain;
+pub mod hmac_auth;
 pub mod http_auth;
+pub mod idempotency;
+pub mod maintenance;
+pub mod rate_limit;
+pub mod response_cache;
 pub mod role_mapping;
+pub mod route_timeout;
+pub mod tap;
 
+pub use buffer_body::*;
+pub use concurrency_limit::*;
+pub use content_type::*;
+pub use drain::*;
+pub use hmac_auth::*;
 pub use http_auth::*;
+pub use idempotency::*;
+pub use maintenance::*;
+pub use rate_limit::*;
+pub use response_cache::*;
 pub use role_mapping::*;
+pub use route_timeout::*;
+pub use tap::*;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header, Request};
+use http_body::Body;
+
+/// Check if the request is a websocket upgrade handshake.
+///
+/// The upgraded connection is taken over from the underlying transport instead
+/// of the body, so layers reading, buffering or replacing bodies (e.p. [IdempotencyLayer],
+/// [TapLayer]) must pass upgrade requests through untouched, or the `101 Switching Protocols`
+/// handshake is broken. Role mapping layers enforce on the upgrade request (path, method
+/// and subject) and then pass the connection through.
+pub fn is_websocket_upgrade<B>(req: &Request<B>) -> bool {
+    let connection_upgrade = req
+        .headers()
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let upgrade_websocket = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    connection_upgrade && upgrade_websocket
+}
+
+/// Read the whole body into memory.
+pub(crate) async fn to_bytes<B: Body>(body: B) -> Result<Bytes, B::Error> {
+    tokio::pin!(body);
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        // a chunk may be made of several segments
+        buf.put(chunk?);
+    }
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_websocket_upgrade, to_bytes};
+    use bytes::{Buf, Bytes};
+    use http::Request;
+    use http_body::Full;
+
+    #[test]
+    fn test_websocket_upgrade() {
+        let req = Request::get("/ws")
+            .header("connection", "keep-alive, Upgrade")
+            .header("upgrade", "websocket")
+            .body(())
+            .unwrap();
+        assert!(is_websocket_upgrade(&req));
+        let req = Request::get("/ws")
+            .header("upgrade", "websocket")
+            .body(())
+            .unwrap();
+        assert!(!is_websocket_upgrade(&req));
+    }
+
+    #[tokio::test]
+    async fn test_to_bytes() {
+        let chunk = Bytes::from("hello, ").chain(Bytes::from("world"));
+        let body = to_bytes(Full::new(chunk)).await.unwrap();
+        assert_eq!(body, "hello, world");
+    }
+}
@@ -0,0 +1,528 @@
+/// Limit the request rate per key with token buckets, the requests over the rate
+/// are responded with `429 Too Many Requests` and `Retry-After`.
+///
+/// The key is read by a [RateKey]: the client ip ([ClientIp]), a header ([HeaderKey])
+/// or the typed identity inserted by the auth layer ([IdentityKey]). Keyed by identity,
+/// the layer must be placed after the auth layer, and before the role mapping layer
+/// so that the denied requests are counted as well:
+///
+/// ```rust,ignore
+/// let key = IdentityKey::<UserId>::new().fallback_ip().tier(|id| id.plan());
+/// let svc = ServiceBuilder::new()
+///     .layer(AsyncHttpAuthLayer::new(auth)) // inserts `UserId`
+///     .layer(RateLimitLayer::new(key, Rate::per_minute(60)).tier("pro", Rate::per_minute(600)))
+///     .layer(RoleMappingLayer::<UserId, _>::new(enforcer))
+///     .service(svc);
+/// ```
+///
+/// The buckets are kept in memory of the process, so the rate is per instance.
+/// Up to 100k keys are kept, the requests of new keys beyond are limited until
+/// the idle keys are pruned.
+use crate::status::error_body::{error_response, ErrorResponseBody};
+use futures::future::{ready, Either, Ready};
+use http::header::{HeaderName, RETRY_AFTER};
+use http::{HeaderValue, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+/// The full buckets are pruned once the number of keys reaches it, then once it
+/// doubles the keys left, so that pruning costs amortized O(1) per key
+const PRUNE_KEYS: usize = 10_000;
+/// The max number of keys, the requests of new keys beyond are limited
+const MAX_KEYS: usize = 100_000;
+/// The min interval between prunes once there are [MAX_KEYS]
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `requests` in each `period`, allowing a burst of `requests`.
+/// All requests are limited with 0 `requests`, e.p. to suspend a tier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Rate {
+    pub fn new(requests: u32, period: Duration) -> Self {
+        Self { requests, period }
+    }
+
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(60))
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// How the bucket key and the tier are read from a request
+pub trait RateKey {
+    /// The bucket key, the requests without a key are not limited
+    fn key<B>(&self, req: &Request<B>) -> Option<String>;
+
+    /// The tier selecting the rate, None for the default rate
+    fn tier<B>(&self, _req: &Request<B>) -> Option<String> {
+        None
+    }
+}
+
+/// The client ip, the [SocketAddr] extension of the peer by default.
+///
+/// The forwarded headers are only read behind the trusted proxies, see
+/// [ClientIp::behind_proxies], as the clients could set them to anything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientIp {
+    trusted_proxies: usize,
+}
+
+impl ClientIp {
+    /// Read the ip from `X-Forwarded-For` behind the number of proxies. Each proxy
+    /// appends the address of its peer, so the client is the `trusted_proxies`th
+    /// address from the right, the ones on its left are set by the client and ignored.
+    /// `X-Real-IP` is read if there is no `X-Forwarded-For`.
+    pub fn behind_proxies(trusted_proxies: usize) -> Self {
+        Self { trusted_proxies }
+    }
+
+    fn forwarded<B>(&self, req: &Request<B>) -> Option<String> {
+        let forwarded = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .collect::<Vec<_>>();
+        // fewer addresses than proxies, all of them are appended by the proxies
+        let client = forwarded
+            .len()
+            .checked_sub(self.trusted_proxies)
+            .and_then(|i| forwarded.get(i))
+            .or(forwarded.first());
+        match client {
+            Some(ip) => Some(ip.to_string()),
+            None => req
+                .headers()
+                .get("x-real-ip")
+                .and_then(|value| value.to_str().ok())
+                .map(|ip| ip.trim().to_string())
+                .filter(|ip| !ip.is_empty()),
+        }
+    }
+}
+
+impl RateKey for ClientIp {
+    fn key<B>(&self, req: &Request<B>) -> Option<String> {
+        if self.trusted_proxies > 0 {
+            if let Some(ip) = self.forwarded(req) {
+                return Some(ip);
+            }
+        }
+        req.extensions()
+            .get::<SocketAddr>()
+            .map(|addr| addr.ip().to_string())
+    }
+}
+
+/// The value of a header, e.p. an api key
+#[derive(Clone, Debug)]
+pub struct HeaderKey(pub HeaderName);
+
+impl RateKey for HeaderKey {
+    fn key<B>(&self, req: &Request<B>) -> Option<String> {
+        req.headers()
+            .get(&self.0)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string)
+    }
+}
+
+/// The identity extension `I` inserted by the auth layer, the same one as the
+/// subject of [RoleMappingLayer]
+///
+/// [RoleMappingLayer]: crate::layer::RoleMappingLayer
+pub struct IdentityKey<I> {
+    fallback_ip: Option<ClientIp>,
+    tier: Option<fn(&I) -> Option<&str>>,
+    marker: PhantomData<fn() -> I>,
+}
+
+impl<I> Clone for IdentityKey<I> {
+    fn clone(&self) -> Self {
+        Self {
+            fallback_ip: self.fallback_ip,
+            tier: self.tier,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<I> Default for IdentityKey<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> IdentityKey<I> {
+    /// The requests without identity are not limited
+    pub fn new() -> Self {
+        Self {
+            fallback_ip: None,
+            tier: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Limit the requests without identity by the default [ClientIp]
+    pub fn fallback_ip(self) -> Self {
+        self.fallback_client_ip(ClientIp::default())
+    }
+
+    /// Limit the requests without identity by the [ClientIp], e.p. behind proxies
+    pub fn fallback_client_ip(mut self, ip: ClientIp) -> Self {
+        self.fallback_ip = Some(ip);
+        self
+    }
+
+    /// Select the tier from a claim of the identity, e.p. the plan of the user
+    pub fn tier(mut self, tier: fn(&I) -> Option<&str>) -> Self {
+        self.tier = Some(tier);
+        self
+    }
+}
+
+impl<I: AsRef<str> + Send + Sync + 'static> RateKey for IdentityKey<I> {
+    fn key<B>(&self, req: &Request<B>) -> Option<String> {
+        match req.extensions().get::<I>() {
+            Some(id) => Some(format!("id:{}", id.as_ref())),
+            // distinguish from the identities
+            None => self
+                .fallback_ip
+                .and_then(|ip| ip.key(req))
+                .map(|ip| format!("ip:{}", ip)),
+        }
+    }
+
+    fn tier<B>(&self, req: &Request<B>) -> Option<String> {
+        let tier = self.tier?;
+        req.extensions()
+            .get::<I>()
+            .and_then(tier)
+            .map(ToString::to_string)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // the rate of the last request, the tier may change
+    rate: Rate,
+}
+
+impl Bucket {
+    /// Take a token, or the time until one is available
+    fn take(&mut self, rate: &Rate, now: Instant) -> Result<(), Duration> {
+        if rate.requests == 0 {
+            // never refilled
+            self.rate = *rate;
+            return Err(rate.period);
+        }
+        let refill = now.duration_since(self.updated).as_secs_f64() * rate.refill_per_sec();
+        self.tokens = (self.tokens + refill).min(rate.requests as f64);
+        self.updated = now;
+        self.rate = *rate;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / rate.refill_per_sec(),
+        ))
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate.refill_per_sec();
+        self.tokens + refill >= self.rate.requests as f64
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    prune_at: usize,
+    pruned: Option<Instant>,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            prune_at: PRUNE_KEYS,
+            pruned: None,
+        }
+    }
+}
+
+impl Buckets {
+    fn prune(&mut self, now: Instant) {
+        let full = self.buckets.len() >= MAX_KEYS;
+        if full && matches!(self.pruned, Some(pruned) if now - pruned < PRUNE_INTERVAL) {
+            return;
+        }
+        // the full buckets are the same as new ones
+        self.buckets.retain(|_, bucket| !bucket.is_full(now));
+        self.pruned = Some(now);
+        self.prune_at = (self.buckets.len() * 2).clamp(PRUNE_KEYS, MAX_KEYS);
+    }
+}
+
+#[derive(Debug)]
+struct Limiter {
+    rate: Rate,
+    tiers: HashMap<String, Rate>,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+    fn rate(&self, tier: Option<&str>) -> Rate {
+        tier.and_then(|tier| self.tiers.get(tier))
+            .copied()
+            .unwrap_or(self.rate)
+    }
+
+    fn check(&self, key: String, tier: Option<&str>) -> Result<(), Duration> {
+        let rate = self.rate(tier);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.buckets.len() >= buckets.prune_at && !buckets.buckets.contains_key(&key) {
+            buckets.prune(now);
+            if buckets.buckets.len() >= MAX_KEYS {
+                return Err(PRUNE_INTERVAL);
+            }
+        }
+        buckets
+            .buckets
+            .entry(key)
+            .or_insert(Bucket {
+                tokens: rate.requests as f64,
+                updated: now,
+                rate,
+            })
+            .take(&rate, now)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer<K> {
+    key: K,
+    limiter: Arc<Limiter>,
+}
+
+impl<K> RateLimitLayer<K> {
+    pub fn new(key: K, rate: Rate) -> Self {
+        Self {
+            key,
+            limiter: Arc::new(Limiter {
+                rate,
+                tiers: HashMap::new(),
+                buckets: Mutex::new(Buckets::default()),
+            }),
+        }
+    }
+
+    /// The rate of a tier selected by [RateKey::tier], the default rate is used
+    /// for the unknown tiers. Set the tiers before cloning the layer.
+    pub fn tier(mut self, tier: impl Into<String>, rate: Rate) -> Self {
+        let limiter =
+            Arc::get_mut(&mut self.limiter).expect("tier is set after the layer is cloned");
+        limiter.tiers.insert(tier.into(), rate);
+        self
+    }
+}
+
+impl<S, K: Clone> Layer<S> for RateLimitLayer<K> {
+    type Service = RateLimit<S, K>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            key: self.key.clone(),
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimit<S, K> {
+    inner: S,
+    key: K,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, K, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimit<S, K>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    K: RateKey,
+    ResBody: ErrorResponseBody,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let key = match self.key.key(&req) {
+            Some(key) => key,
+            None => return Either::Left(self.inner.call(req)),
+        };
+        let tier = self.key.tier(&req);
+        match self.limiter.check(key, tier.as_deref()) {
+            Ok(()) => Either::Left(self.inner.call(req)),
+            Err(wait) => {
+                // round up, `Retry-After: 0` would be retried at once
+                let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                let mut res = error_response(&req, StatusCode::TOO_MANY_REQUESTS);
+                res.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                Either::Right(ready(Ok(res)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MAX_KEYS, PRUNE_INTERVAL};
+    use crate::layer::{ClientIp, IdentityKey, Rate, RateKey, RateLimitLayer};
+    use http::{Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    struct UserId(String, &'static str);
+
+    impl AsRef<str> for UserId {
+        fn as_ref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_by_identity() {
+        let layer = RateLimitLayer::new(
+            IdentityKey::<UserId>::new()
+                .fallback_client_ip(ClientIp::behind_proxies(1))
+                .tier(|id| Some(id.1)),
+            Rate::per_minute(1),
+        )
+        .tier("pro", Rate::per_minute(2))
+        .tier("suspended", Rate::per_minute(0));
+        let svc = layer.layer(service_fn(|_req: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let call = |user: Option<(&str, &'static str)>, ip: &str| {
+            let mut req = Request::get("/")
+                .header("x-forwarded-for", ip)
+                .body(())
+                .unwrap();
+            if let Some((id, plan)) = user {
+                // inserted by the auth layer in front
+                req.extensions_mut().insert(UserId(id.to_string(), plan));
+            }
+            svc.clone().oneshot(req)
+        };
+
+        let alice = Some(("alice", "free"));
+        assert_eq!(
+            call(alice, "10.0.0.1").await.unwrap().status(),
+            StatusCode::OK
+        );
+        // keyed by identity instead of ip
+        let res = call(alice, "10.0.0.2").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "60");
+
+        let bob = Some(("bob", "pro"));
+        assert_eq!(
+            call(bob, "10.0.0.1").await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(bob, "10.0.0.1").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let res = call(bob, "10.0.0.1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // anonymous requests fall back to ip
+        assert_eq!(
+            call(None, "10.0.0.1").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let res = call(None, "10.0.0.1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let res = call(Some(("eve", "suspended")), "10.0.0.3").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "60");
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let req = |forwarded: &str| {
+            let mut req = Request::get("/")
+                .header("x-forwarded-for", forwarded)
+                .body(())
+                .unwrap();
+            req.extensions_mut()
+                .insert("10.0.0.9:1234".parse::<SocketAddr>().unwrap());
+            req
+        };
+        let forwarded = "1.1.1.1, 2.2.2.2, 10.0.0.1";
+        // the forwarded headers are not trusted by default
+        assert_eq!(
+            ClientIp::default().key(&req(forwarded)).unwrap(),
+            "10.0.0.9"
+        );
+        assert_eq!(
+            ClientIp::behind_proxies(1).key(&req(forwarded)).unwrap(),
+            "10.0.0.1"
+        );
+        assert_eq!(
+            ClientIp::behind_proxies(2).key(&req(forwarded)).unwrap(),
+            "2.2.2.2"
+        );
+        assert_eq!(
+            ClientIp::behind_proxies(5).key(&req(forwarded)).unwrap(),
+            "1.1.1.1"
+        );
+
+        // the keys are bounded
+        let layer = RateLimitLayer::new(ClientIp::default(), Rate::per_minute(1));
+        for i in 0..MAX_KEYS {
+            assert!(layer.limiter.check(i.to_string(), None).is_ok());
+        }
+        assert_eq!(
+            layer.limiter.check("new".to_string(), None),
+            Err(PRUNE_INTERVAL)
+        );
+        assert_eq!(
+            layer.limiter.buckets.lock().unwrap().buckets.len(),
+            MAX_KEYS
+        );
+    }
+}
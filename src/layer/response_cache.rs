@@ -0,0 +1,413 @@
+/// Cache full responses (status, headers and body) of GET requests in redis,
+/// a hit is responded without calling the inner service.
+///
+/// The cache key is method + path and query + the values of vary headers, and
+/// only one of the concurrent misses of a key calls the inner service, others wait
+/// for the cached response (see [CacheAside]). If redis is unavailable, requests go
+/// to the inner service directly.
+///
+/// Responses are cached with the ttl of layer, except:
+/// non-2xx responses
+/// responses with `Set-Cookie`
+/// responses with `Cache-Control: no-store | no-cache | private | max-age=0`
+///
+/// A request bypasses the cache and always calls the inner service, if it has:
+/// `Cache-Control: no-cache` => the response refreshes the cached one
+/// `Cache-Control: no-store` => the response is not cached
+/// the bypass header of layer (e.p. `x-cache-bypass`) => same as `no-cache`
+/// `Authorization` or `Cookie` => same as `no-store`, unless [ResponseCacheLayer::per_credential]
+/// Bypassed responses are marked with `x-cache: BYPASS`.
+///
+/// ```rust,ignore
+/// let cache = CacheAside::new(redis_client).lock_wait(Duration::from_secs(1));
+/// let layer = ResponseCacheLayer::new(cache)
+///     .ttl(Duration::from_secs(30))
+///     .vary(ACCEPT_LANGUAGE);
+/// ```
+///
+/// [CacheAside]: crate::middleware::redis::CacheAside
+use crate::layer::hmac_auth::to_hex;
+use crate::layer::{is_websocket_upgrade, to_bytes, CachedResponse};
+use crate::middleware::redis::CacheAside;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::header::{HeaderName, AUTHORIZATION, CACHE_CONTROL, COOKIE, SET_COOKIE};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+use tracing::warn;
+
+const DEFAULT_RESPONSE_TTL: Duration = Duration::from_secs(60);
+
+/// The headers carrying the credentials of a request
+const CREDENTIAL_HEADERS: [HeaderName; 2] = [AUTHORIZATION, COOKIE];
+
+#[derive(Clone)]
+pub struct ResponseCacheLayer {
+    cache: Arc<CacheAside>,
+    ttl: Duration,
+    vary: Vec<HeaderName>,
+    bypass: Option<HeaderName>,
+    per_credential: bool,
+}
+
+impl ResponseCacheLayer {
+    pub fn new(cache: CacheAside) -> Self {
+        Self {
+            cache: Arc::new(cache),
+            ttl: DEFAULT_RESPONSE_TTL,
+            vary: vec![],
+            bypass: None,
+            per_credential: false,
+        }
+    }
+
+    /// How long a response is cached, default is 60 seconds
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Add a header which varies the response, e.p. `Accept-Language`
+    pub fn vary(mut self, header: HeaderName) -> Self {
+        self.vary.push(header);
+        self
+    }
+
+    /// Requests with the header (whatever the value is) bypass the cache, e.p.
+    /// `x-cache-bypass` for debugging. The response refreshes the cached one.
+    pub fn bypass_header(mut self, header: HeaderName) -> Self {
+        self.bypass = Some(header);
+        self
+    }
+
+    /// Cache the responses of requests with credentials (`Authorization` or `Cookie`)
+    /// separately for each credential, instead of bypassing the cache.
+    /// Responses with `Cache-Control: private` are still not cached.
+    pub fn per_credential(mut self) -> Self {
+        self.per_credential = true;
+        self
+    }
+}
+
+impl<S> Layer<S> for ResponseCacheLayer {
+    type Service = ResponseCache<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseCache {
+            inner,
+            cache: self.cache.clone(),
+            ttl: self.ttl,
+            vary: self.vary.clone(),
+            bypass: self.bypass.clone(),
+            per_credential: self.per_credential,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseCache<S> {
+    inner: S,
+    cache: Arc<CacheAside>,
+    ttl: Duration,
+    vary: Vec<HeaderName>,
+    bypass: Option<HeaderName>,
+    per_credential: bool,
+}
+
+/// How a request bypasses the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bypass {
+    /// Call the inner service and cache the response
+    Refresh,
+    /// Call the inner service only
+    NoStore,
+}
+
+impl<S> ResponseCache<S> {
+    fn bypass<B>(&self, req: &Request<B>) -> Option<Bypass> {
+        let credentialed = CREDENTIAL_HEADERS
+            .iter()
+            .any(|name| req.headers().contains_key(name));
+        if credentialed && !self.per_credential {
+            return Some(Bypass::NoStore);
+        }
+        let mut bypass = None;
+        for directive in directives(req.headers()) {
+            match directive.as_str() {
+                "no-store" => return Some(Bypass::NoStore),
+                "no-cache" => bypass = Some(Bypass::Refresh),
+                _ => {}
+            }
+        }
+        match self.bypass {
+            Some(ref header) if req.headers().contains_key(header) => Some(Bypass::Refresh),
+            _ => bypass,
+        }
+    }
+
+    fn key<B>(&self, req: &Request<B>) -> String {
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let mut key = format!("response:{}:{}", req.method(), path);
+        for name in &self.vary {
+            let values = req
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>();
+            key.push_str(&format!("|{}={}", name, values.join(",")));
+        }
+        if self.per_credential {
+            let mut hasher = Sha256::new();
+            for name in &CREDENTIAL_HEADERS {
+                for value in req.headers().get_all(name) {
+                    hasher.update(name.as_str());
+                    hasher.update(value.as_bytes());
+                }
+            }
+            key.push_str(&format!("|credential={}", to_hex(&hasher.finalize())));
+        }
+        key
+    }
+}
+
+/// The lowercase directives of `Cache-Control`
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_lowercase())
+}
+
+/// Whether the response could be stored in a shared cache
+fn cacheable(status: StatusCode, headers: &HeaderMap) -> bool {
+    if !status.is_success() || headers.contains_key(SET_COOKIE) {
+        return false;
+    }
+    !directives(headers).any(|directive| {
+        matches!(
+            directive.as_str(),
+            "no-store" | "no-cache" | "private" | "max-age=0"
+        )
+    })
+}
+
+fn mark<B>(mut res: Response<B>, status: &'static str) -> Response<B> {
+    res.headers_mut().insert(
+        HeaderName::from_static("x-cache"),
+        HeaderValue::from_static(status),
+    );
+    res
+}
+
+/// The response of a body failed to be read
+fn unreadable<B: From<Bytes>>(err: impl Display) -> Response<B> {
+    warn!("cannot read response body, err: {}", err);
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(B::from(Bytes::new()))
+        .unwrap()
+}
+
+/// The response is not cached
+enum Miss<B, E> {
+    Uncacheable(Response<B>),
+    Failed(E),
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ResponseCache<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ReqBody: Send + 'static,
+    ResBody: http_body::Body + From<Bytes> + Send + 'static,
+    ResBody::Data: Send,
+    ResBody::Error: Display + Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        if req.method() != Method::GET || is_websocket_upgrade(&req) {
+            return Box::pin(self.inner.call(req));
+        }
+        let key = self.key(&req);
+        let bypass = self.bypass(&req);
+
+        // take the service which is ready
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let cache = self.cache.clone();
+        let ttl = self.ttl;
+
+        if let Some(bypass) = bypass {
+            return Box::pin(async move {
+                let res = inner.call(req).await?;
+                if bypass == Bypass::NoStore || !cacheable(res.status(), res.headers()) {
+                    return Ok(mark(res, "BYPASS"));
+                }
+                let (parts, body) = res.into_parts();
+                let body = match to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(err) => return Ok(unreadable(err)),
+                };
+                cache
+                    .put(&key, &CachedResponse::new(&parts, &body), ttl)
+                    .await;
+                Ok(mark(
+                    Response::from_parts(parts, ResBody::from(body)),
+                    "BYPASS",
+                ))
+            });
+        }
+
+        Box::pin(async move {
+            // taken if the response is computed
+            let mut pending = Some((inner, req));
+            let res = cache
+                .get_or_compute(&key, ttl, || {
+                    let (mut inner, req) = pending.take().unwrap();
+                    async move {
+                        let res = inner.call(req).await.map_err(Miss::Failed)?;
+                        if !cacheable(res.status(), res.headers()) {
+                            return Err(Miss::Uncacheable(res));
+                        }
+                        let (parts, body) = res.into_parts();
+                        match to_bytes(body).await {
+                            Ok(body) => Ok(CachedResponse::new(&parts, &body)),
+                            Err(err) => Err(Miss::Uncacheable(unreadable(err))),
+                        }
+                    }
+                })
+                .await;
+            match res {
+                Ok(cached) => {
+                    let status = if pending.is_none() { "MISS" } else { "HIT" };
+                    match (cached.into_response::<ResBody>(), pending) {
+                        (Ok(res), _) => Ok(mark(res, status)),
+                        // a corrupt hit is a miss, the cached one expires with its ttl
+                        (Err(err), Some((mut inner, req))) => {
+                            warn!("corrupt cached response of key {}, err: {}", key, err);
+                            Ok(mark(inner.call(req).await?, "MISS"))
+                        }
+                        (Err(err), None) => Ok(unreadable(err)),
+                    }
+                }
+                Err(Miss::Uncacheable(res)) => Ok(res),
+                Err(Miss::Failed(err)) => Err(err),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::response_cache::{cacheable, Bypass, ResponseCacheLayer};
+    use crate::middleware::redis::CacheAside;
+    use bytes::Bytes;
+    use http::header::HeaderName;
+    use http::header::ACCEPT_LANGUAGE;
+    use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+    use http_body::Full;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tower::{service_fn, BoxError, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        let mut headers = HeaderMap::new();
+        assert!(cacheable(StatusCode::OK, &headers));
+        assert!(!cacheable(StatusCode::INTERNAL_SERVER_ERROR, &headers));
+        headers.insert(
+            "cache-control",
+            HeaderValue::from_static("public, no-store"),
+        );
+        assert!(!cacheable(StatusCode::OK, &headers));
+
+        let layer = ResponseCacheLayer::new(CacheAside::new(
+            // nothing is listening on port 1
+            redis::Client::open("redis://127.0.0.1:1/").unwrap(),
+        ))
+        .vary(ACCEPT_LANGUAGE)
+        .bypass_header(HeaderName::from_static("x-cache-bypass"));
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let svc = layer.layer(service_fn(move |_: Request<()>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move { Ok::<_, BoxError>(Response::new(Full::<Bytes>::from("hello"))) }
+        }));
+
+        let req = Request::get("/greet?name=iGxnon")
+            .header("accept-language", "zh-CN")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            svc.key(&req),
+            "response:GET:/greet?name=iGxnon|accept-language=zh-CN"
+        );
+
+        // degrade to the inner service without redis
+        let res = svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.headers()["x-cache"], "MISS");
+        svc.clone()
+            .oneshot(Request::get("/greet").body(()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        let req = Request::get("/greet")
+            .header("cache-control", "no-cache")
+            .body(())
+            .unwrap();
+        let res = svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.headers()["x-cache"], "BYPASS");
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let bypass = |name: &str, value: &str| {
+            let req = Request::get("/").header(name, value).body(()).unwrap();
+            svc.bypass(&req)
+        };
+        assert_eq!(bypass("cache-control", "max-age=60"), None);
+        assert_eq!(bypass("cache-control", "No-Cache"), Some(Bypass::Refresh));
+        assert_eq!(
+            bypass("cache-control", "no-cache, no-store"),
+            Some(Bypass::NoStore)
+        );
+        assert_eq!(bypass("x-cache-bypass", "1"), Some(Bypass::Refresh));
+        assert_eq!(
+            bypass("authorization", "Bearer token"),
+            Some(Bypass::NoStore)
+        );
+        assert_eq!(bypass("cookie", "session=1"), Some(Bypass::NoStore));
+
+        // cached for each credential
+        let svc = layer.per_credential().layer(());
+        let key = |token: &str| {
+            let req = Request::get("/greet")
+                .header("authorization", token)
+                .body(())
+                .unwrap();
+            assert_eq!(svc.bypass(&req), None);
+            svc.key(&req)
+        };
+        assert!(key("Bearer alice").starts_with("response:GET:/greet|accept-language=|credential="));
+        assert_ne!(key("Bearer alice"), key("Bearer bob"));
+    }
+}
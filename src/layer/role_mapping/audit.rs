@@ -0,0 +1,289 @@
+/// Audit trail of the authorization decisions, every decided request (allowed,
+/// denied or failed) is recorded with its subjects, object, action, request id and
+/// the [AuditSource] deciding it. It is off by default and costs nothing then.
+///
+/// ```rust,ignore
+/// // logged with target `casbin_audit`, e.p. routed to a dedicated file by the target
+/// let layer = RoleMappingLayer::<Uid, _>::new(enforcer)
+///     .audit(AuditSink::log().subject_privacy(SubjectPrivacy::Hashed(key)));
+/// // or handed to a callback, e.p. shipped to an audit store
+/// let layer = DistributeRoleMappingLayer::<Uid, _>::new(enforcer, source)
+///     .audit(AuditSink::callback(move |record| audit_tx.send(record.clone())));
+/// ```
+///
+/// Only the requests on [PublicRoutes] are not recorded, they need no decision.
+///
+/// [PublicRoutes]: crate::layer::PublicRoutes
+use crate::config::secret::Secret;
+use crate::layer::hmac_auth::to_hex;
+use crate::status::error_body::RequestId;
+use hmac::{Hmac, KeyInit, Mac};
+use http::Request;
+use serde::Serialize;
+use sha2::Sha256;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// The target of the records logged by [AuditSink::log]
+pub const AUDIT_TARGET: &str = "casbin_audit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditDecision {
+    Allow,
+    Deny,
+    /// The enforcer failed, the request is rejected
+    Error,
+}
+
+/// What decided the request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditSource {
+    /// Matched no route group, allowed or denied by [RouteEnforcers] without enforcing
+    ///
+    /// [RouteEnforcers]: crate::layer::RouteEnforcers
+    Route,
+    /// The identity is missing, rejected or passed through by [NoIdentity]
+    ///
+    /// [NoIdentity]: crate::layer::NoIdentity
+    NoIdentity,
+    Enforcer,
+    /// The enforcer exceeded the deadline, decided by [DeadlineFallback]
+    ///
+    /// [DeadlineFallback]: crate::layer::DeadlineFallback
+    Deadline,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub subjects: Vec<String>,
+    pub object: String,
+    pub action: String,
+    pub decision: AuditDecision,
+    pub source: AuditSource,
+    pub request_id: Option<String>,
+}
+
+/// How subjects appear in the records
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SubjectPrivacy {
+    #[default]
+    Plain,
+    /// Replaced by `***`
+    Redacted,
+    /// Replaced by the hex of HMAC-SHA256 keyed by the secret, the records of a
+    /// subject could still be correlated, but the subjects cannot be brute forced
+    /// without the key, so keep it out of the audit store.
+    Hashed(Secret<Vec<u8>>),
+}
+
+impl SubjectPrivacy {
+    fn apply(&self, subject: &str) -> String {
+        match self {
+            SubjectPrivacy::Plain => subject.to_string(),
+            SubjectPrivacy::Redacted => "***".to_string(),
+            SubjectPrivacy::Hashed(key) => {
+                let mut mac =
+                    Hmac::<Sha256>::new_from_slice(key.expose_secret()).expect("any key length");
+                mac.update(subject.as_bytes());
+                to_hex(&mac.finalize().into_bytes())
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditSink {
+    emit: Arc<dyn Fn(&AuditRecord) + Send + Sync>,
+    privacy: SubjectPrivacy,
+}
+
+impl Debug for AuditSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditSink")
+            .field("privacy", &self.privacy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditSink {
+    /// Log the records at INFO level with target [AUDIT_TARGET]
+    pub fn log() -> Self {
+        Self::callback(|record| {
+            info!(
+                target: AUDIT_TARGET,
+                timestamp = record.timestamp,
+                subjects = ?record.subjects,
+                object = %record.object,
+                action = %record.action,
+                decision = ?record.decision,
+                source = ?record.source,
+                request_id = record.request_id.as_deref(),
+                "authorization decision"
+            )
+        })
+    }
+
+    /// Hand the records to the callback, it is called in the request path so it
+    /// must not block
+    pub fn callback(emit: impl Fn(&AuditRecord) + Send + Sync + 'static) -> Self {
+        Self {
+            emit: Arc::new(emit),
+            privacy: SubjectPrivacy::default(),
+        }
+    }
+
+    /// Redact or hash the subjects, plain by default
+    pub fn subject_privacy(mut self, privacy: SubjectPrivacy) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    /// The record of a request before enforcing
+    pub(crate) fn entry<B, S: AsRef<str>>(
+        &self,
+        req: &Request<B>,
+        subs: &[S],
+        obj: &str,
+        act: &str,
+    ) -> AuditEntry {
+        AuditEntry {
+            sink: self.clone(),
+            subjects: subs
+                .iter()
+                .map(|sub| self.privacy.apply(sub.as_ref()))
+                .collect(),
+            object: obj.to_string(),
+            action: act.to_string(),
+            request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        }
+    }
+}
+
+/// A request being enforced, recorded once decided
+pub(crate) struct AuditEntry {
+    sink: AuditSink,
+    subjects: Vec<String>,
+    object: String,
+    action: String,
+    request_id: Option<String>,
+}
+
+impl AuditEntry {
+    /// Record the decision of the enforcer
+    pub(crate) fn decided<E>(self, decision: &Result<bool, E>) {
+        self.decided_by(AuditSource::Enforcer, decision)
+    }
+
+    pub(crate) fn decided_by<E>(self, source: AuditSource, decision: &Result<bool, E>) {
+        let decision = match decision {
+            Ok(true) => AuditDecision::Allow,
+            Ok(false) => AuditDecision::Deny,
+            Err(_) => AuditDecision::Error,
+        };
+        self.record(source, decision)
+    }
+
+    pub(crate) fn record(self, source: AuditSource, decision: AuditDecision) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        (self.sink.emit)(&AuditRecord {
+            timestamp,
+            subjects: self.subjects,
+            object: self.object,
+            action: self.action,
+            decision,
+            source,
+            request_id: self.request_id,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::secret::Secret;
+    use crate::layer::role_mapping::fixture::{enforcer, MODEL};
+    use crate::layer::{
+        AuditDecision, AuditSink, AuditSource, RoleMappingLayer, RouteEnforcers, SubjectPrivacy,
+    };
+    use crate::status::error_body::RequestId;
+    use http::{Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_audit() {
+        let enforcer = enforcer(MODEL, &[&["alice", "/books", "GET"]]).await;
+        let records = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let records = records.clone();
+            AuditSink::callback(move |record| records.lock().unwrap().push(record.clone()))
+                .subject_privacy(SubjectPrivacy::Hashed(Secret::new(b"audit-key".to_vec())))
+        };
+        let routes = RouteEnforcers::deny_unmatched().prefix("/books", enforcer);
+        let svc = RoleMappingLayer::<String, _>::with_routes(routes)
+            .audit(sink)
+            .layer(service_fn(|_req: Request<()>| async {
+                Ok::<_, Infallible>(Response::new(String::new()))
+            }));
+        let req = |method: &str, uri: &str, id: &str| {
+            let mut req = Request::builder().method(method).uri(uri).body(()).unwrap();
+            req.extensions_mut().insert("alice".to_string());
+            req.extensions_mut().insert(RequestId(id.to_string()));
+            req
+        };
+
+        let res = svc
+            .clone()
+            .oneshot(req("GET", "/books", "1"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = svc
+            .clone()
+            .oneshot(req("DELETE", "/books", "2"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = svc
+            .clone()
+            .oneshot(req("GET", "/users", "3"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let anonymous = Request::get("/books").body(()).unwrap();
+        let res = svc.clone().oneshot(anonymous).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let records = records.lock().unwrap();
+        let decisions = records
+            .iter()
+            .map(|record| (record.decision, record.source, record.object.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decisions,
+            [
+                (AuditDecision::Allow, AuditSource::Enforcer, "/books"),
+                (AuditDecision::Deny, AuditSource::Enforcer, "/books"),
+                (AuditDecision::Deny, AuditSource::Route, "/users"),
+                (AuditDecision::Deny, AuditSource::NoIdentity, "/books"),
+            ]
+        );
+        assert_eq!(records[1].request_id.as_deref(), Some("2"));
+        assert_eq!(records[1].action, "DELETE");
+        assert!(records[3].subjects.is_empty());
+        // HMAC-SHA256 of alice keyed by audit-key
+        assert_eq!(
+            records[0].subjects,
+            ["0826c5fe1cfbaaa056960e1880b645053c7f9fd55487370925ea299bbc28dafd"]
+        );
+    }
+}
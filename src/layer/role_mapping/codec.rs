@@ -0,0 +1,360 @@
+use crate::layer::EventData;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use tower::BoxError;
+use tracing::warn;
+
+/// Wire format of [EventData] on the policy bus, the sources and the publishers
+/// of a bus must use the same codec. JSON is the default for readability, the
+/// binary codecs are smaller for high-volume buses:
+/// `bincode` feature => [BincodeEventCodec]
+/// `msgpack` feature => [MsgpackEventCodec]
+///
+/// The serde codecs encode any other value in the same format, e.p. the values
+/// cached by [CacheAside](crate::middleware::redis::CacheAside).
+pub trait EventCodec: Send + Sync + 'static {
+    /// The name in logs, e.p. `json`
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, data: &EventData) -> Result<Vec<u8>, BoxError>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventData, BoxError>;
+
+    /// Encode a value other than [EventData], unsupported by default
+    fn encode_value<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        let _ = value;
+        Err(format!("{} codec cannot encode values", self.name()).into())
+    }
+
+    /// Decode a value other than [EventData], unsupported by default
+    fn decode_value<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        let _ = bytes;
+        Err(format!("{} codec cannot decode values", self.name()).into())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonEventCodec;
+
+impl EventCodec for JsonEventCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, data: &EventData) -> Result<Vec<u8>, BoxError> {
+        self.encode_value(data)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventData, BoxError> {
+        self.decode_value(bytes)
+    }
+
+    fn encode_value<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode_value<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeEventCodec;
+
+#[cfg(feature = "bincode")]
+impl EventCodec for BincodeEventCodec {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn encode(&self, data: &EventData) -> Result<Vec<u8>, BoxError> {
+        self.encode_value(data)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventData, BoxError> {
+        self.decode_value(bytes)
+    }
+
+    fn encode_value<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode_value<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MsgpackEventCodec;
+
+#[cfg(feature = "msgpack")]
+impl EventCodec for MsgpackEventCodec {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, data: &EventData) -> Result<Vec<u8>, BoxError> {
+        self.encode_value(data)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventData, BoxError> {
+        self.decode_value(bytes)
+    }
+
+    fn encode_value<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        Ok(rmp_serde::to_vec(value)?)
+    }
+
+    fn decode_value<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// Compress the payloads of the inner codec with gzip, for the bulk updates (e.p.
+/// [EventData::AddPolicies] with thousands of rules) exceeding the message size limit
+/// of brokers. Only the payloads of at least `min_size` (1 KiB by default) bytes are compressed.
+///
+/// ```rust,ignore
+/// publish_redis_codec(conn, "policy", &data, &GzipEventCodec::new(JsonEventCodec)).await?;
+/// ```
+///
+/// The sources detect the compressed payloads by the gzip magic bytes whatever their
+/// codec, so the compressed and plain payloads could be mixed on a bus. Postgres
+/// notifications only carry text, do not compress them.
+#[derive(Clone, Copy, Debug)]
+pub struct GzipEventCodec<C = JsonEventCodec> {
+    inner: C,
+    min_size: usize,
+}
+
+impl<C: EventCodec> GzipEventCodec<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            min_size: 1024,
+        }
+    }
+
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    fn compress(&self, payload: Vec<u8>) -> Result<Vec<u8>, BoxError> {
+        if payload.len() < self.min_size {
+            return Ok(payload);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload)?;
+        Ok(encoder.finish()?)
+    }
+}
+
+impl<C: EventCodec> EventCodec for GzipEventCodec<C> {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn encode(&self, data: &EventData) -> Result<Vec<u8>, BoxError> {
+        self.compress(self.inner.encode(data)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventData, BoxError> {
+        self.inner.decode(&decompress(bytes)?)
+    }
+
+    fn encode_value<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, BoxError> {
+        self.compress(self.inner.encode_value(value)?)
+    }
+
+    fn decode_value<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, BoxError> {
+        self.inner.decode_value(&decompress(bytes)?)
+    }
+}
+
+// JSON, bincode and msgpack payloads of EventData never start with them
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// against the gzip bombs
+const MAX_DECOMPRESSED: u64 = 64 << 20;
+
+/// Decompress the payload if it is compressed by [GzipEventCodec]
+fn decompress(bytes: &[u8]) -> Result<Cow<'_, [u8]>, BoxError> {
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    let mut payload = Vec::new();
+    GzDecoder::new(bytes)
+        .take(MAX_DECOMPRESSED + 1)
+        .read_to_end(&mut payload)?;
+    if payload.len() as u64 > MAX_DECOMPRESSED {
+        return Err(format!("decompressed payload exceeds {} bytes", MAX_DECOMPRESSED).into());
+    }
+    Ok(Cow::Owned(payload))
+}
+
+/// Decode the payloads with a function, for the buses having their own message
+/// format, e.p. casbin policy lines with [casbin_line]:
+///
+/// ```rust,ignore
+/// let codec = FnEventCodec::new("casbin-line", casbin_line);
+/// let source = redis_source_codec("policy", conn, codec).await;
+/// ```
+///
+/// It cannot encode, the events are published by the existing publishers of the bus.
+#[derive(Clone, Copy)]
+pub struct FnEventCodec<F> {
+    name: &'static str,
+    decode: F,
+}
+
+impl<F> FnEventCodec<F>
+where
+    F: Fn(&[u8]) -> Result<EventData, BoxError> + Send + Sync + 'static,
+{
+    pub fn new(name: &'static str, decode: F) -> Self {
+        Self { name, decode }
+    }
+}
+
+impl<F> EventCodec for FnEventCodec<F>
+where
+    F: Fn(&[u8]) -> Result<EventData, BoxError> + Send + Sync + 'static,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn encode(&self, _data: &EventData) -> Result<Vec<u8>, BoxError> {
+        Err(format!("{} codec cannot encode EventData", self.name).into())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<EventData, BoxError> {
+        (self.decode)(bytes)
+    }
+}
+
+/// Decode a casbin policy line into an added policy, e.p. `p, alice, /book, GET`
+/// into [EventData::AddPolicy] and `g, alice, admin` into [EventData::AddGroupingPolicy].
+/// Only the default ptypes `p` and `g` are supported.
+pub fn casbin_line(bytes: &[u8]) -> Result<EventData, BoxError> {
+    let line = std::str::from_utf8(bytes)?.trim();
+    let mut fields = line.split(',').map(|field| field.trim().to_string());
+    let ptype = fields.next().unwrap_or_default();
+    let rule: Vec<String> = fields.collect();
+    if rule.is_empty() {
+        return Err(format!("policy line `{}` has no rule", line).into());
+    }
+    match &*ptype {
+        "p" => Ok(EventData::AddPolicy(rule)),
+        "g" => Ok(EventData::AddGroupingPolicy(rule)),
+        _ => Err(format!("unsupported ptype `{}`", ptype).into()),
+    }
+}
+
+/// Decode the payload of a source, falls back to NIL. The payload is not
+/// assumed to be text, so only its size is logged. The compressed payloads are
+/// decompressed before decoding.
+pub(crate) fn decode_event<C: EventCodec>(codec: &C, payload: &[u8], source: &str) -> EventData {
+    let decoded = decompress(payload).and_then(|payload| codec.decode(&payload));
+    decoded.unwrap_or_else(|err| {
+        warn!(
+            "Cannot decode EventData ({} bytes) from {} with {}, err: {}",
+            payload.len(),
+            source,
+            codec.name(),
+            err
+        );
+        EventData::NIL
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::role_mapping::codec::decode_event;
+    use crate::layer::{
+        casbin_line, EventCodec, EventData, FnEventCodec, GzipEventCodec, JsonEventCodec,
+    };
+
+    #[test]
+    fn test_event_codec() {
+        let data = EventData::AddPolicy(vec!["alice".to_string(), "/users".to_string()]);
+        let bytes = JsonEventCodec.encode(&data).unwrap();
+        assert_eq!(bytes, br#"{"AddPolicy":["alice","/users"]}"#);
+        assert!(matches!(
+            decode_event(&JsonEventCodec, &bytes, "redis"),
+            EventData::AddPolicy(p) if p == ["alice", "/users"]
+        ));
+        assert!(matches!(
+            decode_event(&JsonEventCodec, &[0xff, 0x00], "redis"),
+            EventData::NIL
+        ));
+
+        let codec = FnEventCodec::new("casbin-line", casbin_line);
+        assert!(matches!(
+            decode_event(&codec, b"p, alice, /book, GET\n", "redis"),
+            EventData::AddPolicy(p) if p == ["alice", "/book", "GET"]
+        ));
+        assert!(matches!(
+            decode_event(&codec, b"g, alice, admin", "redis"),
+            EventData::AddGroupingPolicy(p) if p == ["alice", "admin"]
+        ));
+        assert!(matches!(
+            decode_event(&codec, b"p2, alice", "redis"),
+            EventData::NIL
+        ));
+        assert!(codec.encode(&EventData::NIL).is_err());
+    }
+
+    #[test]
+    fn test_gzip_event_codec() {
+        let rules = (0..5000)
+            .map(|i| {
+                vec![
+                    format!("user-{}", i),
+                    format!("/books/{}", i),
+                    "GET".to_string(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let data = EventData::AddPolicies(rules.clone());
+        let codec = GzipEventCodec::new(JsonEventCodec);
+        let plain = JsonEventCodec.encode(&data).unwrap();
+        let compressed = codec.encode(&data).unwrap();
+        assert!(compressed.len() * 5 < plain.len());
+
+        // auto-detected whatever the codec of the source
+        for payload in [&compressed, &plain] {
+            assert!(matches!(
+                decode_event(&JsonEventCodec, payload, "redis"),
+                EventData::AddPolicies(p) if p == rules
+            ));
+            assert!(matches!(
+                decode_event(&codec, payload, "redis"),
+                EventData::AddPolicies(p) if p == rules
+            ));
+        }
+
+        // small payloads are not compressed
+        let data = EventData::AddPolicy(vec!["alice".to_string()]);
+        assert_eq!(
+            codec.encode(&data).unwrap(),
+            JsonEventCodec.encode(&data).unwrap()
+        );
+        assert!(matches!(
+            decode_event(
+                &JsonEventCodec,
+                &compressed[..compressed.len() / 2],
+                "redis"
+            ),
+            EventData::NIL
+        ));
+    }
+}
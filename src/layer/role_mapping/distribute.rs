@@ -3,27 +3,129 @@
 /// Policies are protect by RwLock.
 ///
 /// Initialize this layer with a [Stream] source(Output=[EventData]) additional
+///
+/// A new policy set could be rolled out as a canary: set a candidate enforcer with
+/// [DistributeRoleMappingLayer::set_candidate], a fraction of requests (see [Canary])
+/// are then enforced by the candidate and the divergences with the current enforcer
+/// are logged. [EventData::Candidate] updates the candidate policies, and the
+/// candidate replaces the current enforcer on [EventData::PromoteCandidate].
+///
+/// The model could be evolved (e.p. a new matcher) without redeploying, see
+/// [DistributeRoleMappingLayer::reload_model].
+///
+/// An event tagged with [EventData::Domain] is only applied if the layer is of the
+/// domain, see [DistributeRoleMappingLayer::domain], others are dropped.
+///
+/// Enforcing a request (waiting for the read lock and enforcing) is bounded by a
+/// deadline, 5 seconds by default, so that a stuck enforcer never hangs all requests.
+/// A request exceeding it is decided by [DeadlineFallback].
+use super::objects::Objects;
+use super::{
+    configure_enforcer, enforce_subjects, AuditDecision, AuditSink, AuditSource, DefaultDecision,
+    DenyStatus, Identity, NoIdentity, PublicRoutes,
+};
+use crate::layer::SubjectExtractor;
+use crate::status::error_body::ErrorResponseBody;
 use async_lock::RwLock;
-use casbin::{CoreApi, Event, EventEmitter, MgmtApi};
+use casbin::{CoreApi, DefaultModel, Event, EventEmitter, MgmtApi, TryIntoAdapter};
+use futures::future::BoxFuture;
 use futures::{ready, FutureExt, Stream, StreamExt};
+use http::header::{HeaderName, HeaderValue};
 use http::{Request, Response, StatusCode};
 use pin_project_lite::pin_project;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tower::{Layer, Service};
-use tracing::{error, trace, warn, Instrument};
+use tracing::{error, info, trace, warn, Instrument};
 
 #[derive(Clone)]
 pub struct DistributeRoleMappingLayer<I, E> {
     enforcer: Arc<RwLock<E>>,
+    candidate: Arc<RwLock<Option<E>>>,
+    // reset under the write lock of their enforcers
+    objects: Arc<Objects>,
+    candidate_objects: Arc<Objects>,
+    canary: Arc<Canary>,
+    listen: Arc<ListenOptions>,
+    public: Arc<PublicRoutes>,
+    status: DenyStatus,
+    default: DefaultDecision,
+    no_identity: NoIdentity,
+    deadline: EnforceDeadline,
+    audit: Option<AuditSink>,
     marker: PhantomData<*const I>,
 }
 
-#[derive(Deserialize, Serialize)]
+/// The options of the source listener, set by the layer after it is spawned
+#[derive(Default)]
+struct ListenOptions {
+    chunk: AtomicUsize,
+    domain: std::sync::RwLock<Option<String>>,
+}
+
+/// The decision of requests exceeding the enforce deadline
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeadlineFallback {
+    /// Respond the denied status
+    #[default]
+    Deny,
+    /// Fail-open, allow the requests with a warning
+    Allow,
+}
+
+#[derive(Clone, Debug)]
+struct EnforceDeadline {
+    timeout: Duration,
+    fallback: DeadlineFallback,
+    exceeded: Arc<AtomicU64>,
+}
+
+impl Default for EnforceDeadline {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            fallback: DeadlineFallback::default(),
+            exceeded: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl EnforceDeadline {
+    /// Run the check within the deadline, fallback if it is exceeded, along with
+    /// what decided the request
+    async fn run<E>(
+        &self,
+        check: impl Future<Output = Result<bool, E>>,
+    ) -> (Result<bool, E>, AuditSource) {
+        match tokio::time::timeout(self.timeout, check).await {
+            Ok(res) => (res, AuditSource::Enforcer),
+            Err(_) => {
+                let exceeded = self.exceeded.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    timeout = ?self.timeout,
+                    fallback = ?self.fallback,
+                    exceeded,
+                    "enforce deadline exceeded"
+                );
+                (
+                    Ok(self.fallback == DeadlineFallback::Allow),
+                    AuditSource::Deadline,
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub enum EventData {
     AddPolicy(Vec<String>),
     AddGroupingPolicy(Vec<String>),
@@ -35,11 +137,19 @@ pub enum EventData {
     RemoveGroupingPolicies(Vec<Vec<String>>),
     RemoveFilteredPolicy(usize, Vec<String>),
     RemoveFilteredGroupingPolicy(usize, Vec<String>),
+    /// Apply the event to the candidate enforcer
+    Candidate(Box<EventData>),
+    /// Replace the current enforcer with the candidate
+    PromoteCandidate,
+    /// Apply the event only to the enforcers of the policy domain, see [EventFanout]
+    ///
+    /// [EventFanout]: crate::layer::EventFanout
+    Domain(String, Box<EventData>),
     NIL, // remain for failing deserializing event data
 }
 
 impl EventData {
-    fn kind(&self) -> &'static str {
+    pub(crate) fn kind(&self) -> &'static str {
         match self {
             EventData::AddPolicy(_) => "AddPolicy",
             EventData::AddGroupingPolicy(_) => "AddGroupingPolicy",
@@ -51,37 +161,141 @@ impl EventData {
             EventData::RemoveGroupingPolicies(_) => "RemoveGroupingPolicies",
             EventData::RemoveFilteredPolicy(_, _) => "RemoveFilteredPolicy",
             EventData::RemoveFilteredGroupingPolicy(_, _) => "RemoveFilteredGroupingPolicy",
+            EventData::Candidate(_) => "Candidate",
+            EventData::PromoteCandidate => "PromoteCandidate",
+            EventData::Domain(_, data) => data.kind(),
             EventData::NIL => "NIL",
         }
     }
 }
 
+async fn apply<E: CoreApi + EventEmitter<Event>>(
+    enforcer: &mut E,
+    data: EventData,
+) -> casbin::Result<bool> {
+    match data {
+        EventData::AddPolicy(p) => enforcer.add_policy(p).await,
+        EventData::AddGroupingPolicy(p) => enforcer.add_grouping_policy(p).await,
+        EventData::AddPolicies(p) => enforcer.add_policies(p).await,
+        EventData::AddGroupingPolicies(p) => enforcer.add_grouping_policies(p).await,
+        EventData::RemovePolicy(p) => enforcer.remove_policy(p).await,
+        EventData::RemoveGroupingPolicy(p) => enforcer.remove_grouping_policy(p).await,
+        EventData::RemovePolicies(p) => enforcer.remove_policies(p).await,
+        EventData::RemoveGroupingPolicies(p) => enforcer.remove_grouping_policies(p).await,
+        EventData::RemoveFilteredPolicy(i, p) => enforcer.remove_filtered_policy(i, p).await,
+        EventData::RemoveFilteredGroupingPolicy(i, p) => {
+            enforcer.remove_filtered_grouping_policy(i, p).await
+        }
+        _ => Ok(true),
+    }
+}
+
+/// Apply a large batch chunk by chunk, the write lock is released between chunks
+/// so that requests are not stalled by a bulk update. Chunking is disabled with 0.
+async fn apply_chunked<E: CoreApi + EventEmitter<Event>>(
+    enforcer: &RwLock<E>,
+    objects: &Objects,
+    data: EventData,
+    chunk: usize,
+) -> casbin::Result<bool> {
+    let (batch, rebuild): (_, fn(Vec<Vec<String>>) -> EventData) = match data {
+        EventData::AddPolicies(p) if chunk > 0 && p.len() > chunk => (p, EventData::AddPolicies),
+        EventData::AddGroupingPolicies(p) if chunk > 0 && p.len() > chunk => {
+            (p, EventData::AddGroupingPolicies)
+        }
+        EventData::RemovePolicies(p) if chunk > 0 && p.len() > chunk => {
+            (p, EventData::RemovePolicies)
+        }
+        EventData::RemoveGroupingPolicies(p) if chunk > 0 && p.len() > chunk => {
+            (p, EventData::RemoveGroupingPolicies)
+        }
+        data => {
+            let mut current = enforcer.write().await;
+            let applied = apply(&mut *current, data).await;
+            objects.reset();
+            return applied;
+        }
+    };
+    let mut applied = true;
+    let mut batch = batch.into_iter();
+    loop {
+        let part = batch.by_ref().take(chunk).collect::<Vec<_>>();
+        if part.is_empty() {
+            break;
+        }
+        let mut current = enforcer.write().await;
+        let part = apply(&mut *current, rebuild(part)).await;
+        objects.reset();
+        drop(current);
+        applied &= part?;
+        // let the waiting requests enforce between chunks
+        tokio::task::yield_now().await;
+    }
+    Ok(applied)
+}
+
 fn listen_source<
     E: CoreApi + EventEmitter<Event> + Send + Sync + 'static,
     S: Stream<Item = EventData> + Send + 'static,
 >(
     enforcer: Arc<RwLock<E>>,
+    candidate: Arc<RwLock<Option<E>>>,
+    objects: Arc<Objects>,
+    candidate_objects: Arc<Objects>,
+    listen: Arc<ListenOptions>,
     source: S,
-) {
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
     let listener_loop = async move {
         tokio::pin!(source);
-        while let Some(data) = source.next().await {
-            let mut guard = enforcer.write().await;
+        loop {
+            // the event being applied is not interrupted by shutdown,
+            // so that a policy update is never partially applied.
+            let data = tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => break,
+                data = source.next() => match data {
+                    Some(data) => data,
+                    None => break,
+                },
+            };
+            let data = match data {
+                EventData::Domain(tag, data) => {
+                    if listen.domain.read().unwrap().as_deref() != Some(tag.as_str()) {
+                        trace!("Drop the event data of domain {}", tag);
+                        continue;
+                    }
+                    *data
+                }
+                data => data,
+            };
             let kind = data.kind();
             let res = match data {
-                EventData::AddPolicy(p) => guard.add_policy(p).await,
-                EventData::AddGroupingPolicy(p) => guard.add_grouping_policy(p).await,
-                EventData::AddPolicies(p) => guard.add_policies(p).await,
-                EventData::AddGroupingPolicies(p) => guard.add_grouping_policies(p).await,
-                EventData::RemovePolicy(p) => guard.remove_policy(p).await,
-                EventData::RemoveGroupingPolicy(p) => guard.remove_grouping_policy(p).await,
-                EventData::RemovePolicies(p) => guard.remove_policies(p).await,
-                EventData::RemoveGroupingPolicies(p) => guard.remove_grouping_policies(p).await,
-                EventData::RemoveFilteredPolicy(i, p) => guard.remove_filtered_policy(i, p).await,
-                EventData::RemoveFilteredGroupingPolicy(i, p) => {
-                    guard.remove_filtered_grouping_policy(i, p).await
+                EventData::Candidate(data) => match &mut *candidate.write().await {
+                    Some(candidate) => {
+                        let applied = apply(candidate, *data).await;
+                        candidate_objects.reset();
+                        applied
+                    }
+                    None => Ok(false),
+                },
+                EventData::PromoteCandidate => {
+                    let mut current = enforcer.write().await;
+                    match candidate.write().await.take() {
+                        Some(candidate) => {
+                            *current = candidate;
+                            objects.reset();
+                            candidate_objects.reset();
+                            info!("Candidate enforcer is promoted");
+                            Ok(true)
+                        }
+                        None => Ok(false),
+                    }
+                }
+                data => {
+                    let chunk = listen.chunk.load(Ordering::Relaxed);
+                    apply_chunked(&enforcer, &objects, data, chunk).await
                 }
-                _ => Ok(true),
             };
             match res {
                 Ok(false) => warn!("Failed handle event data {:?}", kind),
@@ -89,21 +303,267 @@ fn listen_source<
                 _ => trace!("Updated enforcer"),
             }
         }
+        trace!("Role mapping listener exited");
     }
     .in_current_span();
     // spawn listener loop
-    tokio::spawn(listener_loop);
+    tokio::spawn(listener_loop)
 }
 
 impl<I, E: CoreApi + EventEmitter<Event> + 'static> DistributeRoleMappingLayer<I, E> {
     /// source is where the policy changes comes from, it might be a message queue.
     pub fn new<S: Stream<Item = EventData> + Send + 'static>(enforcer: E, source: S) -> Self {
+        Self::with_shutdown(enforcer, source, CancellationToken::new()).0
+    }
+
+    /// Same as [DistributeRoleMappingLayer::new] but configure the enforcer before
+    /// listening to the source, see [configure_enforcer]. Use [configure_enforcer]
+    /// with [DistributeRoleMappingLayer::with_shutdown] for graceful shutdown.
+    pub fn configured<S: Stream<Item = EventData> + Send + 'static>(
+        enforcer: E,
+        configure: impl FnOnce(&mut E) -> casbin::Result<()>,
+        source: S,
+    ) -> casbin::Result<Self> {
+        Ok(Self::new(configure_enforcer(enforcer, configure)?, source))
+    }
+
+    /// Same as [DistributeRoleMappingLayer::new], but the listener of source stops
+    /// taking new events once `shutdown` is cancelled. The event being applied
+    /// is finished before exiting, await the returned handle for termination.
+    pub fn with_shutdown<S: Stream<Item = EventData> + Send + 'static>(
+        enforcer: E,
+        source: S,
+        shutdown: CancellationToken,
+    ) -> (Self, JoinHandle<()>) {
         let enforcer = Arc::new(RwLock::new(enforcer));
-        listen_source(enforcer.clone(), source);
+        let candidate = Arc::new(RwLock::new(None));
+        let objects = Arc::new(Objects::default());
+        let candidate_objects = Arc::new(Objects::default());
+        let listen = Arc::new(ListenOptions::default());
+        let handle = listen_source(
+            enforcer.clone(),
+            candidate.clone(),
+            objects.clone(),
+            candidate_objects.clone(),
+            listen.clone(),
+            source,
+            shutdown,
+        );
+        (
+            Self {
+                enforcer,
+                candidate,
+                objects,
+                candidate_objects,
+                canary: Arc::new(Canary::default()),
+                listen,
+                public: Arc::new(PublicRoutes::default()),
+                status: DenyStatus::default(),
+                default: DefaultDecision::default(),
+                no_identity: NoIdentity::default(),
+                deadline: EnforceDeadline::default(),
+                audit: None,
+                marker: PhantomData,
+            },
+            handle,
+        )
+    }
+
+    /// Status of denied requests, `403 Forbidden` by default
+    pub fn denied_status(mut self, status: StatusCode) -> Self {
+        self.status.denied = status;
+        self
+    }
+
+    /// Status of requests failed to be enforced, `500 Internal Server Error` by default
+    pub fn error_status(mut self, status: StatusCode) -> Self {
+        self.status.error = status;
+        self
+    }
+
+    /// Status of requests rejected by [NoIdentity::Deny], `401 Unauthorized` by default
+    pub fn unauthenticated_status(mut self, status: StatusCode) -> Self {
+        self.status.unauthenticated = status;
+        self
+    }
+
+    /// The decision of requests on objects without any policy, `Deny` by default
+    pub fn default_decision(mut self, default: DefaultDecision) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// How requests without identity are handled, `Deny` by default
+    pub fn no_identity(mut self, no_identity: NoIdentity) -> Self {
+        self.no_identity = no_identity;
+        self
+    }
+
+    /// Routes open to everyone without identity and enforcement, see [PublicRoutes]
+    pub fn public_routes(mut self, public: PublicRoutes) -> Self {
+        self.public = Arc::new(public);
+        self
+    }
+
+    /// The max duration of enforcing a request, including waiting for the policies
+    /// being updated, 5 seconds by default
+    pub fn enforce_deadline(mut self, timeout: Duration) -> Self {
+        self.deadline.timeout = timeout;
+        self
+    }
+
+    /// The decision of requests exceeding the enforce deadline, `Deny` by default
+    pub fn deadline_fallback(mut self, fallback: DeadlineFallback) -> Self {
+        self.deadline.fallback = fallback;
+        self
+    }
+
+    /// Record every decision, including the ones decided by [DeadlineFallback],
+    /// see [AuditSink]
+    pub fn audit(mut self, sink: AuditSink) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// The number of requests exceeded the enforce deadline
+    pub fn deadline_exceeded(&self) -> u64 {
+        self.deadline.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Apply large batches (`AddPolicies`, `RemovePolicies`, etc.) of the source
+    /// in chunks of `size` policies and yield between chunks, disabled (0) by default.
+    ///
+    /// The tradeoff is that requests may see a partially applied batch. With allow-only
+    /// policies, an add (or remove) batch only grants (or revokes) permissions step by
+    /// step, so a request permitted both before and after the batch is never denied in
+    /// between. It does not hold for models with deny effects, keep it disabled for them.
+    /// Each chunk is applied by casbin atomically, a chunk containing an existing (or
+    /// missing) policy is not applied and the batch is reported as failed.
+    pub fn chunk_size(self, size: usize) -> Self {
+        self.listen.chunk.store(size, Ordering::Relaxed);
+        self
+    }
+
+    /// The policy domain of the layer, the events tagged with [EventData::Domain] of
+    /// it are applied and the ones of other domains are dropped. None by default, all
+    /// tagged events are dropped. Not needed with the sources of [EventFanout], they
+    /// are untagged.
+    ///
+    /// [EventFanout]: crate::layer::EventFanout
+    pub fn domain(self, domain: impl Into<String>) -> Self {
+        *self.listen.domain.write().unwrap() = Some(domain.into());
+        self
+    }
+
+    /// Requests routed to the candidate enforcer, none by default
+    pub fn canary(mut self, canary: Canary) -> Self {
+        self.canary = Arc::new(canary);
+        self
+    }
+
+    /// Set the candidate enforcer to roll out a new policy set,
+    /// the previous candidate is replaced.
+    pub async fn set_candidate(&self, candidate: E) {
+        let mut current = self.candidate.write().await;
+        *current = Some(candidate);
+        self.candidate_objects.reset();
+    }
+
+    /// Drop the candidate enforcer without promoting it
+    pub async fn discard_candidate(&self) -> Option<E> {
+        let mut current = self.candidate.write().await;
+        self.candidate_objects.reset();
+        current.take()
+    }
+
+    /// Swap in an enforcer of a new model with the policies of `adapter`, configured
+    /// like [configure_enforcer]. The model is parsed before taking the write lock and
+    /// the current enforcer is kept if anything fails.
+    ///
+    /// The policies are loaded under the write lock, so no event is applied to the
+    /// old enforcer in between, the requests wait for it within the enforce deadline.
+    /// The candidate enforcer (if any) is discarded as it is built with the old model.
+    pub async fn reload_model<A: TryIntoAdapter>(
+        &self,
+        model: &str,
+        adapter: A,
+        configure: impl FnOnce(&mut E) -> casbin::Result<()>,
+    ) -> casbin::Result<()> {
+        let parsed = match DefaultModel::from_str(model).await {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                error!(
+                    "Cannot parse casbin model, keep the current one, err: {}",
+                    err
+                );
+                return Err(err);
+            }
+        };
+        let mut current = self.enforcer.write().await;
+        let reloaded = match E::new(parsed, adapter).await {
+            Ok(enforcer) => configure_enforcer(enforcer, configure),
+            Err(err) => Err(err),
+        };
+        match reloaded {
+            Ok(enforcer) => {
+                *current = enforcer;
+                self.objects.reset();
+                let mut candidate = self.candidate.write().await;
+                self.candidate_objects.reset();
+                if candidate.take().is_some() {
+                    warn!("Candidate enforcer is discarded by the model reload");
+                }
+                warn!(model = %model.trim(), "Casbin model is reloaded");
+                Ok(())
+            }
+            Err(err) => {
+                error!(
+                    "Cannot reload casbin model, keep the current one, err: {}",
+                    err
+                );
+                Err(err)
+            }
+        }
+    }
+
+    /// The policies of the current enforcer, read under the read lock, see [dump_policy]
+    ///
+    /// [dump_policy]: crate::layer::dump_policy
+    #[cfg(feature = "policy-dump")]
+    pub async fn dump_policy(&self) -> crate::layer::PolicyDump {
+        super::dump::dump_policy(&*self.enforcer.read().await)
+    }
+}
+
+/// Which requests are enforced by the candidate enforcer
+#[derive(Clone, Debug, Default)]
+pub struct Canary {
+    ratio: f64,
+    header: Option<(HeaderName, HeaderValue)>,
+}
+
+impl Canary {
+    /// A fraction (0.0 to 1.0) of requests picked randomly
+    pub fn new(ratio: f64) -> Self {
         Self {
-            enforcer,
-            marker: PhantomData,
+            ratio: ratio.clamp(0.0, 1.0),
+            header: None,
+        }
+    }
+
+    /// Requests with the header are always routed, e.p. `x-canary: true`
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.header = Some((name, value));
+        self
+    }
+
+    fn routes<B>(&self, req: &Request<B>) -> bool {
+        if let Some((ref name, ref value)) = self.header {
+            if req.headers().get(name) == Some(value) {
+                return true;
+            }
         }
+        self.ratio > 0.0 && rand::thread_rng().gen_bool(self.ratio)
     }
 }
 
@@ -114,6 +574,16 @@ impl<S, I, E> Layer<S> for DistributeRoleMappingLayer<I, E> {
         DistributeRoleMapping {
             inner,
             enforcer: self.enforcer.clone(),
+            candidate: self.candidate.clone(),
+            objects: self.objects.clone(),
+            candidate_objects: self.candidate_objects.clone(),
+            canary: self.canary.clone(),
+            public: self.public.clone(),
+            status: self.status.clone(),
+            default: self.default,
+            no_identity: self.no_identity.clone(),
+            deadline: self.deadline.clone(),
+            audit: self.audit.clone(),
             marker: PhantomData,
         }
     }
@@ -123,20 +593,31 @@ impl<S, I, E> Layer<S> for DistributeRoleMappingLayer<I, E> {
 pub struct DistributeRoleMapping<S, I, E> {
     inner: S,
     enforcer: Arc<RwLock<E>>,
+    candidate: Arc<RwLock<Option<E>>>,
+    // reset under the write lock of their enforcers
+    objects: Arc<Objects>,
+    candidate_objects: Arc<Objects>,
+    canary: Arc<Canary>,
+    public: Arc<PublicRoutes>,
+    status: DenyStatus,
+    default: DefaultDecision,
+    no_identity: NoIdentity,
+    deadline: EnforceDeadline,
+    audit: Option<AuditSink>,
     marker: PhantomData<*const I>,
 }
 
 impl<S, I, E, ReqBody, ResBody> Service<Request<ReqBody>> for DistributeRoleMapping<S, I, E>
 where
-    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Send + 'static,
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
     S::Future: Send + 'static,
-    ResBody: Default,
-    I: AsRef<str> + Send + Sync + 'static,
-    E: CoreApi,
+    ResBody: ErrorResponseBody,
+    I: SubjectExtractor,
+    E: CoreApi + 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
-    type Future = ResponseFuture<E, S, ReqBody, ResBody>;
+    type Future = ResponseFuture<S, ReqBody, ResBody>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.inner.poll_ready(cx)
@@ -146,67 +627,322 @@ where
         // obj => query path
         // act => http method
         // sub => request extension
-        let sub = req
-            .extensions()
-            .get::<I>()
-            .map(|sub| sub.as_ref())
-            .unwrap_or("")
-            .to_string();
+        let mut status = self.status.clone().of(&req);
+        let public = self.public.matches(&req);
+        let identity = if public {
+            Identity::PassThrough
+        } else {
+            self.no_identity.resolve(I::subjects(&req))
+        };
+        // the requests without identity are decided here, the public ones need no decision
+        let no_identity = match (&identity, &self.audit) {
+            (Identity::Unauthenticated, Some(audit)) => Some((audit, AuditDecision::Deny)),
+            (Identity::PassThrough, Some(audit)) if !public => Some((audit, AuditDecision::Allow)),
+            _ => None,
+        };
+        if let Some((audit, decision)) = no_identity {
+            audit
+                .entry::<_, &str>(&req, &[], req.uri().path(), req.method().as_str())
+                .record(AuditSource::NoIdentity, decision);
+        }
+        let subs: Option<Vec<String>> = match identity {
+            Identity::Subjects(subs) => Some(subs.into_iter().map(ToString::to_string).collect()),
+            Identity::Unauthenticated => {
+                // respond the denial with the unauthenticated status
+                status.denied = status.unauthenticated;
+                None
+            }
+            Identity::PassThrough => {
+                let clone = self.inner.clone();
+                let inner = std::mem::replace(&mut self.inner, clone);
+                return ResponseFuture {
+                    status,
+                    state: EnforceState::Enforce {
+                        check: Box::pin(async { Ok(true) }),
+                        inner,
+                        req: Some(req),
+                    },
+                };
+            }
+        };
         let obj = req.uri().path().to_string();
         let act = req.method().to_string();
-        ResponseFuture::<_, S, _, _> {
-            enforcer: self.enforcer.clone(),
-            arguments: (sub, obj, act),
-            fut: self.inner.call(req),
+        let audit = match (&self.audit, &subs) {
+            (Some(audit), Some(subs)) => Some(audit.entry(&req, subs, &obj, &act)),
+            _ => None,
+        };
+        let enforcer = self.enforcer.clone();
+        let candidate = self.candidate.clone();
+        let objects = self.objects.clone();
+        let candidate_objects = self.candidate_objects.clone();
+        let routed = self.canary.routes(&req);
+        let default = self.default;
+        let deadline = self.deadline.clone();
+        let check = async move {
+            let subs = match subs {
+                Some(subs) => subs,
+                None => return Ok(false),
+            };
+            // release the current enforcer before reading the candidate
+            let current = enforce_subjects(
+                &*enforcer.read().await,
+                &objects,
+                &subs,
+                &obj,
+                &act,
+                default,
+            )?;
+            if !routed {
+                return Ok(current);
+            }
+            match &*candidate.read().await {
+                Some(candidate) => match enforce_subjects(
+                    candidate,
+                    &candidate_objects,
+                    &subs,
+                    &obj,
+                    &act,
+                    default,
+                ) {
+                    Ok(decision) => {
+                        if decision != current {
+                            warn!(
+                                ?subs,
+                                obj = %obj,
+                                act = %act,
+                                current,
+                                candidate = decision,
+                                "candidate policies diverge from the current"
+                            );
+                        }
+                        Ok(decision)
+                    }
+                    Err(err) => {
+                        warn!("candidate enforcer is working abnormally, err: {:?}", err);
+                        Ok(current)
+                    }
+                },
+                None => Ok(current),
+            }
+        };
+        let check = Box::pin(async move {
+            let (decision, source) = deadline.run(check).await;
+            if let Some(audit) = audit {
+                audit.decided_by(source, &decision);
+            }
+            decision
+        });
+        // the inner service is called only after the request is authorized,
+        // e.p. a websocket upgrade handshake never starts for a denied request.
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        ResponseFuture {
+            status,
+            state: EnforceState::Enforce {
+                check,
+                inner,
+                req: Some(req),
+            },
         }
     }
 }
 
 pin_project! {
-    pub struct ResponseFuture<E, S, ReqBody, ResBody>
+    pub struct ResponseFuture<S, ReqBody, ResBody>
     where
         S: Service<Request<ReqBody>, Response = Response<ResBody>>
     {
-        enforcer: Arc<RwLock<E>>,
+        status: DenyStatus,
         #[pin]
-        fut: S::Future,
-        arguments: (String, String, String),
+        state: EnforceState<S, ReqBody, S::Future>,
     }
 }
 
-impl<E, S, ReqBody, ResBody> Future for ResponseFuture<E, S, ReqBody, ResBody>
+pin_project! {
+    #[project = EnforceStateProj]
+    enum EnforceState<S, ReqBody, F> {
+        Enforce {
+            check: BoxFuture<'static, Result<bool, casbin::Error>>,
+            inner: S,
+            req: Option<Request<ReqBody>>,
+        },
+        Authorized {
+            #[pin]
+            fut: F,
+        },
+    }
+}
+
+impl<S, ReqBody, ResBody> Future for ResponseFuture<S, ReqBody, ResBody>
 where
-    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Send + 'static,
-    S::Future: Send + 'static,
-    ResBody: Default,
-    E: CoreApi,
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: ErrorResponseBody,
 {
     type Output = Result<S::Response, S::Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        let mut read = this.enforcer.read();
-        let enforcer = ready!(read.poll_unpin(cx));
-        let arg = this.arguments;
-        match enforcer.enforce((&*arg.0, &*arg.1, &*arg.2)) {
-            Ok(checked) => {
-                if checked {
-                    let output = ready!(this.fut.poll(cx));
-                    Poll::Ready(output)
-                } else {
-                    Poll::Ready(Ok(Response::builder()
-                        .status(StatusCode::FORBIDDEN)
-                        .body(ResBody::default())
-                        .unwrap()))
+        let mut this = self.project();
+        loop {
+            match this.state.as_mut().project() {
+                EnforceStateProj::Enforce { check, inner, req } => {
+                    match ready!(check.poll_unpin(cx)) {
+                        Ok(true) => {
+                            let fut = inner.call(req.take().expect("request is taken"));
+                            this.state.set(EnforceState::Authorized { fut });
+                        }
+                        Ok(false) => return Poll::Ready(Ok(this.status.denied())),
+                        Err(err) => {
+                            warn!("enforcer is working abnormally, err: {:?}", err);
+                            return Poll::Ready(Ok(this.status.error()));
+                        }
+                    }
                 }
+                EnforceStateProj::Authorized { fut } => return fut.poll(cx),
             }
-            Err(err) => {
-                warn!("enforcer is working abnormally, err: {:?}", err);
-                Poll::Ready(Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(ResBody::default())
-                    .unwrap()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeadlineFallback, EnforceDeadline};
+    use crate::layer::role_mapping::fixture::{enforcer, MODEL};
+    use crate::layer::{
+        AuditDecision, AuditSink, AuditSource, DistributeRoleMappingLayer, EventData,
+    };
+    use casbin::{CoreApi, DefaultModel, Enforcer, FileAdapter};
+    use http::{Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_enforce_deadline() {
+        let mut deadline = EnforceDeadline {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let stuck = || futures::future::pending::<Result<bool, ()>>();
+        assert_eq!(
+            deadline.run(async { Ok::<_, ()>(true) }).await,
+            (Ok(true), AuditSource::Enforcer)
+        );
+        assert_eq!(
+            deadline.run(stuck()).await,
+            (Ok(false), AuditSource::Deadline)
+        );
+
+        deadline.fallback = DeadlineFallback::Allow;
+        assert_eq!(
+            deadline.run(stuck()).await,
+            (Ok(true), AuditSource::Deadline)
+        );
+        assert_eq!(deadline.exceeded.load(Ordering::Relaxed), 2);
+
+        // recorded as decided by the deadline rather than the enforcer
+        let records = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let records = records.clone();
+            AuditSink::callback(move |record| records.lock().unwrap().push(record.clone()))
+        };
+        let layer = DistributeRoleMappingLayer::<String, _>::new(
+            enforcer(MODEL, &[&["alice", "/users", "GET"]]).await,
+            futures::stream::pending(),
+        )
+        .enforce_deadline(Duration::from_millis(10))
+        .audit(sink);
+        let svc = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let req = Request::get("/users")
+            .extension("alice".to_string())
+            .body(())
+            .unwrap();
+        // the policies are being updated
+        let updating = layer.enforcer.write().await;
+        let res = svc.oneshot(req).await.unwrap();
+        drop(updating);
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].decision, AuditDecision::Deny);
+        assert_eq!(records[0].source, AuditSource::Deadline);
+    }
+
+    #[tokio::test]
+    async fn test_reload_model() {
+        let policy = std::env::temp_dir().join(format!("common-policy-{}.csv", std::process::id()));
+        std::fs::write(&policy, "p, alice, /users/*, GET\n").unwrap();
+        let policy = policy.to_str().unwrap().to_string();
+        let model = DefaultModel::from_str(MODEL).await.unwrap();
+        let enforcer = Enforcer::new(model, FileAdapter::new(policy.clone()))
+            .await
+            .unwrap();
+        let layer =
+            DistributeRoleMappingLayer::<String, _>::new(enforcer, futures::stream::pending());
+        let status = |layer: &DistributeRoleMappingLayer<String, Enforcer>| {
+            let svc = layer.layer(service_fn(|_: Request<()>| async {
+                Ok::<_, Infallible>(Response::new(()))
+            }));
+            let req = Request::get("/users/1")
+                .extension("alice".to_string())
+                .body(())
+                .unwrap();
+            async move { svc.oneshot(req).await.unwrap().status() }
+        };
+        assert_eq!(status(&layer).await, StatusCode::FORBIDDEN);
+
+        // the old enforcer is kept
+        let invalid = layer
+            .reload_model("[matchers]", FileAdapter::new(policy.clone()), |_| Ok(()))
+            .await;
+        assert!(invalid.is_err());
+        assert_eq!(status(&layer).await, StatusCode::FORBIDDEN);
+
+        let key_match = MODEL.replace("r.obj == p.obj", "keyMatch(r.obj, p.obj)");
+        layer
+            .reload_model(&key_match, FileAdapter::new(policy.clone()), |_| Ok(()))
+            .await
+            .unwrap();
+        assert_eq!(status(&layer).await, StatusCode::OK);
+        std::fs::remove_file(policy).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_domain() {
+        let enforcer = enforcer(MODEL, &[]).await;
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let layer = DistributeRoleMappingLayer::<String, _>::new(enforcer, rx).domain("user");
+        let svc = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let status = |sub: &str| {
+            let req = Request::get("/users")
+                .extension(sub.to_string())
+                .body(())
+                .unwrap();
+            let svc = svc.clone();
+            async move { svc.oneshot(req).await.unwrap().status() }
+        };
+        let tagged = |domain: &str, sub: &str| {
+            let policy = vec![sub.to_string(), "/users".to_string(), "GET".to_string()];
+            EventData::Domain(domain.to_string(), Box::new(EventData::AddPolicy(policy)))
+        };
+        tx.unbounded_send(tagged("book", "alice")).unwrap();
+        tx.unbounded_send(tagged("user", "bob")).unwrap();
+
+        let mut bob = StatusCode::FORBIDDEN;
+        for _ in 0..100 {
+            bob = status("bob").await;
+            if bob == StatusCode::OK {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(bob, StatusCode::OK);
+        // applied in order, the event of book is dropped before
+        assert_eq!(status("alice").await, StatusCode::FORBIDDEN);
     }
 }
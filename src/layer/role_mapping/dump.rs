@@ -0,0 +1,80 @@
+//! Dump the policies an enforcer currently holds, for troubleshooting the denied
+//! requests. It is gated by the `policy-dump` feature so that it is never exposed
+//! by accident.
+//!
+//! The policies are not secrets but are sensitive (who could do what), so the
+//! handler must be mounted behind an authenticated admin route, e.p. behind
+//! [HmacAuthLayer] or a role mapping allowing only the admins:
+//!
+//! ```rust,ignore
+//! // GET /admin/policy
+//! let dump = layer.dump_policy().await;
+//! let res: Response<String> = dump.into_response();
+//! ```
+//!
+//! [HmacAuthLayer]: crate::layer::HmacAuthLayer
+
+use casbin::MgmtApi;
+use http::header::CONTENT_TYPE;
+use http::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// The `p` and `g` rules of an enforcer
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyDump {
+    pub policy: Vec<Vec<String>>,
+    pub grouping_policy: Vec<Vec<String>>,
+}
+
+impl PolicyDump {
+    /// A `200 OK` response with the dump in JSON
+    pub fn into_response<ResBody: From<String>>(self) -> Response<ResBody> {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(ResBody::from(serde_json::to_string(&self).unwrap()))
+            .unwrap()
+    }
+}
+
+/// The policies enforcer holds, use [DistributeRoleMappingLayer::dump_policy] to dump
+/// the enforcer of a layer without racing its updates.
+///
+/// [DistributeRoleMappingLayer::dump_policy]: crate::layer::DistributeRoleMappingLayer::dump_policy
+pub fn dump_policy<E: MgmtApi>(enforcer: &E) -> PolicyDump {
+    PolicyDump {
+        policy: enforcer.get_policy(),
+        grouping_policy: enforcer.get_grouping_policy(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::role_mapping::fixture::{enforcer, rule, MODEL};
+    use crate::layer::{dump_policy, DistributeRoleMappingLayer, PolicyDump};
+    use casbin::MgmtApi;
+    use http::Response;
+
+    #[tokio::test]
+    async fn test_dump_policy() {
+        let mut enforcer = enforcer(MODEL, &[&["admin", "/books", "GET"]]).await;
+        enforcer
+            .add_grouping_policy(rule(&["alice", "admin"]))
+            .await
+            .unwrap();
+        let expected = PolicyDump {
+            policy: vec![rule(&["admin", "/books", "GET"])],
+            grouping_policy: vec![rule(&["alice", "admin"])],
+        };
+        assert_eq!(dump_policy(&enforcer), expected);
+
+        let layer =
+            DistributeRoleMappingLayer::<String, _>::new(enforcer, futures::stream::pending());
+        let res: Response<String> = layer.dump_policy().await.into_response();
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(
+            res.body(),
+            r#"{"policy":[["admin","/books","GET"]],"grouping_policy":[["alice","admin"]]}"#
+        );
+    }
+}
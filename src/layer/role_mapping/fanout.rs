@@ -0,0 +1,111 @@
+/// Drive several enforcers with one event source, e.p. the role mapping layers of
+/// the services hosted in one process, so that a single bus subscription keeps
+/// them all updated.
+///
+/// An event tagged with [EventData::Domain] is only dispatched to the enforcers
+/// subscribed to the domain (without the tag), the untagged events are dispatched
+/// to all of them.
+/// Subscribe all the enforcers before starting, the events dispatched before a
+/// subscription are not replayed to it.
+///
+/// ```rust,ignore
+/// let mut fanout = EventFanout::new();
+/// let user = DistributeRoleMappingLayer::new(user_enforcer, fanout.subscribe("user"));
+/// let book = DistributeRoleMappingLayer::new(book_enforcer, fanout.subscribe("book"));
+/// fanout.start(redis_source(client, "policy").await?);
+/// ```
+use super::EventData;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Stream, StreamExt};
+use tokio::task::JoinHandle;
+use tracing::{trace, Instrument};
+
+#[derive(Default)]
+pub struct EventFanout {
+    subscribers: Vec<(String, UnboundedSender<EventData>)>,
+}
+
+impl EventFanout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The events of the policy domain and the untagged events, pass it as the
+    /// source of a [DistributeRoleMappingLayer]
+    ///
+    /// [DistributeRoleMappingLayer]: crate::layer::DistributeRoleMappingLayer
+    pub fn subscribe(&mut self, domain: impl Into<String>) -> UnboundedReceiver<EventData> {
+        let (tx, rx) = unbounded();
+        self.subscribers.push((domain.into(), tx));
+        rx
+    }
+
+    /// Dispatch the events of source until it ends or all subscribers are dropped
+    pub fn start<S: Stream<Item = EventData> + Send + 'static>(
+        mut self,
+        source: S,
+    ) -> JoinHandle<()> {
+        let dispatch = async move {
+            tokio::pin!(source);
+            while let Some(data) = source.next().await {
+                self.dispatch(data);
+                if self.subscribers.is_empty() {
+                    break;
+                }
+            }
+            trace!("Role mapping fanout exited");
+        }
+        .in_current_span();
+        tokio::spawn(dispatch)
+    }
+
+    fn dispatch(&mut self, data: EventData) {
+        let (domain, data) = match data {
+            EventData::Domain(domain, data) => (Some(domain), *data),
+            data => (None, data),
+        };
+        // the closed subscribers are dropped
+        self.subscribers.retain(|(subscribed, tx)| {
+            if domain.as_ref().is_some_and(|domain| domain != subscribed) {
+                return !tx.is_closed();
+            }
+            tx.unbounded_send(data.clone()).is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::{EventData, EventFanout};
+    use futures::StreamExt;
+
+    fn policy(sub: &str) -> EventData {
+        EventData::AddPolicy(vec![sub.to_string()])
+    }
+
+    fn subjects(events: Vec<EventData>) -> Vec<String> {
+        events
+            .into_iter()
+            .map(|data| match data {
+                EventData::AddPolicy(p) => p[0].clone(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fanout() {
+        let mut fanout = EventFanout::new();
+        let user = fanout.subscribe("user");
+        let book = fanout.subscribe("book");
+        let source = futures::stream::iter(vec![
+            policy("all"),
+            EventData::Domain("user".to_string(), Box::new(policy("alice"))),
+            EventData::Domain("book".to_string(), Box::new(policy("bob"))),
+        ]);
+        fanout.start(source).await.unwrap();
+
+        assert_eq!(subjects(user.collect().await), ["all", "alice"]);
+        assert_eq!(subjects(book.collect().await), ["all", "bob"]);
+    }
+}
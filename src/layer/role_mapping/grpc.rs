@@ -0,0 +1,212 @@
+/// Casbin role mapping for pure gRPC services built with tonic, it shares the
+/// enforce logic with [RoleMappingLayer] so the decisions are the same.
+///
+/// A tonic `Interceptor` cannot see the method path, so this is a tower layer
+/// working on the underlying http/2 request.
+///
+/// Following are the object enforced with casbin:
+/// obj => full method path (/helloworld.Greeter/SayHello)
+/// act => rpc name (SayHello)
+/// sub => values of metadata `x-subject` (uid, group, etc)
+///
+/// The metadata must be set by a trusted party (gateway, auth layer, etc.).
+/// A denied rpc is responded with `PERMISSION_DENIED`, an rpc without subject
+/// with `UNAUTHENTICATED` (see [NoIdentity]) and an enforcer error with `INTERNAL`.
+///
+/// ```rust,ignore
+/// Server::builder()
+///     .layer(GrpcRoleMappingLayer::new(enforcer))
+///     .add_service(GreeterServer::new(greeter))
+/// ```
+///
+/// [RoleMappingLayer]: crate::layer::RoleMappingLayer
+use super::objects::Objects;
+use super::{
+    configure_enforcer, enforce_subjects, AuditDecision, AuditSink, AuditSource, DefaultDecision,
+    Identity, NoIdentity,
+};
+use casbin::CoreApi;
+use futures::future::BoxFuture;
+use http::header::HeaderName;
+use http::{Request, Response};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::Status;
+use tower::{Layer, Service};
+use tracing::warn;
+
+const SUBJECT_METADATA: &str = "x-subject";
+
+#[derive(Clone)]
+pub struct GrpcRoleMappingLayer<E> {
+    enforcer: Arc<E>,
+    objects: Arc<Objects>,
+    metadata_key: HeaderName,
+    default: DefaultDecision,
+    no_identity: NoIdentity,
+    audit: Option<AuditSink>,
+}
+
+impl<E: CoreApi> GrpcRoleMappingLayer<E> {
+    pub fn new(enforcer: E) -> Self {
+        Self {
+            enforcer: Arc::new(enforcer),
+            objects: Arc::new(Objects::default()),
+            metadata_key: HeaderName::from_static(SUBJECT_METADATA),
+            default: DefaultDecision::default(),
+            no_identity: NoIdentity::default(),
+            audit: None,
+        }
+    }
+
+    /// Same as [GrpcRoleMappingLayer::new] but configure the enforcer before serving,
+    /// see [configure_enforcer]
+    pub fn configured(
+        enforcer: E,
+        configure: impl FnOnce(&mut E) -> casbin::Result<()>,
+    ) -> casbin::Result<Self> {
+        Ok(Self::new(configure_enforcer(enforcer, configure)?))
+    }
+
+    /// The metadata key of subjects, `x-subject` by default.
+    /// Panics if the key is not a valid lowercase metadata key.
+    pub fn metadata_key(mut self, key: &'static str) -> Self {
+        self.metadata_key = HeaderName::from_static(key);
+        self
+    }
+
+    /// The decision of rpcs on methods without any policy, `Deny` by default
+    pub fn default_decision(mut self, default: DefaultDecision) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// How rpcs without subject metadata are handled, `Deny` by default
+    pub fn no_identity(mut self, no_identity: NoIdentity) -> Self {
+        self.no_identity = no_identity;
+        self
+    }
+
+    /// Record every decision, see [AuditSink]
+    pub fn audit(mut self, sink: AuditSink) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+}
+
+impl<S, E> Layer<S> for GrpcRoleMappingLayer<E> {
+    type Service = GrpcRoleMapping<S, E>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcRoleMapping {
+            inner,
+            enforcer: self.enforcer.clone(),
+            objects: self.objects.clone(),
+            metadata_key: self.metadata_key.clone(),
+            default: self.default,
+            no_identity: self.no_identity.clone(),
+            audit: self.audit.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcRoleMapping<S, E> {
+    inner: S,
+    enforcer: Arc<E>,
+    objects: Arc<Objects>,
+    metadata_key: HeaderName,
+    default: DefaultDecision,
+    no_identity: NoIdentity,
+    audit: Option<AuditSink>,
+}
+
+/// The rpc name of a method path, e.p. `SayHello` of `/helloworld.Greeter/SayHello`
+fn rpc_name(path: &str) -> &str {
+    path.rsplit_once('/').map(|(_, rpc)| rpc).unwrap_or(path)
+}
+
+impl<S, E, ReqBody> Service<Request<ReqBody>> for GrpcRoleMapping<S, E>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>> + Send + 'static,
+    S::Future: Send + 'static,
+    E: CoreApi,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let subs = req
+            .headers()
+            .get_all(&self.metadata_key)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        let obj = req.uri().path();
+        let act = rpc_name(obj);
+        let subs = match self.no_identity.resolve(subs) {
+            Identity::Subjects(subs) => subs,
+            Identity::Unauthenticated => {
+                if let Some(audit) = &self.audit {
+                    audit
+                        .entry::<_, &str>(&req, &[], obj, act)
+                        .record(AuditSource::NoIdentity, AuditDecision::Deny);
+                }
+                return Box::pin(async move {
+                    Ok(Status::unauthenticated("missing subject").to_http())
+                });
+            }
+            Identity::PassThrough => {
+                if let Some(audit) = &self.audit {
+                    audit
+                        .entry::<_, &str>(&req, &[], obj, act)
+                        .record(AuditSource::NoIdentity, AuditDecision::Allow);
+                }
+                return Box::pin(self.inner.call(req));
+            }
+        };
+
+        let decision = enforce_subjects(
+            &*self.enforcer,
+            &self.objects,
+            &subs,
+            obj,
+            act,
+            self.default,
+        );
+        if let Some(audit) = &self.audit {
+            audit.entry(&req, &subs, obj, act).decided(&decision);
+        }
+        match decision {
+            Ok(true) => Box::pin(self.inner.call(req)),
+            Ok(false) => {
+                Box::pin(
+                    async move { Ok(Status::permission_denied("permission denied").to_http()) },
+                )
+            }
+            Err(err) => {
+                warn!("enforcer is working abnormally, err: {:?}", err);
+                Box::pin(
+                    async move { Ok(Status::internal("cannot enforce the request").to_http()) },
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::rpc_name;
+
+    #[test]
+    fn test_rpc_name() {
+        assert_eq!(rpc_name("/helloworld.Greeter/SayHello"), "SayHello");
+        assert_eq!(rpc_name("SayHello"), "SayHello");
+    }
+}
@@ -0,0 +1,126 @@
+/// Enumerate the methods of gRPC services for authoring the policies of
+/// [GrpcRoleMappingLayer], it is gated by the `grpc-methods` feature and never
+/// required at runtime.
+///
+/// The methods are read from an encoded `FileDescriptorSet`, the same one
+/// registered to the tonic reflection service (e.p. written by
+/// `tonic_build::configure().file_descriptor_set_path(..)`):
+///
+/// ```rust,ignore
+/// const DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("helloworld_descriptor");
+///
+/// let methods = grpc_methods(DESCRIPTOR_SET)?;
+/// // ["/helloworld.Greeter/SayHello"]
+/// check_grpc_policies(&enforcer, &methods);
+/// ```
+///
+/// [GrpcRoleMappingLayer]: crate::layer::GrpcRoleMappingLayer
+use casbin::MgmtApi;
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use tracing::warn;
+
+/// The full method paths (`/{package}.{service}/{method}`) in the descriptor set,
+/// which are the objects enforced by [GrpcRoleMappingLayer]
+///
+/// [GrpcRoleMappingLayer]: crate::layer::GrpcRoleMappingLayer
+pub fn grpc_methods(descriptor_set: &[u8]) -> Result<Vec<String>, prost::DecodeError> {
+    let set = FileDescriptorSet::decode(descriptor_set)?;
+    let mut methods = vec![];
+    for file in set.file {
+        for service in &file.service {
+            let service_name = match file.package() {
+                "" => service.name().to_string(),
+                package => format!("{}.{}", package, service.name()),
+            };
+            for method in &service.method {
+                methods.push(format!("/{}/{}", service_name, method.name()));
+            }
+        }
+    }
+    Ok(methods)
+}
+
+/// Warn on the policies referencing methods which do not exist, and return them.
+/// The object is the second field of a policy (`p = sub, obj, act`), the objects
+/// with wildcards are skipped as they are patterns rather than methods.
+pub fn check_grpc_policies<E: MgmtApi>(enforcer: &E, methods: &[String]) -> Vec<Vec<String>> {
+    let unknown = enforcer
+        .get_policy()
+        .into_iter()
+        .filter(|policy| {
+            policy.get(1).is_some_and(|obj| {
+                !obj.contains('*') && !methods.iter().any(|method| method == obj)
+            })
+        })
+        .collect::<Vec<_>>();
+    for policy in &unknown {
+        warn!("policy {:?} references a nonexistent gRPC method", policy);
+    }
+    unknown
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::role_mapping::fixture::{enforcer, rule, MODEL};
+    use crate::layer::{check_grpc_policies, grpc_methods};
+    use prost::Message;
+    use prost_types::{
+        FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+    };
+
+    fn service(name: &str, methods: &[&str]) -> ServiceDescriptorProto {
+        ServiceDescriptorProto {
+            name: Some(name.to_string()),
+            method: methods
+                .iter()
+                .map(|method| MethodDescriptorProto {
+                    name: Some(method.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_methods() {
+        let set = FileDescriptorSet {
+            file: vec![
+                FileDescriptorProto {
+                    package: Some("helloworld".to_string()),
+                    service: vec![service("Greeter", &["SayHello", "SayGoodbye"])],
+                    ..Default::default()
+                },
+                FileDescriptorProto {
+                    service: vec![service("Echo", &["Echo"])],
+                    ..Default::default()
+                },
+            ],
+        };
+        let methods = grpc_methods(&set.encode_to_vec()).unwrap();
+        assert_eq!(
+            methods,
+            [
+                "/helloworld.Greeter/SayHello",
+                "/helloworld.Greeter/SayGoodbye",
+                "/Echo/Echo"
+            ]
+        );
+        assert!(grpc_methods(&[0xff]).is_err());
+
+        let enforcer = enforcer(
+            MODEL,
+            &[
+                &["alice", "/helloworld.Greeter/SayHello", "SayHello"],
+                &["alice", "/helloworld.Greeter/SayHi", "SayHi"],
+                &["admin", "/helloworld.Greeter/*", "*"],
+            ],
+        )
+        .await;
+        assert_eq!(
+            check_grpc_policies(&enforcer, &methods),
+            [rule(&["alice", "/helloworld.Greeter/SayHi", "SayHi"])]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+/// Load a casbin enforcer from model and policy files without crashing the
+/// service when the files are temporarily missing or malformed, e.p. during a
+/// config outage.
+///
+/// ```rust,ignore
+/// let enforcer = EnforcerLoader::new("rbac_model.conf", "rbac_policy.csv")
+///     .retry(3, Duration::from_secs(2))
+///     .fallback(ModelFallback::DenyAll)
+///     .load()
+///     .await?;
+/// ```
+///
+/// Falling back is opt-in, the service starts in degraded (deny-all) mode then,
+/// and it is shown in the [startup_report].
+///
+/// [startup_report]: crate::utils::startup_report
+use crate::utils::startup::record_degraded;
+use casbin::{CoreApi, DefaultModel, Enforcer, FileAdapter, MemoryAdapter};
+use futures::FutureExt;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, warn};
+
+/// A model without any policy, casbin denies all requests with it
+const DENY_ALL_MODEL: &str = r#"
+[request_definition]
+r = sub, obj, act
+
+[policy_definition]
+p = sub, obj, act
+
+[policy_effect]
+e = some(where (p.eft == allow))
+
+[matchers]
+m = r.sub == p.sub && r.obj == p.obj && r.act == p.act
+"#;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ModelFallback {
+    /// Return the error
+    #[default]
+    Fail,
+    /// Use the bundled deny-all model, all requests are denied until restarted
+    DenyAll,
+}
+
+#[derive(Debug, Error)]
+pub enum LoadEnforcerError {
+    #[error("cannot load enforcer: {0}")]
+    Casbin(#[from] casbin::Error),
+    #[error("casbin panicked while loading enforcer: {0}")]
+    Panic(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct EnforcerLoader {
+    model_path: String,
+    policy_path: String,
+    attempts: usize,
+    interval: Duration,
+    fallback: ModelFallback,
+}
+
+impl EnforcerLoader {
+    pub fn new(model_path: impl Into<String>, policy_path: impl Into<String>) -> Self {
+        Self {
+            model_path: model_path.into(),
+            policy_path: policy_path.into(),
+            attempts: 1,
+            interval: Duration::ZERO,
+            fallback: ModelFallback::Fail,
+        }
+    }
+
+    /// Try to load `attempts` times in total, waiting `interval` between them
+    pub fn retry(mut self, attempts: usize, interval: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.interval = interval;
+        self
+    }
+
+    /// What to do when all the attempts failed, [ModelFallback::Fail] by default
+    pub fn fallback(mut self, fallback: ModelFallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    pub async fn load(&self) -> Result<Enforcer, LoadEnforcerError> {
+        let mut attempt = 1;
+        let err = loop {
+            match self.try_load().await {
+                Ok(enforcer) => return Ok(enforcer),
+                Err(err) if attempt < self.attempts => {
+                    warn!(
+                        "cannot load enforcer from {} and {} (attempt {}/{}), err: {}",
+                        self.model_path, self.policy_path, attempt, self.attempts, err
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(self.interval).await;
+                }
+                Err(err) => break err,
+            }
+        };
+        match self.fallback {
+            ModelFallback::Fail => {
+                record_degraded("casbin enforcer", &format!("failed, {}", err));
+                Err(err)
+            }
+            ModelFallback::DenyAll => {
+                error!(
+                    "cannot load enforcer from {} and {}, DENYING ALL REQUESTS with the fallback model, err: {}",
+                    self.model_path, self.policy_path, err
+                );
+                record_degraded("casbin enforcer", &format!("deny-all fallback, {}", err));
+                let model = DefaultModel::from_str(DENY_ALL_MODEL).await?;
+                Ok(Enforcer::new(model, MemoryAdapter::default()).await?)
+            }
+        }
+    }
+
+    async fn try_load(&self) -> Result<Enforcer, LoadEnforcerError> {
+        let load = async {
+            let model = DefaultModel::from_file(&self.model_path).await?;
+            let adapter = FileAdapter::new(self.policy_path.clone());
+            Enforcer::new(model, adapter).await
+        };
+        // casbin may panic on malformed files
+        match AssertUnwindSafe(load).catch_unwind().await {
+            Ok(res) => Ok(res?),
+            Err(panic) => Err(LoadEnforcerError::Panic(
+                panic
+                    .downcast_ref::<&str>()
+                    .map(ToString::to_string)
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::{EnforcerLoader, ModelFallback};
+    use casbin::CoreApi;
+
+    #[tokio::test]
+    async fn test_enforcer_loader() {
+        let loader = EnforcerLoader::new("missing_model.conf", "missing_policy.csv");
+        assert!(loader.load().await.is_err());
+
+        let enforcer = loader
+            .fallback(ModelFallback::DenyAll)
+            .load()
+            .await
+            .unwrap();
+        assert!(!enforcer.enforce(("alice", "/users", "GET")).unwrap());
+    }
+}
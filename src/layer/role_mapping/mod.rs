@@ -9,34 +9,258 @@
 /// obj => query path (/book, /user, etc)
 /// act => http method (GET, POST, etc)
 /// sub => request extension `I`  (uid, group, etc)
+///
+/// With `I` = [AnySubject], the request is allowed if any subject is permitted.
+///
+/// A request without identity (the extension is missing) is rejected with
+/// `401 Unauthorized` before enforcing by default, see [NoIdentity] for enforcing
+/// it as an anonymous subject or passing it through.
+///
+/// Casbin denies the requests without matching policy, set `default_decision`
+/// to [DefaultDecision::Allow] to allow the requests on objects that no policy
+/// covers (matched like the model matcher, e.p. `keyMatch2`), only the requests
+/// on the covered objects are enforced then.
+///
+/// A denied request is responded with `403 Forbidden` and an enforcer error with
+/// `500 Internal Server Error` by default, override them with `denied_status` and
+/// `error_status`, e.p. `404 Not Found` to hide the existence of resources.
+/// A gRPC request (`application/grpc` content type) is responded with `200 OK`
+/// and the `grpc-status`/`grpc-message` of the status instead, e.p.
+/// `PERMISSION_DENIED` for `403 Forbidden`, as gRPC clients expect.
+///
+/// The [PublicRoutes] skip both the identity requirement and the enforcement,
+/// they take precedence over the route groups and casbin policies.
+///
+/// A websocket upgrade request is enforced like a normal request, the inner
+/// service is only called once it is authorized, and the upgraded connection
+/// is passed through without buffering. See [`is_websocket_upgrade`].
+///
+/// [`is_websocket_upgrade`]: crate::layer::is_websocket_upgrade
+mod audit;
+mod codec;
 mod distribute;
+#[cfg(feature = "policy-dump")]
+mod dump;
+mod fanout;
+#[cfg(feature = "grpc-role-mapping")]
+mod grpc;
+#[cfg(feature = "grpc-methods")]
+mod grpc_methods;
+mod loader;
+mod objects;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod publish;
+mod route;
 mod source;
+mod subject;
 
+pub use audit::*;
+pub use codec::*;
 pub use distribute::*;
+#[cfg(feature = "policy-dump")]
+pub use dump::*;
+pub use fanout::*;
+#[cfg(feature = "grpc-role-mapping")]
+pub use grpc::*;
+#[cfg(feature = "grpc-methods")]
+pub use grpc_methods::*;
+pub use loader::*;
+#[cfg(feature = "postgres")]
+pub use postgres::*;
+pub use publish::*;
+pub use route::*;
 pub use source::*;
+pub use subject::*;
 
+use crate::status::error_body::{ErrorBody, ErrorResponseBody, RequestId};
 use casbin::CoreApi;
 use futures::future::BoxFuture;
+use http::header::CONTENT_TYPE;
 use http::{Request, Response, StatusCode};
+use objects::Objects;
 use std::marker::PhantomData;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use tonic::Code;
 use tower::{Layer, Service};
 use tracing::warn;
 
+/// Statuses responded when the request is not authorized
+#[derive(Clone, Debug)]
+struct DenyStatus {
+    unauthenticated: StatusCode,
+    denied: StatusCode,
+    error: StatusCode,
+    // respond a gRPC status instead, see [is_grpc]
+    grpc: bool,
+    // echoed in the ErrorBody of HTTP responses
+    request_id: Option<String>,
+}
+
+impl Default for DenyStatus {
+    fn default() -> Self {
+        Self {
+            unauthenticated: StatusCode::UNAUTHORIZED,
+            denied: StatusCode::FORBIDDEN,
+            error: StatusCode::INTERNAL_SERVER_ERROR,
+            grpc: false,
+            request_id: None,
+        }
+    }
+}
+
+impl DenyStatus {
+    /// The statuses for the request
+    fn of<B>(mut self, req: &Request<B>) -> Self {
+        self.grpc = is_grpc(req);
+        self.request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+        self
+    }
+
+    fn unauthenticated<ResBody: ErrorResponseBody>(&self) -> Response<ResBody> {
+        self.respond(self.unauthenticated, "missing subject")
+    }
+
+    fn denied<ResBody: ErrorResponseBody>(&self) -> Response<ResBody> {
+        self.respond(self.denied, "permission denied")
+    }
+
+    fn error<ResBody: ErrorResponseBody>(&self) -> Response<ResBody> {
+        self.respond(self.error, "cannot enforce the request")
+    }
+
+    fn respond<ResBody: ErrorResponseBody>(
+        &self,
+        status: StatusCode,
+        message: &str,
+    ) -> Response<ResBody> {
+        if self.grpc {
+            grpc_respond(grpc_code(status), message)
+        } else {
+            ErrorBody {
+                request_id: self.request_id.clone(),
+                ..ErrorBody::from_status(status)
+            }
+            .into_response(status)
+        }
+    }
+}
+
+/// Whether the request is a gRPC call, which expects a gRPC status rather than
+/// an HTTP error status
+fn is_grpc<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// The gRPC code of a configured status, e.p. `404 Not Found` hides the
+/// resources as `NOT_FOUND`
+fn grpc_code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        status if status.is_server_error() => Code::Internal,
+        _ => Code::Unknown,
+    }
+}
+
+/// A trailers-only gRPC response, the status is carried in the headers of a
+/// `200 OK` response without body as the gRPC spec allows
+fn grpc_respond<ResBody: Default>(code: Code, message: &str) -> Response<ResBody> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("grpc-status", code as i32)
+        .header("grpc-message", message)
+        .body(ResBody::default())
+        .unwrap()
+}
+
 #[derive(Clone)]
 pub struct RoleMappingLayer<I, E> {
-    enforcer: Arc<E>,
+    enforcer: Arc<RouteEnforcers<E>>,
+    public: Arc<PublicRoutes>,
+    status: DenyStatus,
+    default: DefaultDecision,
+    no_identity: NoIdentity,
+    audit: Option<AuditSink>,
     marker: PhantomData<*const I>,
 }
 
 impl<I, E: CoreApi> RoleMappingLayer<I, E> {
     pub fn new(enforcer: E) -> Self {
+        Self::with_routes(RouteEnforcers::single(enforcer))
+    }
+
+    /// Same as [RoleMappingLayer::new] but configure the enforcer before serving,
+    /// see [configure_enforcer]
+    pub fn configured(
+        enforcer: E,
+        configure: impl FnOnce(&mut E) -> casbin::Result<()>,
+    ) -> casbin::Result<Self> {
+        Ok(Self::new(configure_enforcer(enforcer, configure)?))
+    }
+
+    /// Select the enforcer by route group, see [RouteEnforcers]
+    pub fn with_routes(routes: RouteEnforcers<E>) -> Self {
         Self {
-            enforcer: Arc::new(enforcer),
+            enforcer: Arc::new(routes),
+            public: Arc::new(PublicRoutes::default()),
+            status: DenyStatus::default(),
+            default: DefaultDecision::default(),
+            no_identity: NoIdentity::default(),
+            audit: None,
             marker: PhantomData::default(),
         }
     }
+
+    /// Status of denied requests, `403 Forbidden` by default
+    pub fn denied_status(mut self, status: StatusCode) -> Self {
+        self.status.denied = status;
+        self
+    }
+
+    /// Status of requests failed to be enforced, `500 Internal Server Error` by default
+    pub fn error_status(mut self, status: StatusCode) -> Self {
+        self.status.error = status;
+        self
+    }
+
+    /// Status of requests rejected by [NoIdentity::Deny], `401 Unauthorized` by default
+    pub fn unauthenticated_status(mut self, status: StatusCode) -> Self {
+        self.status.unauthenticated = status;
+        self
+    }
+
+    /// The decision of requests on objects without any policy, `Deny` by default
+    pub fn default_decision(mut self, default: DefaultDecision) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// How requests without identity are handled, `Deny` by default
+    pub fn no_identity(mut self, no_identity: NoIdentity) -> Self {
+        self.no_identity = no_identity;
+        self
+    }
+
+    /// Routes open to everyone without identity and enforcement
+    pub fn public_routes(mut self, public: PublicRoutes) -> Self {
+        self.public = Arc::new(public);
+        self
+    }
+
+    /// Record every decision, see [AuditSink]
+    pub fn audit(mut self, sink: AuditSink) -> Self {
+        self.audit = Some(sink);
+        self
+    }
 }
 
 impl<S, I, E> Layer<S> for RoleMappingLayer<I, E> {
@@ -46,6 +270,11 @@ impl<S, I, E> Layer<S> for RoleMappingLayer<I, E> {
         RoleMapping {
             inner,
             enforcer: self.enforcer.clone(),
+            public: self.public.clone(),
+            status: self.status.clone(),
+            default: self.default,
+            no_identity: self.no_identity.clone(),
+            audit: self.audit.clone(),
             marker: PhantomData::default(),
         }
     }
@@ -54,7 +283,12 @@ impl<S, I, E> Layer<S> for RoleMappingLayer<I, E> {
 #[derive(Clone)]
 pub struct RoleMapping<S, I, E> {
     inner: S,
-    enforcer: Arc<E>,
+    enforcer: Arc<RouteEnforcers<E>>,
+    public: Arc<PublicRoutes>,
+    status: DenyStatus,
+    default: DefaultDecision,
+    no_identity: NoIdentity,
+    audit: Option<AuditSink>,
     marker: PhantomData<*const I>,
 }
 
@@ -62,8 +296,8 @@ impl<S, I, E, ReqBody, ResBody> Service<Request<ReqBody>> for RoleMapping<S, I,
 where
     S: Service<Request<ReqBody>, Response = Response<ResBody>> + Send + 'static,
     S::Future: Send + 'static,
-    ResBody: Default,
-    I: AsRef<str> + Send + Sync + 'static,
+    ResBody: ErrorResponseBody,
+    I: SubjectExtractor,
     E: CoreApi,
 {
     type Response = S::Response;
@@ -75,53 +309,293 @@ where
     }
 
     fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
-        enforce::<_, _, _, _, I>(&mut self.inner, req, self.enforcer.as_ref())
+        if self.public.matches(&req) {
+            return Box::pin(self.inner.call(req));
+        }
+        let status = self.status.clone().of(&req);
+        match self.enforcer.select(&req) {
+            Selected::Enforcer(enforcer, objects) => enforce::<_, _, _, _, I>(
+                &mut self.inner,
+                req,
+                enforcer,
+                objects,
+                status,
+                self.default,
+                &self.no_identity,
+                self.audit.as_ref(),
+            ),
+            Selected::Allow => {
+                audit_route::<_, I>(self.audit.as_ref(), &req, AuditDecision::Allow);
+                Box::pin(self.inner.call(req))
+            }
+            Selected::Deny => {
+                audit_route::<_, I>(self.audit.as_ref(), &req, AuditDecision::Deny);
+                Box::pin(async move { Ok(status.denied()) })
+            }
+        }
     }
 }
 
-fn enforce<E: CoreApi, ReqBody, ResBody: Default, S, I>(
+fn enforce<E: CoreApi, ReqBody, ResBody: ErrorResponseBody, S, I>(
     inner: &mut S,
     req: Request<ReqBody>,
     enforcer: &E,
+    objects: &Objects,
+    status: DenyStatus,
+    default: DefaultDecision,
+    no_identity: &NoIdentity,
+    audit: Option<&AuditSink>,
 ) -> BoxFuture<'static, Result<S::Response, S::Error>>
 where
     S: Service<Request<ReqBody>, Response = Response<ResBody>> + Send + 'static,
     S::Future: Send + 'static,
-    I: AsRef<str> + Send + Sync + 'static,
+    I: SubjectExtractor,
 {
     // obj => query path
     // act => http method
     // sub => request extension
-    let sub = req
-        .extensions()
-        .get::<I>()
-        .map(|sub| sub.as_ref())
-        .unwrap_or("");
     let obj = req.uri().path();
     let act = req.method().as_str();
+    let subs = match no_identity.resolve(I::subjects(&req)) {
+        Identity::Subjects(subs) => subs,
+        Identity::Unauthenticated => {
+            if let Some(audit) = audit {
+                audit
+                    .entry::<_, &str>(&req, &[], obj, act)
+                    .record(AuditSource::NoIdentity, AuditDecision::Deny);
+            }
+            return Box::pin(async move { Ok(status.unauthenticated()) });
+        }
+        Identity::PassThrough => {
+            if let Some(audit) = audit {
+                audit
+                    .entry::<_, &str>(&req, &[], obj, act)
+                    .record(AuditSource::NoIdentity, AuditDecision::Allow);
+            }
+            return Box::pin(inner.call(req));
+        }
+    };
 
-    match enforcer.enforce((sub, obj, act)) {
+    let decision = enforce_subjects(enforcer, objects, &subs, obj, act, default);
+    if let Some(audit) = audit {
+        audit.entry(&req, &subs, obj, act).decided(&decision);
+    }
+    match decision {
         Ok(checked) => {
             if checked {
                 let fut = inner.call(req);
                 Box::pin(async move { fut.await })
             } else {
-                Box::pin(async move {
-                    Ok(Response::builder()
-                        .status(StatusCode::FORBIDDEN)
-                        .body(ResBody::default())
-                        .unwrap())
-                })
+                Box::pin(async move { Ok(status.denied()) })
             }
         }
         Err(err) => {
             warn!("enforcer is working abnormally, err: {:?}", err);
-            Box::pin(async move {
-                Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(ResBody::default())
-                    .unwrap())
-            })
+            Box::pin(async move { Ok(status.error()) })
+        }
+    }
+}
+
+/// Record the decision of a request matching no route group
+fn audit_route<B, I: SubjectExtractor>(
+    audit: Option<&AuditSink>,
+    req: &Request<B>,
+    decision: AuditDecision,
+) {
+    if let Some(audit) = audit {
+        let subs = I::subjects(req);
+        audit
+            .entry(req, &subs, req.uri().path(), req.method().as_str())
+            .record(AuditSource::Route, decision);
+    }
+}
+
+/// Configure a built enforcer before serving, e.p. register custom matching
+/// functions or set a role manager for hierarchical roles:
+///
+/// ```rust,ignore
+/// let enforcer = configure_enforcer(enforcer, |e| {
+///     e.add_function("ipMatch", ip_match);
+///     e.set_role_manager(Arc::new(RwLock::new(DefaultRoleManager::new(20))))
+/// })?;
+/// ```
+///
+/// The ordering is:
+/// 1. the initial policies are loaded when the enforcer is built
+/// 2. `configure` runs, then the role links are rebuilt with the configured role
+///    manager against the initial policies
+/// 3. the distributed event stream (if any) starts applying events
+pub fn configure_enforcer<E: CoreApi>(
+    mut enforcer: E,
+    configure: impl FnOnce(&mut E) -> casbin::Result<()>,
+) -> casbin::Result<E> {
+    configure(&mut enforcer)?;
+    enforcer.build_role_links()?;
+    Ok(enforcer)
+}
+
+/// The decision of requests on objects without any policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DefaultDecision {
+    /// Closed-world, only the permitted requests are allowed
+    #[default]
+    Deny,
+    /// Open-world, the requests on objects covered by no policy are allowed,
+    /// the objects are matched like the model matcher, e.p. with `keyMatch2`
+    /// a policy on `/book/:id` covers `/book/1`
+    Allow,
+}
+
+/// How requests without identity are handled
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum NoIdentity {
+    /// Rejected before enforcing, with `401 Unauthorized` or `UNAUTHENTICATED`
+    #[default]
+    Deny,
+    /// Enforced as the named subject, e.p. `anonymous`, so that policies could
+    /// open public objects of a mixed public and authenticated service
+    Anonymous(String),
+    /// Passed to the inner service without enforcing, the inner service must
+    /// authorize anonymous requests by itself
+    PassThrough,
+}
+
+/// The subjects of a request after applying [NoIdentity]
+enum Identity<'a> {
+    Subjects(Vec<&'a str>),
+    Unauthenticated,
+    PassThrough,
+}
+
+impl NoIdentity {
+    fn resolve<'a>(&'a self, subs: Vec<&'a str>) -> Identity<'a> {
+        if !subs.is_empty() {
+            return Identity::Subjects(subs);
+        }
+        match self {
+            NoIdentity::Deny => Identity::Unauthenticated,
+            NoIdentity::Anonymous(name) => Identity::Subjects(vec![name.as_str()]),
+            NoIdentity::PassThrough => Identity::PassThrough,
+        }
+    }
+}
+
+/// Allowed by the first permitted subject, shared by all role mapping layers
+/// so that they make the same decisions.
+fn enforce_subjects<E: CoreApi, S: AsRef<str>>(
+    enforcer: &E,
+    objects: &Objects,
+    subs: &[S],
+    obj: &str,
+    act: &str,
+    default: DefaultDecision,
+) -> casbin::Result<bool> {
+    for sub in subs {
+        if enforcer.enforce((sub.as_ref(), obj, act))? {
+            return Ok(true);
         }
     }
+    // explicitly denied or no matching policy
+    Ok(default == DefaultDecision::Allow && !objects.covers(enforcer, obj))
+}
+
+/// The model and enforcers shared by the tests of role mapping
+#[cfg(test)]
+pub(crate) mod fixture {
+    use casbin::{CoreApi, DefaultModel, Enforcer, MemoryAdapter, MgmtApi};
+
+    /// A subject is granted the policies of its own and its roles
+    pub(crate) const MODEL: &str = r#"
+[request_definition]
+r = sub, obj, act
+
+[policy_definition]
+p = sub, obj, act
+
+[role_definition]
+g = _, _
+
+[policy_effect]
+e = some(where (p.eft == allow))
+
+[matchers]
+m = g(r.sub, p.sub) && r.obj == p.obj && r.act == p.act
+"#;
+
+    pub(crate) fn rule(rule: &[&str]) -> Vec<String> {
+        rule.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// An enforcer of model holding the policies in memory
+    pub(crate) async fn enforcer(model: &str, policies: &[&[&str]]) -> Enforcer {
+        let model = DefaultModel::from_str(model).await.unwrap();
+        let mut enforcer = Enforcer::new(model, MemoryAdapter::default())
+            .await
+            .unwrap();
+        for policy in policies {
+            enforcer.add_policy(rule(policy)).await.unwrap();
+        }
+        enforcer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DenyStatus, Identity, NoIdentity};
+    use http::{Request, Response, StatusCode};
+
+    fn resolve(no_identity: &NoIdentity, subs: Vec<&'static str>) -> Option<Vec<String>> {
+        match no_identity.resolve(subs) {
+            Identity::Subjects(subs) => Some(subs.into_iter().map(String::from).collect()),
+            Identity::Unauthenticated => None,
+            Identity::PassThrough => Some(vec![]),
+        }
+    }
+
+    #[test]
+    fn test_no_identity() {
+        let anonymous = NoIdentity::Anonymous("anonymous".to_string());
+        assert_eq!(
+            resolve(&NoIdentity::Deny, vec!["uid"]),
+            Some(vec!["uid".to_string()])
+        );
+        assert_eq!(resolve(&NoIdentity::Deny, vec![]), None);
+        assert_eq!(
+            resolve(&anonymous, vec![]),
+            Some(vec!["anonymous".to_string()])
+        );
+        assert_eq!(resolve(&NoIdentity::PassThrough, vec![]), Some(vec![]));
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        let req = |content_type: &str| {
+            Request::post("/helloworld.Greeter/SayHello")
+                .header("content-type", content_type)
+                .body(())
+                .unwrap()
+        };
+        let grpc_status =
+            |res: &Response<()>| res.headers()["grpc-status"].to_str().unwrap().to_string();
+
+        let status = DenyStatus::default().of(&req("application/grpc+proto"));
+        let res = status.denied::<()>();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(grpc_status(&res), "7");
+        assert_eq!(res.headers()["grpc-message"], "permission denied");
+        assert_eq!(grpc_status(&status.unauthenticated()), "16");
+        assert_eq!(grpc_status(&status.error()), "13");
+
+        let hidden = DenyStatus {
+            denied: StatusCode::NOT_FOUND,
+            ..status
+        };
+        assert_eq!(grpc_status(&hidden.denied()), "5");
+
+        // plain HTTP is unchanged
+        let status = DenyStatus::default().of(&req("application/json"));
+        let res = status.denied::<()>();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(res.headers().get("grpc-status").is_none());
+    }
 }
@@ -0,0 +1,198 @@
+/// The objects referenced by `p` policies (the `obj` field of `policy_definition`,
+/// e.p. the third one of `p = sub, dom, obj, act`), consulted by
+/// [DefaultDecision::Allow] to tell the requests on objects without any policy.
+///
+/// A request object is matched with the function matching `r.obj` against `p.obj`
+/// in the model matcher, e.p. with `keyMatch2(r.obj, p.obj)` a request on `/book/1`
+/// is covered by the policies on `/book/:id`, so they are not allowed by default.
+/// If the matcher is not understood or `p` has no `obj` field, every object is
+/// covered once there is a policy.
+///
+/// The index is built on the first lookup and reset whenever the policies change.
+///
+/// [DefaultDecision::Allow]: crate::layer::DefaultDecision::Allow
+use casbin::function_map::{key_match, key_match2, key_match3, regex_match};
+use casbin::CoreApi;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+type MatchFn = fn(&str, &str) -> bool;
+
+const MATCH_FUNCTIONS: [(&str, MatchFn); 4] = [
+    ("keyMatch", key_match),
+    ("keyMatch2", key_match2),
+    ("keyMatch3", key_match3),
+    ("regexMatch", regex_match),
+];
+
+#[derive(Clone, Copy)]
+enum ObjectMatch {
+    /// `r.obj == p.obj`
+    Equal,
+    Function(MatchFn),
+    Unknown,
+}
+
+impl ObjectMatch {
+    fn of<E: CoreApi>(enforcer: &E) -> Self {
+        let matcher = enforcer
+            .get_model()
+            .get_model()
+            .get("m")
+            .and_then(|section| section.get("m"))
+            .map(|assertion| assertion.value.as_str())
+            .unwrap_or_default();
+        // the tokens are escaped by casbin once the model is loaded
+        let matcher = matcher
+            .replace("r_obj", "r.obj")
+            .replace("p_obj", "p.obj")
+            .replace(' ', "");
+        for (name, function) in MATCH_FUNCTIONS {
+            if matcher.contains(&format!("{}(r.obj,p.obj)", name)) {
+                return ObjectMatch::Function(function);
+            }
+        }
+        if matcher.contains("r.obj==p.obj") {
+            return ObjectMatch::Equal;
+        }
+        ObjectMatch::Unknown
+    }
+}
+
+/// The position of `obj` in the `p` policies
+fn obj_position<E: CoreApi>(enforcer: &E) -> Option<usize> {
+    enforcer
+        .get_model()
+        .get_model()
+        .get("p")
+        .and_then(|section| section.get("p"))
+        // the tokens are escaped by casbin, e.p. `p_obj`
+        .and_then(|assertion| assertion.tokens.iter().position(|token| token == "p_obj"))
+}
+
+pub(crate) struct ObjectIndex {
+    matcher: ObjectMatch,
+    objects: HashSet<String>,
+    any_policy: bool,
+}
+
+impl ObjectIndex {
+    fn build<E: CoreApi>(enforcer: &E) -> Self {
+        let policies = enforcer.get_model().get_policy("p", "p");
+        let any_policy = !policies.is_empty();
+        match obj_position(enforcer) {
+            Some(position) => Self {
+                matcher: ObjectMatch::of(enforcer),
+                objects: policies
+                    .into_iter()
+                    .filter_map(|policy| policy.get(position).cloned())
+                    .collect(),
+                any_policy,
+            },
+            None => Self {
+                matcher: ObjectMatch::Unknown,
+                objects: HashSet::new(),
+                any_policy,
+            },
+        }
+    }
+
+    /// Whether any policy covers the object
+    fn covers(&self, obj: &str) -> bool {
+        if self.objects.contains(obj) {
+            return true;
+        }
+        match self.matcher {
+            ObjectMatch::Equal => false,
+            ObjectMatch::Function(function) => {
+                self.objects.iter().any(|pattern| function(obj, pattern))
+            }
+            ObjectMatch::Unknown => self.any_policy,
+        }
+    }
+}
+
+/// The [ObjectIndex] of an enforcer, it must be reset under the write lock of
+/// the enforcer once the policies or the model of the enforcer change.
+#[derive(Default)]
+pub(crate) struct Objects(RwLock<Option<Arc<ObjectIndex>>>);
+
+impl Objects {
+    pub(crate) fn covers<E: CoreApi>(&self, enforcer: &E, obj: &str) -> bool {
+        let index = self.0.read().unwrap().clone();
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let index = Arc::new(ObjectIndex::build(enforcer));
+                *self.0.write().unwrap() = Some(index.clone());
+                index
+            }
+        };
+        index.covers(obj)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.write().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Objects;
+    use crate::layer::role_mapping::fixture::{enforcer, rule, MODEL};
+    use casbin::MgmtApi;
+
+    #[tokio::test]
+    async fn test_objects() {
+        let policies: &[&[&str]] = &[&["alice", "/book/:id", "GET"]];
+        let mut key_match = enforcer(
+            &MODEL.replace("r.obj == p.obj", "keyMatch2(r.obj, p.obj)"),
+            policies,
+        )
+        .await;
+        let objects = Objects::default();
+        assert!(objects.covers(&key_match, "/book/1"));
+        assert!(objects.covers(&key_match, "/book/:id"));
+        assert!(!objects.covers(&key_match, "/user/1"));
+
+        // rebuilt after reset
+        key_match
+            .add_policy(rule(&["alice", "/user/:id", "GET"]))
+            .await
+            .unwrap();
+        assert!(!objects.covers(&key_match, "/user/1"));
+        objects.reset();
+        assert!(objects.covers(&key_match, "/user/1"));
+
+        let equal = enforcer(MODEL, policies).await;
+        let objects = Objects::default();
+        assert!(!objects.covers(&equal, "/book/1"));
+        assert!(objects.covers(&equal, "/book/:id"));
+
+        // not understood, every object is covered
+        let unknown = enforcer(
+            &MODEL.replace("r.obj == p.obj", "customMatch(r.obj, p.obj)"),
+            policies,
+        )
+        .await;
+        assert!(Objects::default().covers(&unknown, "/user/1"));
+
+        // the object is the third field of a domain model
+        let domain = enforcer(
+            &MODEL
+                .replace("r = sub, obj, act", "r = sub, dom, obj, act")
+                .replace("p = sub, obj, act", "p = sub, dom, obj, act")
+                .replace("g = _, _", "g = _, _, _")
+                .replace(
+                    "g(r.sub, p.sub)",
+                    "g(r.sub, p.sub, r.dom) && r.dom == p.dom",
+                ),
+            &[&["alice", "tenant", "/book", "GET"]],
+        )
+        .await;
+        let objects = Objects::default();
+        assert!(objects.covers(&domain, "/book"));
+        assert!(!objects.covers(&domain, "tenant"));
+        assert!(!objects.covers(&domain, "/user"));
+    }
+}
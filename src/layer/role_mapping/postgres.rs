@@ -0,0 +1,159 @@
+/// Casbin policies stored in postgres.
+///
+/// The expected table schema is the same as the common casbin adapters:
+///
+/// ```sql
+/// CREATE TABLE casbin_rule (
+///     id    SERIAL PRIMARY KEY,
+///     ptype VARCHAR NOT NULL,  -- p, g, g2, etc.
+///     v0    VARCHAR NOT NULL DEFAULT '',
+///     v1    VARCHAR NOT NULL DEFAULT '',
+///     v2    VARCHAR NOT NULL DEFAULT '',
+///     v3    VARCHAR NOT NULL DEFAULT '',
+///     v4    VARCHAR NOT NULL DEFAULT '',
+///     v5    VARCHAR NOT NULL DEFAULT ''
+/// );
+/// ```
+///
+/// Changes are published by `NOTIFY` with the JSON of [EventData] as payload, which is
+/// the same as [redis_source](crate::layer::redis_source), or any other format with
+/// [postgres_source_codec], e.p.
+///
+/// ```sql
+/// SELECT pg_notify('casbin_rule', '{"AddPolicy": ["alice", "/book", "GET"]}');
+/// ```
+///
+/// Load the policies with the client of source after it is listening, so no change is
+/// missed between the load and the listen:
+///
+/// ```rust,ignore
+/// let source = postgres_source("casbin_rule", conf).await?;
+/// load_postgres_policies(&mut enforcer, source.client(), "casbin_rule").await?;
+/// ```
+///
+/// The source is not reconnected, it ends with an error logged once its connection
+/// is closed, and the changes since then are missed until it is rebuilt and the
+/// policies are reloaded.
+use crate::layer::role_mapping::codec::decode_event;
+use crate::layer::{EventCodec, EventData, JsonEventCodec};
+use crate::middleware::postgres::{Postgres, PostgresConf};
+use casbin::MgmtApi;
+use futures::{ready, Stream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_postgres::{Client, Notification};
+use tracing::{error, info};
+
+#[derive(Debug, Error)]
+pub enum PostgresPolicyError {
+    #[error("cannot query policies, err: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("cannot add policies, err: {0}")]
+    Casbin(#[from] casbin::Error),
+}
+
+/// Load all policies in `table` into the enforcer, rules with ptype `p*` are added
+/// as policies and `g*` as grouping policies. The table name is not escaped.
+pub async fn load_postgres_policies<E: MgmtApi>(
+    enforcer: &mut E,
+    client: &Client,
+    table: &str,
+) -> Result<(), PostgresPolicyError> {
+    let rows = client
+        .query(
+            &format!("SELECT ptype, v0, v1, v2, v3, v4, v5 FROM {}", table),
+            &[],
+        )
+        .await?;
+    let mut loaded = 0;
+    for row in rows {
+        let ptype: String = row.try_get(0)?;
+        let mut rule = (1..=6)
+            .map(|idx| {
+                row.try_get::<_, Option<String>>(idx)
+                    .map(Option::unwrap_or_default)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // unused trailing fields
+        while rule.last().map(String::is_empty).unwrap_or(false) {
+            rule.pop();
+        }
+        let added = if ptype.starts_with('g') {
+            enforcer.add_named_grouping_policy(&ptype, rule).await?
+        } else {
+            enforcer.add_named_policy(&ptype, rule).await?
+        };
+        if added {
+            loaded += 1;
+        }
+    }
+    info!("loaded {} policies from postgres table {}", loaded, table);
+    Ok(())
+}
+
+/// Listen to the `NOTIFY` channel on a dedicated connection made by the [Postgres]
+/// middleware, the connection is closed once the stream is dropped.
+pub async fn postgres_source(
+    channel: &str,
+    conf: PostgresConf,
+) -> Result<PostgresSource, tokio_postgres::Error> {
+    postgres_source_codec(channel, conf, JsonEventCodec).await
+}
+
+/// Same as [postgres_source] but the payloads are decoded with the codec
+pub async fn postgres_source_codec<C: EventCodec + Unpin>(
+    channel: &str,
+    conf: PostgresConf,
+    codec: C,
+) -> Result<PostgresSource<C>, tokio_postgres::Error> {
+    let (client, rx) = Postgres::new(conf).make_listener().await?;
+    client
+        .batch_execute(&format!("LISTEN \"{}\"", channel.replace('"', "\"\"")))
+        .await?;
+    Ok(PostgresSource {
+        rx,
+        client,
+        channel: channel.to_string(),
+        codec,
+    })
+}
+
+pub struct PostgresSource<C = JsonEventCodec> {
+    rx: UnboundedReceiver<Notification>,
+    // keep the connection alive
+    client: Client,
+    channel: String,
+    codec: C,
+}
+
+impl<C> PostgresSource<C> {
+    /// The client of the listening connection, e.p. to load the policies
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl<C: EventCodec + Unpin> Stream for PostgresSource<C> {
+    type Item = EventData;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let notification = ready!(self.rx.poll_recv(cx));
+        let data = match notification {
+            Some(notification) => Some(decode_event(
+                &self.codec,
+                notification.payload().as_bytes(),
+                "postgres",
+            )),
+            None => {
+                error!(
+                    "postgres connection listening {} is closed, policies are no longer updated",
+                    self.channel
+                );
+                None
+            }
+        };
+        Poll::Ready(data)
+    }
+}
@@ -0,0 +1,174 @@
+/// Publishers of [EventData], the counterparts of the sources, e.p. for admin
+/// tools writing policy changes. The publisher and the sources of a bus must
+/// use the same codec.
+///
+/// ```rust,ignore
+/// let data = EventData::AddPolicy(vec!["alice".into(), "/users".into(), "GET".into()]);
+/// publish_redis(&mut conn, "role_mapping", &data).await?;
+/// // consumed by `redis_source("role_mapping", conn)`
+/// ```
+use crate::layer::{EventCodec, EventData, JsonEventCodec};
+use amqprs::channel::{BasicPublishArguments, Channel};
+use amqprs::BasicProperties;
+use redis::aio::ConnectionLike;
+use redis::RedisError;
+use thiserror::Error;
+use tower::BoxError;
+
+#[derive(Debug, Error)]
+pub enum PublishError {
+    #[error("cannot encode event data: {0}")]
+    Encode(BoxError),
+    #[error("cannot publish to redis: {0}")]
+    Redis(#[from] RedisError),
+    #[error("cannot publish to rabbitmq: {0}")]
+    Amqp(#[from] amqprs::error::Error),
+}
+
+/// Publish to a redis channel consumed by [redis_source]
+///
+/// [redis_source]: crate::layer::redis_source
+pub async fn publish_redis<C: ConnectionLike + Send>(
+    conn: &mut C,
+    channel: &str,
+    data: &EventData,
+) -> Result<(), PublishError> {
+    publish_redis_codec(conn, channel, data, &JsonEventCodec).await
+}
+
+/// Same as [publish_redis] but the payload is encoded with the codec
+pub async fn publish_redis_codec<C: ConnectionLike + Send, Codec: EventCodec>(
+    conn: &mut C,
+    channel: &str,
+    data: &EventData,
+    codec: &Codec,
+) -> Result<(), PublishError> {
+    let payload = codec.encode(data).map_err(PublishError::Encode)?;
+    redis::cmd("PUBLISH")
+        .arg(channel)
+        .arg(payload)
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+/// Publish to a rabbitmq exchange, routed to the queues consumed by [amqp_source]
+///
+/// [amqp_source]: crate::layer::amqp_source
+pub async fn publish_amqp(
+    chan: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    data: &EventData,
+) -> Result<(), PublishError> {
+    publish_amqp_codec(chan, exchange, routing_key, data, &JsonEventCodec).await
+}
+
+/// Same as [publish_amqp] but the payload is encoded with the codec
+pub async fn publish_amqp_codec<Codec: EventCodec>(
+    chan: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    data: &EventData,
+    codec: &Codec,
+) -> Result<(), PublishError> {
+    let payload = codec.encode(data).map_err(PublishError::Encode)?;
+    chan.basic_publish(
+        BasicProperties::default(),
+        payload,
+        BasicPublishArguments::new(exchange, routing_key),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::role_mapping::codec::decode_event;
+    use crate::layer::role_mapping::fixture::{enforcer, MODEL};
+    use crate::layer::{publish_redis, DistributeRoleMappingLayer, EventData, JsonEventCodec};
+    use http::{Request, Response, StatusCode};
+    use redis::aio::ConnectionLike;
+    use redis::{Arg, Cmd, Pipeline, RedisFuture, Value};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::sync::mpsc::UnboundedSender;
+    use tower::{service_fn, Layer, Service, ServiceExt};
+
+    /// Records the PUBLISH commands like a broker, the channels and payloads are sent to the sender
+    struct Recorder(UnboundedSender<(String, Vec<u8>)>);
+
+    impl ConnectionLike for Recorder {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            let args: Vec<_> = cmd
+                .args_iter()
+                .filter_map(|arg| match arg {
+                    Arg::Simple(arg) => Some(arg.to_vec()),
+                    Arg::Cursor => None,
+                })
+                .collect();
+            assert_eq!(args[0], b"PUBLISH");
+            let channel = String::from_utf8(args[1].clone()).unwrap();
+            self.0.send((channel, args[2].clone())).unwrap();
+            Box::pin(async { Ok(Value::Int(1)) })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a Pipeline,
+            _: usize,
+            _: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            unimplemented!("pipelines are not published")
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_enforcer() {
+        let enforcer = enforcer(MODEL, &[]).await;
+
+        // the published payloads are consumed like `redis_source`
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let source = futures::stream::unfold(rx, |mut rx| async move {
+            let (channel, payload): (String, Vec<u8>) = rx.recv().await?;
+            assert_eq!(channel, "role_mapping");
+            Some((decode_event(&JsonEventCodec, &payload, "redis"), rx))
+        });
+        let layer = DistributeRoleMappingLayer::<String, _>::new(enforcer, source);
+        let mut svc = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let request = || {
+            Request::get("/users")
+                .extension("alice".to_string())
+                .body(())
+                .unwrap()
+        };
+        let res = svc.ready().await.unwrap().call(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let data = EventData::AddPolicy(vec![
+            "alice".to_string(),
+            "/users".to_string(),
+            "GET".to_string(),
+        ]);
+        publish_redis(&mut Recorder(tx), "role_mapping", &data)
+            .await
+            .unwrap();
+
+        let mut status = StatusCode::FORBIDDEN;
+        for _ in 0..100 {
+            let res = svc.ready().await.unwrap().call(request()).await.unwrap();
+            status = res.status();
+            if status == StatusCode::OK {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,234 @@
+/// Compose multiple enforcers behind one [RoleMappingLayer], each route group
+/// enforces with its own model and policy, e.p. RBAC for admin and ABAC for data APIs.
+///
+/// A request is routed by the [RouteGroup] extension first, then by the longest
+/// matched path prefix, requests matching no group fall through to the fallback.
+///
+/// [RoleMappingLayer]: crate::layer::RoleMappingLayer
+use super::objects::Objects;
+use http::{Method, Request};
+
+/// A route group name in request extensions used to select the enforcer,
+/// it takes precedence over path prefixes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteGroup(pub String);
+
+enum Route {
+    Prefix(String),
+    Group(String),
+}
+
+pub(crate) enum Selected<'a, E> {
+    Enforcer(&'a E, &'a Objects),
+    Allow,
+    Deny,
+}
+
+pub struct RouteEnforcers<E> {
+    routes: Vec<(Route, E, Objects)>,
+    fallback: Option<(E, Objects)>,
+    allow_unmatched: bool,
+}
+
+impl<E> RouteEnforcers<E> {
+    /// A single enforcer for all requests
+    pub fn single(enforcer: E) -> Self {
+        Self {
+            routes: vec![],
+            fallback: Some((enforcer, Objects::default())),
+            allow_unmatched: false,
+        }
+    }
+
+    /// Requests matching no group are allowed
+    pub fn allow_unmatched() -> Self {
+        Self {
+            routes: vec![],
+            fallback: None,
+            allow_unmatched: true,
+        }
+    }
+
+    /// Requests matching no group are denied
+    pub fn deny_unmatched() -> Self {
+        Self {
+            routes: vec![],
+            fallback: None,
+            allow_unmatched: false,
+        }
+    }
+
+    /// Requests matching no group are enforced by the enforcer
+    pub fn fallback(mut self, enforcer: E) -> Self {
+        self.fallback = Some((enforcer, Objects::default()));
+        self
+    }
+
+    /// Enforce requests under the path prefix, e.p. `/admin` matches `/admin/users`
+    /// but not `/administrator`.
+    pub fn prefix(mut self, prefix: impl ToString, enforcer: E) -> Self {
+        self.routes.push((
+            Route::Prefix(prefix.to_string()),
+            enforcer,
+            Objects::default(),
+        ));
+        self
+    }
+
+    /// Enforce requests with the [RouteGroup] extension
+    pub fn group(mut self, group: impl ToString, enforcer: E) -> Self {
+        self.routes.push((
+            Route::Group(group.to_string()),
+            enforcer,
+            Objects::default(),
+        ));
+        self
+    }
+
+    pub(crate) fn select<B>(&self, req: &Request<B>) -> Selected<'_, E> {
+        if let Some(RouteGroup(group)) = req.extensions().get::<RouteGroup>() {
+            let found = self
+                .routes
+                .iter()
+                .find_map(|(route, enforcer, objects)| match route {
+                    Route::Group(name) if name == group => Some((enforcer, objects)),
+                    _ => None,
+                });
+            if let Some((enforcer, objects)) = found {
+                return Selected::Enforcer(enforcer, objects);
+            }
+        }
+
+        let path = req.uri().path();
+        let found = self
+            .routes
+            .iter()
+            .filter_map(|(route, enforcer, objects)| match route {
+                Route::Prefix(prefix) if match_prefix(path, prefix) => {
+                    Some((prefix, enforcer, objects))
+                }
+                _ => None,
+            })
+            .max_by_key(|(prefix, _, _)| prefix.len());
+        if let Some((_, enforcer, objects)) = found {
+            return Selected::Enforcer(enforcer, objects);
+        }
+
+        match &self.fallback {
+            Some((enforcer, objects)) => Selected::Enforcer(enforcer, objects),
+            None if self.allow_unmatched => Selected::Allow,
+            None => Selected::Deny,
+        }
+    }
+}
+
+/// The routes open to everyone, e.p. login, signup and docs. A public request skips
+/// both the identity requirement ([NoIdentity]) and the enforcement, so they need
+/// no casbin policy.
+///
+/// Public routes take precedence over all the others: they are matched before the
+/// route groups and casbin, a deny policy on a public route has no effect.
+///
+/// ```rust,ignore
+/// let public = PublicRoutes::new()
+///     .path("/docs/*")
+///     .method(Method::POST, "/login")
+///     .method(Method::POST, "/signup");
+/// let layer = RoleMappingLayer::<Uid, _>::new(enforcer).public_routes(public);
+/// ```
+///
+/// [NoIdentity]: crate::layer::NoIdentity
+#[derive(Clone, Debug, Default)]
+pub struct PublicRoutes(Vec<(Option<Method>, String)>);
+
+impl PublicRoutes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The path of all methods, `/prefix/*` matches all paths under the prefix
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.0.push((None, path.into()));
+        self
+    }
+
+    /// Same as [PublicRoutes::path] but only of the method
+    pub fn method(mut self, method: Method, path: impl Into<String>) -> Self {
+        self.0.push((Some(method), path.into()));
+        self
+    }
+
+    pub(crate) fn matches<B>(&self, req: &Request<B>) -> bool {
+        let path = req.uri().path();
+        self.0.iter().any(|(method, public)| {
+            method.as_ref().is_none_or(|method| method == req.method())
+                && match public.strip_suffix("/*") {
+                    Some(prefix) => match_prefix(path, prefix),
+                    None => public == path,
+                }
+        })
+    }
+}
+
+fn match_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn select(routes: &RouteEnforcers<&'static str>, req: Request<()>) -> &'static str {
+        match routes.select(&req) {
+            Selected::Enforcer(enforcer, _) => enforcer,
+            Selected::Allow => "allow",
+            Selected::Deny => "deny",
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let routes = RouteEnforcers::deny_unmatched()
+            .prefix("/admin", "rbac")
+            .prefix("/admin/data", "abac")
+            .group("data", "abac");
+
+        let req = |path: &str| Request::get(path).body(()).unwrap();
+        assert_eq!(select(&routes, req("/admin")), "rbac");
+        assert_eq!(select(&routes, req("/admin/users")), "rbac");
+        assert_eq!(select(&routes, req("/admin/data/1")), "abac");
+        assert_eq!(select(&routes, req("/administrator")), "deny");
+
+        let mut grouped = req("/books");
+        grouped
+            .extensions_mut()
+            .insert(RouteGroup("data".to_string()));
+        assert_eq!(select(&routes, grouped), "abac");
+
+        let routes = RouteEnforcers::allow_unmatched().prefix("/admin", "rbac");
+        assert_eq!(select(&routes, req("/books")), "allow");
+    }
+
+    #[test]
+    fn test_public_routes() {
+        let public = PublicRoutes::new()
+            .path("/docs/*")
+            .method(Method::POST, "/login");
+        let req = |method: Method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(())
+                .unwrap()
+        };
+        assert!(public.matches(&req(Method::GET, "/docs")));
+        assert!(public.matches(&req(Method::GET, "/docs/index.html")));
+        assert!(!public.matches(&req(Method::GET, "/docsearch")));
+        assert!(public.matches(&req(Method::POST, "/login")));
+        assert!(!public.matches(&req(Method::GET, "/login")));
+        assert!(!public.matches(&req(Method::POST, "/login/reset")));
+    }
+}
@@ -1,15 +1,196 @@
-use crate::layer::EventData;
+use crate::layer::role_mapping::codec::decode_event;
+use crate::layer::{EventCodec, EventData, JsonEventCodec};
+use crate::middleware::consul::kv_value;
 use amqprs::channel::{BasicConsumeArguments, Channel, ConsumerMessage};
+use consul::kv::KV;
+use consul::QueryOptions;
+use etcd_client::{EventType, GetOptions, WatchOptions, WatchStream, Watcher};
 use futures::{ready, Stream, StreamExt};
 use redis::Msg;
+use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedReceiver;
-use tracing::warn;
+use tokio::sync::Notify;
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{trace, warn, Instrument};
+
+/// What to do when the buffer of a throttled source is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the oldest buffered event with a warning
+    #[default]
+    DropOldest,
+    /// Stop pulling from the source until there is space, so that
+    /// the broker holds the events
+    Backpressure,
+}
+
+/// Flow control of policy event sources, protects the enforcer from event storms
+#[derive(Clone, Copy, Debug)]
+pub struct SourceLimit {
+    buffer: usize,
+    rate: Option<u32>,
+    overflow: Overflow,
+}
+
+impl Default for SourceLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SourceLimit {
+    /// 1024 events buffered without rate limit by default
+    pub fn new() -> Self {
+        Self {
+            buffer: 1024,
+            rate: None,
+            overflow: Overflow::default(),
+        }
+    }
+
+    /// The max number of events buffered
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer.max(1);
+        self
+    }
+
+    /// The max events yielded per second
+    pub fn rate(mut self, per_second: u32) -> Self {
+        self.rate = Some(per_second.max(1));
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+}
+
+struct Buffer {
+    queue: Mutex<VecDeque<EventData>>,
+    closed: AtomicBool,
+    item: Notify,
+    space: Notify,
+}
+
+impl Buffer {
+    async fn push(&self, data: EventData, limit: &SourceLimit) {
+        loop {
+            let space = self.space.notified();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.len() < limit.buffer {
+                    queue.push_back(data);
+                    break;
+                }
+                if limit.overflow == Overflow::DropOldest {
+                    if let Some(dropped) = queue.pop_front() {
+                        warn!("Event source buffer is full, drop {:?}", dropped.kind());
+                    }
+                    queue.push_back(data);
+                    break;
+                }
+            }
+            space.await;
+        }
+        self.item.notify_one();
+    }
+
+    async fn pop(&self) -> Option<EventData> {
+        loop {
+            let item = self.item.notified();
+            {
+                if let Some(data) = self.queue.lock().unwrap().pop_front() {
+                    self.space.notify_one();
+                    return Some(data);
+                }
+                if self.closed.load(Ordering::Acquire) {
+                    return None;
+                }
+            }
+            item.await;
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.item.notify_one();
+    }
+}
+
+/// Buffer and throttle a source with the limit, the source is pulled in background
+/// until the returned stream is dropped.
+pub fn throttle_source<S: Stream<Item = EventData> + Send + 'static>(
+    source: S,
+    limit: SourceLimit,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    let buffer = Arc::new(Buffer {
+        queue: Mutex::new(VecDeque::with_capacity(limit.buffer.min(1024))),
+        closed: AtomicBool::new(false),
+        item: Notify::new(),
+        space: Notify::new(),
+    });
+    let stopped = CancellationToken::new();
+
+    let pump = {
+        let buffer = buffer.clone();
+        let stopped = stopped.clone();
+        async move {
+            tokio::pin!(source);
+            loop {
+                let data = tokio::select! {
+                    _ = stopped.cancelled() => break,
+                    data = source.next() => match data {
+                        Some(data) => data,
+                        None => break,
+                    },
+                };
+                tokio::select! {
+                    _ = stopped.cancelled() => break,
+                    _ = buffer.push(data, &limit) => {},
+                }
+            }
+            buffer.close();
+            trace!("Event source pump exited");
+        }
+        .in_current_span()
+    };
+    tokio::spawn(pump);
+
+    let ticker = limit.rate.map(|rate| {
+        let mut ticker = interval(Duration::from_secs(1) / rate);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+    // stop the pump once the stream is dropped
+    let state = (buffer, ticker, stopped.drop_guard());
+    futures::stream::unfold(state, |(buffer, mut ticker, guard)| async move {
+        if let Some(ref mut ticker) = ticker {
+            ticker.tick().await;
+        }
+        let data = buffer.pop().await?;
+        Some((data, (buffer, ticker, guard)))
+    })
+}
 
 pub async fn redis_source(
     channel: &str,
     conn: redis::aio::Connection,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    redis_source_codec(channel, conn, JsonEventCodec).await
+}
+
+/// Same as [redis_source] but the payloads are decoded with the codec
+pub async fn redis_source_codec<C: EventCodec>(
+    channel: &str,
+    conn: redis::aio::Connection,
+    codec: C,
 ) -> impl Stream<Item = EventData> + Send + 'static {
     let mut pub_sub = conn.into_pubsub();
     pub_sub
@@ -17,22 +198,31 @@ pub async fn redis_source(
         .await
         .unwrap_or_else(|_| panic!("Cannot subscribe channel {}", channel));
     let on_msg = pub_sub.into_on_message();
-    on_msg.map(|msg: Msg| {
-        let payload = msg.get_payload_bytes();
-        serde_json::from_slice::<EventData>(payload).unwrap_or_else(|_| {
-            warn!(
-                "Cannot deserialize EventData({}) from redis",
-                String::from_utf8_lossy(payload)
-            );
-            EventData::NIL
-        })
-    })
+    on_msg.map(move |msg: Msg| decode_event(&codec, msg.get_payload_bytes(), "redis"))
+}
+
+/// Same as [redis_source] but buffered and throttled with the limit
+pub async fn redis_source_with(
+    channel: &str,
+    conn: redis::aio::Connection,
+    limit: SourceLimit,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    throttle_source(redis_source(channel, conn).await, limit)
 }
 
 /// queue_name and a bind queue channel
 pub async fn amqp_source(
     queue_name: &str,
     chan: Channel,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    amqp_source_codec(queue_name, chan, JsonEventCodec).await
+}
+
+/// Same as [amqp_source] but the payloads are decoded with the codec
+pub async fn amqp_source_codec<C: EventCodec + Unpin>(
+    queue_name: &str,
+    chan: Channel,
+    codec: C,
 ) -> impl Stream<Item = EventData> + Send + 'static {
     let (_, rx) = chan
         .basic_consume_rx(BasicConsumeArguments::new(
@@ -41,29 +231,345 @@ pub async fn amqp_source(
         ))
         .await
         .unwrap_or_else(|_| panic!("Cannot consume queue {}", queue_name));
-    AMQPSource { rx }
+    AMQPSource { rx, codec }
 }
 
-pub struct AMQPSource {
+/// Same as [amqp_source] but buffered and throttled with the limit
+pub async fn amqp_source_with(
+    queue_name: &str,
+    chan: Channel,
+    limit: SourceLimit,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    throttle_source(amqp_source(queue_name, chan).await, limit)
+}
+
+pub struct AMQPSource<C = JsonEventCodec> {
     rx: UnboundedReceiver<ConsumerMessage>,
+    codec: C,
 }
 
-impl Stream for AMQPSource {
+impl<C: EventCodec + Unpin> Stream for AMQPSource<C> {
     type Item = EventData;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let msg = ready!(self.rx.poll_recv(cx));
-        let data = msg.and_then(|msg| msg.content).map(|content| {
-            serde_json::from_slice::<EventData>(content.as_slice()).unwrap_or_else(|_| {
-                warn!(
-                    "Cannot deserialize EventData({}) from rabbitmq",
-                    String::from_utf8_lossy(content.as_slice())
-                );
-                EventData::NIL
-            })
-        });
+        let data = msg
+            .and_then(|msg| msg.content)
+            .map(|content| decode_event(&self.codec, &content, "rabbitmq"));
         Poll::Ready(data)
     }
 }
 
+/// The max duration of a consul blocking query
+const CONSUL_WAIT: Duration = Duration::from_secs(300);
+
+/// The interval of retrying a failed KV store query or watch
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The blocking query bookkeeping of a consul KV prefix
+#[derive(Default)]
+struct ConsulKvState {
+    /// The index of the last listing, None before the first listing
+    index: Option<u64>,
+    /// The modify index of each key
+    revisions: HashMap<String, u64>,
+}
+
+impl ConsulKvState {
+    /// Record a listing of (key, modify index, value), returns the values of
+    /// added or modified keys in the order of modification. The values existed
+    /// before the first listing are not changes.
+    fn update(
+        &mut self,
+        index: Option<u64>,
+        pairs: impl IntoIterator<Item = (String, u64, Vec<u8>)>,
+    ) -> Vec<Vec<u8>> {
+        let initial = self.index.is_none();
+        let index = index.unwrap_or(0);
+        // reset the index if it goes backwards (e.p. consul restored a snapshot),
+        // and it must be at least 1 to block
+        self.index = match self.index {
+            Some(old) if index < old => Some(0),
+            _ => Some(index.max(1)),
+        };
+
+        let mut changed = vec![];
+        let mut revisions = HashMap::with_capacity(self.revisions.len());
+        for (key, modify_index, value) in pairs {
+            if !initial && self.revisions.get(&key) != Some(&modify_index) {
+                changed.push((modify_index, value));
+            }
+            revisions.insert(key, modify_index);
+        }
+        // deleted keys are forgotten
+        self.revisions = revisions;
+        changed.sort_by_key(|(modify_index, _)| *modify_index);
+        changed.into_iter().map(|(_, value)| value).collect()
+    }
+}
+
+/// Watch the keys under `key_prefix` in consul KV with blocking queries, each
+/// added or modified value is an EventData in JSON. The keys existed before
+/// are skipped, the policies should have been loaded by the adapter.
+///
+/// ```shell
+/// consul kv put role_mapping/events/1 '{"AddPolicy":["alice","/users","GET"]}'
+/// ```
+pub fn consul_kv_source(
+    client: consul::Client,
+    key_prefix: &str,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    consul_kv_source_codec(client, key_prefix, JsonEventCodec)
+}
+
+/// Same as [consul_kv_source] but the values are decoded with the codec
+pub fn consul_kv_source_codec<C: EventCodec>(
+    client: consul::Client,
+    key_prefix: &str,
+    codec: C,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    let state = (
+        client,
+        key_prefix.to_string(),
+        codec,
+        ConsulKvState::default(),
+        VecDeque::new(),
+    );
+    futures::stream::unfold(
+        state,
+        |(client, prefix, codec, mut kv, mut pending)| async move {
+            loop {
+                if let Some(data) = pending.pop_front() {
+                    return Some((data, (client, prefix, codec, kv, pending)));
+                }
+                let options = QueryOptions {
+                    wait_index: kv.index,
+                    wait_time: kv.index.map(|_| CONSUL_WAIT),
+                    ..Default::default()
+                };
+                match client.list(&prefix, Some(&options)).await {
+                    Ok((pairs, meta)) => {
+                        let pairs = pairs.into_iter().map(|pair| {
+                            let value = kv_value(&pair);
+                            (pair.Key, pair.ModifyIndex.unwrap_or(0), value)
+                        });
+                        let changed = kv.update(meta.last_index, pairs);
+                        pending.extend(
+                            changed
+                                .into_iter()
+                                .map(|payload| decode_event(&codec, &payload, "consul")),
+                        );
+                    }
+                    Err(err) => {
+                        warn!("Cannot list consul KV prefix {}, err: {}", prefix, err);
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Same as [consul_kv_source] but buffered and throttled with the limit
+pub fn consul_kv_source_with(
+    client: consul::Client,
+    key_prefix: &str,
+    limit: SourceLimit,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    throttle_source(consul_kv_source(client, key_prefix), limit)
+}
+
+struct EtcdSource<C> {
+    client: etcd_client::Client,
+    key: String,
+    prefix: bool,
+    codec: C,
+    /// The latest revision seen, None before the initial read
+    revision: Option<i64>,
+    watch: Option<(Watcher, WatchStream)>,
+    pending: VecDeque<EventData>,
+}
+
+impl<C: EventCodec> EtcdSource<C> {
+    /// Watch from the next revision of the latest seen one, read the current
+    /// revision first if there is none
+    async fn start(&mut self) -> Result<(Watcher, WatchStream), etcd_client::Error> {
+        let revision = match self.revision {
+            Some(revision) => revision,
+            None => {
+                let options = self
+                    .prefix
+                    .then(|| GetOptions::new().with_prefix().with_keys_only());
+                let resp = self.client.get(self.key.as_str(), options).await?;
+                let revision = resp.header().map(|header| header.revision()).unwrap_or(0);
+                self.revision = Some(revision);
+                revision
+            }
+        };
+        let mut options = WatchOptions::new().with_start_revision(revision + 1);
+        if self.prefix {
+            options = options.with_prefix();
+        }
+        self.client.watch(self.key.as_str(), Some(options)).await
+    }
+
+    async fn next(&mut self) -> EventData {
+        loop {
+            if let Some(data) = self.pending.pop_front() {
+                return data;
+            }
+            if self.watch.is_none() {
+                match self.start().await {
+                    Ok(watch) => self.watch = Some(watch),
+                    Err(err) => {
+                        warn!("Cannot watch etcd key {}, err: {}", self.key, err);
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+            let (_, stream) = self.watch.as_mut().unwrap();
+            let resp = match stream.message().await {
+                Ok(Some(resp)) => resp,
+                Ok(None) => {
+                    warn!("Etcd watch stream of {} is closed, rewatch", self.key);
+                    self.watch = None;
+                    continue;
+                }
+                Err(err) => {
+                    warn!("Etcd watch stream of {} error: {}, rewatch", self.key, err);
+                    self.watch = None;
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            if resp.canceled() {
+                // the revision to start from is compacted, events in between are lost
+                if resp.compact_revision() > 0 {
+                    warn!(
+                        "Etcd revision of {} is compacted to {}, rewatch from the current revision",
+                        self.key,
+                        resp.compact_revision()
+                    );
+                    self.revision = None;
+                }
+                self.watch = None;
+                continue;
+            }
+            for event in resp.events() {
+                if let Some(kv) = event.kv() {
+                    self.revision = self.revision.max(Some(kv.mod_revision()));
+                    if matches!(event.event_type(), EventType::Put) {
+                        self.pending
+                            .push_back(decode_event(&self.codec, kv.value(), "etcd"));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Watch an etcd key (or all keys with the prefix if `prefix`), each put value is
+/// an EventData in JSON. The watch starts from the revision of an initial read, so
+/// no event is missed in between, and it is rewatched from the latest revision seen
+/// after errors.
+///
+/// ```shell
+/// etcdctl put role_mapping/events '{"AddPolicy":["alice","/users","GET"]}'
+/// ```
+pub fn etcd_source(
+    client: etcd_client::Client,
+    key: &str,
+    prefix: bool,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    etcd_source_codec(client, key, prefix, JsonEventCodec)
+}
+
+/// Same as [etcd_source] but the values are decoded with the codec
+pub fn etcd_source_codec<C: EventCodec>(
+    client: etcd_client::Client,
+    key: &str,
+    prefix: bool,
+    codec: C,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    let source = EtcdSource {
+        client,
+        key: key.to_string(),
+        prefix,
+        codec,
+        revision: None,
+        watch: None,
+        pending: VecDeque::new(),
+    };
+    futures::stream::unfold(source, |mut source| async move {
+        let data = source.next().await;
+        Some((data, source))
+    })
+}
+
+/// Same as [etcd_source] but buffered and throttled with the limit
+pub fn etcd_source_with(
+    client: etcd_client::Client,
+    key: &str,
+    prefix: bool,
+    limit: SourceLimit,
+) -> impl Stream<Item = EventData> + Send + 'static {
+    throttle_source(etcd_source(client, key, prefix), limit)
+}
+
 // todo other source...
+
+#[cfg(test)]
+mod test {
+    use crate::layer::role_mapping::source::ConsulKvState;
+    use crate::layer::{throttle_source, EventData, Overflow, SourceLimit};
+    use futures::StreamExt;
+    use std::time::{Duration, Instant};
+
+    fn events(n: usize) -> Vec<EventData> {
+        (0..n)
+            .map(|i| EventData::AddPolicy(vec![i.to_string()]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_throttle_source() {
+        // drop oldest
+        let limit = SourceLimit::new().buffer(2);
+        let source = throttle_source(futures::stream::iter(events(5)), limit);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let received: Vec<_> = source.collect().await;
+        assert!(
+            matches!(&received[..], [EventData::AddPolicy(a), EventData::AddPolicy(b)] if a[0] == "3" && b[0] == "4")
+        );
+
+        // backpressure and rate
+        let limit = SourceLimit::new()
+            .buffer(1)
+            .rate(50)
+            .overflow(Overflow::Backpressure);
+        let start = Instant::now();
+        let source = throttle_source(futures::stream::iter(events(5)), limit);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(source.count().await, 5);
+        assert!(start.elapsed() >= Duration::from_millis(80));
+    }
+
+    #[test]
+    fn test_consul_kv_state() {
+        let pair = |key: &str, index: u64| (key.to_string(), index, key.as_bytes().to_vec());
+        let mut kv = ConsulKvState::default();
+        assert!(kv.update(Some(10), [pair("a", 5)]).is_empty());
+        assert_eq!(kv.index, Some(10));
+
+        let changed = kv.update(Some(12), [pair("a", 5), pair("c", 12), pair("b", 11)]);
+        assert_eq!(changed, vec![b"b".to_vec(), b"c".to_vec()]);
+
+        // the index goes backwards
+        assert!(kv
+            .update(Some(3), [pair("b", 11), pair("c", 12)])
+            .is_empty());
+        assert_eq!(kv.index, Some(0));
+        assert_eq!(kv.update(Some(4), [pair("a", 4)]), vec![b"a".to_vec()]);
+    }
+}
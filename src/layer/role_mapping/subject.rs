@@ -0,0 +1,137 @@
+/// Subjects enforced for a request, read from the request extension `I`.
+///
+/// By default `I: AsRef<str>` is a single subject. Use [AnySubject] as `I` when the
+/// identity carries several roles or groups, the request is allowed if any of
+/// them is permitted, e.p. `RoleMappingLayer<AnySubject<Vec<String>>, E>`.
+///
+/// In debug builds, a request missing `I` but carrying another likely identity
+/// (e.p. a `String` while `I` is `Vec<String>`) is logged, it is usually the auth
+/// layer inserting another type or being ordered after the role mapping layer.
+use crate::layer::KeyId;
+use http::Request;
+use std::any::{type_name, TypeId};
+use std::marker::PhantomData;
+use tracing::warn;
+
+/// An identity exposing multiple subjects, e.p. the groups of a user
+pub trait AsSubjects {
+    fn subjects(&self) -> Vec<&str>;
+}
+
+impl AsSubjects for Vec<String> {
+    fn subjects(&self) -> Vec<&str> {
+        self.iter().map(String::as_str).collect()
+    }
+}
+
+impl AsSubjects for Vec<&'static str> {
+    fn subjects(&self) -> Vec<&str> {
+        self.clone()
+    }
+}
+
+/// Enforce with all subjects of extension `I`, allow if any of them is permitted.
+/// An identity without any subject is handled as a missing identity.
+pub struct AnySubject<I>(PhantomData<I>);
+
+/// How subjects are read from the request, implemented for `I: AsRef<str>`
+/// and [AnySubject]
+pub trait SubjectExtractor {
+    /// Subjects in enforcing order, empty if the extension is missing,
+    /// see [NoIdentity] for how such requests are handled.
+    ///
+    /// [NoIdentity]: crate::layer::NoIdentity
+    fn subjects<B>(req: &Request<B>) -> Vec<&str>;
+}
+
+impl<I: AsRef<str> + Send + Sync + 'static> SubjectExtractor for I {
+    fn subjects<B>(req: &Request<B>) -> Vec<&str> {
+        match req.extensions().get::<I>() {
+            Some(sub) => vec![sub.as_ref()],
+            None => {
+                warn_mismatched_identity::<I, B>(req);
+                vec![]
+            }
+        }
+    }
+}
+
+impl<I: AsSubjects + Send + Sync + 'static> SubjectExtractor for AnySubject<I> {
+    fn subjects<B>(req: &Request<B>) -> Vec<&str> {
+        match req.extensions().get::<I>() {
+            Some(subs) => subs.subjects(),
+            None => {
+                warn_mismatched_identity::<I, B>(req);
+                vec![]
+            }
+        }
+    }
+}
+
+fn warn_mismatched_identity<I: 'static, B>(req: &Request<B>) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let found = likely_identities::<I, B>(req);
+    if !found.is_empty() {
+        warn!(
+            "identity {} is missing in request extensions but {} is present, \
+            check the type inserted by the auth layer and that it is layered before role mapping",
+            type_name::<I>(),
+            found.join(", ")
+        );
+    }
+}
+
+/// The extensions other than `I` which are likely identities
+fn likely_identities<I: 'static, B>(req: &Request<B>) -> Vec<&'static str> {
+    fn present<T: Send + Sync + 'static, I: 'static, B>(req: &Request<B>) -> Option<&'static str> {
+        let present =
+            TypeId::of::<T>() != TypeId::of::<I>() && req.extensions().get::<T>().is_some();
+        present.then(type_name::<T>)
+    }
+    [
+        present::<String, I, B>(req),
+        present::<&'static str, I, B>(req),
+        present::<Vec<String>, I, B>(req),
+        present::<Vec<&'static str>, I, B>(req),
+        present::<KeyId, I, B>(req),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subjects() {
+        let mut req = Request::get("/").body(()).unwrap();
+        assert!(<String as SubjectExtractor>::subjects(&req).is_empty());
+        assert!(AnySubject::<Vec<String>>::subjects(&req).is_empty());
+
+        req.extensions_mut().insert("uid".to_string());
+        req.extensions_mut()
+            .insert(vec!["admin".to_string(), "editor".to_string()]);
+        assert_eq!(<String as SubjectExtractor>::subjects(&req), vec!["uid"]);
+        assert_eq!(
+            AnySubject::<Vec<String>>::subjects(&req),
+            vec!["admin", "editor"]
+        );
+    }
+
+    #[test]
+    fn test_likely_identities() {
+        let mut req = Request::get("/").body(()).unwrap();
+        assert!(likely_identities::<String, _>(&req).is_empty());
+
+        req.extensions_mut().insert("uid".to_string());
+        assert!(likely_identities::<String, _>(&req).is_empty());
+        assert_eq!(
+            likely_identities::<Vec<String>, _>(&req),
+            vec![type_name::<String>()]
+        );
+    }
+}
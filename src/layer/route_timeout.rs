@@ -0,0 +1,222 @@
+/// Time out requests by route rather than with one global timeout, e.p. a report
+/// endpoint needs 30 seconds while everything else needs 2. A request taking longer
+/// than the timeout of its route is responded with `504 Gateway Timeout`.
+///
+/// The timeouts are read from configuration:
+///
+/// ```yaml
+/// route_timeouts:
+///   default: 2000
+///   routes:
+///     /reports/*: 30000
+///     /books/*/export: 10000
+/// ```
+///
+/// and follow the hot-reloaded configuration of [ConfigWatcher]:
+///
+/// ```rust,ignore
+/// let (conf, _handle) = ConfigWatcher::<R::Config>::new().watch::<R>().await?;
+/// let layer = RouteTimeoutLayer::reloadable(conf, |conf| &conf.route_timeouts);
+/// ```
+///
+/// [ConfigWatcher]: crate::utils::reload::ConfigWatcher
+use crate::status::error_body::{ErrorBody, ErrorResponseBody};
+use futures::future::BoxFuture;
+use http::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::watch;
+use tower::{Layer, Service};
+
+/// The timeouts in milliseconds of route patterns, a pattern is matched as:
+/// `/reports` => the exact path
+/// `/reports/*` => the prefix itself and all paths under it
+/// `/books/*/export` => `*` matches one segment
+/// The exact path wins, then the longest pattern, then the one with fewer `*`,
+/// then the lexically smallest one, so the timeout never depends on the map order.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteTimeouts {
+    /// The timeout of paths matching no pattern, no timeout if missing
+    #[serde(default)]
+    pub default: Option<u64>,
+    #[serde(default)]
+    pub routes: HashMap<String, u64>,
+}
+
+impl RouteTimeouts {
+    pub fn timeout_of(&self, path: &str) -> Option<Duration> {
+        if let Some(millis) = self.routes.get(path) {
+            return Some(Duration::from_millis(*millis));
+        }
+        self.routes
+            .iter()
+            .filter(|(pattern, _)| match_pattern(pattern, path))
+            .max_by_key(|(pattern, _)| {
+                let wildcards = pattern.split('/').filter(|s| *s == "*").count();
+                (pattern.len(), Reverse(wildcards), Reverse(pattern.as_str()))
+            })
+            .map(|(_, millis)| *millis)
+            .or(self.default)
+            .map(Duration::from_millis)
+    }
+}
+
+fn match_pattern(pattern: &str, path: &str) -> bool {
+    let (pattern, prefix) = match pattern.strip_suffix("/*") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut segments = path.split('/');
+    for expected in pattern.split('/') {
+        match segments.next() {
+            Some(segment) if expected == "*" || expected == segment => {}
+            _ => return false,
+        }
+    }
+    prefix || segments.next().is_none()
+}
+
+type TimeoutOf = Arc<dyn Fn(&str) -> Option<Duration> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RouteTimeoutLayer {
+    timeout_of: TimeoutOf,
+}
+
+impl Debug for RouteTimeoutLayer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RouteTimeoutLayer").finish_non_exhaustive()
+    }
+}
+
+impl RouteTimeoutLayer {
+    pub fn new(timeouts: RouteTimeouts) -> Self {
+        Self {
+            timeout_of: Arc::new(move |path| timeouts.timeout_of(path)),
+        }
+    }
+
+    /// The timeouts selected from the current configuration, so that the changes
+    /// take effect on the next requests
+    pub fn reloadable<T>(conf: watch::Receiver<Arc<T>>, select: fn(&T) -> &RouteTimeouts) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        Self {
+            timeout_of: Arc::new(move |path| select(&conf.borrow()).timeout_of(path)),
+        }
+    }
+}
+
+impl<S> Layer<S> for RouteTimeoutLayer {
+    type Service = RouteTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RouteTimeout {
+            inner,
+            timeout_of: self.timeout_of.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RouteTimeout<S> {
+    inner: S,
+    timeout_of: TimeoutOf,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RouteTimeout<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: ErrorResponseBody,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let timeout = match (self.timeout_of)(req.uri().path()) {
+            Some(timeout) => timeout,
+            None => return Box::pin(self.inner.call(req)),
+        };
+        let timed_out = ErrorBody::from_status(StatusCode::GATEWAY_TIMEOUT).of_request(&req);
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, fut).await {
+                Ok(res) => res,
+                Err(_) => Ok(timed_out.into_response(StatusCode::GATEWAY_TIMEOUT)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::{RouteTimeoutLayer, RouteTimeouts};
+    use http::{Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::watch;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_route_timeout() {
+        let timeouts: RouteTimeouts = serde_json::from_str(
+            r#"{
+                "default": 2000,
+                "routes": {
+                    "/reports": 5000,
+                    "/reports/*": 30000,
+                    "/books/*/export": 10000,
+                    "/books/*": 1000
+                }
+            }"#,
+        )
+        .unwrap();
+        let timeout_of = |path| timeouts.timeout_of(path).map(|t| t.as_millis());
+        assert_eq!(timeout_of("/reports"), Some(5000));
+        assert_eq!(timeout_of("/reports/2023/q1"), Some(30000));
+        assert_eq!(timeout_of("/books/1/export"), Some(10000));
+        assert_eq!(timeout_of("/books/1"), Some(1000));
+        assert_eq!(timeout_of("/books"), Some(1000));
+        assert_eq!(timeout_of("/reportsx"), Some(2000));
+        assert_eq!(timeout_of("/"), Some(2000));
+        assert_eq!(RouteTimeouts::default().timeout_of("/"), None);
+
+        // the same length, fewer wildcards then the lexically smallest
+        let ties: RouteTimeouts =
+            serde_json::from_str(r#"{"routes": {"/*/*/c": 1, "/a/*/c": 2, "/*/b/c": 3}}"#).unwrap();
+        assert_eq!(ties.timeout_of("/a/x/c"), Some(Duration::from_millis(2)));
+        assert_eq!(ties.timeout_of("/a/b/c"), Some(Duration::from_millis(3)));
+
+        // reloaded
+        let (tx, rx) = watch::channel(Arc::new(timeouts));
+        let svc = RouteTimeoutLayer::reloadable(rx, |timeouts| timeouts).layer(service_fn(
+            |_req: Request<()>| async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, Infallible>(Response::new(String::new()))
+            },
+        ));
+        let get = || Request::get("/books/1").body(()).unwrap();
+        let res = svc.clone().oneshot(get()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut timeouts = RouteTimeouts::default();
+        timeouts.routes.insert("/books/*".to_string(), 10);
+        tx.send(Arc::new(timeouts)).unwrap();
+        let res = svc.clone().oneshot(get()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(res.headers()["content-type"], "application/json");
+    }
+}
@@ -0,0 +1,337 @@
+/// Debugging layer which tees request and response bodies into a `tracing` event
+/// or a provided callback, without altering the bytes seen by the inner service.
+///
+/// It is default-off, enable it for every request with [TapLayer::enabled] or only
+/// for requests carrying a header with [TapLayer::header].
+/// The captured bytes are bounded by [TapLayer::max_bytes], the rest bytes are still
+/// passed through but marked as truncated in [TapRecord].
+use crate::layer::is_websocket_upgrade;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::ready;
+use http::header::HeaderName;
+use http::{HeaderMap, Request, Response};
+use http_body::{Body, SizeHint};
+use pin_project_lite::pin_project;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::debug;
+
+const DEFAULT_TAP_MAX_BYTES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapDirection {
+    Request,
+    Response,
+}
+
+/// A captured body
+#[derive(Clone, Debug)]
+pub struct TapRecord {
+    pub direction: TapDirection,
+    /// `METHOD URI` of the request
+    pub target: String,
+    pub body: Bytes,
+    /// Whether the body is larger than the capture limit
+    pub truncated: bool,
+}
+
+type TapSink = Arc<dyn Fn(TapRecord) + Send + Sync>;
+
+fn trace_sink(record: TapRecord) {
+    debug!(
+        direction = ?record.direction,
+        target = %record.target,
+        truncated = record.truncated,
+        "{}",
+        String::from_utf8_lossy(&record.body)
+    );
+}
+
+#[derive(Clone)]
+pub struct TapLayer {
+    enabled: bool,
+    header: Option<HeaderName>,
+    max_bytes: usize,
+    sink: TapSink,
+}
+
+impl Debug for TapLayer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TapLayer")
+            .field("enabled", &self.enabled)
+            .field("header", &self.header)
+            .field("max_bytes", &self.max_bytes)
+            .finish()
+    }
+}
+
+impl Default for TapLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TapLayer {
+    /// Create a disabled tap layer which emits records as `tracing` debug events.
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            header: None,
+            max_bytes: DEFAULT_TAP_MAX_BYTES,
+            sink: Arc::new(trace_sink),
+        }
+    }
+
+    /// Tap every request
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Tap requests carrying the header
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    /// Max captured bytes of each body, default is 4096
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Emit records to the callback instead of `tracing`
+    pub fn on_tap(mut self, f: impl Fn(TapRecord) + Send + Sync + 'static) -> Self {
+        self.sink = Arc::new(f);
+        self
+    }
+
+    fn is_active(&self, headers: &HeaderMap) -> bool {
+        self.enabled
+            || self
+                .header
+                .as_ref()
+                .map(|header| headers.contains_key(header))
+                .unwrap_or(false)
+    }
+}
+
+impl<S> Layer<S> for TapLayer {
+    type Service = Tap<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Tap {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Tap<S> {
+    inner: S,
+    layer: TapLayer,
+}
+
+struct Capture {
+    direction: TapDirection,
+    target: String,
+    buf: BytesMut,
+    limit: usize,
+    truncated: bool,
+    sink: TapSink,
+}
+
+impl Capture {
+    fn new(direction: TapDirection, target: String, layer: &TapLayer) -> Self {
+        Self {
+            direction,
+            target,
+            buf: BytesMut::new(),
+            limit: layer.max_bytes,
+            truncated: false,
+            sink: layer.sink.clone(),
+        }
+    }
+
+    fn record(&mut self, data: &[u8]) {
+        let remain = self.limit - self.buf.len();
+        if data.len() > remain {
+            self.truncated = true;
+        }
+        self.buf.extend_from_slice(&data[..data.len().min(remain)]);
+    }
+}
+
+impl Drop for Capture {
+    // emit the record once the body is consumed or discarded
+    fn drop(&mut self) {
+        (self.sink)(TapRecord {
+            direction: self.direction,
+            target: std::mem::take(&mut self.target),
+            body: std::mem::take(&mut self.buf).freeze(),
+            truncated: self.truncated,
+        })
+    }
+}
+
+pin_project! {
+    /// A body passing through the bytes of inner body and capturing them
+    pub struct TapBody<B> {
+        #[pin]
+        inner: B,
+        capture: Option<Capture>,
+    }
+}
+
+impl<B: Body> Body for TapBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let data = ready!(this.inner.poll_data(cx)).map(|data| {
+            data.map(|mut data| {
+                let bytes = data.copy_to_bytes(data.remaining());
+                if let Some(capture) = this.capture.as_mut() {
+                    capture.record(&bytes);
+                }
+                bytes
+            })
+        });
+        Poll::Ready(data)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Tap<S>
+where
+    S: Service<Request<TapBody<ReqBody>>, Response = Response<ResBody>>,
+{
+    type Response = Response<TapBody<ResBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let target = format!("{} {}", req.method(), req.uri());
+        // upgraded connections are not captured
+        let active = self.layer.is_active(req.headers()) && !is_websocket_upgrade(&req);
+        let req = req.map(|inner| TapBody {
+            inner,
+            capture: active
+                .then(|| Capture::new(TapDirection::Request, target.clone(), &self.layer)),
+        });
+        ResponseFuture {
+            fut: self.inner.call(req),
+            capture: active.then(|| Capture::new(TapDirection::Response, target, &self.layer)),
+        }
+    }
+}
+
+pin_project! {
+    pub struct ResponseFuture<F> {
+        #[pin]
+        fut: F,
+        capture: Option<Capture>,
+    }
+}
+
+impl<F, ResBody, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = Result<Response<TapBody<ResBody>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let res = ready!(this.fut.poll(cx))?;
+        let capture = this.capture.take().map(|mut capture| {
+            capture.target = format!("{} {}", res.status(), capture.target);
+            capture
+        });
+        Poll::Ready(Ok(res.map(|inner| TapBody { inner, capture })))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::layer::tap::{TapDirection, TapLayer};
+    use crate::layer::to_bytes;
+    use bytes::Bytes;
+    use http::{Request, Response};
+    use http_body::Full;
+    use std::sync::{Arc, Mutex};
+    use tower::{service_fn, BoxError, Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn test_tap() {
+        let records = Arc::new(Mutex::new(vec![]));
+        let sink = records.clone();
+        let layer = TapLayer::new()
+            .header("x-debug-tap".parse().unwrap())
+            .max_bytes(4)
+            .on_tap(move |record| sink.lock().unwrap().push(record));
+        let svc = layer.layer(service_fn(|req: Request<_>| async move {
+            let body = to_bytes(req.into_body()).await?;
+            Ok::<_, BoxError>(Response::new(Full::new(body)))
+        }));
+
+        let resp = svc
+            .clone()
+            .oneshot(
+                Request::post("/echo")
+                    .header("x-debug-tap", "1")
+                    .body(Full::new(Bytes::from("hello")))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        // the bytes seen by client are not altered
+        assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "hello");
+        {
+            let records = records.lock().unwrap();
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[0].direction, TapDirection::Request);
+            assert_eq!(records[1].direction, TapDirection::Response);
+            assert_eq!(records[1].body, "hell");
+            assert!(records[1].truncated);
+        }
+
+        // default-off
+        let resp = svc
+            .oneshot(
+                Request::post("/echo")
+                    .body(Full::new(Bytes::from("hello")))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(to_bytes(resp.into_body()).await.unwrap(), "hello");
+        assert_eq!(records.lock().unwrap().len(), 2);
+    }
+}
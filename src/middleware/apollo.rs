@@ -1,10 +1,11 @@
 use crate::config::env::{optional, optional_some, require};
+use crate::config::secret::Secret;
 use crate::define_config;
 use crate::middleware::{parse_config_type, Middleware};
 use async_trait::async_trait;
 use kosei::apollo::{ApolloClient, Builder};
 use serde::Serialize;
-use std::convert::Infallible;
+use thiserror::Error;
 
 define_config! {
     #[derive(Serialize, Debug)]
@@ -30,12 +31,23 @@ define_config! {
             optional("APOLLO_CLUSTER_NAME", "default")
         },
         #[default_secret = "default_secret"]
-        pub secret -> Option<String> {
-            optional_some("APOLLO_SECRET")
+        pub secret -> Option<Secret<String>> {
+            optional_some("APOLLO_SECRET").map(Secret::new)
         }
     }
 }
 
+#[derive(Debug, Error)]
+pub enum ApolloError {
+    #[error("invalid apollo address {addr}: {source}")]
+    InvalidAddr {
+        addr: String,
+        source: url::ParseError,
+    },
+    #[error("apollo {0} is empty")]
+    Missing(&'static str),
+}
+
 pub struct Apollo(ApolloConf);
 
 impl Apollo {
@@ -47,17 +59,27 @@ impl Apollo {
 #[async_trait]
 impl Middleware for Apollo {
     type Client = ApolloClient;
-    type Error = Infallible;
+    type Error = ApolloError;
 
     async fn make_client(&self) -> Result<Self::Client, Self::Error> {
         let conf = &self.0;
+        url::Url::parse(&conf.addr).map_err(|source| ApolloError::InvalidAddr {
+            addr: conf.addr.clone(),
+            source,
+        })?;
+        if conf.app_id.is_empty() {
+            return Err(ApolloError::Missing("app id"));
+        }
+        if conf.namespace.is_empty() {
+            return Err(ApolloError::Missing("namespace"));
+        }
         let mut builder = Builder::new()
             .server_url(&conf.addr)
             .app_id(&conf.app_id)
             .cluster(&conf.cluster_name)
             .namespace(&conf.namespace, parse_config_type(&conf.config_type));
-        if let Some(ref secret) = self.0.secret {
-            builder = builder.secret(secret);
+        if let Some(ref secret) = conf.secret {
+            builder = builder.secret(secret.expose_secret());
         }
         Ok(builder.finish())
     }
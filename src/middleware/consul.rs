@@ -1,19 +1,24 @@
 use crate::config::env::{optional, optional_some};
+use crate::config::secret::Secret;
 use crate::define_config;
-use crate::middleware::Middleware;
+use crate::middleware::{unix_socket_path, Middleware};
 use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use consul::kv::KVPair;
 use serde::Serialize;
 
 define_config! {
     #[derive(Serialize, Debug)]
     pub ConsulConf {
+        // `unix:///run/consul.sock` is recognized but not supported by the HTTP client
         #[default_addr = "default_addr"]
         pub addr -> String {
             optional("CONSUL_HTTP_ADDR", "http://127.0.0.1:8500")
         },
         #[default_token = "default_token"]
-        pub token -> Option<String> {
-            optional_some("CONSUL_HTTP_TOKEN")
+        pub token -> Option<Secret<String>> {
+            optional_some("CONSUL_HTTP_TOKEN").map(Secret::new)
         }
     }
 }
@@ -33,7 +38,28 @@ impl Middleware for Consul {
     type Error = consul::errors::Error;
 
     async fn make_client(&self) -> Result<Self::Client, Self::Error> {
-        let conf = consul::Config::new_from_addr(&self.0.addr, self.0.token.clone())?;
+        if let Some(path) = unix_socket_path(&self.0.addr) {
+            // the reqwest based client of consul only speaks TCP
+            return Err(format!(
+                "consul client cannot connect to unix socket {}, use the HTTP address of agent",
+                path
+            )
+            .into());
+        }
+        let conf = consul::Config::new_from_addr(
+            &self.0.addr,
+            self.0
+                .token
+                .as_ref()
+                .map(|token| token.expose_secret().clone()),
+        )?;
         Ok(consul::Client::new(conf))
     }
 }
+
+/// The value of a KV pair, consul responds it in base64
+pub(crate) fn kv_value(pair: &KVPair) -> Vec<u8> {
+    STANDARD
+        .decode(&pair.Value)
+        .unwrap_or_else(|_| pair.Value.clone().into_bytes())
+}
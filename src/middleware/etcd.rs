@@ -1,15 +1,17 @@
-use crate::config::env::optional;
+use crate::config::env::{optional, optional_json, optional_some};
+use crate::config::secret::Secret;
 use crate::define_config;
-use crate::middleware::Middleware;
+use crate::middleware::{connect_timeout, HealthCheck, Middleware};
 use async_trait::async_trait;
 use etcd_client::ConnectOptions;
 use serde::Serialize;
 use std::ops::Deref;
+use std::time::Duration;
 
 define_config! {
     #[derive(Serialize, Debug)]
     pub EtcdConf (
-        pub user: Option<(String, String)>,
+        pub user: Option<(String, Secret<String>)>,
     ) {
         #[default_endpoints = "default_endpoints"]
         pub endpoints -> Vec<String> {
@@ -21,12 +23,45 @@ define_config! {
         #[default_keep_alive_while_idle = "default_keep_alive_while_idle"]
         pub keep_alive_while_idle -> bool {
             true
+        },
+        // seconds
+        #[default_connect_timeout = "default_connect_timeout"]
+        pub connect_timeout -> u64 {
+            optional_json("ETCD_CONNECT_TIMEOUT", 10)
+        },
+        // prepended to the keys of registration, discovery and election, so that
+        // environments could share one etcd cluster, e.p. `staging/`
+        #[default_key_prefix = "default_key_prefix"]
+        pub key_prefix -> Option<String> {
+            optional_some("ETCD_KEY_PREFIX")
         }
     }
 }
 
+impl EtcdConf {
+    /// The key in etcd of a logical key
+    pub fn prefixed(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix.as_deref().unwrap_or_default(), key)
+    }
+
+    /// The logical key of a key in etcd
+    pub fn strip_prefix<'a>(&self, key: &'a str) -> &'a str {
+        key.strip_prefix(self.key_prefix.as_deref().unwrap_or_default())
+            .unwrap_or(key)
+    }
+}
+
 pub struct Etcd(EtcdConf);
 
+#[async_trait]
+impl HealthCheck for etcd_client::Client {
+    type Error = etcd_client::Error;
+
+    async fn ping(&self) -> Result<(), Self::Error> {
+        self.maintenance_client().status().await.map(|_| ())
+    }
+}
+
 impl Etcd {
     pub fn new(conf: EtcdConf) -> Self {
         Self(conf)
@@ -42,10 +77,37 @@ impl Middleware for Etcd {
         let options = match self.0.user.as_ref() {
             None => ConnectOptions::new().with_keep_alive_while_idle(self.0.keep_alive_while_idle),
             Some((name, password)) => ConnectOptions::new()
-                .with_user(name, password)
+                .with_user(name, password.expose_secret())
                 .with_keep_alive_while_idle(self.0.keep_alive_while_idle),
         };
 
-        etcd_client::Client::connect(self.0.endpoints.deref(), Some(options)).await
+        connect_timeout(
+            "etcd",
+            Duration::from_secs(self.0.connect_timeout),
+            etcd_client::Client::connect(self.0.endpoints.deref(), Some(options)),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::etcd::EtcdConf;
+
+    #[test]
+    fn test_key_prefix() {
+        let conf = EtcdConf {
+            key_prefix: Some("staging/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(conf.prefixed("sys-grpc:user"), "staging/sys-grpc:user");
+        assert_eq!(conf.strip_prefix("staging/sys-grpc:user"), "sys-grpc:user");
+
+        let conf = EtcdConf {
+            key_prefix: None,
+            ..Default::default()
+        };
+        assert_eq!(conf.prefixed("sys-grpc:user"), "sys-grpc:user");
+        assert_eq!(conf.strip_prefix("sys-grpc:user"), "sys-grpc:user");
     }
 }
@@ -1,12 +1,25 @@
 use async_trait::async_trait;
 use kosei::ConfigType;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use thiserror::Error;
 
 pub mod apollo;
 pub mod consul;
+pub mod dsn;
 pub mod etcd;
 pub mod nacos;
+pub mod pool;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod rabbitmq;
 pub mod redis;
+pub mod refresh;
+pub mod retryable;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod validate;
 
 /// TODO: better design
 #[async_trait]
@@ -17,6 +30,99 @@ pub trait Middleware {
     async fn make_client(&self) -> Result<Self::Client, Self::Error>;
 }
 
+/// A cheap round trip to a middleware client, used as a readiness sub-check,
+/// see [Readiness::ping]
+///
+/// [Readiness::ping]: crate::infra::Readiness::ping
+#[async_trait]
+pub trait HealthCheck {
+    type Error: Display;
+
+    async fn ping(&self) -> Result<(), Self::Error>;
+}
+
+/// Establishing the connection of middleware took longer than its `connect_timeout`.
+/// Only middlewares connecting in `make_client` (etcd, rabbitmq, postgres) have it,
+/// others (redis, consul, etc.) connect lazily.
+#[derive(Debug, Error)]
+#[error("connecting to {middleware} timed out after {timeout:?}")]
+pub struct ConnectTimeout {
+    pub middleware: &'static str,
+    pub timeout: Duration,
+}
+
+impl From<ConnectTimeout> for etcd_client::Error {
+    fn from(err: ConnectTimeout) -> Self {
+        etcd_client::Error::IoError(std::io::Error::new(std::io::ErrorKind::TimedOut, err))
+    }
+}
+
+/// Fail the connection with [ConnectTimeout] if it is not established within timeout
+async fn connect_timeout<T, E: From<ConnectTimeout>>(
+    middleware: &'static str,
+    timeout: Duration,
+    connect: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    tokio::time::timeout(timeout, connect)
+        .await
+        .map_err(|_| ConnectTimeout {
+            middleware,
+            timeout,
+        })?
+}
+
+/// The socket of a `unix://` (or `redis+unix://`) address is missing or not a socket
+#[derive(Debug, Error)]
+#[error("unix socket {path} of {middleware} is not accessible: {reason}")]
+pub struct UnixSocketError {
+    pub middleware: &'static str,
+    pub path: String,
+    pub reason: String,
+}
+
+impl From<UnixSocketError> for ::redis::RedisError {
+    fn from(err: UnixSocketError) -> Self {
+        ::redis::RedisError::from(std::io::Error::new(std::io::ErrorKind::NotFound, err))
+    }
+}
+
+/// The socket path of a unix domain socket address, e.p. `unix:///run/consul.sock`,
+/// None for the other schemes which are connected with TCP.
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    let (scheme, rest) = addr.split_once("://")?;
+    if !matches!(scheme, "unix" | "redis+unix") {
+        return None;
+    }
+    // drop the query, e.p. `?db=1` of redis
+    Some(rest.split('?').next().unwrap_or(rest))
+}
+
+/// Check the socket exists and is accessible before connecting, so that a missing
+/// sidecar is reported clearly instead of an obscure connection error.
+fn check_unix_socket(middleware: &'static str, path: &str) -> Result<(), UnixSocketError> {
+    let err = |reason: String| UnixSocketError {
+        middleware,
+        path: path.to_string(),
+        reason,
+    };
+    let metadata = std::fs::metadata(path).map_err(|e| err(e.to_string()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if !metadata.file_type().is_socket() {
+            return Err(err("not a socket".to_string()));
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        return Err(err(
+            "unix sockets are not supported on this platform".to_string()
+        ));
+    }
+    Ok(())
+}
+
 #[inline]
 fn parse_config_type(typ: &str) -> ConfigType {
     match &*typ.to_lowercase() {
@@ -25,3 +131,30 @@ fn parse_config_type(typ: &str) -> ConfigType {
         _ => ConfigType::YAML,
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::middleware::{check_unix_socket, unix_socket_path};
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_unix_socket() {
+        assert_eq!(
+            unix_socket_path("redis+unix:///run/redis.sock?db=1"),
+            Some("/run/redis.sock")
+        );
+        assert_eq!(
+            unix_socket_path("unix:///run/consul.sock"),
+            Some("/run/consul.sock")
+        );
+        assert_eq!(unix_socket_path("http://127.0.0.1:8500"), None);
+
+        let path = std::env::temp_dir().join(format!("common-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let err = check_unix_socket("redis", path).unwrap_err();
+        assert!(err.to_string().starts_with("unix socket"));
+        let _listener = UnixListener::bind(path).unwrap();
+        assert!(check_unix_socket("redis", path).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,10 +1,18 @@
 use crate::config::env::{optional, optional_some, require};
+use crate::config::secret::Secret;
 use crate::define_config;
 use crate::middleware::{parse_config_type, Middleware};
+use crate::utils::deserialize::format_of;
+use crate::utils::ConfigFormat;
 use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, KeyInit, Mac};
 use kosei::nacos::{Builder, NacosClient};
-use serde::Serialize;
-use std::convert::Infallible;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 
 define_config! {
     #[derive(Serialize, Debug)]
@@ -25,19 +33,98 @@ define_config! {
         pub config_type -> String {
             optional("NACOS_CONFIG_TYPE", "yaml")
         },
+        // like '[username]:[password]', the password may contain colons
         #[default_credential = "default_credential"]
-        pub credential -> Option<[String; 2]> {
-            optional_some("NACOS_CREDENTIAL").map(|v| {
-                v.split(':')
-                    .map(ToOwned::to_owned)
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .expect("environment 'NACOS_CREDENTIAL' must be like '[username]:[password]'")
-            })
+        pub credential -> Option<Secret<NacosCredential>> {
+            optional_some("NACOS_CREDENTIAL").map(|v| Secret::new(NacosCredential::Joined(v)))
+        },
+        // the access key auth of some deployments, e.p. aliyun MSE
+        #[default_access_key = "default_access_key"]
+        pub access_key -> Option<String> {
+            optional_some("NACOS_ACCESS_KEY")
+        },
+        #[default_secret_key = "default_secret_key"]
+        pub secret_key -> Option<Secret<String>> {
+            optional_some("NACOS_SECRET_KEY").map(Secret::new)
         }
     }
 }
 
+/// The username and password of nacos, either `[username, password]` or
+/// `[username]:[password]` in configuration files
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum NacosCredential {
+    Pair([String; 2]),
+    /// Split on the first colon, the password may contain colons
+    Joined(String),
+}
+
+/// How the client authenticates with nacos
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NacosAuth {
+    Password {
+        username: String,
+        password: Secret<String>,
+    },
+    /// Requests are signed with the secret key, e.p. by aliyun MSE
+    AccessKey {
+        access_key: String,
+        secret_key: Secret<String>,
+    },
+}
+
+impl NacosConf {
+    /// The auth mode configured, None for an anonymous client
+    pub fn auth(&self) -> Result<Option<NacosAuth>, NacosError> {
+        let invalid = |reason: &str| Err(NacosError::InvalidCredential(reason.to_string()));
+        match (&self.credential, &self.access_key, &self.secret_key) {
+            (Some(_), Some(_), _) => invalid("both credential and access key are set"),
+            (Some(credential), None, _) => {
+                let pair = match credential.expose_secret() {
+                    NacosCredential::Pair([username, password]) => {
+                        Some((username.as_str(), password.as_str()))
+                    }
+                    NacosCredential::Joined(credential) => credential.split_once(':'),
+                };
+                match pair {
+                    Some((username, password)) if !username.is_empty() => {
+                        Ok(Some(NacosAuth::Password {
+                            username: username.to_string(),
+                            password: Secret::new(password.to_string()),
+                        }))
+                    }
+                    _ => invalid("credential must be like '[username]:[password]'"),
+                }
+            }
+            (None, Some(access_key), Some(secret_key)) => Ok(Some(NacosAuth::AccessKey {
+                access_key: access_key.clone(),
+                secret_key: secret_key.clone(),
+            })),
+            (None, Some(_), None) => invalid("access key is set without secret key"),
+            (None, None, Some(_)) => invalid("secret key is set without access key"),
+            (None, None, None) => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum NacosError {
+    #[error("invalid nacos address {addr}: {source}")]
+    InvalidAddr {
+        addr: String,
+        source: url::ParseError,
+    },
+    #[error("nacos {0} is empty")]
+    Missing(&'static str),
+    #[error("invalid nacos credential: {0}")]
+    InvalidCredential(String),
+    #[error("cannot fetch nacos configuration: {0}")]
+    Fetch(#[from] reqwest::Error),
+    #[error("cannot parse nacos configuration: {0}")]
+    Parse(String),
+}
+
 pub struct Nacos(NacosConf);
 
 impl Nacos {
@@ -46,20 +133,204 @@ impl Nacos {
     }
 }
 
+/// The client made by [Nacos::make_client]
+pub enum NacosConfigClient {
+    /// The client of kosei, anonymous or authenticated with username and password
+    Kosei(NacosClient),
+    /// The client of kosei cannot sign requests with the access key
+    AccessKey(AccessKeyClient),
+}
+
+/// Fetch the configuration with the open API of nacos, every request is signed
+/// with the secret key the same as the official clients
+pub struct AccessKeyClient {
+    http: reqwest::Client,
+    conf: NacosConf,
+    access_key: String,
+    secret_key: Secret<String>,
+}
+
+impl AccessKeyClient {
+    /// Fetch and parse the configuration by `config_type`
+    pub async fn fetch(&self) -> Result<serde_json::Value, NacosError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string();
+        let signature = sign(
+            self.secret_key.expose_secret(),
+            &self.conf.group,
+            &timestamp,
+        );
+        let content = self
+            .http
+            .get(format!(
+                "{}/nacos/v1/cs/configs",
+                self.conf.addr.trim_end_matches('/')
+            ))
+            .query(&[
+                ("dataId", self.conf.data_id.as_str()),
+                ("group", self.conf.group.as_str()),
+            ])
+            .header("Spas-AccessKey", &self.access_key)
+            .header("Timestamp", &timestamp)
+            .header("Spas-Signature", signature)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let parsed = match format_of(&self.conf.config_type) {
+            ConfigFormat::JSON => serde_json::from_str(&content).map_err(|e| e.to_string()),
+            ConfigFormat::YAML => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+            ConfigFormat::TOML => toml::from_str(&content).map_err(|e| e.to_string()),
+        };
+        parsed.map_err(NacosError::Parse)
+    }
+}
+
+/// The signature of a configuration request, base64 of the HMAC-SHA1 of `[group]+[timestamp]`
+fn sign(secret_key: &str, group: &str, timestamp: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret_key.as_bytes()).expect("any key length");
+    mac.update(format!("{}+{}", group, timestamp).as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
 #[async_trait]
 impl Middleware for Nacos {
-    type Client = NacosClient;
-    type Error = Infallible;
+    type Client = NacosConfigClient;
+    type Error = NacosError;
 
     async fn make_client(&self) -> Result<Self::Client, Self::Error> {
+        let conf = &self.0;
+        url::Url::parse(&conf.addr).map_err(|source| NacosError::InvalidAddr {
+            addr: conf.addr.clone(),
+            source,
+        })?;
+        if conf.data_id.is_empty() {
+            return Err(NacosError::Missing("data id"));
+        }
         let mut builder = Builder::new()
-            .server_url(self.0.addr.as_str())
-            .data_id(self.0.data_id.as_str())
-            .group(self.0.group.as_str())
-            .config_type(parse_config_type(self.0.config_type.as_str()));
-        if let Some(ref credential) = self.0.credential {
-            builder = builder.credential(&credential[0], &credential[1]);
+            .server_url(conf.addr.as_str())
+            .data_id(conf.data_id.as_str())
+            .group(conf.group.as_str())
+            .config_type(parse_config_type(conf.config_type.as_str()));
+        match conf.auth()? {
+            Some(NacosAuth::Password { username, password }) => {
+                builder = builder.credential(&username, password.expose_secret());
+            }
+            Some(NacosAuth::AccessKey {
+                access_key,
+                secret_key,
+            }) => {
+                return Ok(NacosConfigClient::AccessKey(AccessKeyClient {
+                    http: reqwest::Client::new(),
+                    conf: conf.clone(),
+                    access_key,
+                    secret_key,
+                }))
+            }
+            None => {}
         }
-        Ok(builder.finish())
+        Ok(NacosConfigClient::Kosei(builder.finish()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::secret::Secret;
+    use crate::middleware::nacos::{
+        sign, Nacos, NacosAuth, NacosConf, NacosConfigClient, NacosCredential, NacosError,
+    };
+    use crate::middleware::Middleware;
+
+    #[tokio::test]
+    async fn test_invalid_conf() {
+        let conf = NacosConf {
+            addr: "not a url".to_string(),
+            data_id: "user".to_string(),
+            group: "DEFAULT_GROUP".to_string(),
+            config_type: "yaml".to_string(),
+            credential: None,
+            access_key: None,
+            secret_key: None,
+        };
+        let client = Nacos::new(conf.clone()).make_client().await;
+        assert!(matches!(client, Err(NacosError::InvalidAddr { .. })));
+
+        let conf = NacosConf {
+            addr: "http://127.0.0.1:8848".to_string(),
+            data_id: String::new(),
+            ..conf
+        };
+        let client = Nacos::new(conf.clone()).make_client().await;
+        assert!(matches!(client, Err(NacosError::Missing("data id"))));
+
+        let with_credential = |credential: &str| NacosConf {
+            credential: Some(Secret::new(NacosCredential::Joined(credential.to_string()))),
+            ..conf.clone()
+        };
+        assert_eq!(
+            with_credential("nacos:pass:word").auth().unwrap(),
+            Some(NacosAuth::Password {
+                username: "nacos".to_string(),
+                password: Secret::new("pass:word".to_string()),
+            })
+        );
+        assert!(matches!(
+            with_credential("nacos").auth(),
+            Err(NacosError::InvalidCredential(_))
+        ));
+        let conf = NacosConf {
+            access_key: Some("ak".to_string()),
+            ..conf
+        };
+        assert!(matches!(conf.auth(), Err(NacosError::InvalidCredential(_))));
+        let conf = NacosConf {
+            secret_key: Some(Secret::new("sk".to_string())),
+            ..conf
+        };
+        assert!(matches!(conf.auth(), Ok(Some(NacosAuth::AccessKey { .. }))));
+        let client = Nacos::new(conf).make_client().await;
+        assert!(matches!(client, Ok(NacosConfigClient::AccessKey(_))));
+    }
+
+    #[test]
+    fn test_credential() {
+        let password = |credential: serde_json::Value| {
+            let credential = serde_json::from_value(credential).unwrap();
+            let conf = NacosConf {
+                addr: "http://127.0.0.1:8848".to_string(),
+                data_id: "user".to_string(),
+                group: "DEFAULT_GROUP".to_string(),
+                config_type: "yaml".to_string(),
+                credential: Some(credential),
+                access_key: None,
+                secret_key: None,
+            };
+            conf.auth()
+        };
+        let expected = Some(NacosAuth::Password {
+            username: "nacos".to_string(),
+            password: Secret::new("pass:word".to_string()),
+        });
+        assert_eq!(
+            password(serde_json::json!(["nacos", "pass:word"])).unwrap(),
+            expected
+        );
+        assert_eq!(
+            password(serde_json::json!("nacos:pass:word")).unwrap(),
+            expected
+        );
+        assert!(password(serde_json::json!(["", "password"])).is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("sk", "DEFAULT_GROUP", "1700000000000"),
+            "9hbrlmLnPXkuBl5HFQwTAQFJlLo="
+        );
     }
 }
@@ -0,0 +1,213 @@
+/// Connection statistics of a middleware pool for capacity planning: active and
+/// idle connections, the depth of the wait queue and the acquire latency. The
+/// counters are atomics so that reading them is cheap.
+///
+/// None of the middlewares pools connections yet, a pool (e.p. `bb8` or a custom
+/// one) reports to [PoolStats] by wrapping its acquisitions with [PoolStats::acquire]
+/// and updating the idle count.
+///
+/// ```rust,ignore
+/// let stats = Arc::new(PoolStats::new());
+/// let (conn, _active) = stats.acquire(pool.get()).await?;
+/// stats.set_idle(pool.state().idle_connections as usize);
+///
+/// // append to the `/metrics` response
+/// let mut out = registry_metrics().render();
+/// out.push_str(&render_pool_stats(&[("redis", stats.snapshot())]));
+/// ```
+use crate::registry::metrics::escape;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    active: AtomicUsize,
+    idle: AtomicUsize,
+    waiting: AtomicUsize,
+    acquired: AtomicU64,
+    // the sum of acquire latency in microseconds
+    acquire_micros: AtomicU64,
+}
+
+/// The statistics at a moment
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolSnapshot {
+    pub active: usize,
+    pub idle: usize,
+    pub waiting: usize,
+    /// The number of successful acquisitions
+    pub acquired: u64,
+    /// The sum of acquire latency of successful acquisitions
+    pub acquire_latency: Duration,
+}
+
+impl PoolSnapshot {
+    pub fn mean_acquire_latency(&self) -> Duration {
+        match self.acquired {
+            0 => Duration::ZERO,
+            // the count may exceed u32
+            acquired => {
+                let nanos = self.acquire_latency.as_nanos() / acquired as u128;
+                Duration::from_nanos(nanos as u64)
+            }
+        }
+    }
+}
+
+/// An acquisition waiting for a connection, it leaves the queue once dropped,
+/// including when the acquiring future is cancelled
+struct Waiting<'a>(&'a PoolStats);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An acquired connection in use, it is released once dropped
+#[derive(Debug)]
+pub struct Active(Arc<PoolStats>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl PoolStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the acquisition as waiting until it resolves, the connection is
+    /// counted as active until the returned guard is dropped.
+    pub async fn acquire<T, E>(
+        self: &Arc<Self>,
+        acquire: impl Future<Output = Result<T, E>>,
+    ) -> Result<(T, Active), E> {
+        let start = Instant::now();
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let waiting = Waiting(self);
+        let res = acquire.await;
+        drop(waiting);
+        let conn = res?;
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.acquire_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Ok((conn, Active(self.clone())))
+    }
+
+    /// The number of idle connections kept by the pool
+    pub fn set_idle(&self, idle: usize) {
+        self.idle.store(idle, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            active: self.active.load(Ordering::Relaxed),
+            idle: self.idle.load(Ordering::Relaxed),
+            waiting: self.waiting.load(Ordering::Relaxed),
+            acquired: self.acquired.load(Ordering::Relaxed),
+            acquire_latency: Duration::from_micros(self.acquire_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Render the snapshots of pools in Prometheus text format, labeled by the pool name
+pub fn render_pool_stats(pools: &[(&str, PoolSnapshot)]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, typ: &str, help: &str, value: fn(&PoolSnapshot) -> f64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, typ);
+        for (pool, snapshot) in pools {
+            let _ = writeln!(
+                out,
+                "{}{{pool=\"{}\"}} {}",
+                name,
+                escape(pool),
+                value(snapshot)
+            );
+        }
+    };
+    metric(
+        "pool_connections_active",
+        "gauge",
+        "Connections in use.",
+        |s| s.active as f64,
+    );
+    metric("pool_connections_idle", "gauge", "Idle connections.", |s| {
+        s.idle as f64
+    });
+    metric(
+        "pool_wait_queue_depth",
+        "gauge",
+        "Acquisitions waiting for a connection.",
+        |s| s.waiting as f64,
+    );
+    metric(
+        "pool_acquire_total",
+        "counter",
+        "Successful acquisitions.",
+        |s| s.acquired as f64,
+    );
+    metric(
+        "pool_acquire_seconds_sum",
+        "counter",
+        "Total time spent acquiring connections.",
+        |s| s.acquire_latency.as_secs_f64(),
+    );
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::pool::{render_pool_stats, PoolSnapshot, PoolStats};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_pool_stats() {
+        let stats = Arc::new(PoolStats::new());
+        let (_conn, active) = stats.acquire(async { Ok::<_, ()>("conn") }).await.unwrap();
+        assert!(stats
+            .acquire(async { Err::<(), _>("exhausted") })
+            .await
+            .is_err());
+        stats.set_idle(4);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            (
+                snapshot.active,
+                snapshot.idle,
+                snapshot.waiting,
+                snapshot.acquired
+            ),
+            (1, 4, 0, 1)
+        );
+        drop(active);
+        assert_eq!(stats.snapshot().active, 0);
+
+        let rendered = render_pool_stats(&[("redis", stats.snapshot())]);
+        assert!(rendered.contains("pool_connections_idle{pool=\"redis\"} 4\n"));
+        assert!(rendered.contains("# TYPE pool_acquire_total counter\n"));
+
+        // the cancelled acquisition leaves the queue
+        let pending = stats.acquire(futures::future::pending::<Result<(), ()>>());
+        assert!(tokio::time::timeout(Duration::from_millis(10), pending)
+            .await
+            .is_err());
+        assert_eq!(stats.snapshot().waiting, 0);
+
+        let snapshot = PoolSnapshot {
+            acquired: 1 << 32,
+            acquire_latency: Duration::from_secs(1 << 32),
+            ..Default::default()
+        };
+        assert_eq!(snapshot.mean_acquire_latency(), Duration::from_secs(1));
+    }
+}
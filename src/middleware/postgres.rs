@@ -0,0 +1,90 @@
+use crate::config::env::{optional, optional_json};
+use crate::define_config;
+use crate::middleware::Middleware;
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_postgres::{AsyncMessage, NoTls, Notification};
+use tracing::{warn, Instrument};
+
+define_config! {
+    #[derive(Serialize, Debug)]
+    pub PostgresConf {
+        #[default_dsn = "default_dsn"]
+        pub dsn -> String {
+            optional("POSTGRES_DSN", "postgres://postgres@127.0.0.1/postgres")
+        },
+        // seconds
+        #[default_connect_timeout = "default_connect_timeout"]
+        pub connect_timeout -> u64 {
+            optional_json("POSTGRES_CONNECT_TIMEOUT", 10)
+        }
+    }
+}
+
+pub struct Postgres(PostgresConf);
+
+impl Postgres {
+    pub fn new(conf: PostgresConf) -> Self {
+        Self(conf)
+    }
+
+    fn config(&self) -> Result<tokio_postgres::Config, tokio_postgres::Error> {
+        let mut config: tokio_postgres::Config = self.0.dsn.parse()?;
+        // the error of tokio_postgres cannot be constructed, use its own timeout
+        config.connect_timeout(Duration::from_secs(self.0.connect_timeout));
+        Ok(config)
+    }
+
+    /// Same as [Middleware::make_client], and the notifications received by the
+    /// connection (e.p. of `LISTEN`) are forwarded to the receiver, which ends once
+    /// the connection is closed.
+    pub async fn make_listener(
+        &self,
+    ) -> Result<(tokio_postgres::Client, UnboundedReceiver<Notification>), tokio_postgres::Error>
+    {
+        let (client, mut connection) = self.config()?.connect(NoTls).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let task = async move {
+            let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        if tx.send(notification).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("postgres connection closed cause err: {}", err);
+                        return;
+                    }
+                }
+            }
+        }
+        .in_current_span();
+        tokio::spawn(task);
+        Ok((client, rx))
+    }
+}
+
+#[async_trait]
+impl Middleware for Postgres {
+    type Client = tokio_postgres::Client;
+    type Error = tokio_postgres::Error;
+
+    /// The connection is driven in background until the client is dropped
+    async fn make_client(&self) -> Result<Self::Client, Self::Error> {
+        let (client, connection) = self.config()?.connect(NoTls).await?;
+        let task = async move {
+            if let Err(err) = connection.await {
+                warn!("postgres connection closed cause err: {}", err);
+            }
+        }
+        .in_current_span();
+        tokio::spawn(task);
+        Ok(client)
+    }
+}
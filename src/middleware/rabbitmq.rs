@@ -1,9 +1,12 @@
-use crate::config::env::optional;
+use crate::config::env::{optional, optional_json};
 use crate::define_config;
-use crate::middleware::Middleware;
+use crate::middleware::dsn::{Dsn, DsnError};
+use crate::middleware::{connect_timeout, ConnectTimeout, Middleware};
 use amqprs::connection::OpenConnectionArguments;
 use async_trait::async_trait;
 use serde::Serialize;
+use std::time::Duration;
+use thiserror::Error;
 
 define_config! {
     #[derive(Serialize, Debug)]
@@ -19,10 +22,40 @@ define_config! {
         #[default_heartbeat = "default_heartbeat"]
         pub heartbeat -> u16 {
             60
+        },
+        // seconds
+        #[default_connect_timeout = "default_connect_timeout"]
+        pub connect_timeout -> u64 {
+            optional_json("RABBITMQ_CONNECT_TIMEOUT", 10)
         }
     }
 }
 
+impl RabbitMQConf {
+    /// The endpoint with the virtual host, `amqp://` is prepended if the endpoint has
+    /// no scheme, e.p. `guest:guest@localhost:5672`.
+    pub fn to_dsn(&self) -> Result<Dsn, DsnError> {
+        let dsn = if self.enpoint.contains("://") {
+            Dsn::parse(&self.enpoint)?
+        } else {
+            Dsn::parse(&format!("amqp://{}", self.enpoint))?
+        };
+        Ok(dsn
+            .path(&self.virtual_host)
+            .query("heartbeat", self.heartbeat.to_string()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RabbitMQError {
+    #[error("invalid rabbitmq endpoint: {0}")]
+    InvalidEndpoint(#[from] DsnError),
+    #[error("cannot connect to rabbitmq: {0}")]
+    Connect(#[from] amqprs::error::Error),
+    #[error(transparent)]
+    Timeout(#[from] ConnectTimeout),
+}
+
 pub struct RabbitMQ(RabbitMQConf);
 
 impl RabbitMQ {
@@ -34,20 +67,30 @@ impl RabbitMQ {
 #[async_trait]
 impl Middleware for RabbitMQ {
     type Client = amqprs::connection::Connection;
-    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Error = RabbitMQError;
 
     async fn make_client(&self) -> Result<Self::Client, Self::Error> {
-        let url = url::Url::parse(&self.0.enpoint)?;
+        // user and password are percent-decoded
+        let dsn = self.0.to_dsn()?;
         let arg = OpenConnectionArguments::new(
-            url.host_str().unwrap_or("localhost"),
-            url.port().unwrap_or(5672),
-            url.username(),
-            url.password().unwrap_or(""),
+            dsn.host_str(),
+            dsn.port_u16().unwrap_or(5672),
+            dsn.user_str().unwrap_or_default(),
+            dsn.password_str().unwrap_or_default(),
         )
         .virtual_host(&self.0.virtual_host)
         .heartbeat(self.0.heartbeat)
         .finish();
-        let conn = amqprs::connection::Connection::open(&arg).await?;
+        let conn = connect_timeout(
+            "rabbitmq",
+            Duration::from_secs(self.0.connect_timeout),
+            async {
+                amqprs::connection::Connection::open(&arg)
+                    .await
+                    .map_err(RabbitMQError::Connect)
+            },
+        )
+        .await?;
         Ok(conn)
     }
 }
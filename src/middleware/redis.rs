@@ -1,19 +1,66 @@
-use crate::config::env::optional;
+use crate::config::env::{optional, optional_some};
+use crate::config::secret::Secret;
 use crate::define_config;
-use crate::middleware::Middleware;
+use crate::layer::{EventCodec, JsonEventCodec};
+use crate::middleware::dsn::{Dsn, DsnError};
+use crate::middleware::{check_unix_socket, unix_socket_path, HealthCheck, Middleware};
 use async_trait::async_trait;
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::{FromRedisValue, IntoConnectionInfo, RedisError, RedisResult, ToRedisArgs};
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::future::Future;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tracing::warn;
 
 define_config! {
     #[derive(Serialize, Debug)]
     pub RedisConf {
+        // `unix:///run/redis.sock` or `redis+unix://` for a unix domain socket
         #[default_dsn = "default_dsn"]
         pub dsn -> String {
             optional("REDIS_ENDPOINT", "redis://127.0.0.1/")
+        },
+        #[default_password = "default_password"]
+        pub password -> Option<Secret<String>> {
+            optional_some("REDIS_PASSWORD").map(Secret::new)
+        },
+        // takes precedence over the db in dsn
+        #[default_db = "default_db"]
+        pub db -> Option<u8> {
+            optional_some("REDIS_DB").map(|db| {
+                db.parse()
+                    .expect("environment 'REDIS_DB' must be a database index")
+            })
+        },
+        #[default_key_prefix = "default_key_prefix"]
+        pub key_prefix -> Option<String> {
+            optional_some("REDIS_KEY_PREFIX")
         }
     }
 }
 
+impl RedisConf {
+    /// The dsn with the password configured separately, e.p. log it redacted with
+    /// `info!("connecting to {}", conf.to_dsn()?)`
+    pub fn to_dsn(&self) -> Result<Dsn, DsnError> {
+        let mut dsn = Dsn::parse(&self.dsn)?;
+        if let Some(ref password) = self.password {
+            dsn = dsn.password(password.expose_secret());
+        }
+        if let Some(db) = self.db {
+            dsn = dsn.db(db as i64);
+        }
+        Ok(dsn)
+    }
+
+    /// Wrap the connection to prefix keys with `key_prefix`
+    pub fn namespaced<C>(&self, conn: C) -> Namespaced<C> {
+        Namespaced::new(conn, self.key_prefix.clone().unwrap_or_default())
+    }
+}
+
 pub struct Redis(RedisConf);
 
 impl Redis {
@@ -28,6 +75,492 @@ impl Middleware for Redis {
     type Error = redis::RedisError;
 
     async fn make_client(&self) -> Result<Self::Client, Self::Error> {
-        redis::Client::open(&*self.0.dsn)
+        if let Some(path) = unix_socket_path(&self.0.dsn) {
+            check_unix_socket("redis", path)?;
+        }
+        let mut info = self.0.dsn.as_str().into_connection_info()?;
+        // password configured separately takes precedence over the one in dsn
+        if let Some(ref password) = self.0.password {
+            info.redis.password = Some(password.expose_secret().clone());
+        }
+        if let Some(db) = self.0.db {
+            info.redis.db = db as i64;
+        }
+        redis::Client::open(info)
+    }
+}
+
+#[async_trait]
+impl HealthCheck for redis::Client {
+    type Error = RedisError;
+
+    async fn ping(&self) -> Result<(), Self::Error> {
+        let mut conn = self.get_async_connection().await?;
+        redis::cmd("PING").query_async(&mut conn).await
+    }
+}
+
+/// A connection prefixing keys with a namespace, so that services sharing a
+/// redis server will not collide, e.p. `user:` + `1:name` => `user:1:name`
+#[derive(Clone)]
+pub struct Namespaced<C> {
+    conn: C,
+    prefix: String,
+}
+
+impl<C> Namespaced<C> {
+    pub fn new(conn: C, prefix: impl ToString) -> Self {
+        Self {
+            conn,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// The prefixed key
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// The underlying connection, keys are not prefixed
+    pub fn inner(&mut self) -> &mut C {
+        &mut self.conn
+    }
+}
+
+impl<C: ConnectionLike + Send> Namespaced<C> {
+    pub async fn get<V: FromRedisValue>(&mut self, key: &str) -> RedisResult<V> {
+        redis::cmd("GET")
+            .arg(self.key(key))
+            .query_async(&mut self.conn)
+            .await
+    }
+
+    pub async fn set<V: ToRedisArgs>(&mut self, key: &str, value: V) -> RedisResult<()> {
+        redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(value)
+            .query_async(&mut self.conn)
+            .await
+    }
+
+    pub async fn set_ex<V: ToRedisArgs>(
+        &mut self,
+        key: &str,
+        value: V,
+        ttl: Duration,
+    ) -> RedisResult<()> {
+        redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(value)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut self.conn)
+            .await
+    }
+
+    pub async fn del(&mut self, key: &str) -> RedisResult<()> {
+        redis::cmd("DEL")
+            .arg(self.key(key))
+            .query_async(&mut self.conn)
+            .await
+    }
+}
+
+const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(5);
+const DEFAULT_LOCK_WAIT: Duration = Duration::from_secs(3);
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// only the lock holder could release it
+const RELEASE_LOCK: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Cache-aside over redis: read the cache, compute and store it on miss.
+///
+/// Only one caller computes a missing key at a time (guarded by a short lock in redis),
+/// others wait for the cached value. If redis is unavailable or does not respond in
+/// the command timeout, the value is computed directly instead of failing the request,
+/// and the connection is re-established by the next call once it is broken.
+///
+/// Values are encoded with an [EventCodec], JSON by default.
+///
+/// ```rust,no_run
+/// use common::middleware::redis::CacheAside;
+/// use std::time::Duration;
+///
+/// # async fn example() -> Result<(), std::io::Error> {
+/// let cache = CacheAside::new(redis::Client::open("redis://127.0.0.1/").unwrap());
+/// let name: String = cache
+///     .get_or_compute("user:1:name", Duration::from_secs(60), || async {
+///         Ok::<_, std::io::Error>("iGxnon".to_string())
+///     })
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CacheAside<C = JsonEventCodec> {
+    client: redis::Client,
+    conn: RwLock<Option<MultiplexedConnection>>,
+    prefix: String,
+    lock_ttl: Duration,
+    lock_wait: Duration,
+    timeout: Duration,
+    codec: C,
+}
+
+impl CacheAside {
+    pub fn new(client: redis::Client) -> Self {
+        Self {
+            client,
+            conn: RwLock::new(None),
+            prefix: "cache:".to_string(),
+            lock_ttl: DEFAULT_LOCK_TTL,
+            lock_wait: DEFAULT_LOCK_WAIT,
+            timeout: DEFAULT_COMMAND_TIMEOUT,
+            codec: JsonEventCodec,
+        }
+    }
+}
+
+impl<C: EventCodec> CacheAside<C> {
+    /// Prefix of cache keys, default is `cache:`
+    pub fn prefix(mut self, prefix: impl ToString) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Expiration of the per-key lock, it should be longer than computing a value.
+    /// Default is 5 seconds.
+    pub fn lock_ttl(mut self, lock_ttl: Duration) -> Self {
+        self.lock_ttl = lock_ttl;
+        self
+    }
+
+    /// How long to wait for the lock holder before computing directly.
+    /// Default is 3 seconds.
+    pub fn lock_wait(mut self, lock_wait: Duration) -> Self {
+        self.lock_wait = lock_wait;
+        self
+    }
+
+    /// The timeout of connecting and each command, a timed out read is a miss.
+    /// Default is 1 second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Replace the serialization format, e.p. `BincodeEventCodec`
+    pub fn codec<C2: EventCodec>(self, codec: C2) -> CacheAside<C2> {
+        CacheAside {
+            client: self.client,
+            conn: self.conn,
+            prefix: self.prefix,
+            lock_ttl: self.lock_ttl,
+            lock_wait: self.lock_wait,
+            timeout: self.timeout,
+            codec,
+        }
+    }
+
+    /// Fail with a timeout error if redis does not respond in time
+    async fn timed<T>(&self, fut: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+        match tokio::time::timeout(self.timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(RedisError::from(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("redis does not respond in {:?}", self.timeout),
+            ))),
+        }
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, RedisError> {
+        if let Some(conn) = self.conn.read().unwrap().clone() {
+            return Ok(conn);
+        }
+        let conn = self
+            .timed(self.client.get_multiplexed_async_connection())
+            .await?;
+        *self.conn.write().unwrap() = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// Drop the connection if it is broken, so that the next call reconnects
+    fn check_connection(&self, err: &RedisError) {
+        if err.is_io_error()
+            || err.is_connection_dropped()
+            || err.is_connection_refusal()
+            || err.is_timeout()
+        {
+            self.conn.write().unwrap().take();
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        conn: &mut MultiplexedConnection,
+        key: &str,
+    ) -> Result<Option<T>, RedisError> {
+        let value: Option<Vec<u8>> = self
+            .timed(redis::cmd("GET").arg(key).query_async(conn))
+            .await?;
+        Ok(
+            value.and_then(|value| match self.codec.decode_value(&value) {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    // treat as a miss, the value will be overwritten
+                    warn!("cannot decode cached value of {}, err: {}", key, err);
+                    None
+                }
+            }),
+        )
+    }
+
+    /// Read the value of key from cache, or compute and cache it with ttl.
+    /// Errors of `compute` are returned as is and never cached.
+    pub async fn get_or_compute<T, E, F, Fut>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        compute: F,
+    ) -> Result<T, E>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let key = format!("{}{}", self.prefix, key);
+        let mut conn = match self.connection().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(
+                    "redis is unavailable, compute {} directly, err: {}",
+                    key, err
+                );
+                return compute().await;
+            }
+        };
+        match self.get(&mut conn, &key).await {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => {}
+            Err(err) => {
+                self.check_connection(&err);
+                warn!(
+                    "redis is unavailable, compute {} directly, err: {}",
+                    key, err
+                );
+                return compute().await;
+            }
+        }
+
+        let lock = format!("{}:lock", key);
+        let token = uuid::Uuid::new_v4().to_string();
+        let locked: Result<Option<String>, RedisError> = self
+            .timed(
+                redis::cmd("SET")
+                    .arg(&lock)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(self.lock_ttl.as_millis() as u64)
+                    .query_async(&mut conn),
+            )
+            .await;
+        match locked {
+            Ok(Some(_)) => {
+                let value = compute().await;
+                if let Ok(ref value) = value {
+                    self.set(&mut conn, &key, value, ttl).await;
+                }
+                let released: Result<i64, RedisError> = self
+                    .timed(
+                        redis::Script::new(RELEASE_LOCK)
+                            .key(&lock)
+                            .arg(&token)
+                            .invoke_async(&mut conn),
+                    )
+                    .await;
+                if let Err(err) = released {
+                    self.check_connection(&err);
+                    warn!("cannot release cache lock {}, err: {}", lock, err);
+                }
+                value
+            }
+            Ok(None) => {
+                // another caller is computing the value, wait for it
+                let deadline = Instant::now() + self.lock_wait;
+                while Instant::now() < deadline {
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+                    match self.get(&mut conn, &key).await {
+                        Ok(Some(value)) => return Ok(value),
+                        Ok(None) => continue,
+                        Err(err) => {
+                            self.check_connection(&err);
+                            break;
+                        }
+                    }
+                }
+                compute().await
+            }
+            Err(err) => {
+                self.check_connection(&err);
+                warn!(
+                    "redis is unavailable, compute {} directly, err: {}",
+                    key, err
+                );
+                compute().await
+            }
+        }
+    }
+
+    async fn set<T: Serialize>(
+        &self,
+        conn: &mut MultiplexedConnection,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) {
+        let value = match self.codec.encode_value(value) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("cannot encode value of {}, err: {}", key, err);
+                return;
+            }
+        };
+        let stored = self
+            .timed(
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(value)
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64)
+                    .query_async::<_, ()>(conn),
+            )
+            .await;
+        if let Err(err) = stored {
+            self.check_connection(&err);
+            warn!("cannot cache value of {}, err: {}", key, err);
+        }
+    }
+
+    /// Cache the value of key with ttl, overwriting the cached one.
+    /// Failures are logged only, like [CacheAside::get_or_compute].
+    pub async fn put<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) {
+        let key = format!("{}{}", self.prefix, key);
+        match self.connection().await {
+            Ok(mut conn) => self.set(&mut conn, &key, value, ttl).await,
+            Err(err) => warn!("redis is unavailable, cannot cache {}, err: {}", key, err),
+        }
+    }
+
+    /// Remove the cached value of key, e.p. after the source is updated
+    pub async fn invalidate(&self, key: &str) -> Result<(), RedisError> {
+        let mut conn = self.connection().await?;
+        let deleted = self
+            .timed(
+                redis::cmd("DEL")
+                    .arg(format!("{}{}", self.prefix, key))
+                    .query_async::<_, ()>(&mut conn),
+            )
+            .await;
+        if let Err(ref err) = deleted {
+            self.check_connection(err);
+        }
+        deleted
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::redis::{CacheAside, Namespaced, Redis, RedisConf};
+    use crate::middleware::Middleware;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_db_and_namespace() {
+        let conf = RedisConf {
+            dsn: "redis://127.0.0.1/2".to_string(),
+            ..Default::default()
+        };
+        let client = Redis::new(conf.clone()).make_client().await.unwrap();
+        assert_eq!(client.get_connection_info().redis.db, 2);
+
+        let conf = RedisConf {
+            db: Some(5),
+            key_prefix: Some("user:".to_string()),
+            ..conf
+        };
+        let client = Redis::new(conf.clone()).make_client().await.unwrap();
+        assert_eq!(client.get_connection_info().redis.db, 5);
+        assert_eq!(conf.namespaced(()).key("1:name"), "user:1:name");
+        assert_eq!(Namespaced::new((), "").key("1:name"), "1:name");
+    }
+
+    #[tokio::test]
+    async fn test_degrade_without_redis() {
+        // nothing is listening on port 1
+        let cache = CacheAside::new(redis::Client::open("redis://127.0.0.1:1/").unwrap());
+        let value: Result<Vec<u32>, ()> = cache
+            .get_or_compute("numbers", Duration::from_secs(1), || async {
+                Ok(vec![1, 2])
+            })
+            .await;
+        assert_eq!(value.unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        // a server hanging up every connection after the first command
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = socket.read(&mut [0; 1024]).await;
+            }
+        });
+
+        let cache = CacheAside::new(redis::Client::open(format!("redis://{}/", addr)).unwrap());
+        for _ in 0..2 {
+            let value: Result<u32, ()> = cache
+                .get_or_compute("number", Duration::from_secs(1), || async { Ok(1) })
+                .await;
+            assert_eq!(value.unwrap(), 1);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // a server never responding
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                sockets.push(socket);
+            }
+        });
+
+        let cache = CacheAside::new(redis::Client::open(format!("redis://{}/", addr)).unwrap())
+            .timeout(Duration::from_millis(50));
+        let value: Result<u32, ()> = tokio::time::timeout(
+            Duration::from_secs(1),
+            cache.get_or_compute("number", Duration::from_secs(1), || async { Ok(1) }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(value.unwrap(), 1);
     }
 }
@@ -0,0 +1,235 @@
+/// Keep a client authenticated with an expiring credential (e.p. a consul ACL token
+/// or a nacos access token issued by vault) usable for long-running services, the
+/// client is rebuilt with a fresh credential before the current one expires.
+///
+/// ```rust,ignore
+/// let consul = RefreshingClient::new("consul", RefreshSchedule::new(), || async {
+///     let lease = vault.consul_token().await?;
+///     let conf = ConsulConf {
+///         token: Some(Secret::new(lease.token)),
+///         ..Default::default()
+///     };
+///     Ok::<_, BoxError>(Lease::new(Consul::new(conf).make_client().await?, lease.ttl))
+/// })
+/// .await?;
+/// let client = consul.client();
+/// ```
+///
+/// The calls in flight keep the client they started with, so a refresh never
+/// disrupts them. A failed refresh is retried and the current client is kept
+/// meanwhile, the refreshing stops once all the clones are dropped.
+use crate::utils::clock::{system_clock, Clock, SharedClock};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// A client and how long its credential lives
+#[derive(Debug)]
+pub struct Lease<C> {
+    pub client: C,
+    pub ttl: Duration,
+}
+
+impl<C> Lease<C> {
+    pub fn new(client: C, ttl: Duration) -> Self {
+        Self { client, ttl }
+    }
+}
+
+/// When to refresh a lease
+#[derive(Clone, Debug)]
+pub struct RefreshSchedule {
+    ahead: Duration,
+    retry: Duration,
+    clock: SharedClock,
+}
+
+impl Default for RefreshSchedule {
+    fn default() -> Self {
+        Self {
+            ahead: Duration::from_secs(30),
+            retry: Duration::from_secs(5),
+            clock: system_clock(),
+        }
+    }
+}
+
+impl RefreshSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refresh this long before the expiry, at most half of the ttl
+    pub fn ahead(mut self, ahead: Duration) -> Self {
+        self.ahead = ahead;
+        self
+    }
+
+    /// The interval of retrying a failed refresh, also the minimum interval of
+    /// refreshing so that a lease already expired is not refreshed in a hot loop
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The clock of the schedule, e.p. a [MockClock] in tests
+    ///
+    /// [MockClock]: crate::utils::clock::MockClock
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn delay(&self, ttl: Duration) -> Duration {
+        (ttl - self.ahead.min(ttl / 2)).max(self.retry)
+    }
+}
+
+pub struct RefreshingClient<C> {
+    current: Arc<RwLock<Arc<C>>>,
+}
+
+impl<C> Clone for RefreshingClient<C> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<C: Send + Sync + 'static> RefreshingClient<C> {
+    /// Build the first client with refresh, it fails if the first one fails
+    pub async fn new<F, Fut, E>(
+        name: &'static str,
+        schedule: RefreshSchedule,
+        refresh: F,
+    ) -> Result<Self, E>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Lease<C>, E>> + Send + 'static,
+        E: Display + 'static,
+    {
+        let lease = refresh().await?;
+        let current = Arc::new(RwLock::new(Arc::new(lease.client)));
+        tokio::spawn(refresh_loop(
+            name,
+            schedule,
+            refresh,
+            lease.ttl,
+            Arc::downgrade(&current),
+        ));
+        Ok(Self { current })
+    }
+
+    /// The current client, hold it for a call rather than across calls
+    pub fn client(&self) -> Arc<C> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+async fn refresh_loop<C, F, Fut, E>(
+    name: &'static str,
+    schedule: RefreshSchedule,
+    refresh: F,
+    ttl: Duration,
+    current: Weak<RwLock<Arc<C>>>,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Lease<C>, E>>,
+    E: Display,
+{
+    let mut delay = schedule.delay(ttl);
+    loop {
+        schedule.clock.sleep(delay).await;
+        if current.strong_count() == 0 {
+            return;
+        }
+        match refresh().await {
+            Ok(lease) => {
+                let Some(current) = current.upgrade() else {
+                    return;
+                };
+                *current.write().unwrap() = Arc::new(lease.client);
+                info!("client of {} is refreshed, ttl: {:?}", name, lease.ttl);
+                delay = schedule.delay(lease.ttl);
+            }
+            Err(err) => {
+                warn!(
+                    "cannot refresh client of {}, retry in {:?}, err: {}",
+                    name, schedule.retry, err
+                );
+                delay = schedule.retry;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::refresh::{Lease, RefreshSchedule, RefreshingClient};
+    use crate::utils::clock::MockClock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_refreshing_client() {
+        let clock = MockClock::new();
+        // the refreshing task sleeps before the clock is advanced
+        let settle = || async {
+            for _ in 0..8 {
+                tokio::task::yield_now().await;
+            }
+        };
+        let advance = |duration: Duration| {
+            let clock = clock.clone();
+            async move {
+                settle().await;
+                clock.advance(duration);
+                settle().await;
+            }
+        };
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let schedule = RefreshSchedule::new()
+            .ahead(Duration::from_secs(20))
+            .retry(Duration::from_secs(4))
+            .clock(clock.clone());
+        assert_eq!(schedule.delay(Duration::ZERO), Duration::from_secs(4));
+        let client = RefreshingClient::new("test", schedule, move || {
+            let token = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                // the second refresh fails
+                if token == 2 {
+                    return Err("vault is sealed");
+                }
+                Ok(Lease::new(token, Duration::from_secs(40)))
+            }
+        })
+        .await
+        .unwrap();
+        let in_flight = client.client();
+        assert_eq!(*in_flight, 0);
+
+        advance(Duration::from_secs(19)).await;
+        assert_eq!(*client.client(), 0);
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(*client.client(), 1);
+        assert_eq!(*in_flight, 0);
+
+        // refreshed after a retry
+        advance(Duration::from_secs(20)).await;
+        assert_eq!(*client.client(), 1);
+        advance(Duration::from_secs(4)).await;
+        assert_eq!(*client.client(), 3);
+
+        // stops refreshing once dropped
+        drop(client);
+        advance(Duration::from_secs(20)).await;
+        let stopped = issued.load(Ordering::SeqCst);
+        advance(Duration::from_secs(40)).await;
+        assert_eq!(issued.load(Ordering::SeqCst), stopped);
+    }
+}
@@ -0,0 +1,200 @@
+/// Whether a failure is transient and worth retrying, e.p. a refused connection or a
+/// timeout is retryable while an authentication failure or a bad configuration is not.
+/// The retries of [CircuitBreaker::call_classified] and [EgressClient] key off it,
+/// implement it for your own error types to customize the classification.
+///
+/// [CircuitBreaker::call_classified]: crate::registry::CircuitBreaker::call_classified
+/// [EgressClient]: crate::registry::EgressClient
+use crate::middleware::apollo::ApolloError;
+use crate::middleware::nacos::NacosError;
+use crate::middleware::rabbitmq::RabbitMQError;
+use crate::middleware::ConnectTimeout;
+use crate::registry::EgressError;
+use http::StatusCode;
+use std::io;
+use tonic::Code;
+
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+/// 5xx and 429 Too Many Requests
+impl Retryable for StatusCode {
+    fn is_retryable(&self) -> bool {
+        self.is_server_error() || *self == StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+impl Retryable for tonic::Status {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self.code(),
+            Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted
+        )
+    }
+}
+
+/// The broken connections and timeouts, a missing file or socket is not retryable
+impl Retryable for io::Error {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+}
+
+impl Retryable for ConnectTimeout {
+    fn is_retryable(&self) -> bool {
+        true
+    }
+}
+
+impl Retryable for etcd_client::Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            etcd_client::Error::IoError(err) => err.is_retryable(),
+            etcd_client::Error::TransportError(_)
+            | etcd_client::Error::WatchError(_)
+            | etcd_client::Error::LeaseKeepAliveError(_) => true,
+            etcd_client::Error::GRpcStatus(status) => status.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+impl Retryable for redis::RedisError {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            redis::ErrorKind::TryAgain
+                | redis::ErrorKind::BusyLoadingError
+                | redis::ErrorKind::ClusterDown
+                | redis::ErrorKind::MasterDown
+        ) || self.is_timeout()
+            || self.is_connection_refusal()
+            || self.is_connection_dropped()
+    }
+}
+
+/// The consul client only exposes the message, the ACL denials are not retryable
+impl Retryable for consul::errors::Error {
+    fn is_retryable(&self) -> bool {
+        let message = self.to_string();
+        !message.contains("Permission denied") && !message.contains("ACL not found")
+    }
+}
+
+impl Retryable for RabbitMQError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            RabbitMQError::InvalidEndpoint(_) => false,
+            RabbitMQError::Connect(err) => matches!(err, amqprs::error::Error::NetworkError(_)),
+            RabbitMQError::Timeout(err) => err.is_retryable(),
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Retryable for tokio_postgres::Error {
+    fn is_retryable(&self) -> bool {
+        use tokio_postgres::error::SqlState;
+        match self.code() {
+            Some(code) => [
+                SqlState::CANNOT_CONNECT_NOW,
+                SqlState::TOO_MANY_CONNECTIONS,
+                SqlState::ADMIN_SHUTDOWN,
+                SqlState::T_R_SERIALIZATION_FAILURE,
+                SqlState::T_R_DEADLOCK_DETECTED,
+            ]
+            .contains(code),
+            None => {
+                self.is_closed()
+                    || std::error::Error::source(self)
+                        .and_then(|source| source.downcast_ref::<io::Error>())
+                        .is_some_and(Retryable::is_retryable)
+            }
+        }
+    }
+}
+
+/// The configurations are invalid
+impl Retryable for NacosError {
+    fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
+/// The configurations are invalid
+impl Retryable for ApolloError {
+    fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
+/// The configurations are invalid
+#[cfg(feature = "s3")]
+impl Retryable for crate::middleware::s3::S3Error {
+    fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
+/// All but the open circuit, the instances may show up or recover in the next try
+impl Retryable for EgressError {
+    fn is_retryable(&self) -> bool {
+        !matches!(self, EgressError::CircuitOpen(_))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::retryable::Retryable;
+    use crate::middleware::ConnectTimeout;
+    use crate::registry::{CircuitOpen, EgressError};
+    use http::StatusCode;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retryable() {
+        assert!(StatusCode::SERVICE_UNAVAILABLE.is_retryable());
+        assert!(StatusCode::INTERNAL_SERVER_ERROR.is_retryable());
+        assert!(StatusCode::TOO_MANY_REQUESTS.is_retryable());
+        assert!(!StatusCode::UNAUTHORIZED.is_retryable());
+        assert!(!StatusCode::NOT_FOUND.is_retryable());
+
+        assert!(tonic::Status::unavailable("down").is_retryable());
+        assert!(!tonic::Status::unauthenticated("bad token").is_retryable());
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(refused.is_retryable());
+        assert!(!io::Error::from(io::ErrorKind::NotFound).is_retryable());
+
+        let timeout = ConnectTimeout {
+            middleware: "etcd",
+            timeout: Duration::from_secs(3),
+        };
+        assert!(etcd_client::Error::from(timeout).is_retryable());
+        let denied = tonic::Status::permission_denied("etcdserver: permission denied");
+        assert!(!etcd_client::Error::GRpcStatus(denied).is_retryable());
+
+        let refused = redis::RedisError::from(refused);
+        assert!(refused.is_retryable());
+        let auth = redis::RedisError::from((redis::ErrorKind::AuthenticationFailed, "WRONGPASS"));
+        assert!(!auth.is_retryable());
+
+        assert!(EgressError::NoInstance("sys-grpc".to_string()).is_retryable());
+        let open = EgressError::CircuitOpen(CircuitOpen {
+            backend: "sys-grpc",
+        });
+        assert!(!open.is_retryable());
+    }
+}
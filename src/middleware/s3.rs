@@ -0,0 +1,86 @@
+use crate::config::env::{optional, require};
+use crate::config::secret::Secret;
+use crate::define_config;
+use crate::middleware::Middleware;
+use async_trait::async_trait;
+use aws_sdk_s3::config::{Builder, Credentials, Region};
+use serde::Serialize;
+use thiserror::Error;
+
+define_config! {
+    #[derive(Serialize, Debug)]
+    pub S3Conf {
+        #[default_endpoint = "default_endpoint"]
+        pub endpoint -> String {
+            optional("S3_ENDPOINT", "http://127.0.0.1:9000")
+        },
+        #[default_region = "default_region"]
+        pub region -> String {
+            optional("S3_REGION", "us-east-1")
+        },
+        #[default_access_key = "default_access_key"]
+        pub access_key -> String {
+            require("S3_ACCESS_KEY")
+        },
+        #[default_secret_key = "default_secret_key"]
+        pub secret_key -> Secret<String> {
+            Secret::new(require("S3_SECRET_KEY"))
+        },
+        #[default_bucket = "default_bucket"]
+        pub bucket -> String {
+            require("S3_BUCKET")
+        },
+        // `http://endpoint/bucket/key` instead of `http://bucket.endpoint/key`,
+        // self-hosted MinIO usually needs it.
+        #[default_path_style = "default_path_style"]
+        pub path_style -> bool {
+            optional("S3_PATH_STYLE", "true") == "true"
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum S3Error {
+    #[error("invalid s3 endpoint {endpoint}: {source}")]
+    InvalidEndpoint {
+        endpoint: String,
+        source: url::ParseError,
+    },
+}
+
+pub struct S3(S3Conf);
+
+impl S3 {
+    pub fn new(conf: S3Conf) -> Self {
+        Self(conf)
+    }
+}
+
+#[async_trait]
+impl Middleware for S3 {
+    type Client = aws_sdk_s3::Client;
+    type Error = S3Error;
+
+    /// The bucket is not bound to the client, use [S3Conf::bucket] in requests
+    async fn make_client(&self) -> Result<Self::Client, Self::Error> {
+        let conf = &self.0;
+        url::Url::parse(&conf.endpoint).map_err(|source| S3Error::InvalidEndpoint {
+            endpoint: conf.endpoint.clone(),
+            source,
+        })?;
+        let credentials = Credentials::new(
+            &conf.access_key,
+            conf.secret_key.expose_secret(),
+            None,
+            None,
+            "common-s3-conf",
+        );
+        let config = Builder::new()
+            .endpoint_url(&conf.endpoint)
+            .region(Region::new(conf.region.clone()))
+            .credentials_provider(credentials)
+            .force_path_style(conf.path_style)
+            .build();
+        Ok(aws_sdk_s3::Client::from_conf(config))
+    }
+}
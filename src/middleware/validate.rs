@@ -0,0 +1,138 @@
+/// Validate all middlewares of a service at startup concurrently, and report all
+/// failures together instead of one by one as connections fail sequentially.
+///
+/// ```rust,ignore
+/// validate_all(&[
+///     &Redis::new(conf.redis.clone()),
+///     &Etcd::new(conf.etcd.clone()),
+///     &RabbitMQ::new(conf.rabbitmq.clone()),
+/// ])
+/// .await
+/// .unwrap_or_else(|report| panic!("{}", report));
+/// ```
+///
+/// A middleware is checked by `make_client`, which validates the configuration
+/// (addresses, required fields, etc.) and connects if the middleware connects
+/// eagerly (etcd, rabbitmq, postgres). Lazy middlewares (redis, consul, etc.)
+/// are not connected, ping their clients with [HealthCheck] for that.
+///
+/// [HealthCheck]: crate::middleware::HealthCheck
+use crate::middleware::Middleware;
+use async_trait::async_trait;
+use std::fmt::{Display, Formatter};
+use tower::BoxError;
+
+/// Object safe [Middleware], so that different middlewares could be checked together
+#[async_trait]
+pub trait DynMiddleware: Send + Sync {
+    /// The type name, e.p. `Redis`
+    fn name(&self) -> &'static str;
+
+    async fn check(&self) -> Result<(), BoxError>;
+}
+
+#[async_trait]
+impl<M> DynMiddleware for M
+where
+    M: Middleware + Send + Sync,
+    M::Client: Send,
+    M::Error: Display + Send,
+{
+    fn name(&self) -> &'static str {
+        let name = std::any::type_name::<M>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    async fn check(&self) -> Result<(), BoxError> {
+        self.make_client()
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string().into())
+    }
+}
+
+/// A middleware failed to be validated
+#[derive(Debug)]
+pub struct MiddlewareFailure {
+    pub name: &'static str,
+    pub error: BoxError,
+}
+
+/// All failures of [validate_all]
+#[derive(Debug)]
+pub struct ValidationReport(pub Vec<MiddlewareFailure>);
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} middleware(s) failed to be validated", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "\n  {}: {}", failure.name, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+/// Check all middlewares concurrently, the failures are in the order of middlewares
+pub async fn validate_all(middlewares: &[&dyn DynMiddleware]) -> Result<(), ValidationReport> {
+    let results = futures::future::join_all(
+        middlewares
+            .iter()
+            .map(|middleware| async move { (middleware.name(), middleware.check().await) }),
+    )
+    .await;
+    let failures = results
+        .into_iter()
+        .filter_map(|(name, res)| res.err().map(|error| MiddlewareFailure { name, error }))
+        .collect::<Vec<_>>();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationReport(failures))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::validate::validate_all;
+    use crate::middleware::Middleware;
+    use async_trait::async_trait;
+
+    struct Fine;
+
+    struct Broken(&'static str);
+
+    #[async_trait]
+    impl Middleware for Fine {
+        type Client = ();
+        type Error = String;
+
+        async fn make_client(&self) -> Result<Self::Client, Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Middleware for Broken {
+        type Client = ();
+        type Error = String;
+
+        async fn make_client(&self) -> Result<Self::Client, Self::Error> {
+            Err(self.0.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validate_all() {
+        assert!(validate_all(&[&Fine]).await.is_ok());
+
+        let report = validate_all(&[&Broken("invalid addr"), &Fine, &Broken("timed out")])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            report.to_string(),
+            "2 middleware(s) failed to be validated\n  Broken: invalid addr\n  Broken: timed out"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+/// Retry and circuit breaking of the calls to registry backends (etcd, consul),
+/// transient failures are retried with backoff, and a sustained outage opens the
+/// circuit so that calls fail fast with [CircuitOpen] instead of piling up.
+///
+/// closed => calls pass, `failure_threshold` consecutive failures open the circuit
+/// open => calls fail fast until `open_duration` elapses
+/// half-open => one trial call passes, a success closes the circuit and a failure opens it again
+use crate::middleware::retryable::Retryable;
+use crate::utils::clock::{system_clock, Clock, SharedClock};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+#[error("circuit of {backend} is open, calls fail fast until it recovers")]
+pub struct CircuitOpen {
+    pub backend: &'static str,
+}
+
+impl From<CircuitOpen> for etcd_client::Error {
+    fn from(err: CircuitOpen) -> Self {
+        etcd_client::Error::IoError(std::io::Error::other(err))
+    }
+}
+
+impl From<CircuitOpen> for consul::errors::Error {
+    fn from(err: CircuitOpen) -> Self {
+        consul::errors::Error::from(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    pub(crate) backend: &'static str,
+    pub(crate) retries: u32,
+    pub(crate) backoff: Duration,
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<State>,
+    pub(crate) clock: SharedClock,
+}
+
+impl CircuitBreaker {
+    /// 2 retries from 200ms backoff, opened by 5 consecutive failures for 10 seconds
+    pub fn new(backend: &'static str) -> Self {
+        Self {
+            backend,
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(10),
+            state: Mutex::new(State::Closed { failures: 0 }),
+            clock: system_clock(),
+        }
+    }
+
+    /// The max retries of a call
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The first backoff before retrying, doubled on each retry
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn failure_threshold(mut self, threshold: u32) -> Self {
+        self.failure_threshold = threshold.max(1);
+        self
+    }
+
+    pub fn open_duration(mut self, duration: Duration) -> Self {
+        self.open_duration = duration;
+        self
+    }
+
+    /// The clock of the open duration and backoff, e.p. a [MockClock] in tests
+    ///
+    /// [MockClock]: crate::utils::clock::MockClock
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Whether a call could pass
+    pub(crate) fn acquire(&self) -> bool {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            // the trial call is lost (e.p. cancelled), start another one
+            State::HalfOpen { since } if now - since >= self.open_duration => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            // the trial call of half-open is in progress
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub(crate) fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("circuit of {} is closed", self.backend);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub(crate) fn on_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let open = match *state {
+            State::Closed { failures } if failures + 1 < self.failure_threshold => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+                false
+            }
+            State::Open { .. } => false,
+            _ => true,
+        };
+        if open {
+            warn!(
+                "circuit of {} is open for {:?}",
+                self.backend, self.open_duration
+            );
+            *state = State::Open {
+                until: self.clock.now() + self.open_duration,
+            };
+        }
+    }
+
+    /// Call with retries, fail fast with [CircuitOpen] if the circuit is open.
+    /// The last error is returned if the circuit is opened while retrying.
+    pub async fn call<T, E, F, Fut>(&self, f: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<CircuitOpen> + Display,
+    {
+        self.call_with(f, |_| true).await
+    }
+
+    /// Same as [CircuitBreaker::call] but only the [Retryable] errors are retried and
+    /// count as failures, the others (e.p. an auth failure) are returned at once
+    /// without opening the circuit since the backend does answer.
+    pub async fn call_classified<T, E, F, Fut>(&self, f: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<CircuitOpen> + Retryable + Display,
+    {
+        self.call_with(f, E::is_retryable).await
+    }
+
+    async fn call_with<T, E, F, Fut>(&self, mut f: F, retryable: fn(&E) -> bool) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<CircuitOpen> + Display,
+    {
+        let mut backoff = self.backoff;
+        let mut last_err = None;
+        for attempt in 0..=self.retries {
+            if !self.acquire() {
+                break;
+            }
+            match f().await {
+                Ok(value) => {
+                    self.on_success();
+                    return Ok(value);
+                }
+                Err(err) if !retryable(&err) => {
+                    self.on_success();
+                    return Err(err);
+                }
+                Err(err) => {
+                    self.on_failure();
+                    if attempt < self.retries {
+                        warn!(
+                            "call {} failed, retry in {:?}, err: {}",
+                            self.backend, backoff, err
+                        );
+                        self.clock.sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            CircuitOpen {
+                backend: self.backend,
+            }
+            .into()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::retryable::Retryable;
+    use crate::registry::breaker::{CircuitBreaker, CircuitOpen};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Backend,
+        Denied,
+        Open,
+    }
+
+    impl Retryable for Error {
+        fn is_retryable(&self) -> bool {
+            *self == Error::Backend
+        }
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    impl From<CircuitOpen> for Error {
+        fn from(_: CircuitOpen) -> Self {
+            Error::Open
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new("test")
+            .retries(1)
+            .backoff(Duration::from_millis(1))
+            .failure_threshold(3)
+            .open_duration(Duration::from_millis(50));
+        let calls = AtomicUsize::new(0);
+        let failing = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(Error::Backend)
+        };
+
+        // retried once
+        assert_eq!(breaker.call(failing).await, Err(Error::Backend));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // opened by the 3rd failure
+        assert_eq!(breaker.call(failing).await, Err(Error::Backend));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // fail fast
+        assert_eq!(breaker.call(failing).await, Err(Error::Open));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // half-open trial closes it
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.call(|| async { Ok::<_, Error>(1) }).await, Ok(1));
+        assert_eq!(breaker.call(|| async { Ok::<_, Error>(2) }).await, Ok(2));
+
+        // neither retried nor opening the circuit
+        calls.store(0, Ordering::SeqCst);
+        let denied = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(Error::Denied)
+        };
+        for _ in 0..3 {
+            assert_eq!(breaker.call_classified(denied).await, Err(Error::Denied));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(breaker.call(|| async { Ok::<_, Error>(3) }).await, Ok(3));
+    }
+}
@@ -1,24 +1,93 @@
 use crate::config::service::ServiceConf;
 use crate::middleware::consul::{Consul, ConsulConf};
 use crate::middleware::Middleware;
-use crate::registry::{ConsulRegistryOption, ServiceRegister};
+use crate::registry::{
+    is_canary, keep_watching, registered_meta, registry_metrics, CircuitBreaker,
+    ConsulRegistryOption, DiscoveredService, EndpointBuilder, ListInstances, ServiceDiscover,
+    ServiceRegister, Weighted,
+};
+use crate::utils::startup::record_service_key;
 use async_trait::async_trait;
 use consul::agent::{Agent, RegisterAgentService};
+use consul::health::{Health, ServiceEntry};
+use consul::QueryOptions;
+use futures::future;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tonic::transport::Endpoint;
+use tower::discover::Change;
+use tracing::{info, trace, warn, Instrument};
 
-#[derive(Debug, Default)]
-pub struct ConsulRegistry(ConsulRegistryOption);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The max duration of a blocking health query
+const HEALTH_WAIT: Duration = Duration::from_secs(300);
+
+/// A blocking query without response for it is stalled, consul adds a jitter of up
+/// to 1/16 of the wait
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(360);
+
+#[derive(Debug)]
+pub struct ConsulRegistry(
+    ConsulRegistryOption,
+    Arc<CircuitBreaker>,
+    EndpointBuilder,
+    Duration,
+);
+
+impl Default for ConsulRegistry {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
 
 impl ConsulRegistry {
+    fn consul_conf(&self) -> &ConsulConf {
+        match &self.0 {
+            ConsulRegistryOption::Register { consul, .. } => consul,
+            ConsulRegistryOption::Discover { consul } => consul,
+        }
+    }
+
     pub fn new(conf: ConsulRegistryOption) -> Self {
-        Self(conf)
+        Self(
+            conf,
+            Arc::new(CircuitBreaker::new("consul")),
+            EndpointBuilder::new(),
+            DISCOVER_TIMEOUT,
+        )
     }
 
     pub fn discover(consul: ConsulConf) -> Self {
-        Self(ConsulRegistryOption::discover(consul))
+        Self::new(ConsulRegistryOption::discover(consul))
     }
 
     pub fn register(consul: ConsulConf, service: ServiceConf) -> Self {
-        Self(ConsulRegistryOption::register(consul, service))
+        Self::new(ConsulRegistryOption::register(consul, service))
+    }
+
+    /// Retry and circuit breaking of the registration and discovery calls
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.1 = Arc::new(breaker);
+        self
+    }
+
+    /// Transport settings of the discovered endpoints
+    pub fn endpoint_builder(mut self, builder: EndpointBuilder) -> Self {
+        self.2 = builder;
+        self
+    }
+
+    /// A blocking health query without response for it is stalled and restarted,
+    /// 6 minutes by default, keep it above the 5 minutes wait of the queries
+    pub fn discover_timeout(mut self, timeout: Duration) -> Self {
+        self.3 = timeout;
+        self
     }
 }
 
@@ -61,33 +130,447 @@ impl ServiceRegister for ConsulRegistry {
             }
         };
         let consul = Consul::new(conf);
-        let client = consul.make_client().await.unwrap();
-        let discover_url =
-            url::Url::parse(&service.discover_addr).expect("Not a valid discover addr");
-        let address = discover_url.host_str().expect("Not a valid discover addr");
-        let port = discover_url
+        let client = consul.make_client().await?;
+        let (address, port) = advertised(service)?;
+        let agent_service = RegisterAgentService {
+            Name: service_key.to_string(),
+            ID: format!("{}:{}", service_key, service.name),
+            Address: address,
+            Port: port,
+            EnableTagOverride: enable_tag_override,
+            Tags: tags,
+            Meta: Some(registered_meta(service, &meta.unwrap_or_default())),
+            Check: check,
+            Weights: weights.or_else(|| service.weights.clone()),
+            ..Default::default()
+        };
+        self.1
+            .call_classified(|| client.register_service(&agent_service, replace_existing_checks))
+            .await?;
+        registry_metrics().registered(service_key, &service.name, true);
+        record_service_key(service_key);
+        Ok(())
+    }
+}
+
+/// The registered address and port, `advertise_addr` and `advertise_port` of the
+/// service win over the ones parsed from `discover_addr`
+fn advertised(service: &ServiceConf) -> Result<(String, u16), String> {
+    let discover = || {
+        url::Url::parse(&service.discover_addr)
+            .map_err(|err| format!("invalid discover addr {}: {}", service.discover_addr, err))
+    };
+    let address = match &service.advertise_addr {
+        Some(addr) => {
+            // a bare host, the port is advertised separately
+            url::Host::parse(addr)
+                .map_err(|err| format!("invalid advertise addr {}: {}", addr, err))?;
+            addr.clone()
+        }
+        None => discover()?
+            .host_str()
+            .ok_or_else(|| format!("no host in discover addr {}", service.discover_addr))?
+            .to_string(),
+    };
+    let port = match service.advertise_port {
+        Some(0) => return Err("invalid advertise port 0".to_string()),
+        Some(port) => port,
+        None => discover()?
             .port_or_known_default()
-            .expect("Not a valid discover addr");
-        client
-            .register_service(
-                &RegisterAgentService {
-                    Name: service_key.to_string(),
-                    ID: format!("{}:{}", service_key, service.name),
-                    Address: address.to_string(),
-                    Port: port,
-                    EnableTagOverride: enable_tag_override,
-                    Tags: tags,
-                    Meta: meta,
-                    Check: check,
-                    Weights: weights,
-                    ..Default::default()
-                },
-                replace_existing_checks,
-            )
+            .ok_or_else(|| format!("no port in discover addr {}", service.discover_addr))?,
+    };
+    Ok((address, port))
+}
+
+/// A service instance in the health query result
+struct Instance {
+    id: String,
+    endpoint: String,
+    meta: HashMap<String, String>,
+    weight: u32,
+    healthy: bool,
+}
+
+fn instances(entries: Vec<ServiceEntry>) -> Vec<Instance> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            let address = if entry.Service.Address.is_empty() {
+                entry.Node.Address
+            } else {
+                entry.Service.Address
+            };
+            // the warning weight is used once any check is warning, both are 1 by default
+            let warning = entry.Checks.iter().any(|check| check.Status == "warning");
+            let weight = entry
+                .Service
+                .Weights
+                .unwrap_or_default()
+                .get(if warning { "Warning" } else { "Passing" })
+                .map_or(1, |weight| (*weight).max(0) as u32);
+            Instance {
+                id: entry.Service.ID,
+                endpoint: format!("http://{}:{}", address, entry.Service.Port),
+                meta: entry.Service.Meta.unwrap_or_default(),
+                weight,
+                // unhealthy as soon as any check is critical
+                healthy: entry.Checks.iter().all(|check| check.Status != "critical"),
+            }
+        })
+        .collect()
+}
+
+/// The discovered healthy instances, id => (endpoint, weight, canary)
+#[derive(Default)]
+struct HealthySet {
+    instances: HashMap<String, (String, u32, bool)>,
+    // re-insert an instance when only its weight changes
+    weighted: bool,
+}
+
+impl HealthySet {
+    fn weighted() -> Self {
+        Self {
+            weighted: true,
+            ..Default::default()
+        }
+    }
+
+    /// Changes which make the discovered set the same as the healthy instances
+    fn update(
+        &mut self,
+        instances: Vec<Instance>,
+        builder: &EndpointBuilder,
+    ) -> Vec<Change<String, Weighted<Endpoint>>> {
+        let weighted = self.weighted;
+        let healthy = instances
+            .into_iter()
+            .filter(|instance| instance.healthy)
+            .map(|instance| {
+                let weight = if weighted { instance.weight } else { 1 };
+                (instance.id, (instance.endpoint, weight, instance.meta))
+            })
+            .collect::<HashMap<_, _>>();
+        let mut changes = vec![];
+        self.instances.retain(|id, _| {
+            let keep = healthy.contains_key(id);
+            if !keep {
+                trace!("service {} is unhealthy or going down", id);
+                changes.push(Change::Remove(id.clone()));
+            }
+            keep
+        });
+        for (id, (endpoint, weight, meta)) in healthy {
+            let canary = is_canary(&meta);
+            if self.instances.get(&id) == Some(&(endpoint.clone(), weight, canary)) {
+                continue;
+            }
+            if let Some(parsed) = builder.build(&endpoint, &meta) {
+                trace!(
+                    "discover a healthy service {}: {} ({})",
+                    id,
+                    endpoint,
+                    weight
+                );
+                let weighted = Weighted::new(parsed, weight).canary(canary);
+                changes.push(Change::Insert(id.clone(), weighted));
+                self.instances.insert(id, (endpoint, weight, canary));
+            }
+        }
+        changes
+    }
+}
+
+/// The blocking health queries of service from index, it ends once a query fails
+fn health_queries(
+    client: consul::Client,
+    service_key: String,
+    index: Option<u64>,
+) -> BoxStream<'static, Vec<ServiceEntry>> {
+    let queries = (client, service_key, index);
+    stream::unfold(queries, |(client, service_key, index)| async move {
+        let options = QueryOptions {
+            wait_index: index,
+            wait_time: Some(HEALTH_WAIT),
+            ..Default::default()
+        };
+        match client
+            .service(&service_key, None, false, Some(&options))
+            .await
+        {
+            Ok((entries, meta)) => {
+                // the index goes backwards when consul is restored, reset it
+                let index = meta
+                    .last_index
+                    .filter(|last| !matches!(index, Some(idx) if *last < idx));
+                Some((entries, (client, service_key, index)))
+            }
+            Err(err) => {
+                warn!("cannot query health of {}, err: {}", service_key, err);
+                None
+            }
+        }
+    })
+    .boxed()
+}
+
+impl ConsulRegistry {
+    /// Discover with the health endpoint by blocking queries, see [ServiceDiscover]
+    async fn watch_health<V: Send + 'static>(
+        &self,
+        service_key: &str,
+        mut healthy: HealthySet,
+        tx: Sender<Change<String, V>>,
+        into: fn(Weighted<Endpoint>) -> V,
+    ) -> Result<(), consul::errors::Error> {
+        let client = Consul::new(self.consul_conf().clone())
+            .make_client()
+            .await?;
+        let (entries, meta) = self
+            .1
+            .call_classified(|| client.service(service_key, None, false, None))
             .await?;
+        let changes = healthy.update(instances(entries), &self.2);
+
+        info!(
+            "initial discover {} services from domain '{}'",
+            healthy.instances.len(),
+            service_key
+        );
+        registry_metrics()
+            .reset_discovered(service_key, healthy.instances.keys().map(String::as_str));
+        for change in changes {
+            let _ = tx.send(map_change(change, into)).await;
+        }
+
+        let service_key = service_key.to_string();
+        let builder = self.2.clone();
+        let timeout = self.3;
+        let task = async move {
+            let watch = health_queries(client.clone(), service_key.clone(), meta.last_index);
+            // query the current instances again
+            let rewatch = || {
+                let queries = health_queries(client.clone(), service_key.clone(), None);
+                future::ready(Ok::<_, Infallible>(queries))
+            };
+            let handle = |entries| {
+                healthy
+                    .update(instances(entries), &builder)
+                    .into_iter()
+                    .map(|change| map_change(change, into))
+                    .collect::<Vec<_>>()
+            };
+            keep_watching(
+                &service_key,
+                timeout,
+                RETRY_INTERVAL,
+                watch,
+                rewatch,
+                handle,
+                tx,
+            )
+            .await;
+        }
+        .in_current_span();
+
+        tokio::spawn(task);
+
         Ok(())
     }
+
+    /// Only the healthy instances
+    async fn healthy_instances(
+        &self,
+        service_key: &str,
+    ) -> Result<Vec<DiscoveredService<String, Weighted<Endpoint>>>, consul::errors::Error> {
+        let client = Consul::new(self.consul_conf().clone())
+            .make_client()
+            .await?;
+        let (entries, _) = self
+            .1
+            .call_classified(|| client.service(service_key, None, false, None))
+            .await?;
+        Ok(instances(entries)
+            .into_iter()
+            .filter(|instance| instance.healthy)
+            .filter_map(|instance| {
+                self.2
+                    .build(&instance.endpoint, &instance.meta)
+                    .map(|endpoint| DiscoveredService {
+                        key: instance.id,
+                        endpoint: Weighted::new(endpoint, instance.weight)
+                            .canary(is_canary(&instance.meta)),
+                        meta: instance.meta,
+                    })
+            })
+            .collect())
+    }
+}
+
+fn map_change<V>(
+    change: Change<String, Weighted<Endpoint>>,
+    into: fn(Weighted<Endpoint>) -> V,
+) -> Change<String, V> {
+    match change {
+        Change::Insert(id, weighted) => Change::Insert(id, into(weighted)),
+        Change::Remove(id) => Change::Remove(id),
+    }
+}
+
+#[async_trait]
+impl ServiceDiscover<String> for ConsulRegistry {
+    type Error = consul::errors::Error;
+
+    /// Discover with the health endpoint by blocking queries, an instance is removed
+    /// as soon as consul marks any of its checks critical and inserted back once
+    /// it recovers, instead of waiting for the next poll cycle.
+    async fn discover_to_channel(
+        &self,
+        service_key: &str,
+        tx: Sender<Change<String, Endpoint>>,
+    ) -> Result<(), Self::Error> {
+        self.watch_health(service_key, HealthySet::default(), tx, |weighted| {
+            weighted.value
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ListInstances<String> for ConsulRegistry {
+    /// Only the healthy instances
+    async fn list_instances(
+        &self,
+        service_key: &str,
+    ) -> Result<Vec<DiscoveredService>, consul::errors::Error> {
+        Ok(self
+            .healthy_instances(service_key)
+            .await?
+            .into_iter()
+            .map(|service| DiscoveredService {
+                key: service.key,
+                endpoint: service.endpoint.value,
+                meta: service.meta,
+            })
+            .collect())
+    }
 }
 
-// TODO consul ServiceDiscover
-// optional, we can use consul dns resolver to discover service
+/// Discover with the consul service weights, an instance is inserted again once
+/// its weight changes, e.p. feed a [DiscoverySet]. The warning weight is used when
+/// any check of the instance is warning.
+///
+/// [DiscoverySet]: crate::registry::DiscoverySet
+#[async_trait]
+impl ServiceDiscover<String, Weighted<Endpoint>> for ConsulRegistry {
+    type Error = consul::errors::Error;
+
+    async fn discover_to_channel(
+        &self,
+        service_key: &str,
+        tx: Sender<Change<String, Weighted<Endpoint>>>,
+    ) -> Result<(), Self::Error> {
+        self.watch_health(service_key, HealthySet::weighted(), tx, |weighted| weighted)
+            .await
+    }
+}
+
+#[async_trait]
+impl ListInstances<String, Weighted<Endpoint>> for ConsulRegistry {
+    async fn list_instances(
+        &self,
+        service_key: &str,
+    ) -> Result<Vec<DiscoveredService<String, Weighted<Endpoint>>>, consul::errors::Error> {
+        self.healthy_instances(service_key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{advertised, HealthySet, Instance};
+    use crate::config::service::ServiceConf;
+    use crate::registry::EndpointBuilder;
+    use std::collections::HashMap;
+    use tower::discover::Change;
+
+    fn instance(id: &str, healthy: bool) -> Instance {
+        Instance {
+            id: id.to_string(),
+            endpoint: format!("http://127.0.0.1:{}", 3000 + id.len()),
+            meta: HashMap::new(),
+            weight: 1,
+            healthy,
+        }
+    }
+
+    #[test]
+    fn test_health_transition() {
+        let builder = EndpointBuilder::new();
+        let mut set = HealthySet::default();
+        let changes = set.update(vec![instance("a", true), instance("bb", true)], &builder);
+        assert_eq!(changes.len(), 2);
+
+        // not changed
+        assert!(set
+            .update(vec![instance("a", true), instance("bb", true)], &builder)
+            .is_empty());
+
+        // bb becomes critical
+        let changes = set.update(vec![instance("a", true), instance("bb", false)], &builder);
+        assert!(matches!(&changes[..], [Change::Remove(id)] if id == "bb"));
+
+        // bb recovers
+        let changes = set.update(vec![instance("a", true), instance("bb", true)], &builder);
+        assert!(matches!(&changes[..], [Change::Insert(id, _)] if id == "bb"));
+
+        // a is deregistered
+        let changes = set.update(vec![instance("bb", true)], &builder);
+        assert!(matches!(&changes[..], [Change::Remove(id)] if id == "a"));
+
+        // bb is draining
+        let mut draining = instance("bb", true);
+        draining.weight = 0;
+        assert!(set.update(vec![draining], &builder).is_empty());
+        let mut weighted = HealthySet::weighted();
+        weighted.update(vec![instance("bb", true)], &builder);
+        let mut draining = instance("bb", true);
+        draining.weight = 0;
+        let changes = weighted.update(vec![draining], &builder);
+        assert!(matches!(&changes[..], [Change::Insert(id, w)] if id == "bb" && w.weight == 0));
+    }
+
+    #[test]
+    fn test_advertised() {
+        let mut service = ServiceConf {
+            discover_addr: "http://10.0.0.1:3000".to_string(),
+            advertise_addr: None,
+            advertise_port: None,
+            ..Default::default()
+        };
+        assert_eq!(
+            advertised(&service).unwrap(),
+            ("10.0.0.1".to_string(), 3000)
+        );
+
+        service.advertise_addr = Some("203.0.113.7".to_string());
+        service.advertise_port = Some(30080);
+        assert_eq!(
+            advertised(&service).unwrap(),
+            ("203.0.113.7".to_string(), 30080)
+        );
+
+        // only the address is overridden
+        service.advertise_addr = Some("user.svc.cluster.local".to_string());
+        service.advertise_port = None;
+        assert_eq!(
+            advertised(&service).unwrap(),
+            ("user.svc.cluster.local".to_string(), 3000)
+        );
+
+        service.advertise_addr = Some("10.0.0.1:3000/".to_string());
+        assert!(advertised(&service).is_err());
+        service.advertise_addr = None;
+        service.advertise_port = Some(0);
+        assert!(advertised(&service).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+/// Outbound client of a discovered service, the client-side counterpart of the
+/// server layers. Requests are load-balanced across the instances of a [DiscoverySet]
+/// kept updated by discovery, bounded by a timeout, and retried with backoff under
+/// a [CircuitBreaker] shared by all clones of the client.
+///
+/// A discovered value is turned into the transport by `connect`, e.p. a lazy tonic
+/// [Channel] for gRPC (and HTTP/2 REST) targets:
+///
+/// ```rust,ignore
+/// let client = ClientBuilder::new("user-grpc")
+///     .timeout(Duration::from_secs(3))
+///     .circuit_breaker(CircuitBreaker::new("user-grpc").retries(1))
+///     .discover(&consul_registry, lazy_channel)
+///     .await?;
+/// // the first instance is discovered
+/// client.ready().await?;
+/// let mut user = UserClient::new(client);
+/// ```
+///
+/// or any HTTP client service for REST targets, e.p. a hyper client rewriting the
+/// authority of requests to the picked [Endpoint].
+///
+/// Only the requests which could be replayed are retried, i.e. the idempotent
+/// methods with an empty body, the retried ones are rebuilt without extensions.
+/// The gRPC calls are streamed (POST with body) and never retried by the client.
+/// A transport error, timeout or retryable status counts as a failure and is retried.
+/// The statuses are classified by their [Retryable] (`5xx` and `429`) by default,
+/// override it with [ClientBuilder::retryable_status], e.p. to leave `429` to the caller.
+///
+/// A share of requests could be routed to the canary instances with [CanarySplit],
+/// sticky by a request header (e.p. the user id) optionally.
+///
+/// [Channel]: tonic::transport::Channel
+use crate::middleware::retryable::Retryable;
+use crate::registry::{
+    CanarySplit, CircuitBreaker, CircuitOpen, DiscoverySet, ServiceDiscover, Weighted,
+};
+use futures::future::BoxFuture;
+use http::header::HeaderName;
+use http::{HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use http_body::Body;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::watch;
+use tonic::transport::{Channel, Endpoint};
+use tower::discover::Change;
+use tower::{BoxError, Service, ServiceExt};
+use tracing::{warn, Instrument};
+
+#[derive(Debug, Error)]
+pub enum EgressError {
+    #[error("no available instance of {0}")]
+    NoInstance(String),
+    #[error("calling {service} timed out after {timeout:?}")]
+    Timeout { service: String, timeout: Duration },
+    #[error("no instance of {service} is discovered within {timeout:?}")]
+    NotReady { service: String, timeout: Duration },
+    #[error(transparent)]
+    CircuitOpen(#[from] CircuitOpen),
+    #[error("calling {service} failed, err: {source}")]
+    Transport { service: String, source: BoxError },
+}
+
+/// Connect to a discovered gRPC endpoint lazily, a `connect` of [ClientBuilder]
+pub fn lazy_channel(endpoint: Endpoint) -> Channel {
+    endpoint.connect_lazy()
+}
+
+pub struct ClientBuilder {
+    service_key: String,
+    timeout: Duration,
+    ready_timeout: Duration,
+    breaker: CircuitBreaker,
+    canary: Option<CanarySplit>,
+    sticky: Option<HeaderName>,
+    retryable_status: fn(StatusCode) -> bool,
+}
+
+impl ClientBuilder {
+    /// 30 seconds timeout, 10 seconds ready timeout, and the retries and circuit
+    /// of [CircuitBreaker::new]
+    pub fn new(service_key: impl Into<String>) -> Self {
+        Self {
+            service_key: service_key.into(),
+            timeout: Duration::from_secs(30),
+            ready_timeout: Duration::from_secs(10),
+            breaker: CircuitBreaker::new("egress"),
+            canary: None,
+            sticky: None,
+            retryable_status: |status| status.is_retryable(),
+        }
+    }
+
+    /// The timeout of each attempt
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long [EgressClient::ready] waits for the first instance
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = timeout;
+        self
+    }
+
+    /// The retries, backoff and circuit of the calls to the service
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    /// Split the traffic between the stable and canary instances, no split by default
+    pub fn canary(mut self, split: CanarySplit) -> Self {
+        self.canary = Some(split);
+        self
+    }
+
+    /// The requests with the same value of the header go to the same subset of
+    /// the canary split
+    pub fn sticky_header(mut self, header: HeaderName) -> Self {
+        self.sticky = Some(header);
+        self
+    }
+
+    /// Which statuses count as failures and are retried, [Retryable] of the status
+    /// by default
+    pub fn retryable_status(mut self, retryable: fn(StatusCode) -> bool) -> Self {
+        self.retryable_status = retryable;
+        self
+    }
+
+    /// Discover the instances of the service with the registry, e.p. [ConsulRegistry]
+    /// or [EtcdRegistry] with their weights and canaries
+    ///
+    /// [ConsulRegistry]: crate::registry::ConsulRegistry
+    /// [EtcdRegistry]: crate::registry::EtcdRegistry
+    pub async fn discover<D, V, S, F>(
+        self,
+        registry: &D,
+        connect: F,
+    ) -> Result<EgressClient<String, S>, D::Error>
+    where
+        D: ServiceDiscover<String, Weighted<V>> + Sync,
+        V: Send + 'static,
+        S: Send + 'static,
+        F: Fn(V) -> S + Send + 'static,
+    {
+        let (tx, rx) = channel(16);
+        registry.discover_to_channel(&self.service_key, tx).await?;
+        Ok(self.build(rx, connect))
+    }
+
+    /// Build with the changes of discovery, e.p. from [ServiceDiscover::discover_to_channel]
+    pub fn build<K, V, S, F>(
+        self,
+        mut changes: Receiver<Change<K, Weighted<V>>>,
+        connect: F,
+    ) -> EgressClient<K, S>
+    where
+        K: Eq + Send + 'static,
+        V: Send + 'static,
+        S: Send + 'static,
+        F: Fn(V) -> S + Send + 'static,
+    {
+        let instances = Arc::new(Mutex::new(DiscoverySet::new()));
+        let updating = instances.clone();
+        let (discovered_tx, discovered) = watch::channel(0);
+        let update = async move {
+            while let Some(change) = changes.recv().await {
+                let change = match change {
+                    Change::Insert(key, weighted) => Change::Insert(
+                        key,
+                        Weighted::new(connect(weighted.value), weighted.weight)
+                            .canary(weighted.canary),
+                    ),
+                    Change::Remove(key) => Change::Remove(key),
+                };
+                let len = {
+                    let mut instances = updating.lock().unwrap();
+                    instances.apply(change);
+                    instances.len()
+                };
+                let _ = discovered_tx.send(len);
+            }
+        }
+        .in_current_span();
+        tokio::spawn(update);
+        EgressClient {
+            service_key: Arc::new(self.service_key),
+            instances,
+            discovered,
+            timeout: self.timeout,
+            ready_timeout: self.ready_timeout,
+            breaker: Arc::new(self.breaker),
+            canary: self.canary,
+            sticky: self.sticky,
+            retryable_status: self.retryable_status,
+        }
+    }
+}
+
+pub struct EgressClient<K, S> {
+    service_key: Arc<String>,
+    instances: Arc<Mutex<DiscoverySet<K, S>>>,
+    // the number of instances, updated after each change
+    discovered: watch::Receiver<usize>,
+    timeout: Duration,
+    ready_timeout: Duration,
+    breaker: Arc<CircuitBreaker>,
+    canary: Option<CanarySplit>,
+    sticky: Option<HeaderName>,
+    retryable_status: fn(StatusCode) -> bool,
+}
+
+impl<K, S> Clone for EgressClient<K, S> {
+    fn clone(&self) -> Self {
+        Self {
+            service_key: self.service_key.clone(),
+            instances: self.instances.clone(),
+            discovered: self.discovered.clone(),
+            timeout: self.timeout,
+            ready_timeout: self.ready_timeout,
+            breaker: self.breaker.clone(),
+            canary: self.canary.clone(),
+            sticky: self.sticky.clone(),
+            retryable_status: self.retryable_status,
+        }
+    }
+}
+
+/// The request without body and extensions, rebuilt for retries
+struct Replay {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+}
+
+impl Replay {
+    fn of<B: Body>(req: &Request<B>) -> Option<Self> {
+        let idempotent = matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+        );
+        (idempotent && req.body().is_end_stream()).then(|| Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+        })
+    }
+
+    fn rebuild<B: Default>(&self) -> Request<B> {
+        let mut req = Request::new(B::default());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        req
+    }
+}
+
+impl<K: Eq, S: Clone> EgressClient<K, S> {
+    /// The number of discovered instances
+    pub fn instances(&self) -> usize {
+        self.instances.lock().unwrap().len()
+    }
+
+    /// Wait until the first instance is discovered, await it before the first
+    /// request so that it does not race the discovery and fail with
+    /// [EgressError::NoInstance]. Fails with [EgressError::NotReady] after the
+    /// ready timeout, or once the discovery stops without any instance.
+    pub async fn ready(&self) -> Result<(), EgressError> {
+        let mut discovered = self.discovered.clone();
+        let discover = async move {
+            while *discovered.borrow_and_update() == 0 {
+                discovered.changed().await.ok()?;
+            }
+            Some(())
+        };
+        match tokio::time::timeout(self.ready_timeout, discover).await {
+            Ok(Some(())) => Ok(()),
+            Ok(None) | Err(_) => Err(EgressError::NotReady {
+                service: self.service_key.to_string(),
+                timeout: self.ready_timeout,
+            }),
+        }
+    }
+
+    /// Call a picked instance once, the circuit is updated by the result
+    async fn attempt<B, ResBody>(&self, req: Request<B>) -> Result<Response<ResBody>, EgressError>
+    where
+        S: Service<Request<B>, Response = Response<ResBody>>,
+        S::Error: Into<BoxError>,
+    {
+        if !self.breaker.acquire() {
+            return Err(CircuitOpen {
+                backend: self.breaker.backend,
+            }
+            .into());
+        }
+        let instance = {
+            let mut instances = self.instances.lock().unwrap();
+            match &self.canary {
+                Some(split) => {
+                    let sticky = self
+                        .sticky
+                        .as_ref()
+                        .and_then(|header| req.headers().get(header))
+                        .and_then(|value| value.to_str().ok());
+                    instances.pick_split(split, sticky).cloned()
+                }
+                None => instances.pick().cloned(),
+            }
+        };
+        let instance = match instance {
+            Some(instance) => instance,
+            None => return Err(EgressError::NoInstance(self.service_key.to_string())),
+        };
+        match tokio::time::timeout(self.timeout, instance.oneshot(req)).await {
+            Ok(Ok(res)) if !(self.retryable_status)(res.status()) => {
+                self.breaker.on_success();
+                Ok(res)
+            }
+            Ok(Ok(res)) => {
+                self.breaker.on_failure();
+                Ok(res)
+            }
+            Ok(Err(err)) => {
+                self.breaker.on_failure();
+                Err(EgressError::Transport {
+                    service: self.service_key.to_string(),
+                    source: err.into(),
+                })
+            }
+            Err(_) => {
+                self.breaker.on_failure();
+                Err(EgressError::Timeout {
+                    service: self.service_key.to_string(),
+                    timeout: self.timeout,
+                })
+            }
+        }
+    }
+}
+
+impl<K, S, B, ResBody> Service<Request<B>> for EgressClient<K, S>
+where
+    K: Eq + Send + 'static,
+    S: Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: Body + Default + Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = EgressError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    /// The instance is picked and made ready in the call
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let client = self.clone();
+        let replay = Replay::of(&req);
+        Box::pin(async move {
+            let mut req = Some(req);
+            let mut backoff = client.breaker.backoff;
+            let mut attempt = 0;
+            loop {
+                let next = match req.take() {
+                    Some(req) => req,
+                    None => replay.as_ref().expect("request is replayable").rebuild(),
+                };
+                let res = client.attempt(next).await;
+                let failed = match &res {
+                    Ok(res) if (client.retryable_status)(res.status()) => res.status().to_string(),
+                    Err(err) if err.is_retryable() => err.to_string(),
+                    Ok(_) | Err(_) => return res,
+                };
+                if replay.is_none() || attempt >= client.breaker.retries {
+                    return res;
+                }
+                warn!(
+                    "call {} failed, retry in {:?}, err: {}",
+                    client.service_key, backoff, failed
+                );
+                client.breaker.clock.sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::middleware::retryable::Retryable;
+    use crate::registry::{CircuitBreaker, ClientBuilder, EgressError, Weighted};
+    use http::{Request, Response, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc::channel;
+    use tower::discover::Change;
+    use tower::{service_fn, ServiceExt};
+
+    #[tokio::test]
+    async fn test_egress_client() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let calls = calls.clone();
+            move |status: StatusCode| {
+                let calls = calls.clone();
+                service_fn(move |_req: Request<String>| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let res = Response::builder().status(status).body(()).unwrap();
+                    async move { Ok::<_, Infallible>(res) }
+                })
+            }
+        };
+        let breaker = CircuitBreaker::new("book")
+            .retries(1)
+            .backoff(Duration::ZERO)
+            .failure_threshold(3);
+        let (tx, rx) = channel(16);
+        let client = ClientBuilder::new("book")
+            .circuit_breaker(breaker)
+            .build(rx, connect);
+        let get = || Request::get("/book").body(String::new()).unwrap();
+
+        let err = client.clone().oneshot(get()).await.unwrap_err();
+        assert!(matches!(err, EgressError::NoInstance(_)));
+
+        let down = Weighted::new(StatusCode::SERVICE_UNAVAILABLE, 1);
+        tx.send(Change::Insert("down", down)).await.unwrap();
+        tx.send(Change::Insert("up", Weighted::new(StatusCode::OK, 1)))
+            .await
+            .unwrap();
+        while client.instances() < 2 {
+            tokio::task::yield_now().await;
+        }
+        // retried on the other instance
+        let res = client.clone().oneshot(get()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // a request with body is not replayed
+        tx.send(Change::Remove("up")).await.unwrap();
+        while client.instances() > 1 {
+            tokio::task::yield_now().await;
+        }
+        let post = Request::post("/book").body("book".to_string()).unwrap();
+        let res = client.clone().oneshot(post).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // opened by the 3rd failure while retrying
+        let res = client.clone().oneshot(get()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+        let err = client.clone().oneshot(get()).await.unwrap_err();
+        assert!(matches!(err, EgressError::CircuitOpen(_)));
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_egress_ready() {
+        let connect = |status: StatusCode| {
+            service_fn(move |_req: Request<String>| async move {
+                Ok::<_, Infallible>(Response::builder().status(status).body(()).unwrap())
+            })
+        };
+        let (tx, rx) = channel(16);
+        let client = ClientBuilder::new("book").build(rx, connect);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            tx.send(Change::Insert("up", Weighted::new(StatusCode::OK, 1)))
+                .await
+                .unwrap();
+            // keep discovering
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        client.ready().await.unwrap();
+        assert_eq!(client.instances(), 1);
+        let get = Request::get("/book").body(String::new()).unwrap();
+        let res = client.clone().oneshot(get).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // 429 is retried by default
+        let calls = Arc::new(AtomicUsize::new(0));
+        let limited = |retryable_status: fn(StatusCode) -> bool| {
+            let calls = calls.clone();
+            let (tx, rx) = channel(16);
+            tx.try_send(Change::Insert(
+                "limited",
+                Weighted::new(StatusCode::TOO_MANY_REQUESTS, 1),
+            ))
+            .unwrap();
+            let connect = move |status: StatusCode| {
+                let calls = calls.clone();
+                service_fn(move |_req: Request<String>| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let res = Response::builder().status(status).body(()).unwrap();
+                    async move { Ok::<_, Infallible>(res) }
+                })
+            };
+            ClientBuilder::new("book")
+                .circuit_breaker(
+                    CircuitBreaker::new("book")
+                        .retries(1)
+                        .backoff(Duration::ZERO),
+                )
+                .retryable_status(retryable_status)
+                .build(rx, connect)
+        };
+        let get = || Request::get("/book").body(String::new()).unwrap();
+        let client = limited(|status| status.is_retryable());
+        client.ready().await.unwrap();
+        client.clone().oneshot(get()).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let client = limited(|status| status.is_server_error());
+        client.ready().await.unwrap();
+        let res = client.clone().oneshot(get()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let (_tx, rx) = channel::<Change<&str, _>>(16);
+        let client = ClientBuilder::new("book")
+            .ready_timeout(Duration::from_millis(20))
+            .build(rx, connect);
+        let err = client.ready().await.unwrap_err();
+        assert!(matches!(err, EgressError::NotReady { .. }));
+    }
+}
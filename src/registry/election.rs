@@ -0,0 +1,86 @@
+/// Leader election among the registered instances of a service.
+///
+/// Only [EtcdRegistry](crate::registry::EtcdRegistry) implements [LeaderElection], on top
+/// of the etcd election API with a lease kept alive by the campaign. Consul sessions are
+/// not supported, so [ConsulRegistry](crate::registry::ConsulRegistry) cannot campaign.
+/// A registry built from a discover config has no service to campaign with, and its
+/// campaign returns an error.
+use async_trait::async_trait;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Elect a single leader among instances sharing the same `service_key`,
+/// e.p. running a scheduler or a migration on exactly one replica.
+#[async_trait]
+pub trait LeaderElection {
+    type Error;
+
+    /// Join the election of `service_key`, the campaign keeps running in
+    /// background until the returned [Leadership] is resigned or dropped.
+    async fn campaign(&self, service_key: &str) -> Result<Leadership, Self::Error>;
+}
+
+/// Handle of a running campaign.
+/// Dropping it relinquishes leadership in background, use [Leadership::resign]
+/// to wait until the leadership is actually released on graceful shutdown.
+pub struct Leadership {
+    state: watch::Receiver<bool>,
+    shutdown: CancellationToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Leadership {
+    pub(crate) fn new(
+        state: watch::Receiver<bool>,
+        shutdown: CancellationToken,
+        handle: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            state,
+            shutdown,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// Wait until this instance becomes the leader.
+    /// Return false if the campaign is terminated before that.
+    pub async fn on_became_leader(&mut self) -> bool {
+        self.wait_for(true).await
+    }
+
+    /// Wait until this instance loses its leadership.
+    /// Return false if the campaign is terminated before that.
+    pub async fn on_lost_leadership(&mut self) -> bool {
+        self.wait_for(false).await
+    }
+
+    async fn wait_for(&mut self, expect: bool) -> bool {
+        loop {
+            if *self.state.borrow_and_update() == expect {
+                return true;
+            }
+            if self.state.changed().await.is_err() {
+                return *self.state.borrow() == expect;
+            }
+        }
+    }
+
+    /// Stop the campaign and release leadership (if held) promptly.
+    pub async fn resign(mut self) {
+        self.shutdown.cancel();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.await;
+        }
+    }
+}
+
+impl Drop for Leadership {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
@@ -0,0 +1,132 @@
+/// Transport settings shared by all discovered endpoints, so that they are
+/// configured the same as the hand-built ones.
+///
+/// ```rust,ignore
+/// let builder = EndpointBuilder::new()
+///     .connect_timeout(Duration::from_secs(1))
+///     .user_agent(HeaderValue::from_static("user-service"))
+///     .tls(ClientTlsConfig::new().ca_certificate(ca));
+/// let registry = EtcdRegistry::discover(conf).endpoint_builder(builder);
+/// ```
+///
+/// With TLS, the domain name verified is the `tls_domain` in the metadata of
+/// the instance, or the host of the discovered address if it is absent.
+use http::HeaderValue;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tracing::warn;
+
+/// The metadata key of the domain name verified with TLS
+pub const TLS_DOMAIN_META: &str = "tls_domain";
+
+#[derive(Clone, Debug)]
+pub struct EndpointBuilder {
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    user_agent: Option<HeaderValue>,
+    tls: Option<ClientTlsConfig>,
+}
+
+impl Default for EndpointBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EndpointBuilder {
+    /// 5 seconds connect timeout and 60 seconds TCP keepalive by default
+    pub fn new() -> Self {
+        Self {
+            connect_timeout: Some(Duration::from_secs(5)),
+            timeout: None,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            user_agent: None,
+            tls: None,
+        }
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// The timeout of each request
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// TCP keepalive interval, None to disable it
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.tcp_keepalive = interval;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    pub fn tls(mut self, tls: ClientTlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Build the endpoint of a discovered address with the metadata of its instance,
+    /// None if the address cannot be parsed.
+    pub fn build(&self, addr: &str, meta: &HashMap<String, String>) -> Option<Endpoint> {
+        let mut endpoint = match Endpoint::from_str(addr) {
+            Ok(endpoint) => endpoint,
+            Err(_) => {
+                warn!(
+                    "unexpected service endpoint {}, cannot parse it to an Endpoint",
+                    addr
+                );
+                return None;
+            }
+        };
+        if let Some(timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        endpoint = endpoint.tcp_keepalive(self.tcp_keepalive);
+        if let Some(ref user_agent) = self.user_agent {
+            endpoint = endpoint
+                .user_agent(user_agent.clone())
+                .expect("user agent is a valid header value");
+        }
+        if let Some(ref tls) = self.tls {
+            let tls = match meta.get(TLS_DOMAIN_META) {
+                Some(domain) => tls.clone().domain_name(domain),
+                None => tls.clone(),
+            };
+            endpoint = match endpoint.tls_config(tls) {
+                Ok(endpoint) => endpoint,
+                Err(err) => {
+                    warn!("cannot configure tls of endpoint {}, err: {}", addr, err);
+                    return None;
+                }
+            };
+        }
+        Some(endpoint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry::EndpointBuilder;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_endpoint_builder() {
+        let builder = EndpointBuilder::new();
+        let endpoint = builder.build("http://10.0.0.1:3000", &HashMap::new());
+        assert_eq!(endpoint.unwrap().uri().to_string(), "http://10.0.0.1:3000/");
+        assert!(builder.build("not an address", &HashMap::new()).is_none());
+    }
+}
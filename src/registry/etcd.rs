@@ -1,29 +1,89 @@
 use super::*;
 use crate::middleware::etcd::Etcd;
 use crate::middleware::Middleware;
-use etcd_client::{EventType, GetOptions, PutOptions, WatchOptions};
-use std::str::FromStr;
+use crate::utils::startup::record_service_key;
+use etcd_client::{
+    EventType, GetOptions, LeaseKeepAliveStream, LeaseKeeper, PutOptions, ResignOptions,
+    WatchOptions, WatchResponse, WatchStream, Watcher,
+};
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 use tonic::transport::Endpoint;
 use tower::discover::Change;
 use tracing::Instrument;
 use tracing::{info, trace, warn};
 
-#[derive(Debug, Default)]
-pub struct EtcdRegistry(EtcdRegistryOption);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A watch without response for it is stalled, etcd notifies the progress of an
+/// idle watch every 10 minutes by default
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(660);
+
+#[derive(Debug)]
+pub struct EtcdRegistry(
+    EtcdRegistryOption,
+    Arc<CircuitBreaker>,
+    EndpointBuilder,
+    Duration,
+);
+
+impl Default for EtcdRegistry {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
 
 impl EtcdRegistry {
+    fn etcd_conf(&self) -> &EtcdConf {
+        match &self.0 {
+            EtcdRegistryOption::Register { etcd, .. } => etcd,
+            EtcdRegistryOption::Discover { etcd } => etcd,
+        }
+    }
+
     pub fn new(conf: EtcdRegistryOption) -> Self {
-        Self(conf)
+        Self(
+            conf,
+            Arc::new(CircuitBreaker::new("etcd")),
+            EndpointBuilder::new(),
+            DISCOVER_TIMEOUT,
+        )
     }
 
     pub fn discover(etcd: EtcdConf) -> Self {
-        Self(EtcdRegistryOption::discover(etcd))
+        Self::new(EtcdRegistryOption::discover(etcd))
     }
 
     pub fn register(etcd: EtcdConf, service: ServiceConf) -> Self {
-        Self(EtcdRegistryOption::register(etcd, service))
+        Self::new(EtcdRegistryOption::register(etcd, service))
+    }
+
+    /// Retry and circuit breaking of the registration and discovery calls
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.1 = Arc::new(breaker);
+        self
+    }
+
+    /// Transport settings of the discovered endpoints
+    pub fn endpoint_builder(mut self, builder: EndpointBuilder) -> Self {
+        self.2 = builder;
+        self
+    }
+
+    /// A watch without response (including the progress notifications) for it is
+    /// stalled and rewatched, 11 minutes by default, keep it above the progress
+    /// notify interval of etcd (`--experimental-watch-progress-notify-interval`)
+    pub fn discover_timeout(mut self, timeout: Duration) -> Self {
+        self.3 = timeout;
+        self
     }
 }
 
@@ -32,13 +92,24 @@ impl ServiceRegister for EtcdRegistry {
     type Error = etcd_client::Error;
 
     async fn register_service(&self, service_key: &str) -> Result<(), Self::Error> {
-        let (etcd, service, grant_ttl, keep_alive_interval) = match &self.0 {
+        let (etcd, service, grant_ttl, keep_alive_interval, keep_alive_jitter, meta) = match &self.0
+        {
             EtcdRegistryOption::Register {
                 etcd,
                 service,
                 grant_ttl,
                 keep_alive_interval,
-            } => (etcd, service, *grant_ttl, *keep_alive_interval),
+                keep_alive_jitter,
+                meta,
+                encode_meta,
+            } => (
+                etcd,
+                service,
+                *grant_ttl,
+                *keep_alive_interval,
+                *keep_alive_jitter,
+                encode_meta.then(|| registered_meta(service, meta)),
+            ),
             EtcdRegistryOption::Discover { .. } => {
                 panic!("Cannot register service with a discover config")
             }
@@ -46,20 +117,39 @@ impl ServiceRegister for EtcdRegistry {
 
         debug_assert!(grant_ttl > keep_alive_interval as i64);
 
+        let prefixed_key = etcd.prefixed(service_key);
+        let breaker = &self.1;
         let etcd = Etcd::new(etcd.clone());
-        let mut client = etcd.make_client().await?;
+        let client = breaker.call_classified(|| etcd.make_client()).await?;
 
-        let lease_id = client.lease_grant(grant_ttl, None).await?.id();
-        let (mut keeper, _) = client.lease_keep_alive(lease_id).await?;
+        let lease_id = breaker
+            .call_classified(|| {
+                let mut client = client.clone();
+                async move { client.lease_grant(grant_ttl, None).await }
+            })
+            .await?
+            .id();
+        let (mut keeper, _) = breaker
+            .call_classified(|| {
+                let mut client = client.clone();
+                async move { client.lease_keep_alive(lease_id).await }
+            })
+            .await?;
 
+        let name = service.name.as_str();
+        let value = encode_value(&service.discover_addr, &meta.unwrap_or_default());
+
+        let (key, instance) = (service_key.to_string(), name.to_string());
         let task = async move {
-            let mut tick = tokio::time::interval(Duration::from_secs(keep_alive_interval));
             loop {
-                tick.tick().await;
+                tokio::time::sleep(jittered(keep_alive_interval, keep_alive_jitter, grant_ttl))
+                    .await;
                 if let Err(err) = keeper.keep_alive().await {
                     warn!("keep lease alive failed cause err: {}", err);
+                    registry_metrics().registered(&key, &instance, false);
                     break;
                 }
+                registry_metrics().renewed(&key, &instance);
                 trace!("kept lease alive");
             }
         }
@@ -67,118 +157,663 @@ impl ServiceRegister for EtcdRegistry {
 
         tokio::spawn(task);
 
-        let name = service.name.as_str();
-        let discover_addr = service.discover_addr.as_str();
+        breaker
+            .call_classified(|| {
+                let mut client = client.clone();
+                let key = format!("{}:{}", prefixed_key, name);
+                let value = value.as_str();
+                async move {
+                    client
+                        .put(key, value, Some(PutOptions::new().with_lease(lease_id)))
+                        .await
+                }
+            })
+            .await?;
+        registry_metrics().registered(service_key, name, true);
+        record_service_key(service_key);
 
-        client
-            .put(
-                format!("{}:{}", service_key, name),
-                discover_addr,
-                Some(PutOptions::new().with_lease(lease_id)),
-            )
+        Ok(())
+    }
+}
+
+/// The value of a registered instance with metadata, an instance without metadata
+/// (or [EtcdRegistryOption::encode_meta] disabled) is registered with the plain
+/// discover address as before
+#[derive(Serialize, Deserialize)]
+struct InstanceValue {
+    addr: String,
+    #[serde(default)]
+    meta: HashMap<String, String>,
+}
+
+fn encode_value(addr: &str, meta: &HashMap<String, String>) -> String {
+    if meta.is_empty() {
+        return addr.to_string();
+    }
+    serde_json::to_string(&InstanceValue {
+        addr: addr.to_string(),
+        meta: meta.clone(),
+    })
+    .expect("instance value is serializable")
+}
+
+/// The discover address and metadata of a registered value, both forms are accepted
+fn decode_value(value: &str) -> (String, HashMap<String, String>) {
+    match serde_json::from_str::<InstanceValue>(value) {
+        Ok(InstanceValue { addr, meta }) => (addr, meta),
+        Err(_) => (value.to_string(), HashMap::new()),
+    }
+}
+
+/// How a discovered endpoint and its metadata are sent to the discover channel
+type IntoValue<V> = fn(Endpoint, &HashMap<String, String>) -> V;
+
+/// The weighted endpoint, the weight is [WEIGHT_META] of the metadata, 1 if it is
+/// absent or invalid
+fn weighted(endpoint: Endpoint, meta: &HashMap<String, String>) -> Weighted<Endpoint> {
+    let weight = meta
+        .get(WEIGHT_META)
+        .and_then(|weight| weight.parse().ok())
+        .unwrap_or(1);
+    Weighted::new(endpoint, weight).canary(is_canary(meta))
+}
+
+/// The instances emitted to the discover channel, key => endpoint uri and metadata,
+/// so that an instance is only inserted again when its endpoint or metadata changes
+/// (e.p. weight, canary), not on the duplicated puts seen by both the watch and the
+/// snapshot, or a re-snapshot after rewatching.
+#[derive(Default)]
+struct Emitted(HashMap<String, (String, HashMap<String, String>)>);
+
+impl Emitted {
+    fn insert<V>(
+        &mut self,
+        key: &str,
+        endpoint: Endpoint,
+        meta: &HashMap<String, String>,
+        into: IntoValue<V>,
+    ) -> Option<Change<String, V>> {
+        let emitted = (endpoint.uri().to_string(), meta.clone());
+        if self.0.get(key) == Some(&emitted) {
+            trace!("suppress the duplicated insert of service {}", key);
+            return None;
+        }
+        self.0.insert(key.to_string(), emitted);
+        Some(Change::Insert(key.to_string(), into(endpoint, meta)))
+    }
+
+    fn remove<V>(&mut self, key: &str) -> Option<Change<String, V>> {
+        self.0.remove(key).map(|_| Change::Remove(key.to_string()))
+    }
+
+    /// Changes which make the emitted instances the same as the snapshot
+    fn reconcile<V>(
+        &mut self,
+        services: Vec<DiscoveredService>,
+        into: IntoValue<V>,
+    ) -> Vec<Change<String, V>> {
+        let keys = services
+            .iter()
+            .map(|service| service.key.clone())
+            .collect::<HashSet<_>>();
+        let gone = self
+            .0
+            .keys()
+            .filter(|key| !keys.contains(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut changes = gone
+            .iter()
+            .filter_map(|key| self.remove(key))
+            .collect::<Vec<_>>();
+        for service in services {
+            changes.extend(self.insert(&service.key, service.endpoint, &service.meta, into));
+        }
+        changes
+    }
+}
+
+/// Watch the services and then list them, the puts between are seen twice and
+/// deduplicated by [Emitted]
+async fn watch_and_list(
+    client: &mut etcd_client::Client,
+    conf: &EtcdConf,
+    builder: &EndpointBuilder,
+    service_key: &str,
+) -> Result<(Watcher, WatchStream, Vec<DiscoveredService>), etcd_client::Error> {
+    let (watcher, stream) = client
+        .watch(
+            conf.prefixed(service_key),
+            Some(WatchOptions::new().with_prefix().with_progress_notify()),
+        )
+        .await?;
+    trace!("create a watch id {}", watcher.watch_id());
+    let services = list_prefix(client, conf, builder, service_key).await?;
+    Ok((watcher, stream, services))
+}
+
+/// A response of a watch, or the snapshot listed after rewatching
+enum Watched {
+    Snapshot(Vec<DiscoveredService>),
+    Response(WatchResponse),
+}
+
+/// The snapshot if any and then the responses of a watch, it ends once the watch is
+/// canceled or broken. The watcher is kept alive with the stream.
+fn watch_events(
+    watcher: Watcher,
+    stream: WatchStream,
+    snapshot: Option<Vec<DiscoveredService>>,
+) -> BoxStream<'static, Watched> {
+    let responses = stream::unfold((watcher, stream), |(watcher, mut stream)| async move {
+        match stream.message().await {
+            Ok(Some(resp)) if resp.canceled() => {
+                warn!(
+                    "watcher has been canceled, reason: {}",
+                    resp.cancel_reason()
+                );
+                None
+            }
+            Ok(Some(resp)) => Some((Watched::Response(resp), (watcher, stream))),
+            Ok(None) => None,
+            Err(err) => {
+                warn!("watch is broken, err: {}", err);
+                None
+            }
+        }
+    });
+    stream::iter(snapshot.map(Watched::Snapshot))
+        .chain(responses)
+        .boxed()
+}
+
+impl EtcdRegistry {
+    /// Watch the services, see [ServiceDiscover::discover_to_channel]
+    async fn watch<V: Send + 'static>(
+        &self,
+        service_key: &str,
+        tx: Sender<Change<String, V>>,
+        into: IntoValue<V>,
+    ) -> Result<(), etcd_client::Error> {
+        let breaker = self.1.clone();
+        let conf = self.etcd_conf().clone();
+        let etcd = Etcd::new(conf.clone());
+        let client = breaker.call_classified(|| etcd.make_client()).await?;
+
+        // the watcher is kept alive in the task, the watch ends once it is dropped
+        let (watcher, stream, services) = breaker
+            .call_classified(|| {
+                let mut client = client.clone();
+                let conf = &conf;
+                async move { watch_and_list(&mut client, conf, &self.2, service_key).await }
+            })
             .await?;
 
+        info!(
+            "initial discover {} services from domain '{}'",
+            services.len(),
+            service_key
+        );
+
+        registry_metrics().reset_discovered(
+            service_key,
+            services.iter().map(|service| service.key.as_str()),
+        );
+        let mut emitted = Emitted::default();
+        for change in emitted.reconcile(services, into) {
+            let _ = tx.send(change).await;
+        }
+
+        let service_key = service_key.to_string();
+        let builder = self.2.clone();
+        let timeout = self.3;
+        let task = async move {
+            let watch = watch_events(watcher, stream, None);
+            let rewatch = || {
+                let (breaker, client, conf, builder, service_key) =
+                    (&breaker, &client, &conf, &builder, &service_key);
+                async move {
+                    let (watcher, stream, services) =
+                        breaker
+                            .call_classified(|| {
+                                let mut client = client.clone();
+                                async move {
+                                    watch_and_list(&mut client, conf, builder, service_key).await
+                                }
+                            })
+                            .await?;
+                    Ok::<_, etcd_client::Error>(watch_events(watcher, stream, Some(services)))
+                }
+            };
+            let handle = |watched: Watched| match watched {
+                Watched::Snapshot(services) => emitted.reconcile(services, into),
+                Watched::Response(resp) => {
+                    if resp.created() {
+                        trace!("watcher create a new watch request");
+                    }
+                    resp.events()
+                        .iter()
+                        .filter_map(|event| match (event.event_type(), event.kv()) {
+                            (EventType::Put, Some(kv)) => {
+                                let key = conf.strip_prefix(kv.key_str().unwrap());
+                                let (addr, meta) = decode_value(kv.value_str().unwrap());
+
+                                if kv.version() == 1 {
+                                    trace!("discover a new service {}: {}", key, addr);
+                                } else {
+                                    trace!("service {} changed its endpoint to {}", key, addr)
+                                }
+
+                                builder
+                                    .build(&addr, &meta)
+                                    .and_then(|endpoint| emitted.insert(key, endpoint, &meta, into))
+                            }
+                            (EventType::Delete, Some(kv)) => {
+                                let key = conf.strip_prefix(kv.key_str().unwrap());
+                                trace!("service {} is going down", key);
+                                emitted.remove(key)
+                            }
+                            _ => None,
+                        })
+                        .collect()
+                }
+            };
+            keep_watching(
+                &service_key,
+                timeout,
+                RETRY_INTERVAL,
+                watch,
+                rewatch,
+                handle,
+                tx,
+            )
+            .await;
+        }
+        .in_current_span();
+
+        tokio::spawn(task);
+
         Ok(())
     }
+
+    async fn list(&self, service_key: &str) -> Result<Vec<DiscoveredService>, etcd_client::Error> {
+        let breaker = &self.1;
+        let conf = self.etcd_conf();
+        let etcd = Etcd::new(conf.clone());
+        let client = breaker.call_classified(|| etcd.make_client()).await?;
+        breaker
+            .call_classified(|| {
+                let mut client = client.clone();
+                async move { list_prefix(&mut client, conf, &self.2, service_key).await }
+            })
+            .await
+    }
 }
 
 #[async_trait]
 impl ServiceDiscover<String> for EtcdRegistry {
     type Error = etcd_client::Error;
 
+    /// An instance whose lease lapses (e.p. it crashed without deregistering) is
+    /// removed once etcd deletes its key at the lease expiry. The watch is rebuilt
+    /// once it is broken, and only the differences of the new snapshot are sent.
     async fn discover_to_channel(
         &self,
         service_key: &str,
         tx: Sender<Change<String, Endpoint>>,
     ) -> Result<(), Self::Error> {
-        let etcd_conf = match &self.0 {
-            EtcdRegistryOption::Register { etcd, .. } => etcd,
-            EtcdRegistryOption::Discover { etcd } => etcd,
-        };
-        let etcd = Etcd::new(etcd_conf.clone());
-        let mut client = etcd.make_client().await?;
+        self.watch(service_key, tx, |endpoint, _| endpoint).await
+    }
+}
 
-        let (mut watcher, mut stream) = client
-            .watch(service_key, Some(WatchOptions::new().with_prefix()))
-            .await?;
-        watcher.request_progress().await.unwrap();
+#[async_trait]
+impl ListInstances<String> for EtcdRegistry {
+    async fn list_instances(
+        &self,
+        service_key: &str,
+    ) -> Result<Vec<DiscoveredService>, etcd_client::Error> {
+        self.list(service_key).await
+    }
+}
+
+/// Discover with the weights of [WEIGHT_META] and the canaries of [CANARY_META]
+/// registered with [EtcdRegistryOption::encode_meta], e.p. for [ClientBuilder::discover].
+/// An instance is inserted again once its metadata changes.
+///
+/// [ClientBuilder::discover]: crate::registry::ClientBuilder::discover
+#[async_trait]
+impl ServiceDiscover<String, Weighted<Endpoint>> for EtcdRegistry {
+    type Error = etcd_client::Error;
 
-        let watch_id = watcher.watch_id();
-        trace!("create a watch id {}", watch_id);
+    async fn discover_to_channel(
+        &self,
+        service_key: &str,
+        tx: Sender<Change<String, Weighted<Endpoint>>>,
+    ) -> Result<(), Self::Error> {
+        self.watch(service_key, tx, weighted).await
+    }
+}
 
+#[async_trait]
+impl ListInstances<String, Weighted<Endpoint>> for EtcdRegistry {
+    async fn list_instances(
+        &self,
+        service_key: &str,
+    ) -> Result<Vec<DiscoveredService<String, Weighted<Endpoint>>>, etcd_client::Error> {
+        Ok(self
+            .list(service_key)
+            .await?
+            .into_iter()
+            .map(|service| DiscoveredService {
+                endpoint: weighted(service.endpoint, &service.meta),
+                key: service.key,
+                meta: service.meta,
+            })
+            .collect())
+    }
+}
+
+/// Spread the keep alive interval (in seconds) randomly by `±jitter`,
+/// it is kept at least 1 second before the lease ttl expires.
+fn jittered(interval: u64, jitter: f64, ttl: i64) -> Duration {
+    let interval = interval as f64;
+    let spread = interval * jitter * rand::thread_rng().gen_range(-1.0..=1.0);
+    let max = (ttl as f64 - 1.0).max(0.0);
+    Duration::from_secs_f64((interval + spread).clamp(0.0, max))
+}
+
+/// The number of keys fetched in one page when listing a service set
+const LIST_PAGE_SIZE: i64 = 256;
+
+/// The smallest key which is greater than all keys prefixed with `prefix`
+fn prefix_end(prefix: &str) -> Vec<u8> {
+    let mut end = prefix.as_bytes().to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // all keys
+    vec![0]
+}
+
+/// List all services prefixed with `service_key` page by page, the `key_prefix`
+/// of conf is stripped from the keys.
+/// All pages are read at the revision of the first page, so the result
+/// is a consistent snapshot no matter how large the service set is.
+async fn list_prefix(
+    client: &mut etcd_client::Client,
+    conf: &EtcdConf,
+    builder: &EndpointBuilder,
+    service_key: &str,
+) -> Result<Vec<DiscoveredService>, etcd_client::Error> {
+    let service_key = conf.prefixed(service_key);
+    let range_end = prefix_end(&service_key);
+    let mut start = service_key.as_bytes().to_vec();
+    let mut revision = 0;
+    let mut services = vec![];
+
+    loop {
         let res = client
-            .get(service_key, Some(GetOptions::new().with_prefix()))
+            .get(
+                start.clone(),
+                Some(
+                    GetOptions::new()
+                        .with_range(range_end.clone())
+                        .with_limit(LIST_PAGE_SIZE)
+                        .with_revision(revision),
+                ),
+            )
             .await?;
-
-        info!(
-            "initial discover {} services from domain '{}'",
-            res.count(),
-            service_key
-        );
+        if revision == 0 {
+            revision = res.header().map(|header| header.revision()).unwrap_or(0);
+        }
 
         for kv in res.kvs() {
-            let key = kv.key_str().unwrap();
-            let value = kv.value_str().unwrap();
+            let key = conf.strip_prefix(kv.key_str()?);
+            let (addr, meta) = decode_value(kv.value_str()?);
 
-            if let Ok(endpoint) = Endpoint::from_str(value) {
-                let _ = tx.send(Change::Insert(key.to_string(), endpoint)).await;
-            } else {
-                warn!(
-                    "unexpected service endpoint {}, cannot parse it to an Endpoint",
-                    value
-                );
+            if let Some(endpoint) = builder.build(&addr, &meta) {
+                services.push(DiscoveredService {
+                    key: key.to_string(),
+                    endpoint,
+                    meta,
+                });
             }
         }
 
-        let task = async move {
-            while let Ok(Some(resp)) = stream.message().await {
-                if resp.canceled() {
-                    warn!(
-                        "watcher has been canceled, reason: {}",
-                        resp.cancel_reason()
-                    );
-                    break;
-                }
-                if resp.created() {
-                    trace!("watcher create a new watch request");
-                }
+        match res.kvs().last() {
+            Some(kv) if res.more() => {
+                // continue right after the last key of this page
+                start = kv.key().to_vec();
+                start.push(0);
+            }
+            _ => break,
+        }
+    }
 
-                for event in resp.events() {
-                    match event.event_type() {
-                        EventType::Put => {
-                            if let Some(kv) = event.kv() {
-                                let key = kv.key_str().unwrap();
-                                let value = kv.value_str().unwrap();
+    Ok(services)
+}
 
-                                if kv.version() == 1 {
-                                    trace!("discover a new service {}: {}", key, value);
-                                } else {
-                                    trace!("service {} changed its endpoint to {}", key, value)
-                                }
+async fn keep_lease_alive(keeper: &mut LeaseKeeper, stream: &mut LeaseKeepAliveStream) -> bool {
+    if let Err(err) = keeper.keep_alive().await {
+        warn!("keep lease alive failed cause err: {}", err);
+        return false;
+    }
+    match stream.message().await {
+        Ok(Some(resp)) if resp.ttl() > 0 => {
+            trace!("kept lease alive");
+            true
+        }
+        _ => {
+            warn!("lease {} has expired", keeper.id());
+            false
+        }
+    }
+}
 
-                                if let Ok(endpoint) = Endpoint::from_str(value) {
-                                    let _ =
-                                        tx.send(Change::Insert(key.to_string(), endpoint)).await;
-                                } else {
-                                    warn!("unexpected service endpoint {}, cannot parse it to an Endpoint", value);
-                                }
+#[async_trait]
+impl LeaderElection for EtcdRegistry {
+    type Error = etcd_client::Error;
+
+    async fn campaign(&self, service_key: &str) -> Result<Leadership, Self::Error> {
+        let (etcd, service, grant_ttl, keep_alive_interval, keep_alive_jitter) = match &self.0 {
+            EtcdRegistryOption::Register {
+                etcd,
+                service,
+                grant_ttl,
+                keep_alive_interval,
+                keep_alive_jitter,
+                ..
+            } => (
+                etcd,
+                service,
+                *grant_ttl,
+                *keep_alive_interval,
+                *keep_alive_jitter,
+            ),
+            EtcdRegistryOption::Discover { .. } => {
+                return Err(etcd_client::Error::InvalidArgs(
+                    "cannot campaign with a discover config".to_string(),
+                ))
+            }
+        };
+
+        // do not share the prefix with the registered services, or it will be discovered
+        let name = etcd.prefixed(&format!("election/{}", service_key));
+
+        let etcd = Etcd::new(etcd.clone());
+        let mut client = etcd.make_client().await?;
+
+        let lease_id = client.lease_grant(grant_ttl, None).await?.id();
+        let (mut keeper, mut stream) = client.lease_keep_alive(lease_id).await?;
+
+        let value = service.name.clone();
+
+        let (tx, rx) = watch::channel(false);
+        let shutdown = CancellationToken::new();
+        let token = shutdown.clone();
+
+        let task = async move {
+            let tick =
+                || tokio::time::sleep(jittered(keep_alive_interval, keep_alive_jitter, grant_ttl));
+            let leader = {
+                let campaign = client.campaign(name.as_str(), value.as_str(), lease_id);
+                tokio::pin!(campaign);
+                loop {
+                    tokio::select! {
+                        _ = token.cancelled() => break None,
+                        _ = tick() => {
+                            if !keep_lease_alive(&mut keeper, &mut stream).await {
+                                break None;
                             }
                         }
-                        EventType::Delete => {
-                            if let Some(kv) = event.kv() {
-                                let key = kv.key_str().unwrap();
-                                trace!("service {} is going down", key);
+                        res = &mut campaign => match res {
+                            Ok(resp) => break resp.leader().cloned(),
+                            Err(err) => {
+                                warn!("campaign for {} failed cause err: {}", name, err);
+                                break None;
+                            }
+                        }
+                    }
+                }
+            };
 
-                                let _ = tx.send(Change::Remove(key.to_string())).await;
+            if let Some(leader) = leader {
+                info!("became the leader of {}", name);
+                let _ = tx.send(true);
+                loop {
+                    tokio::select! {
+                        _ = token.cancelled() => break,
+                        _ = tick() => {
+                            if !keep_lease_alive(&mut keeper, &mut stream).await {
+                                warn!("lost the leadership of {}", name);
+                                let _ = tx.send(false);
+                                return;
                             }
                         }
                     }
                 }
+                if let Err(err) = client
+                    .resign(Some(ResignOptions::new().with_leader(leader)))
+                    .await
+                {
+                    warn!("resign {} failed cause err: {}", name, err);
+                }
+                let _ = tx.send(false);
+                info!("resigned the leadership of {}", name);
             }
-        }.in_current_span();
 
-        tokio::spawn(task);
+            // release the lease, so the other candidates do not wait until it expires
+            if let Err(err) = client.lease_revoke(lease_id).await {
+                trace!("revoke lease {} failed cause err: {}", lease_id, err);
+            }
+        }
+        .in_current_span();
 
-        Ok(())
+        let handle = tokio::spawn(task);
+
+        Ok(Leadership::new(rx, shutdown, handle))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_value, encode_value, jittered, prefix_end, weighted, Emitted};
+    use crate::config::service::ServiceConf;
+    use crate::registry::{registered_meta, DiscoveredService};
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tonic::transport::Endpoint;
+    use tower::discover::Change;
+
+    fn service(key: &str, port: u16) -> DiscoveredService {
+        DiscoveredService {
+            key: key.to_string(),
+            endpoint: Endpoint::from_shared(format!("http://127.0.0.1:{}", port)).unwrap(),
+            meta: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_resnapshot_dedup() {
+        let mut emitted = Emitted::default();
+        let changes = emitted.reconcile(vec![service("a", 3000), service("b", 3001)], weighted);
+        assert_eq!(changes.len(), 2);
+
+        // the put seen by both the watch and the snapshot
+        let meta = HashMap::new();
+        let mut insert = |port: u16, meta: &HashMap<String, String>| {
+            emitted.insert("a", service("a", port).endpoint, meta, weighted)
+        };
+        assert!(insert(3000, &meta).is_none());
+        assert!(insert(3002, &meta).is_some());
+
+        // only the metadata changes
+        let canary = HashMap::from([
+            ("canary".to_string(), "true".to_string()),
+            ("weight".to_string(), "5".to_string()),
+        ]);
+        assert!(matches!(
+            insert(3002, &canary),
+            Some(Change::Insert(_, value)) if value.canary && value.weight == 5
+        ));
+        assert!(insert(3002, &canary).is_none());
+        assert!(matches!(
+            insert(3002, &meta),
+            Some(Change::Insert(_, value)) if !value.canary && value.weight == 1
+        ));
+
+        // re-snapshot after rewatching, b is gone and c is new
+        let changes = emitted.reconcile(vec![service("a", 3002), service("c", 3003)], weighted);
+        assert!(matches!(
+            &changes[..],
+            [Change::Remove(b), Change::Insert(c, _)] if b == "b" && c == "c"
+        ));
+        assert!(emitted.remove::<Endpoint>("b").is_none());
+    }
+
+    #[test]
+    fn test_instance_value() {
+        let addr = "http://127.0.0.1:3000";
+        assert_eq!(encode_value(addr, &HashMap::new()), addr);
+        assert_eq!(decode_value(addr), (addr.to_string(), HashMap::new()));
+
+        let meta = HashMap::from([("zone".to_string(), "cn-east-1a".to_string())]);
+        let value = encode_value(addr, &meta);
+        assert_eq!(decode_value(&value), (addr.to_string(), meta));
+
+        // the meta of ServiceConf is overridden by the one of options
+        let service = ServiceConf {
+            meta: HashMap::from([
+                ("team".to_string(), "infra".to_string()),
+                ("zone".to_string(), "cn-east-1a".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let option = HashMap::from([("zone".to_string(), "cn-east-1b".to_string())]);
+        let value = encode_value(addr, &registered_meta(&service, &option));
+        let (_, meta) = decode_value(&value);
+        assert_eq!(meta["team"], "infra");
+        assert_eq!(meta["zone"], "cn-east-1b");
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end("sys-grpc"), b"sys-grpd".to_vec());
+        assert_eq!(prefix_end("a\u{7f}"), b"a\x80".to_vec());
+        assert_eq!(prefix_end(""), vec![0]);
+    }
+
+    #[test]
+    fn test_jittered() {
+        for _ in 0..100 {
+            let interval = jittered(20, 0.1, 61);
+            assert!(interval >= Duration::from_secs(18) && interval <= Duration::from_secs(22));
+            // never reaches the lease ttl
+            assert!(jittered(20, 1.0, 21) <= Duration::from_secs(20));
+        }
+        assert_eq!(jittered(20, 0.0, 61), Duration::from_secs(20));
     }
 }
@@ -0,0 +1,17 @@
+use tonic::transport::NamedService;
+use tonic_health::proto::health_server::{Health, HealthServer};
+use tonic_health::server::{health_reporter, HealthReporter};
+
+/// The standard gRPC health service (`grpc.health.v1.Health`) reporting `S` as serving,
+/// add it to the server along with `S` to be checked by
+/// [ConsulRegistryOption::with_grpc_check].
+///
+/// Use the returned reporter to change the status, e.p. `set_not_serving::<S>()`
+/// before shutting down.
+///
+/// [ConsulRegistryOption::with_grpc_check]: crate::registry::ConsulRegistryOption::with_grpc_check
+pub async fn grpc_health_service<S: NamedService>() -> (HealthReporter, HealthServer<impl Health>) {
+    let (mut reporter, service) = health_reporter();
+    reporter.set_serving::<S>().await;
+    (reporter, service)
+}
@@ -0,0 +1,180 @@
+/// Current state of the registries rendered in Prometheus text format,
+/// mount [metrics_handler] at `/metrics` to be scraped.
+///
+/// Following are the exported metrics:
+/// registry_discovered_instances{service} => instances currently discovered
+/// registry_registered{service, instance} => 1 if the instance is registered, otherwise 0
+/// registry_lease_last_renewal_timestamp_seconds{service, instance} => last kept alive time
+use http::header::CONTENT_TYPE;
+use http::{Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tower::discover::Change;
+
+static METRICS: Lazy<RegistryMetrics> = Lazy::new(RegistryMetrics::default);
+
+/// The registry state shared by all registries in process
+pub fn registry_metrics() -> &'static RegistryMetrics {
+    &METRICS
+}
+
+#[derive(Default)]
+struct Registration {
+    registered: bool,
+    last_renewal: Option<SystemTime>,
+}
+
+#[derive(Default)]
+pub struct RegistryMetrics {
+    // service_key => discovered keys
+    discovered: Mutex<BTreeMap<String, BTreeSet<String>>>,
+    // (service_key, instance) => registration
+    registrations: Mutex<BTreeMap<(String, String), Registration>>,
+}
+
+impl RegistryMetrics {
+    /// Replace the discovered set of service_key with the initial snapshot
+    pub(crate) fn reset_discovered<'a>(
+        &self,
+        service_key: &str,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) {
+        let keys = keys.into_iter().map(ToString::to_string).collect();
+        self.discovered
+            .lock()
+            .unwrap()
+            .insert(service_key.to_string(), keys);
+    }
+
+    pub(crate) fn discovered<V>(&self, service_key: &str, change: &Change<String, V>) {
+        let mut discovered = self.discovered.lock().unwrap();
+        let keys = discovered.entry(service_key.to_string()).or_default();
+        match change {
+            Change::Insert(key, _) => keys.insert(key.clone()),
+            Change::Remove(key) => keys.remove(key),
+        };
+    }
+
+    pub(crate) fn registered(&self, service_key: &str, instance: &str, registered: bool) {
+        let mut registrations = self.registrations.lock().unwrap();
+        let registration = registrations
+            .entry((service_key.to_string(), instance.to_string()))
+            .or_default();
+        registration.registered = registered;
+        if registered {
+            registration.last_renewal = Some(SystemTime::now());
+        }
+    }
+
+    pub(crate) fn renewed(&self, service_key: &str, instance: &str) {
+        let mut registrations = self.registrations.lock().unwrap();
+        registrations
+            .entry((service_key.to_string(), instance.to_string()))
+            .or_default()
+            .last_renewal = Some(SystemTime::now());
+    }
+
+    /// The number of instances currently discovered with service_key
+    pub fn instances(&self, service_key: &str) -> usize {
+        self.discovered
+            .lock()
+            .unwrap()
+            .get(service_key)
+            .map(BTreeSet::len)
+            .unwrap_or(0)
+    }
+
+    /// Render the current state in Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP registry_discovered_instances Instances currently discovered.\n");
+        out.push_str("# TYPE registry_discovered_instances gauge\n");
+        for (service, keys) in self.discovered.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "registry_discovered_instances{{service=\"{}\"}} {}",
+                escape(service),
+                keys.len()
+            );
+        }
+
+        let registrations = self.registrations.lock().unwrap();
+        out.push_str("# HELP registry_registered Whether the instance is registered.\n");
+        out.push_str("# TYPE registry_registered gauge\n");
+        for ((service, instance), registration) in registrations.iter() {
+            let _ = writeln!(
+                out,
+                "registry_registered{{service=\"{}\",instance=\"{}\"}} {}",
+                escape(service),
+                escape(instance),
+                registration.registered as u8
+            );
+        }
+        out.push_str(
+            "# HELP registry_lease_last_renewal_timestamp_seconds Last successful lease renewal.\n",
+        );
+        out.push_str("# TYPE registry_lease_last_renewal_timestamp_seconds gauge\n");
+        for ((service, instance), registration) in registrations.iter() {
+            if let Some(last_renewal) = registration.last_renewal {
+                let timestamp = last_renewal
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                let _ = writeln!(
+                    out,
+                    "registry_lease_last_renewal_timestamp_seconds{{service=\"{}\",instance=\"{}\"}} {:.3}",
+                    escape(service),
+                    escape(instance),
+                    timestamp
+                );
+            }
+        }
+        out
+    }
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A handler to be mounted as `/metrics`
+pub async fn metrics_handler<B, ResBody>(_req: Request<B>) -> Response<ResBody>
+where
+    ResBody: From<String>,
+{
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(ResBody::from(registry_metrics().render()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::RegistryMetrics;
+    use tower::discover::Change;
+
+    #[test]
+    fn test_render() {
+        let metrics = RegistryMetrics::default();
+        metrics.reset_discovered("sys-grpc", ["sys-grpc:a", "sys-grpc:b"]);
+        metrics.discovered::<()>("sys-grpc", &Change::Remove("sys-grpc:a".to_string()));
+        metrics.discovered("sys-grpc", &Change::Insert("sys-grpc:c".to_string(), ()));
+        metrics.registered("sys-grpc", "c", true);
+        assert_eq!(metrics.instances("sys-grpc"), 2);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("registry_discovered_instances{service=\"sys-grpc\"} 2\n"));
+        assert!(rendered.contains("registry_registered{service=\"sys-grpc\",instance=\"c\"} 1\n"));
+        assert!(rendered.contains(
+            "registry_lease_last_renewal_timestamp_seconds{service=\"sys-grpc\",instance=\"c\"}"
+        ));
+    }
+}
@@ -1,19 +1,41 @@
+pub mod breaker;
 pub mod consul;
+pub mod egress;
+pub mod election;
+pub mod endpoint;
 pub mod etcd;
+pub mod health;
+pub mod metrics;
+pub mod weighted;
 
 pub use self::consul::*;
+pub use breaker::*;
+pub use egress::*;
+pub use election::*;
+pub use endpoint::*;
 pub use etcd::*;
+pub use health::*;
+pub use metrics::*;
 use std::collections::HashMap;
+pub use weighted::*;
 
+use crate::config::env::optional_some;
 use crate::config::service::ServiceConf;
 use crate::middleware::consul::ConsulConf;
 use crate::middleware::etcd::EtcdConf;
 use ::consul::agent::AgentCheck;
 use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::future::Future;
 use std::hash::Hash;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::sync::mpsc::Sender;
-use tonic::transport::Endpoint;
+use tonic::transport::{Endpoint, NamedService};
 use tower::discover::Change;
+use tracing::{trace, warn};
 
 /// `service_key` must be unique crossing all service
 /// see [`Resolver::service_key`]
@@ -24,6 +46,27 @@ pub trait ServiceRegister {
     type Error;
 
     async fn register_service(&self, service_key: &str) -> Result<(), Self::Error>;
+
+    /// Register the service only after `ready` resolves, so that no traffic is
+    /// routed to the instance before it could serve, e.p. during rolling deployments.
+    /// Use [wait_ready] to wait for [Resolver::ready].
+    ///
+    /// ```rust,ignore
+    /// registry
+    ///     .register_when_ready(&R::service_key(), wait_ready(&resolver, Duration::from_secs(1)))
+    ///     .await?;
+    /// ```
+    ///
+    /// [wait_ready]: crate::infra::wait_ready
+    /// [Resolver::ready]: crate::infra::Resolver::ready
+    async fn register_when_ready<F>(&self, service_key: &str, ready: F) -> Result<(), Self::Error>
+    where
+        F: Future<Output = ()> + Send,
+        Self: Sync,
+    {
+        ready.await;
+        self.register_service(service_key).await
+    }
 }
 
 #[async_trait]
@@ -40,6 +83,148 @@ where
     ) -> Result<(), Self::Error>;
 }
 
+/// The one-shot listing of a [ServiceDiscover], kept apart so that the existing
+/// implementations of [ServiceDiscover] are not required to list
+#[async_trait]
+pub trait ListInstances<K, V = Endpoint>: ServiceDiscover<K, V>
+where
+    K: Hash + Eq + Send + Clone + 'static,
+{
+    /// Return the current full set of services with `service_key` without
+    /// subscribing to a watch, e.p. for admin/debug endpoints or polling callers.
+    async fn list_instances(
+        &self,
+        service_key: &str,
+    ) -> Result<Vec<DiscoveredService<K, V>>, Self::Error>;
+}
+
+/// A service instance found by [ServiceDiscover]
+#[derive(Clone, Debug)]
+pub struct DiscoveredService<K = String, V = Endpoint> {
+    pub key: K,
+    pub endpoint: V,
+    /// The metadata registered with the instance, see [instance_meta]
+    pub meta: HashMap<String, String>,
+}
+
+/// The metadata key of the deployed version, from environment `APP_VERSION`
+pub const VERSION_META: &str = "version";
+
+/// The metadata key of the deployed zone, from environment `DEPLOY_ZONE`
+pub const ZONE_META: &str = "zone";
+
+/// The metadata key of canary instances, `true` from environment `DEPLOY_CANARY`,
+/// see [CanarySplit]
+pub const CANARY_META: &str = "canary";
+
+/// The metadata key of the weight of an etcd instance, 1 if it is absent, see [Weighted].
+/// Consul instances are weighted by the service weights instead.
+pub const WEIGHT_META: &str = "weight";
+
+/// Whether the metadata of an instance marks it as a canary
+pub fn is_canary(meta: &HashMap<String, String>) -> bool {
+    meta.get(CANARY_META)
+        .is_some_and(|canary| canary.eq_ignore_ascii_case("true"))
+}
+
+/// The metadata registered with an instance, the deployment metadata from the
+/// environments merged with `meta`, the values of `meta` win on conflicts.
+pub fn instance_meta(meta: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut merged = HashMap::new();
+    if let Some(version) = optional_some("APP_VERSION") {
+        merged.insert(VERSION_META.to_string(), version);
+    }
+    if let Some(zone) = optional_some("DEPLOY_ZONE") {
+        merged.insert(ZONE_META.to_string(), zone);
+    }
+    if let Some(canary) = optional_some("DEPLOY_CANARY") {
+        merged.insert(CANARY_META.to_string(), canary);
+    }
+    merged.extend(meta.clone());
+    merged
+}
+
+/// Await a response of a discovery watch, None if no response comes within `timeout`,
+/// e.p. the connection is half-open, then the watch should be restarted.
+async fn unless_stalled<F: Future>(
+    timeout: Duration,
+    service_key: &str,
+    response: F,
+) -> Option<F::Output> {
+    match tokio::time::timeout(timeout, response).await {
+        Ok(output) => Some(output),
+        Err(_) => {
+            warn!(
+                "discovery of {} is stalled for {:?}, restart it",
+                service_key, timeout
+            );
+            None
+        }
+    }
+}
+
+/// Keep a discovery watch of service_key, the changes handled from its responses are
+/// sent to tx until the receiver is dropped. Once the watch ends or stalls, it is
+/// restarted by `rewatch` every `retry` until a new watch is made within `timeout`.
+async fn keep_watching<W, R, Fut, E, H, V>(
+    service_key: &str,
+    timeout: Duration,
+    retry: Duration,
+    mut watch: W,
+    mut rewatch: R,
+    mut handle: H,
+    tx: Sender<Change<String, V>>,
+) where
+    W: Stream + Unpin,
+    R: FnMut() -> Fut,
+    Fut: Future<Output = Result<W, E>>,
+    E: Display,
+    H: FnMut(W::Item) -> Vec<Change<String, V>>,
+{
+    loop {
+        while let Some(Some(response)) = unless_stalled(timeout, service_key, watch.next()).await {
+            for change in handle(response) {
+                registry_metrics().discovered(service_key, &change);
+                if tx.send(change).await.is_err() {
+                    trace!(
+                        "discover receiver of {} is dropped, stop watching",
+                        service_key
+                    );
+                    return;
+                }
+            }
+        }
+        warn!("watch of {} is broken, rewatch", service_key);
+        watch = loop {
+            tokio::time::sleep(retry).await;
+            if tx.is_closed() {
+                trace!(
+                    "discover receiver of {} is dropped, stop watching",
+                    service_key
+                );
+                return;
+            }
+            match unless_stalled(timeout, service_key, rewatch()).await {
+                Some(Ok(watch)) => break watch,
+                Some(Err(err)) => warn!("cannot rewatch {}, err: {}", service_key, err),
+                // stalled as well, rewatch again
+                None => {}
+            }
+        };
+    }
+}
+
+/// The metadata registered with the service, the `meta` of [ServiceConf] merged with
+/// `meta` of the registry options, see [instance_meta]
+fn registered_meta(
+    service: &ServiceConf,
+    meta: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut merged = service.meta.clone();
+    merged.extend(meta.clone());
+    instance_meta(&merged)
+}
+
 // The combination of discovery and registration services.
 // It is not suitable for use in a custom configuration, so
 // it does not derive serde traits.
@@ -50,6 +235,9 @@ pub enum EtcdRegistryOption {
         service: ServiceConf,
         grant_ttl: i64,
         keep_alive_interval: u64,
+        keep_alive_jitter: f64,
+        meta: HashMap<String, String>,
+        encode_meta: bool,
     },
     Discover {
         etcd: EtcdConf,
@@ -67,6 +255,9 @@ impl EtcdRegistryOption {
             service,
             grant_ttl: 61,
             keep_alive_interval: 20,
+            keep_alive_jitter: 0.1,
+            meta: HashMap::new(),
+            encode_meta: false,
         }
     }
 
@@ -87,6 +278,39 @@ impl EtcdRegistryOption {
         }
         self
     }
+
+    /// Spread each keep alive interval randomly by the fraction (e.p. 0.1 for ±10%),
+    /// so instances started together do not renew leases in lockstep. Default is 0.1,
+    /// and the jittered interval never reaches the lease ttl.
+    pub fn keep_alive_jitter(mut self, jitter: f64) -> Self {
+        if let EtcdRegistryOption::Register {
+            keep_alive_jitter, ..
+        } = &mut self
+        {
+            *keep_alive_jitter = jitter.clamp(0.0, 1.0);
+        }
+        self
+    }
+
+    /// Metadata of the registered instance, merged into the `meta` of [ServiceConf]
+    /// and the deployment metadata, see [instance_meta]
+    pub fn meta(mut self, meta: HashMap<String, String>) -> Self {
+        if let EtcdRegistryOption::Register { meta: current, .. } = &mut self {
+            current.extend(meta);
+        }
+        self
+    }
+
+    /// Register the metadata with the instance, its value becomes
+    /// `{"addr": "http://..", "meta": {..}}` instead of the plain discover address.
+    /// Disabled by default, enable it only after all the discovering services are
+    /// upgraded to this version, the older ones cannot parse the address of JSON.
+    pub fn encode_meta(mut self) -> Self {
+        if let EtcdRegistryOption::Register { encode_meta, .. } = &mut self {
+            *encode_meta = true;
+        }
+        self
+    }
 }
 
 impl Default for EtcdRegistryOption {
@@ -139,4 +363,212 @@ impl ConsulRegistryOption {
             weights: None,
         }
     }
+
+    /// Metadata of the registered instance, merged into the `meta` of [ServiceConf]
+    /// and the deployment metadata, see [instance_meta]
+    pub fn meta(mut self, meta: HashMap<String, String>) -> Self {
+        if let ConsulRegistryOption::Register { meta: current, .. } = &mut self {
+            current.get_or_insert_with(HashMap::new).extend(meta);
+        }
+        self
+    }
+
+    /// The consul weights, e.p. `{"Passing": 10, "Warning": 1}`, instead of the
+    /// `weights` of [ServiceConf]
+    pub fn weights(mut self, weights: HashMap<String, i32>) -> Self {
+        if let ConsulRegistryOption::Register {
+            weights: current, ..
+        } = &mut self
+        {
+            *current = Some(weights);
+        }
+        self
+    }
+
+    /// Check the service by a HTTP GET on `path` of the service discover address
+    pub fn with_http_check(
+        self,
+        path: &str,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<Self, CheckAddrError> {
+        self.with_check(|service| {
+            let url = discover_url(service)?
+                .join(path)
+                .map_err(|err| CheckAddrError::new(service, err))?;
+            Ok(json!({
+                "Name": format!("HTTP check on {}", url),
+                "HTTP": url.to_string(),
+                "Method": "GET",
+                "Interval": go_duration(interval),
+                "Timeout": go_duration(timeout),
+            }))
+        })
+    }
+
+    /// Check the service by a TCP connection to the service discover address
+    pub fn with_tcp_check(self, interval: Duration) -> Result<Self, CheckAddrError> {
+        self.with_check(|service| {
+            let tcp = host_port(service)?;
+            Ok(json!({
+                "Name": format!("TCP check on {}", tcp),
+                "TCP": tcp,
+                "Interval": go_duration(interval),
+            }))
+        })
+    }
+
+    /// Check the service by the standard gRPC health checking protocol (`grpc.health.v1.Health`)
+    /// on the service discover address. The health service name is the name of `S`, serve
+    /// [grpc_health_service] with the same `S` so that they always match.
+    pub fn with_grpc_check<S: NamedService>(
+        self,
+        interval: Duration,
+    ) -> Result<Self, CheckAddrError> {
+        self.with_check(|service| {
+            let grpc = format!("{}/{}", host_port(service)?, S::NAME);
+            Ok(json!({
+                "Name": format!("gRPC check on {}", grpc),
+                "GRPC": grpc,
+                "GRPCUseTLS": service.tls_cert.is_some(),
+                "Interval": go_duration(interval),
+            }))
+        })
+    }
+
+    fn with_check(
+        mut self,
+        definition: impl FnOnce(&ServiceConf) -> Result<Value, CheckAddrError>,
+    ) -> Result<Self, CheckAddrError> {
+        if let ConsulRegistryOption::Register { service, check, .. } = &mut self {
+            // build from the JSON form of check definition, it is the same as the consul agent api
+            let agent_check = serde_json::from_value::<AgentCheck>(definition(service)?)
+                .expect("unexpected check definition");
+            *check = Some(Box::new(agent_check));
+        }
+        Ok(self)
+    }
+}
+
+/// The discover address of service cannot be checked
+#[derive(Debug, Error)]
+#[error("invalid discover addr {addr} to check: {reason}")]
+pub struct CheckAddrError {
+    pub addr: String,
+    pub reason: String,
+}
+
+impl CheckAddrError {
+    fn new(service: &ServiceConf, reason: impl Display) -> Self {
+        Self {
+            addr: service.discover_addr.clone(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+fn discover_url(service: &ServiceConf) -> Result<url::Url, CheckAddrError> {
+    url::Url::parse(&service.discover_addr).map_err(|err| CheckAddrError::new(service, err))
+}
+
+/// The `host:port` of the discover address
+fn host_port(service: &ServiceConf) -> Result<String, CheckAddrError> {
+    let url = discover_url(service)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| CheckAddrError::new(service, "no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| CheckAddrError::new(service, "no port"))?;
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Format duration like "1500ms" which could be parsed by golang
+fn go_duration(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::service::ServiceConf;
+    use crate::middleware::consul::ConsulConf;
+    use crate::registry::{keep_watching, ConsulRegistryOption};
+    use futures::stream::{self, BoxStream};
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tower::discover::Change;
+
+    #[tokio::test]
+    async fn test_stalled_watch() {
+        // the first watch stalls after a response like a half-open connection,
+        // so does the first rewatch
+        let watch = stream::iter([1]).chain(stream::pending()).boxed();
+        let mut rewatches: Vec<Option<BoxStream<'static, u32>>> =
+            vec![Some(stream::iter([2, 3]).boxed()), None];
+        let mut rewatched = 0;
+        let rewatch = || {
+            rewatched += 1;
+            let watch = rewatches.pop().flatten();
+            async move {
+                match watch {
+                    Some(watch) => Ok::<_, &str>(watch),
+                    None => futures::future::pending().await,
+                }
+            }
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let timeout = Duration::from_millis(50);
+        let watching = keep_watching(
+            "sys-grpc",
+            timeout,
+            Duration::from_millis(10),
+            watch,
+            rewatch,
+            |n| vec![Change::Insert(n.to_string(), n)],
+            tx,
+        );
+        let receiving = async move {
+            let mut seen = vec![];
+            while seen.len() < 3 {
+                match rx.recv().await {
+                    Some(Change::Insert(_, n)) => seen.push(n),
+                    _ => unreachable!("only inserted"),
+                }
+            }
+            seen
+        };
+        // stops once the receiver is dropped
+        let ((), seen) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(watching, receiving)
+        })
+        .await
+        .unwrap();
+        assert_eq!(seen, [1, 2, 3]);
+        assert_eq!(rewatched, 2);
+    }
+
+    #[test]
+    fn test_check_addr() {
+        let option = |discover_addr: &str| {
+            let service = ServiceConf {
+                discover_addr: discover_addr.to_string(),
+                ..Default::default()
+            };
+            ConsulRegistryOption::register(ConsulConf::default(), service)
+        };
+        let interval = Duration::from_secs(10);
+
+        let checked = option("http://10.0.0.1:8080").with_tcp_check(interval);
+        assert!(matches!(
+            checked,
+            Ok(ConsulRegistryOption::Register { check: Some(_), .. })
+        ));
+        let err = option("10.0.0.1:8080")
+            .with_http_check("/health", interval, interval)
+            .unwrap_err();
+        assert_eq!(err.addr, "10.0.0.1:8080");
+        assert!(option("unix:/run/book.sock")
+            .with_tcp_check(interval)
+            .is_err());
+    }
 }
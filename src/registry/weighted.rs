@@ -0,0 +1,288 @@
+/// Weighted selection of the discovered instances, e.p. to steer traffic by
+/// adjusting weights live or drain an instance before deregistering it.
+///
+/// ```rust,ignore
+/// let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+/// ServiceDiscover::<String, Weighted<Endpoint>>::discover_to_channel(&registry, "sys-grpc", tx).await?;
+/// let mut set = DiscoverySet::new();
+/// while let Some(change) = rx.recv().await {
+///     set.apply(change);
+/// }
+/// let endpoint = set.pick();
+/// ```
+///
+/// A percentage of traffic could be split to the canary instances (registered with
+/// [CANARY_META]) for progressive rollout, see [CanarySplit].
+///
+/// [CANARY_META]: crate::registry::CANARY_META
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tower::discover::Change;
+
+/// A discovered value with its weight, an instance with weight 0 is never picked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Weighted<V> {
+    pub value: V,
+    pub weight: u32,
+    /// A canary instance, only picked by the canary share of [CanarySplit]
+    pub canary: bool,
+}
+
+impl<V> Weighted<V> {
+    pub fn new(value: V, weight: u32) -> Self {
+        Self {
+            value,
+            weight,
+            canary: false,
+        }
+    }
+
+    pub fn canary(mut self, canary: bool) -> Self {
+        self.canary = canary;
+        self
+    }
+}
+
+/// The share of traffic routed to the canary instances, adjustable live as the
+/// clones share it. The traffic falls back to the other subset if one is empty.
+#[derive(Clone, Debug, Default)]
+pub struct CanarySplit {
+    // the share in basis points (1/10000)
+    basis_points: Arc<AtomicU32>,
+}
+
+impl CanarySplit {
+    /// `ratio` (0.0 to 1.0) of traffic is routed to the canary instances
+    pub fn new(ratio: f64) -> Self {
+        let split = Self::default();
+        split.set_ratio(ratio);
+        split
+    }
+
+    pub fn set_ratio(&self, ratio: f64) {
+        let basis_points = (ratio.clamp(0.0, 1.0) * 10_000.0).round() as u32;
+        self.basis_points.store(basis_points, Ordering::Relaxed);
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.basis_points.load(Ordering::Relaxed) as f64 / 10_000.0
+    }
+
+    /// Whether a request goes to the canary, the requests with the same sticky key
+    /// (e.p. the user id) always go to the same subset under the same ratio.
+    pub fn routes(&self, sticky_key: Option<&str>) -> bool {
+        let basis_points = self.basis_points.load(Ordering::Relaxed);
+        let draw = match sticky_key {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % 10_000) as u32
+            }
+            None => rand::thread_rng().gen_range(0..10_000),
+        };
+        draw < basis_points
+    }
+}
+
+#[derive(Debug)]
+struct Instance<K, V> {
+    key: K,
+    value: V,
+    weight: u32,
+    canary: bool,
+    current: i64,
+}
+
+/// The discovered instances picked by smooth weighted round robin. Inserting a
+/// known key updates the instance in place and rebalances the picker without
+/// dropping it, so lowering the weight to 0 drains it gracefully.
+#[derive(Debug)]
+pub struct DiscoverySet<K, V> {
+    instances: Vec<Instance<K, V>>,
+}
+
+impl<K, V> Default for DiscoverySet<K, V> {
+    fn default() -> Self {
+        Self { instances: vec![] }
+    }
+}
+
+impl<K: Eq, V> DiscoverySet<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&mut self, change: Change<K, Weighted<V>>) {
+        match change {
+            Change::Insert(key, weighted) => {
+                let known = self
+                    .instances
+                    .iter_mut()
+                    .find(|instance| instance.key == key);
+                let reweighted = match known {
+                    Some(instance) => {
+                        // a duplicated insert only replaces the value
+                        let reweighted = instance.weight != weighted.weight
+                            || instance.canary != weighted.canary;
+                        instance.value = weighted.value;
+                        instance.weight = weighted.weight;
+                        instance.canary = weighted.canary;
+                        reweighted
+                    }
+                    None => {
+                        self.instances.push(Instance {
+                            key,
+                            value: weighted.value,
+                            weight: weighted.weight,
+                            canary: weighted.canary,
+                            current: 0,
+                        });
+                        true
+                    }
+                };
+                if reweighted {
+                    // restart the round so that the new weights take effect at once
+                    self.instances
+                        .iter_mut()
+                        .for_each(|instance| instance.current = 0);
+                }
+            }
+            Change::Remove(key) => self.instances.retain(|instance| instance.key != key),
+        }
+    }
+
+    /// Pick an instance, None if there is no instance with a positive weight
+    pub fn pick(&mut self) -> Option<&V> {
+        self.pick_in(None)
+    }
+
+    /// Pick an instance of the subset chosen by the split, see [CanarySplit::routes]
+    pub fn pick_split(&mut self, split: &CanarySplit, sticky_key: Option<&str>) -> Option<&V> {
+        let canary = split.routes(sticky_key);
+        let available = |canary: bool| {
+            self.instances
+                .iter()
+                .any(|instance| instance.canary == canary && instance.weight > 0)
+        };
+        let canary = if available(canary) { canary } else { !canary };
+        self.pick_in(Some(canary))
+    }
+
+    /// Smooth weighted round robin in the canary or stable subset, or all instances
+    fn pick_in(&mut self, canary: Option<bool>) -> Option<&V> {
+        let included =
+            |instance: &Instance<K, V>| canary.is_none_or(|canary| instance.canary == canary);
+        let total = self
+            .instances
+            .iter()
+            .filter(|instance| included(instance))
+            .map(|instance| instance.weight as i64)
+            .sum::<i64>();
+        if total == 0 {
+            return None;
+        }
+        let mut picked: Option<&mut Instance<K, V>> = None;
+        for instance in self.instances.iter_mut() {
+            if instance.weight == 0 || !included(instance) {
+                continue;
+            }
+            instance.current += instance.weight as i64;
+            let heavier = match &picked {
+                Some(picked) => instance.current > picked.current,
+                None => true,
+            };
+            if heavier {
+                picked = Some(instance);
+            }
+        }
+        let instance = picked?;
+        instance.current -= total;
+        Some(&instance.value)
+    }
+
+    pub fn weight(&self, key: &K) -> Option<u32> {
+        self.instances
+            .iter()
+            .find(|instance| &instance.key == key)
+            .map(|instance| instance.weight)
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry::{CanarySplit, DiscoverySet, Weighted};
+    use tower::discover::Change;
+
+    fn picks(set: &mut DiscoverySet<&str, &str>, n: usize) -> usize {
+        (0..n).filter(|_| set.pick() == Some(&"b")).count()
+    }
+
+    #[test]
+    fn test_weight_change() {
+        let mut set = DiscoverySet::new();
+        set.apply(Change::Insert("a", Weighted::new("a", 10)));
+        set.apply(Change::Insert("b", Weighted::new("b", 10)));
+        assert_eq!(picks(&mut set, 100), 50);
+
+        // duplicated inserts do not disturb the round
+        set.apply(Change::Insert("b", Weighted::new("b", 10)));
+        set.apply(Change::Insert("b", Weighted::new("b", 10)));
+        assert_eq!(set.len(), 2);
+        assert_eq!(picks(&mut set, 100), 50);
+
+        // b re-registers with a lower weight
+        set.apply(Change::Insert("b", Weighted::new("b", 1)));
+        assert_eq!(set.len(), 2);
+        assert_eq!(picks(&mut set, 110), 10);
+
+        // b is draining
+        set.apply(Change::Insert("b", Weighted::new("b", 0)));
+        assert_eq!(set.len(), 2);
+        assert_eq!(picks(&mut set, 100), 0);
+
+        set.apply(Change::Remove("a"));
+        assert_eq!(set.pick(), None);
+    }
+
+    #[test]
+    fn test_canary_split() {
+        let mut set = DiscoverySet::new();
+        set.apply(Change::Insert("a", Weighted::new("stable", 1)));
+        set.apply(Change::Insert("b", Weighted::new("stable", 1)));
+        set.apply(Change::Insert("c", Weighted::new("canary", 1).canary(true)));
+        let split = CanarySplit::new(0.1);
+        let canary = |set: &mut DiscoverySet<&str, &str>, split: &CanarySplit| {
+            (0..10_000)
+                .filter(|_| set.pick_split(split, None) == Some(&"canary"))
+                .count()
+        };
+        let picked = canary(&mut set, &split);
+        assert!((800..1200).contains(&picked), "{} picks of canary", picked);
+
+        // adjusted live
+        split.clone().set_ratio(0.5);
+        assert_eq!(split.ratio(), 0.5);
+        let picked = canary(&mut set, &split);
+        assert!((4500..5500).contains(&picked), "{} picks of canary", picked);
+
+        // sticky
+        let first = set.pick_split(&split, Some("alice")).copied();
+        assert!((0..100).all(|_| set.pick_split(&split, Some("alice")).copied() == first));
+
+        // fall back to the stable instances
+        set.apply(Change::Remove("c"));
+        assert_eq!(canary(&mut set, &split), 0);
+        assert!(set.pick_split(&split, None).is_some());
+    }
+}
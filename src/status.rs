@@ -17,6 +17,7 @@ pub extern crate faststr;
 pub mod prelude {
     pub use crate::debug_expand;
     pub use crate::status::detail::*;
+    pub use crate::status::error_body::*;
     pub use crate::status::ext::*;
     pub use crate::status::faststr::*;
 }
@@ -632,6 +633,7 @@ pub mod prompt {
 }
 
 pub mod ext {
+    use super::i18n::ErrorCatalog;
     use super::*;
     use crate::{debug_expand, internal};
     use faststr::FastStr;
@@ -742,6 +744,25 @@ pub mod ext {
             }
         }
 
+        /// Replace the message with the translation of code negotiated by `Accept-Language`,
+        /// the locale and message are also attached as [ErrorDetail::LocalizedMessage].
+        /// The original message is kept if code is not translated.
+        pub fn localize(
+            mut self,
+            catalog: &ErrorCatalog,
+            code: &str,
+            accept_language: Option<&str>,
+        ) -> Self {
+            if let Some((locale, message)) = catalog.negotiate(code, accept_language) {
+                self.message = FastStr::new(message);
+                self = self.add_detail(ErrorDetail::LocalizedMessage {
+                    locale: FastStr::new(locale),
+                    message: FastStr::new(message),
+                });
+            }
+            self
+        }
+
         pub fn filter_sensitive_details(mut self, details: &[ErrorDetail]) -> Self {
             for detail in details {
                 if !detail.is_sensitive() {
@@ -1022,3 +1043,366 @@ pub mod ext {
         }
     }
 }
+
+/// Localize user-facing error messages by `Accept-Language`.
+///
+/// ```rust
+/// use common::status::i18n::ErrorCatalog;
+/// use common::status::prelude::*;
+/// use http::StatusCode;
+///
+/// let catalog = ErrorCatalog::default().message("book_not_found", "zh", "书籍不存在。");
+/// let status = HttpStatus::with_code(StatusCode::NOT_FOUND).localize(
+///     &catalog,
+///     "book_not_found",
+///     Some("zh-CN,zh;q=0.9,en;q=0.8"),
+/// );
+/// ```
+pub mod i18n {
+    use super::*;
+    use http::header::ACCEPT_LANGUAGE;
+    use http::Request;
+
+    /// Messages keyed by error code and locale, the builtin codes are the snake case
+    /// names of [Code] (e.p. `not_found`, `permission_denied`) in English and Chinese.
+    #[derive(Clone, Debug)]
+    pub struct ErrorCatalog {
+        default_locale: String,
+        messages: HashMap<String, Vec<(String, String)>>,
+    }
+
+    impl Default for ErrorCatalog {
+        fn default() -> Self {
+            let mut catalog = Self::new("en");
+            for (code, en, zh) in BUILTIN_MESSAGES {
+                catalog = catalog.message(code, "en", en).message(code, "zh", zh);
+            }
+            catalog
+        }
+    }
+
+    impl ErrorCatalog {
+        /// An empty catalog without builtin messages
+        pub fn new(default_locale: impl ToString) -> Self {
+            Self {
+                default_locale: default_locale.to_string(),
+                messages: HashMap::new(),
+            }
+        }
+
+        /// The locale used when none of the accepted languages is translated
+        pub fn default_locale(mut self, locale: impl ToString) -> Self {
+            self.default_locale = locale.to_string();
+            self
+        }
+
+        /// Add or override the message of code in locale
+        pub fn message(
+            mut self,
+            code: impl ToString,
+            locale: impl ToString,
+            message: impl ToString,
+        ) -> Self {
+            let locale = locale.to_string();
+            let translations = self.messages.entry(code.to_string()).or_default();
+            match translations
+                .iter_mut()
+                .find(|(exist, _)| exist.eq_ignore_ascii_case(&locale))
+            {
+                Some((_, exist)) => *exist = message.to_string(),
+                None => translations.push((locale, message.to_string())),
+            }
+            self
+        }
+
+        /// Select the best translation of code by `Accept-Language`, e.p. `zh-CN,zh;q=0.9,en;q=0.8`,
+        /// return `(locale, message)` or `None` if the code is not translated in any acceptable locale.
+        pub fn negotiate(&self, code: &str, accept_language: Option<&str>) -> Option<(&str, &str)> {
+            let translations = self.messages.get(code)?;
+            let find = |tag: &str| {
+                translations
+                    .iter()
+                    .find(|(locale, _)| locale.eq_ignore_ascii_case(tag))
+                    .or_else(|| {
+                        // zh-CN matches zh, en matches en-US
+                        let primary = primary_subtag(tag);
+                        translations.iter().find(|(locale, _)| {
+                            primary_subtag(locale).eq_ignore_ascii_case(primary)
+                        })
+                    })
+                    .map(|(locale, message)| (locale.as_str(), message.as_str()))
+            };
+            parse_accept_language(accept_language.unwrap_or_default())
+                .into_iter()
+                .find_map(|tag| if tag == "*" { None } else { find(tag) })
+                .or_else(|| find(&self.default_locale))
+        }
+
+        /// Same as [ErrorCatalog::negotiate] with the `Accept-Language` of request
+        pub fn negotiate_request<B>(&self, code: &str, req: &Request<B>) -> Option<(&str, &str)> {
+            let accept_language = req
+                .headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok());
+            self.negotiate(code, accept_language)
+        }
+    }
+
+    fn primary_subtag(tag: &str) -> &str {
+        tag.split(['-', '_']).next().unwrap_or(tag)
+    }
+
+    /// Language tags sorted by quality, tags with `q=0` are excluded
+    fn parse_accept_language(header: &str) -> Vec<&str> {
+        let mut tags: Vec<(&str, f32)> = header
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let tag = parts.next().filter(|tag| !tag.is_empty())?;
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map(|q| q.parse().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                (quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        // stable sort keeps the order of tags with the same quality
+        tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+        tags.into_iter().map(|(tag, _)| tag).collect()
+    }
+
+    /// The snake case name of code, used as the builtin error code
+    pub fn code_name(code: Code) -> &'static str {
+        match code {
+            Code::Ok => "ok",
+            Code::Cancelled => "cancelled",
+            Code::Unknown => "unknown",
+            Code::InvalidArgument => "invalid_argument",
+            Code::DeadlineExceeded => "deadline_exceeded",
+            Code::NotFound => "not_found",
+            Code::AlreadyExists => "already_exists",
+            Code::PermissionDenied => "permission_denied",
+            Code::ResourceExhausted => "resource_exhausted",
+            Code::FailedPrecondition => "failed_precondition",
+            Code::Aborted => "aborted",
+            Code::OutOfRange => "out_of_range",
+            Code::Unimplemented => "unimplemented",
+            Code::Internal => "internal",
+            Code::Unavailable => "unavailable",
+            Code::DataLoss => "data_loss",
+            Code::Unauthenticated => "unauthenticated",
+        }
+    }
+
+    const BUILTIN_MESSAGES: [(&str, &str, &str); 16] = [
+        (
+            "cancelled",
+            "Request cancelled by the client.",
+            "请求已被客户端取消。",
+        ),
+        ("unknown", "Unknown error.", "未知错误。"),
+        (
+            "invalid_argument",
+            "Request field is invalid.",
+            "请求参数无效。",
+        ),
+        ("deadline_exceeded", "Gateway timeout.", "网关超时。"),
+        ("not_found", "Resource not found.", "资源不存在。"),
+        ("already_exists", "Resource already exists.", "资源已存在。"),
+        ("permission_denied", "Permission denied.", "权限不足。"),
+        ("resource_exhausted", "Too many requests.", "请求过于频繁。"),
+        ("failed_precondition", "Operation failed.", "操作失败。"),
+        ("aborted", "Request aborted.", "请求已中止。"),
+        (
+            "out_of_range",
+            "Parameter is out of range.",
+            "参数超出范围。",
+        ),
+        ("unimplemented", "Not implemented.", "功能未实现。"),
+        ("internal", "Internal error.", "内部错误。"),
+        ("unavailable", "Service Unavailable.", "服务不可用。"),
+        ("data_loss", "Internal error.", "内部错误。"),
+        (
+            "unauthenticated",
+            "Invalid authentication credentials.",
+            "身份认证凭据无效。",
+        ),
+    ];
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_negotiate() {
+            let catalog = ErrorCatalog::default().message("not_found", "zh-TW", "資源不存在。");
+            let negotiate = |accept| catalog.negotiate("not_found", accept).unwrap().0;
+            assert_eq!(negotiate(Some("zh-CN,zh;q=0.9,en;q=0.8")), "zh");
+            assert_eq!(negotiate(Some("zh-TW")), "zh-TW");
+            assert_eq!(negotiate(Some("en-US,zh;q=0.5")), "en");
+            assert_eq!(negotiate(Some("fr;q=0.9, zh;q=0.95")), "zh");
+            assert_eq!(negotiate(Some("zh;q=0, fr")), "en");
+            assert_eq!(negotiate(Some("*")), "en");
+            assert_eq!(negotiate(None), "en");
+            assert!(catalog.negotiate("book_not_found", None).is_none());
+        }
+    }
+}
+
+/// A uniform JSON error body of the responses rejected by the crate layers, so that
+/// the clients could handle the errors the same way regardless of which layer rejected:
+///
+/// ```json
+/// {"code":"too_many_requests","message":"Too Many Requests","request_id":"..."}
+/// ```
+pub mod error_body {
+    use super::*;
+    use http::header::CONTENT_TYPE;
+    use http::{HeaderValue, Request, Response};
+
+    /// The id of the request in its extensions, echoed by [ErrorBody]
+    #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    pub struct RequestId(pub String);
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct ErrorBody {
+        /// The snake case reason, e.p. `payload_too_large`
+        pub code: String,
+        pub message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub request_id: Option<String>,
+    }
+
+    impl ErrorBody {
+        pub fn new(code: impl ToString, message: impl ToString) -> Self {
+            Self {
+                code: code.to_string(),
+                message: message.to_string(),
+                request_id: None,
+            }
+        }
+
+        /// The body of status with its canonical reason, e.p. `429` => `too_many_requests`
+        pub fn from_status(status: StatusCode) -> Self {
+            let reason = status.canonical_reason().unwrap_or("Unknown");
+            Self::new(reason.to_ascii_lowercase().replace([' ', '-'], "_"), reason)
+        }
+
+        /// Echo the [RequestId] of request if there is one
+        pub fn of_request<B>(mut self, req: &Request<B>) -> Self {
+            self.request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+            self
+        }
+
+        /// A response with the JSON body, left empty if ResBody cannot carry it,
+        /// see [ErrorResponseBody]
+        pub fn into_response<ResBody: ErrorResponseBody>(
+            self,
+            status: StatusCode,
+        ) -> Response<ResBody> {
+            let mut res = match ResBody::from_json(self.to_json()) {
+                Some(body) => {
+                    let mut res = Response::new(body);
+                    res.headers_mut()
+                        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    res
+                }
+                None => Response::new(ResBody::default()),
+            };
+            *res.status_mut() = status;
+            res
+        }
+
+        /// The JSON of the body
+        pub fn to_json(&self) -> Vec<u8> {
+            serde_json::to_vec(self).expect("ErrorBody is serializable")
+        }
+    }
+
+    /// A response body the [ErrorBody] is rendered into by the layers.
+    ///
+    /// Implement it for a custom body type, the default `from_json` leaves the body
+    /// empty, e.p. `impl ErrorResponseBody for MyBody {}`.
+    pub trait ErrorResponseBody: Default {
+        /// The body carrying the JSON, None to respond with an empty body
+        fn from_json(json: Vec<u8>) -> Option<Self> {
+            let _ = json;
+            None
+        }
+    }
+
+    impl ErrorResponseBody for String {
+        fn from_json(json: Vec<u8>) -> Option<Self> {
+            String::from_utf8(json).ok()
+        }
+    }
+
+    impl ErrorResponseBody for Vec<u8> {
+        fn from_json(json: Vec<u8>) -> Option<Self> {
+            Some(json)
+        }
+    }
+
+    impl ErrorResponseBody for Bytes {
+        fn from_json(json: Vec<u8>) -> Option<Self> {
+            Some(Bytes::from(json))
+        }
+    }
+
+    impl ErrorResponseBody for http_body::Full<Bytes> {
+        fn from_json(json: Vec<u8>) -> Option<Self> {
+            Some(http_body::Full::new(Bytes::from(json)))
+        }
+    }
+
+    impl ErrorResponseBody for tonic::transport::Body {
+        fn from_json(json: Vec<u8>) -> Option<Self> {
+            Some(tonic::transport::Body::from(json))
+        }
+    }
+
+    /// The gRPC clients read the status from the trailers rather than the body
+    impl ErrorResponseBody for tonic::body::BoxBody {}
+
+    impl ErrorResponseBody for () {}
+
+    /// The response of status with the [ErrorBody] of request
+    pub fn error_response<B, ResBody: ErrorResponseBody>(
+        req: &Request<B>,
+        status: StatusCode,
+    ) -> Response<ResBody> {
+        ErrorBody::from_status(status)
+            .of_request(req)
+            .into_response(status)
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_error_body() {
+            let mut req = Request::new(());
+            req.extensions_mut().insert(RequestId("req-1".to_string()));
+            let res = error_response::<_, String>(&req, StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+            assert_eq!(
+                res.body(),
+                r#"{"code":"payload_too_large","message":"Payload Too Large","request_id":"req-1"}"#
+            );
+
+            let res = error_response::<_, Bytes>(&Request::new(()), StatusCode::TOO_MANY_REQUESTS);
+            let body: ErrorBody = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(
+                body,
+                ErrorBody::new("too_many_requests", "Too Many Requests")
+            );
+
+            // not a JSON body
+            let res = error_response::<_, ()>(&req, StatusCode::FORBIDDEN);
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert!(res.headers().get(CONTENT_TYPE).is_none());
+        }
+    }
+}
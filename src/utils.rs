@@ -1,63 +1,268 @@
-use crate::config::env::optional;
+use crate::config::env::{optional, optional_some, EnvVarDoc};
 use crate::infra::Resolver;
 use crate::middleware::apollo::{Apollo, ApolloConf};
-use crate::middleware::nacos::{Nacos, NacosConf};
+use crate::middleware::nacos::{Nacos, NacosConf, NacosConfigClient};
 use crate::middleware::Middleware;
+use crate::utils::cache::{fetch_or_cached, ConfigCache};
 use colored::Colorize;
+use http::header::CONTENT_TYPE;
+use http::{Response, StatusCode};
 use kosei::{Config, ConfigType};
 use serde::Serialize;
 use std::cmp::Ordering;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+pub mod cache;
+pub mod clock;
+pub mod deserialize;
+pub mod diff;
+pub mod merge;
+pub mod multipart;
+pub mod pagination;
+pub mod reload;
+pub mod routing;
+pub mod startup;
+
+pub use deserialize::ConfigError;
+pub use diff::{config_diff, FieldChange};
+pub use merge::merge_value;
+pub use startup::startup_report;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// Load the configuration from the sources listed in environment `CONFIG_CHAIN`
+/// in priority order, e.p. `CONFIG_CHAIN=nacos,file,default`. The next source
+/// is tried if the previous one fails, and the failure reason is logged.
+///
+/// Sources are `apollo`, `nacos`, `etcd`, `consul`, `file` (fails if no file found)
+/// and `default`. Use [reload::ConfigWatcher] to hot reload from etcd or consul.
+/// Without `CONFIG_CHAIN`, the single source `CONFIG_TYPE` is used, where `file`
+/// falls back to `default` as before.
+///
+/// The `file` source reads `CONFIG_PATH`, which could be comma separated paths
+/// like `config/base.yml,config/prod.yml`, the files are deep merged in order,
+/// see [merge] for the merge behavior.
+///
+/// A malformed configuration fails with [ConfigError::Deserialize], which tells
+/// the source, the field path and the line if possible.
+///
+/// The `etcd` and `consul` sources fall back to the last-known-good configuration
+/// cached in `CONFIG_CACHE_PATH` when they are unreachable, see [cache].
+///
+/// With feature `unique-service-key`, it panics if the registered resolvers share
+/// a service key, see [register_resolver](crate::register_resolver).
 pub async fn parse_config<R: Resolver>() -> Result<R::Config, Error> {
-    let typ = optional("CONFIG_TYPE", "file");
-    match typ.to_lowercase().as_str() {
-        "file" => {
-            let path = optional("CONFIG_PATH", "config");
-            let path: &Path = path.as_ref();
-
-            // parse config from directory with service_domain
-            if path.is_dir() {
-                let path = path.join(format!(
-                    "{}.{}.{}",
-                    R::DOMAIN,
-                    R::TARGET,
-                    optional("CONFIG_FILETYPE", "yml")
-                ));
-                if path.exists() {
-                    return Ok(Config::<R::Config>::from_file(path).into_inner());
+    #[cfg(feature = "unique-service-key")]
+    crate::infra::assert_unique_service_keys();
+    let chain = match std::env::var("CONFIG_CHAIN") {
+        Ok(chain) => chain,
+        Err(_) => match optional("CONFIG_TYPE", "file").to_lowercase().as_str() {
+            "file" => "file,default".to_string(),
+            typ => typ.to_string(),
+        },
+    };
+    let mut last_err: Option<Error> = None;
+    for source in chain.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match parse_config_from::<R>(&source.to_lowercase()).await {
+            Ok(config) => {
+                info!("configuration is loaded from {}", source);
+                startup::record_config_source(source);
+                return Ok(config);
+            }
+            Err(err) => {
+                warn!("cannot load configuration from {}, err: {}", source, err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| format!("no configuration source in '{}'", chain).into()))
+}
+
+/// The files of `CONFIG_PATH` in merge order. A directory gives the file
+/// `{domain}.{target}.{env}.{CONFIG_FILETYPE}` in it if exists, where the env is
+/// `CONFIG_ENV` or `APP_ENV` (e.p. `sys.grpc.prod.yml`), then the file
+/// `{domain}.{target}.{CONFIG_FILETYPE}`, otherwise all its configuration files
+/// sorted by name.
+fn config_files<R: Resolver>(paths: &str) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    for path in paths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let path: &Path = path.as_ref();
+        if path.is_dir() {
+            let env = optional_some("CONFIG_ENV").or_else(|| optional_some("APP_ENV"));
+            let ext = optional("CONFIG_FILETYPE", "yml");
+            match named_config_file(path, R::DOMAIN, R::TARGET, env.as_deref(), &ext) {
+                Some(file) => {
+                    info!("configuration file {} is chosen", file.display());
+                    files.push(file);
                 }
+                None => files.extend(merge::dir_files(path)?),
             }
-            if path.exists() {
-                return Ok(Config::<R::Config>::from_file(path).into_inner());
+        } else if path.is_file() {
+            files.push(path.to_path_buf());
+        } else if paths.contains(',') {
+            // a missing overlay would silently change the merged result
+            return Err(format!("configuration file {} is not found", path.display()).into());
+        }
+    }
+    Ok(files)
+}
+
+/// The env-specific file of dir if exists, then the file without env
+fn named_config_file(
+    dir: &Path,
+    domain: &str,
+    target: &str,
+    env: Option<&str>,
+    ext: &str,
+) -> Option<PathBuf> {
+    let env_file = env
+        .filter(|env| !env.is_empty())
+        .map(|env| dir.join(format!("{}.{}.{}.{}", domain, target, env, ext)));
+    let file = dir.join(format!("{}.{}.{}", domain, target, ext));
+    env_file
+        .into_iter()
+        .chain([file])
+        .find(|file| file.exists())
+}
+
+async fn parse_config_from<R: Resolver>(source: &str) -> Result<R::Config, Error> {
+    match source {
+        "file" => {
+            let paths = optional("CONFIG_PATH", "config");
+            let files = config_files::<R>(&paths)?;
+            if files.is_empty() {
+                return Err(format!("no configuration file found in {}", paths).into());
             }
-            Ok(Config::<R::Config>::new("".to_string(), ConfigType::YAML).into_inner())
+            merge::load_files(&files)
         }
+        "default" => Ok(Config::<R::Config>::new("".to_string(), ConfigType::YAML).into_inner()),
         "apollo" => {
-            let apollo = Apollo::new(ApolloConf::default());
-            let client = apollo.make_client().await.unwrap();
-
-            Ok(Config::<R::Config>::from_apollo(&client)
-                .await?
-                .into_inner())
+            let conf = ApolloConf::default();
+            let origin = format!("apollo {}", conf.namespace);
+            let fetch = async {
+                let client = Apollo::new(conf).make_client().await?;
+                let tree = Config::<serde_json::Value>::from_apollo(&client)
+                    .await?
+                    .into_inner();
+                render_tree(tree)
+            };
+            let cache = ConfigCache::from_env();
+            fetch_or_cached(cache.as_ref(), &origin, ConfigFormat::JSON, fetch).await
         }
         "nacos" => {
-            let nacos = Nacos::new(NacosConf::default());
-            let mut client = nacos.make_client().await.unwrap();
-
-            Ok(Config::<R::Config>::from_nacos(&mut client)
-                .await?
-                .into_inner())
+            let conf = NacosConf::default();
+            let origin = format!("nacos {}", conf.data_id);
+            let fetch = async {
+                let tree = match Nacos::new(conf).make_client().await? {
+                    NacosConfigClient::Kosei(mut client) => {
+                        Config::<serde_json::Value>::from_nacos(&mut client)
+                            .await?
+                            .into_inner()
+                    }
+                    NacosConfigClient::AccessKey(client) => client.fetch().await?,
+                };
+                render_tree(tree)
+            };
+            let cache = ConfigCache::from_env();
+            fetch_or_cached(cache.as_ref(), &origin, ConfigFormat::JSON, fetch).await
         }
-        _ => panic!("unsupported config type"),
+        "etcd" => reload::load_etcd(&reload::config_key::<R>()).await,
+        "consul" => reload::load_consul(&reload::config_key::<R>()).await,
+        _ => Err(format!("unsupported config type {}", source).into()),
     }
 }
 
+/// Render the tree parsed by kosei (apollo and nacos) as JSON, so that it is deserialized
+/// and cached the same as the content of other sources, e.p. `enc:` values are decrypted
+fn render_tree(tree: serde_json::Value) -> Result<String, Error> {
+    Ok(serde_json::to_string(&tree)?)
+}
+
+/// The format used to render the configuration in [config_tips_fmt]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    JSON,
+    YAML,
+    TOML,
+}
+
 pub fn config_tips<T: Serialize>(config: &T) {
-    let tips = "That is your configuration";
-    let words = serde_json::to_string_pretty(&config).unwrap();
+    config_tips_fmt(config, ConfigFormat::JSON)
+}
+
+/// Same as [config_tips] but render the configuration with the specified format,
+/// fallback to JSON if the configuration cannot be represented in the format.
+pub fn config_tips_fmt<T: Serialize>(config: &T, format: ConfigFormat) {
+    let words = match format {
+        ConfigFormat::JSON => Ok(serde_json::to_string_pretty(config).unwrap()),
+        ConfigFormat::YAML => serde_yaml::to_string(config).map_err(|e| e.to_string()),
+        ConfigFormat::TOML => toml::to_string_pretty(config).map_err(|e| e.to_string()),
+    };
+    let words = words.unwrap_or_else(|err| {
+        warn!(
+            "cannot render configuration as {:?}, fallback to JSON, err: {}",
+            format, err
+        );
+        serde_json::to_string_pretty(config).unwrap()
+    });
+    print_banner(&words, "That is your configuration");
+}
+
+/// Respond the configuration as JSON, the secrets are redacted the same as
+/// [config_diff], including the fields named like a secret and the passwords in
+/// connection strings. Mount it behind an authenticated admin route (e.p. with
+/// [HmacAuthLayer]) since the configuration still reveals the topology.
+/// Serve the current value of [ConfigWatcher] for the live configuration:
+///
+/// ```rust,ignore
+/// let (conf, _handle) = ConfigWatcher::<R::Config>::new().watch::<R>().await?;
+/// let handler = move |_req| {
+///     let current = conf.borrow().clone();
+///     async move { config_handler::<_, Body>(&*current) }
+/// };
+/// ```
+///
+/// [HmacAuthLayer]: crate::layer::HmacAuthLayer
+/// [ConfigWatcher]: crate::utils::reload::ConfigWatcher
+pub fn config_handler<T: Serialize, ResBody: From<String>>(config: &T) -> Response<ResBody> {
+    let json = diff::redacted(config).and_then(|value| serde_json::to_string_pretty(&value));
+    let (status, body) = match json {
+        Ok(json) => (StatusCode::OK, json),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(ResBody::from(body))
+        .unwrap()
+}
+
+/// Render the environments read by configs, e.p.
+/// `env_tips([RedisConf::describe_env(), S3Conf::describe_env()])` for a `--print-env` flag
+pub fn env_tips<I: IntoIterator<Item = Vec<EnvVarDoc>>>(confs: I) {
+    print_banner(&render_env(confs), "That is your environments");
+}
+
+fn render_env<I: IntoIterator<Item = Vec<EnvVarDoc>>>(confs: I) -> String {
+    let mut keys = Vec::new();
+    let mut lines = Vec::new();
+    for doc in confs.into_iter().flatten() {
+        if keys.contains(&doc.key) {
+            continue;
+        }
+        lines.push(match (doc.required, doc.default) {
+            (true, _) => format!("{} (required)", doc.key),
+            (false, Some(default)) => format!("{} = '{}'", doc.key, default),
+            (false, None) => format!("{} (optional)", doc.key),
+        });
+        keys.push(doc.key);
+    }
+    lines.join("\n")
+}
+
+fn print_banner(words: &str, tips: &str) {
     let mut format_lines = vec!["╭".to_string()];
     for line in words.lines() {
         format_lines.push(format!("│ {}", line))
@@ -155,3 +360,208 @@ pub mod regex {
         assert!(!check_us_phone("igxnon@gmailcom"));
     }
 }
+
+/// Change the tracing filter at runtime without restarting the service,
+/// e.p. turning on debug logging on a misbehaving instance temporarily.
+///
+/// ```rust,no_run
+/// use common::utils::logging;
+///
+/// logging::init("info");
+/// // mount `logging::log_level_handler` as `/loglevel` admin route, or
+/// logging::set_filter("info,common=debug").unwrap();
+/// ```
+pub mod logging {
+    use crate::layer::to_bytes;
+    use http::{Method, Request, Response, StatusCode};
+    use once_cell::sync::OnceCell;
+    use std::sync::Mutex;
+    use thiserror::Error;
+    use tracing::Level;
+    use tracing_subscriber::filter::ParseError;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+    struct Reloadable {
+        handle: reload::Handle<EnvFilter, Registry>,
+        // the directive of the current filter, it is also used to serialize updates
+        directive: Mutex<String>,
+    }
+
+    static RELOADABLE: OnceCell<Reloadable> = OnceCell::new();
+
+    #[derive(Debug, Error)]
+    pub enum LoggingError {
+        #[error("logging is not initialized by logging::init")]
+        NotInitialized,
+        #[error("invalid filter directive: {0}")]
+        InvalidDirective(#[from] ParseError),
+        #[error("cannot reload filter: {0}")]
+        Reload(#[from] reload::Error),
+    }
+
+    /// Install the global subscriber with a reloadable filter.
+    /// The initial filter is read from environment `RUST_LOG`, or `default_directive`
+    /// if it is absent or invalid.
+    pub fn init(default_directive: &str) {
+        let (filter, directive) = initial_filter(
+            std::env::var(EnvFilter::DEFAULT_ENV).ok(),
+            default_directive,
+        );
+        let (filter, handle) = reload::Layer::new(filter);
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt::layer())
+            .init();
+        let _ = RELOADABLE.set(Reloadable {
+            handle,
+            directive: Mutex::new(directive),
+        });
+    }
+
+    /// The initial filter and the directive of it in effect
+    fn initial_filter(env: Option<String>, default_directive: &str) -> (EnvFilter, String) {
+        if let Some(directive) = env {
+            match EnvFilter::try_new(&directive) {
+                Ok(filter) => return (filter, directive),
+                Err(err) => eprintln!("invalid filter directive {}, err: {}", directive, err),
+            }
+        }
+        (
+            EnvFilter::new(default_directive),
+            default_directive.to_string(),
+        )
+    }
+
+    /// Replace the global filter with a directive like `info,common=debug`
+    pub fn set_filter(directive: &str) -> Result<(), LoggingError> {
+        let reloadable = RELOADABLE.get().ok_or(LoggingError::NotInitialized)?;
+        let filter = EnvFilter::try_new(directive)?;
+        let mut current = reloadable.directive.lock().unwrap();
+        reloadable.handle.reload(filter)?;
+        *current = directive.to_string();
+        Ok(())
+    }
+
+    /// Replace the global filter with a max level
+    pub fn set_level(level: Level) -> Result<(), LoggingError> {
+        set_filter(level.as_str())
+    }
+
+    /// The directive of the current filter
+    pub fn current_filter() -> Option<String> {
+        RELOADABLE
+            .get()
+            .map(|reloadable| reloadable.directive.lock().unwrap().clone())
+    }
+
+    /// A handler to be mounted as an admin route (e.p. `/loglevel`)
+    /// `GET` returns the current filter, `PUT`/`POST` replaces it with the directive in body.
+    pub async fn log_level_handler<B, ResBody>(req: Request<B>) -> Response<ResBody>
+    where
+        B: http_body::Body,
+        B::Error: std::fmt::Display,
+        ResBody: From<String>,
+    {
+        let respond = |status: StatusCode, body: String| {
+            Response::builder()
+                .status(status)
+                .body(ResBody::from(body))
+                .unwrap()
+        };
+        match *req.method() {
+            Method::GET => match current_filter() {
+                Some(directive) => respond(StatusCode::OK, directive),
+                None => respond(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    LoggingError::NotInitialized.to_string(),
+                ),
+            },
+            Method::PUT | Method::POST => {
+                let body = match to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return respond(StatusCode::BAD_REQUEST, err.to_string()),
+                };
+                let directive = String::from_utf8_lossy(&body).trim().to_string();
+                match set_filter(&directive) {
+                    Ok(_) => respond(StatusCode::OK, directive),
+                    Err(err @ LoggingError::InvalidDirective(_)) => {
+                        respond(StatusCode::BAD_REQUEST, err.to_string())
+                    }
+                    Err(err) => respond(StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+                }
+            }
+            _ => respond(StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        }
+    }
+
+    #[cfg(test)]
+    #[test]
+    fn test_initial_filter() {
+        let directive = |env: Option<&str>| initial_filter(env.map(ToString::to_string), "info").1;
+        assert_eq!(directive(Some("warn,common=debug")), "warn,common=debug");
+        // falls back to the default one in effect
+        assert_eq!(directive(Some("common=verbose")), "info");
+        assert_eq!(directive(None), "info");
+
+        let (filter, _) = initial_filter(Some("common=verbose".to_string()), "info");
+        assert_eq!(filter.to_string(), "info");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::secret::Secret;
+    use crate::utils::{config_handler, named_config_file};
+    use http::StatusCode;
+    use serde::Serialize;
+
+    #[test]
+    fn test_named_config_file() {
+        let dir = std::env::temp_dir().join(format!("config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let named = |env| named_config_file(&dir, "sys", "grpc", env, "yml");
+        assert_eq!(named(Some("prod")), None);
+
+        std::fs::write(dir.join("sys.grpc.yml"), "").unwrap();
+        assert_eq!(named(None), Some(dir.join("sys.grpc.yml")));
+        assert_eq!(named(Some("prod")), Some(dir.join("sys.grpc.yml")));
+
+        std::fs::write(dir.join("sys.grpc.prod.yml"), "").unwrap();
+        assert_eq!(named(Some("prod")), Some(dir.join("sys.grpc.prod.yml")));
+        assert_eq!(named(Some("")), Some(dir.join("sys.grpc.yml")));
+        assert_eq!(named(None), Some(dir.join("sys.grpc.yml")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_handler() {
+        #[derive(Serialize)]
+        struct Conf {
+            addr: String,
+            password: Secret<String>,
+            token: String,
+            database: String,
+        }
+        let conf = Conf {
+            addr: "127.0.0.1:6379".to_string(),
+            password: Secret::new("hunter2".to_string()),
+            token: "s.vault".to_string(),
+            database: "postgres://app:hunter2@db:5432/app?sslmode=disable".to_string(),
+        };
+        let resp = config_handler::<_, String>(&conf);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_str(resp.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "addr": "127.0.0.1:6379",
+                "password": "***",
+                "token": "***",
+                "database": "postgres://app:***@db:5432/app?sslmode=disable",
+            })
+        );
+    }
+}
@@ -0,0 +1,173 @@
+/// The last-known-good configuration of the remote sources (apollo/nacos/etcd/consul),
+/// so that a service could still start during a config-plane outage.
+///
+/// Caching is enabled by environment `CONFIG_CACHE_PATH`, a configuration loaded
+/// successfully is written to the file (readable by the owner only), and it is used
+/// instead when the source is unreachable on a subsequent startup. Apollo and Nacos
+/// are cached as JSON since their configurations are parsed by kosei.
+use crate::config::env::optional_some;
+use crate::config::ConfigType;
+use crate::utils::deserialize::deserialize_str;
+use crate::utils::startup::record_degraded;
+use crate::utils::ConfigFormat;
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{error, warn};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct ConfigCache {
+    path: PathBuf,
+}
+
+impl ConfigCache {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub(crate) fn from_env() -> Option<Self> {
+        optional_some("CONFIG_CACHE_PATH").map(Self::new)
+    }
+
+    /// Replace the cache atomically, a failure is only logged
+    fn store(&self, content: &str) {
+        let tmp = self.path.with_extension("tmp");
+        let res = write_private(&tmp, content).and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(err) = res {
+            warn!(
+                "cannot cache configuration to {}, err: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    /// The cached content and how long ago it was cached
+    fn load(&self) -> Result<(String, Duration), Error> {
+        let content = std::fs::read_to_string(&self.path)?;
+        let staleness = std::fs::metadata(&self.path)?
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+        Ok((content, staleness))
+    }
+}
+
+/// Write the file readable by the owner only, the configuration may contain secrets
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    // a stale file may be created with other permissions
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+/// Fetch the content in the format from the remote source, fall back to the cache if it fails
+pub(crate) async fn fetch_or_cached<T: ConfigType>(
+    cache: Option<&ConfigCache>,
+    origin: &str,
+    format: ConfigFormat,
+    fetch: impl Future<Output = Result<String, Error>>,
+) -> Result<T, Error> {
+    let err = match fetch.await {
+        Ok(content) => {
+            // only a good configuration is cached
+            let config = deserialize_str(&content, format, origin)?;
+            if let Some(cache) = cache {
+                cache.store(&content);
+            }
+            return Ok(config);
+        }
+        Err(err) => err,
+    };
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return Err(err),
+    };
+    let (content, staleness) = match cache.load() {
+        Ok(cached) => cached,
+        Err(cache_err) => {
+            warn!(
+                "no cached configuration in {}, err: {}",
+                cache.path.display(),
+                cache_err
+            );
+            return Err(err);
+        }
+    };
+    error!(
+        "cannot load configuration from {}, USING THE CACHED CONFIGURATION in {} which is {:?} stale, err: {}",
+        origin,
+        cache.path.display(),
+        staleness,
+        err
+    );
+    record_degraded(
+        "configuration",
+        &format!("cached {:?} ago, {} is unreachable", staleness, origin),
+    );
+    let origin = format!("cache {}", cache.path.display());
+    Ok(deserialize_str(&content, format, &origin)?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::cache::{fetch_or_cached, ConfigCache};
+    use crate::utils::ConfigFormat;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+    struct MyConfig {
+        name: String,
+    }
+
+    const YAML: ConfigFormat = ConfigFormat::YAML;
+
+    #[tokio::test]
+    async fn test_fetch_or_cached() {
+        let path = std::env::temp_dir().join(format!("common-cache-{}.yml", std::process::id()));
+        let cache = ConfigCache::new(&path);
+
+        let config: MyConfig = fetch_or_cached(Some(&cache), "etcd config/sys", YAML, async {
+            Ok("name: user".to_string())
+        })
+        .await
+        .unwrap();
+        assert_eq!(config.name, "user");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // the source is unreachable
+        let unreachable = || async { Err("connection refused".into()) };
+        let config: MyConfig =
+            fetch_or_cached(Some(&cache), "etcd config/sys", YAML, unreachable())
+                .await
+                .unwrap();
+        assert_eq!(config.name, "user");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            fetch_or_cached::<MyConfig>(Some(&cache), "etcd config/sys", YAML, unreachable())
+                .await
+                .is_err()
+        );
+        assert!(
+            fetch_or_cached::<MyConfig>(None, "etcd config/sys", YAML, unreachable())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,141 @@
+/// The time source of time-dependent features (circuit breaker, idempotency TTL, etc.),
+/// so that expiry and backoff could be tested deterministically with [MockClock]
+/// (feature `mock-clock`). [SystemClock] is used by default.
+///
+/// ```rust,ignore
+/// let clock = MockClock::new();
+/// let breaker = CircuitBreaker::new("etcd").clock(clock.clone());
+/// // ... open the circuit
+/// clock.advance(Duration::from_secs(10));
+/// ```
+use futures::future::BoxFuture;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The clock shared by the features
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The real clock, backed by [Instant] and the tokio timer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+pub(crate) fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves by [MockClock::advance], the sleeps wake up once the
+/// clock is advanced past their deadlines.
+#[cfg(any(test, feature = "mock-clock"))]
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Arc<tokio::sync::watch::Sender<Duration>>,
+}
+
+#[cfg(any(test, feature = "mock-clock"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "mock-clock"))]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Arc::new(tokio::sync::watch::channel(Duration::ZERO).0),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+}
+
+#[cfg(any(test, feature = "mock-clock"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut rx = self.elapsed.subscribe();
+        let deadline = *rx.borrow() + duration;
+        Box::pin(async move {
+            while *rx.borrow_and_update() < deadline {
+                // the clock is dropped, never wake up like a real sleep far in future
+                if rx.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry::{CircuitBreaker, CircuitOpen};
+    use crate::utils::clock::{Clock, MockClock};
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq)]
+    enum Error {
+        Backend,
+        Open,
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self)
+        }
+    }
+
+    impl From<CircuitOpen> for Error {
+        fn from(_: CircuitOpen) -> Self {
+            Error::Open
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let sleep = tokio::spawn(clock.sleep(Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(5));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        clock.advance(Duration::from_secs(5));
+        sleep.await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+
+        // the circuit is opened for an hour without waiting for it
+        let breaker = CircuitBreaker::new("test")
+            .retries(0)
+            .failure_threshold(1)
+            .open_duration(Duration::from_secs(3600))
+            .clock(clock.clone());
+        let failing = || async { Err::<(), _>(Error::Backend) };
+        assert_eq!(breaker.call(failing).await, Err(Error::Backend));
+        assert_eq!(breaker.call(failing).await, Err(Error::Open));
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(breaker.call(|| async { Ok::<_, Error>(()) }).await, Ok(()));
+    }
+}
@@ -0,0 +1,205 @@
+/// Deserialize the configuration with the context of where it fails, e.p.
+/// `cannot deserialize configuration from file config/sys.grpc.yml at service.port (line 3): invalid type: ...`
+///
+/// The field path is reported for all formats, the line is reported when the
+/// format provides it (it is absent for merged files).
+use crate::config::encryption::{decrypt_value, EncryptionError, ENC_PREFIX};
+use crate::utils::ConfigFormat;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt::Display;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("cannot read configuration file {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("cannot deserialize configuration from {origin}{}: {message}", location(.field, .line))]
+    Deserialize {
+        // e.p. `file config/sys.grpc.yml`, `etcd config/sys.grpc`
+        origin: String,
+        // e.p. `service.port`, None if the error is not inside a field
+        field: Option<String>,
+        line: Option<usize>,
+        message: String,
+    },
+    #[error("cannot decrypt configuration from {origin}: {source}")]
+    Decrypt {
+        origin: String,
+        source: EncryptionError,
+    },
+}
+
+fn location(field: &Option<String>, line: &Option<usize>) -> String {
+    let mut location = String::new();
+    if let Some(field) = field {
+        location.push_str(&format!(" at {}", field));
+    }
+    if let Some(line) = line {
+        location.push_str(&format!(" (line {})", line));
+    }
+    location
+}
+
+fn deserialize_error<E: Display>(
+    origin: &str,
+    err: serde_path_to_error::Error<E>,
+    line: Option<usize>,
+) -> ConfigError {
+    let field = err.path().to_string();
+    ConfigError::Deserialize {
+        origin: origin.to_string(),
+        field: (field != ".").then_some(field),
+        line,
+        message: err.into_inner().to_string(),
+    }
+}
+
+/// The format of a file extension or `CONFIG_FILETYPE`, YAML if unknown
+pub(crate) fn format_of(extension: &str) -> ConfigFormat {
+    match extension.to_lowercase().as_str() {
+        "json" => ConfigFormat::JSON,
+        "toml" => ConfigFormat::TOML,
+        _ => ConfigFormat::YAML,
+    }
+}
+
+/// Deserialize the content, the `enc:` values are decrypted before deserializing
+/// into `T`, see [encryption](crate::config::encryption)
+pub(crate) fn deserialize_str<T: DeserializeOwned>(
+    content: &str,
+    format: ConfigFormat,
+    origin: &str,
+) -> Result<T, ConfigError> {
+    if !content.contains(ENC_PREFIX) {
+        return deserialize_plain(content, format, origin);
+    }
+    // the lines are lost when deserializing from the decrypted tree
+    let mut value: Value = deserialize_plain(content, format, origin)?;
+    decrypt_value(&mut value).map_err(|source| ConfigError::Decrypt {
+        origin: origin.to_string(),
+        source,
+    })?;
+    deserialize_value(value, origin)
+}
+
+fn deserialize_plain<T: DeserializeOwned>(
+    content: &str,
+    format: ConfigFormat,
+    origin: &str,
+) -> Result<T, ConfigError> {
+    match format {
+        ConfigFormat::JSON => {
+            let mut de = serde_json::Deserializer::from_str(content);
+            let config = serde_path_to_error::deserialize(&mut de).map_err(|err| {
+                let line = err.inner().line();
+                deserialize_error(origin, err, Some(line))
+            })?;
+            de.end().map_err(|err| ConfigError::Deserialize {
+                origin: origin.to_string(),
+                field: None,
+                line: Some(err.line()),
+                message: err.to_string(),
+            })?;
+            Ok(config)
+        }
+        ConfigFormat::YAML => {
+            let de = serde_yaml::Deserializer::from_str(content);
+            serde_path_to_error::deserialize(de).map_err(|err| {
+                let line = err.inner().location().map(|location| location.line());
+                deserialize_error(origin, err, line)
+            })
+        }
+        ConfigFormat::TOML => {
+            let de = toml::Deserializer::new(content);
+            serde_path_to_error::deserialize(de).map_err(|err| {
+                let line = err
+                    .inner()
+                    .span()
+                    .map(|span| content[..span.start].matches('\n').count() + 1);
+                deserialize_error(origin, err, line)
+            })
+        }
+    }
+}
+
+pub(crate) fn deserialize_file<T: DeserializeOwned>(path: &Path) -> Result<T, ConfigError> {
+    let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.display().to_string(),
+        source,
+    })?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    deserialize_str(
+        &content,
+        format_of(extension),
+        &format!("file {}", path.display()),
+    )
+}
+
+pub(crate) fn deserialize_value<T: DeserializeOwned>(
+    value: Value,
+    origin: &str,
+) -> Result<T, ConfigError> {
+    serde_path_to_error::deserialize(value).map_err(|err| deserialize_error(origin, err, None))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::encryption::encrypt;
+    use crate::utils::deserialize::deserialize_str;
+    use crate::utils::ConfigFormat;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct MyConfig {
+        service: Service,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Service {
+        name: String,
+        port: u16,
+    }
+
+    #[test]
+    fn test_deserialize_error() {
+        let content = "service:\n  name: user\n  port: abc\n";
+        let err = deserialize_str::<MyConfig>(content, ConfigFormat::YAML, "file sys.grpc.yml")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with(
+            "cannot deserialize configuration from file sys.grpc.yml at service.port (line 3):"
+        ));
+
+        let content = r#"{"service": {"name": "user"}}"#;
+        let err = deserialize_str::<MyConfig>(content, ConfigFormat::JSON, "etcd config/sys")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("at service"));
+        assert!(err.contains("missing field `port`"));
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let key = [7u8; 32];
+        std::env::set_var("CONFIG_ENCRYPTION_KEY", STANDARD.encode(key));
+        // the tree of apollo and nacos is rendered as JSON
+        let tree = json!({"service": {"name": encrypt(&key, "user").unwrap(), "port": 8080}});
+        let config = deserialize_str::<MyConfig>(
+            &tree.to_string(),
+            ConfigFormat::JSON,
+            "apollo application",
+        )
+        .unwrap();
+        assert_eq!(config.service.name, "user");
+    }
+}
@@ -0,0 +1,200 @@
+/// Structured diff of two configurations for reload auditing, any `Serialize` config
+/// is compared by the changed leaf paths of its JSON form.
+///
+/// [Secret] fields are serialized redacted so their changes are invisible, and values
+/// of fields named like a secret (`password`, `token`, etc.) are redacted as well, so
+/// are the passwords in connection strings, e.p. `postgres://user:***@db:5432/app`.
+///
+/// [Secret]: crate::config::secret::Secret
+use crate::middleware::dsn::Dsn;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use tracing::info;
+
+const REDACTED: &str = "***";
+
+const SECRET_NAMES: [&str; 5] = ["password", "secret", "token", "credential", "private_key"];
+
+/// A changed leaf, `None` if the field is added or removed
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    /// e.p. `service.tls_cert`, `tags[1]`
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl Display for FieldChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let show = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "<none>".to_string(),
+        };
+        write!(
+            f,
+            "{}: {} => {}",
+            self.path,
+            show(&self.old),
+            show(&self.new)
+        )
+    }
+}
+
+/// The changed leaf paths from `old` to `new`, sorted by path
+pub fn config_diff<T: Serialize>(old: &T, new: &T) -> Vec<FieldChange> {
+    let old = serde_json::to_value(old).unwrap_or(Value::Null);
+    let new = serde_json::to_value(new).unwrap_or(Value::Null);
+    let mut changes = vec![];
+    diff_value("", Some(&old), Some(&new), &mut changes);
+    changes
+}
+
+/// The JSON form of config with the secrets redacted the same as [config_diff]
+pub(crate) fn redacted<T: Serialize>(config: &T) -> Result<Value, serde_json::Error> {
+    let mut value = serde_json::to_value(config)?;
+    redact("", &mut value);
+    Ok(value)
+}
+
+/// Emit the changes as tracing events
+pub(crate) fn log_diff(changes: &[FieldChange]) {
+    for change in changes {
+        info!(path = %change.path, "configuration field changed, {}", change);
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn is_secret(path: &str) -> bool {
+    let name = path.rsplit('.').next().unwrap_or(path).to_lowercase();
+    SECRET_NAMES.iter().any(|secret| name.contains(secret))
+}
+
+fn redact(path: &str, value: &mut Value) {
+    match value {
+        Value::Null => {}
+        _ if is_secret(path) => *value = Value::String(REDACTED.to_string()),
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                redact(&join(path, key), value);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                redact(&format!("{}[{}]", path, i), value);
+            }
+        }
+        Value::String(dsn) => {
+            if let Some(redacted) = redact_dsn(dsn) {
+                *dsn = redacted;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The connection string with its password redacted, None if it has no password
+fn redact_dsn(value: &str) -> Option<String> {
+    if !value.contains("://") {
+        return None;
+    }
+    let dsn = Dsn::parse(value).ok()?;
+    dsn.password_str()?;
+    Some(dsn.to_string())
+}
+
+fn diff_value(
+    path: &str,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    changes: &mut Vec<FieldChange>,
+) {
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+            for key in keys {
+                diff_value(&join(path, key), old.get(key), new.get(key), changes);
+            }
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for i in 0..old.len().max(new.len()) {
+                diff_value(&format!("{}[{}]", path, i), old.get(i), new.get(i), changes);
+            }
+        }
+        (old, new) if old != new => {
+            let mask = |value: Option<&Value>| {
+                value.map(|value| {
+                    let mut value = value.clone();
+                    redact(path, &mut value);
+                    value
+                })
+            };
+            changes.push(FieldChange {
+                path: path.to_string(),
+                old: mask(old),
+                new: mask(new),
+            });
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::config_diff;
+    use serde::Serialize;
+    use serde_json::json;
+
+    #[derive(Serialize)]
+    struct Conf {
+        addr: String,
+        password: String,
+        tags: Vec<String>,
+        tls: Option<String>,
+    }
+
+    #[test]
+    fn test_config_diff() {
+        let old = Conf {
+            addr: "127.0.0.1:3000".to_string(),
+            password: "old".to_string(),
+            tags: vec!["v1".to_string()],
+            tls: None,
+        };
+        let new = Conf {
+            addr: "127.0.0.1:3000".to_string(),
+            password: "new".to_string(),
+            tags: vec!["v1".to_string(), "canary".to_string()],
+            tls: Some("cert.pem".to_string()),
+        };
+        let changes = config_diff(&old, &new)
+            .into_iter()
+            .map(|change| (change.path, change.old, change.new))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    "password".to_string(),
+                    Some(json!("***")),
+                    Some(json!("***"))
+                ),
+                ("tags[1]".to_string(), None, Some(json!("canary"))),
+                (
+                    "tls".to_string(),
+                    Some(json!(null)),
+                    Some(json!("cert.pem"))
+                ),
+            ]
+        );
+        assert!(config_diff(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+/// Layered configuration files, e.p. shared defaults in `base.yml` overlaid by `prod.yml`.
+///
+/// Files are parsed with kosei one by one and deep merged in order, later files
+/// override the keys of earlier ones:
+/// maps => merged key by key recursively
+/// sequences and scalars => replaced as a whole
+/// null => replaces the earlier value, so an overlay could unset a key
+use crate::config::ConfigType;
+use crate::utils::deserialize::{deserialize_file, deserialize_value};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const CONFIG_EXTENSIONS: [&str; 4] = ["yml", "yaml", "json", "toml"];
+
+/// Deep merge `overlay` into `base`
+pub fn merge_value(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge_value(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// The configuration files in a directory with known extensions, sorted by file name
+pub(crate) fn dir_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| CONFIG_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                    .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Parse and merge the files in order
+pub(crate) fn load_files<T: ConfigType>(files: &[PathBuf]) -> Result<T, Error> {
+    if let [file] = files {
+        return Ok(deserialize_file(file)?);
+    }
+    let mut merged = Value::Object(Default::default());
+    for file in files {
+        merge_value(&mut merged, deserialize_file(file)?);
+    }
+    let origin = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(deserialize_value(
+        merged,
+        &format!("merged files {}", origin),
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::merge::merge_value;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_value() {
+        let mut base = json!({
+            "service": {"name": "user", "addr": "0.0.0.0:3000"},
+            "tags": ["base", "v1"],
+            "debug": true,
+        });
+        merge_value(
+            &mut base,
+            json!({
+                "service": {"addr": "0.0.0.0:80"},
+                "tags": ["prod"],
+                "debug": null,
+                "replicas": 3,
+            }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "service": {"name": "user", "addr": "0.0.0.0:80"},
+                "tags": ["prod"],
+                "debug": null,
+                "replicas": 3,
+            })
+        );
+    }
+}
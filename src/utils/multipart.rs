@@ -0,0 +1,311 @@
+/// A streaming `multipart/form-data` reader for upload endpoints, which limits the
+/// size of each part, the size of the whole upload and the number of parts.
+///
+/// Parts are read chunk by chunk, the upload is never buffered in memory, and reading
+/// stops with a [MultipartError] as soon as any limit is exceeded. The size limits are
+/// enforced by the underlying reader, so they also cover the parts a handler skips.
+use bytes::{Buf, Bytes};
+use futures::stream;
+use http::header::CONTENT_TYPE;
+use http::{Request, StatusCode};
+use http_body::Body;
+use thiserror::Error;
+
+const DEFAULT_MAX_PART_SIZE: u64 = 10 << 20;
+const DEFAULT_MAX_TOTAL_SIZE: u64 = 50 << 20;
+const DEFAULT_MAX_PART_COUNT: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MultipartLimits {
+    max_part_size: u64,
+    max_total_size: u64,
+    max_part_count: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultipartLimits {
+    /// Default limits are 10MiB per part, 50MiB in total and 16 parts
+    pub fn new() -> Self {
+        Self {
+            max_part_size: DEFAULT_MAX_PART_SIZE,
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_part_count: DEFAULT_MAX_PART_COUNT,
+        }
+    }
+
+    /// Max bytes of each part's content
+    pub fn max_part_size(mut self, max_part_size: u64) -> Self {
+        self.max_part_size = max_part_size;
+        self
+    }
+
+    /// Max bytes of the whole upload, including the boundaries and headers of parts
+    pub fn max_total_size(mut self, max_total_size: u64) -> Self {
+        self.max_total_size = max_total_size;
+        self
+    }
+
+    /// Max number of parts
+    pub fn max_part_count(mut self, max_part_count: usize) -> Self {
+        self.max_part_count = max_part_count;
+        self
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MultipartError {
+    #[error("request is not multipart, err: {0}")]
+    NotMultipart(multer::Error),
+    #[error("multipart has more than {limit} parts")]
+    TooManyParts { limit: usize },
+    #[error("multipart part {name:?} is larger than {limit} bytes")]
+    PartTooLarge { name: Option<String>, limit: u64 },
+    #[error("multipart is larger than {limit} bytes")]
+    TotalTooLarge { limit: u64 },
+    #[error("malformed multipart, err: {0}")]
+    Malformed(multer::Error),
+}
+
+impl From<multer::Error> for MultipartError {
+    fn from(err: multer::Error) -> Self {
+        match err {
+            multer::Error::FieldSizeExceeded { limit, field_name } => {
+                MultipartError::PartTooLarge {
+                    name: field_name,
+                    limit,
+                }
+            }
+            multer::Error::StreamSizeExceeded { limit } => MultipartError::TotalTooLarge { limit },
+            // multer reports the exceeded stream as a failed read
+            multer::Error::StreamReadFailed(err) => match err.downcast::<multer::Error>() {
+                Ok(err) => MultipartError::from(*err),
+                Err(err) => MultipartError::Malformed(multer::Error::StreamReadFailed(err)),
+            },
+            err => MultipartError::Malformed(err),
+        }
+    }
+}
+
+impl MultipartError {
+    /// The http status responded to client
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MultipartError::NotMultipart(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MultipartError::TooManyParts { .. }
+            | MultipartError::PartTooLarge { .. }
+            | MultipartError::TotalTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            MultipartError::Malformed(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// ```rust,no_run
+/// use bytes::Bytes;
+/// use common::utils::multipart::{GuardedMultipart, MultipartLimits};
+/// use http::Request;
+/// use http_body::Full;
+///
+/// async fn upload(req: Request<Full<Bytes>>) -> Result<(), Box<dyn std::error::Error>> {
+///     let limits = MultipartLimits::new().max_part_size(1 << 20).max_part_count(4);
+///     let mut multipart = GuardedMultipart::from_request(req, limits)?;
+///     while let Some(mut part) = multipart.next_part().await? {
+///         while let Some(_chunk) = part.chunk().await? {
+///             // write chunk to storage
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct GuardedMultipart<'r> {
+    inner: multer::Multipart<'r>,
+    limits: MultipartLimits,
+    parts: usize,
+}
+
+impl<'r> GuardedMultipart<'r> {
+    /// Wrap a stream of multipart bytes with the boundary
+    pub fn new<S, O, E>(stream: S, boundary: impl Into<String>, limits: MultipartLimits) -> Self
+    where
+        S: futures::Stream<Item = Result<O, E>> + Send + 'r,
+        O: Into<Bytes> + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'r,
+    {
+        let size_limit = multer::SizeLimit::new()
+            .whole_stream(limits.max_total_size)
+            .per_field(limits.max_part_size);
+        let constraints = multer::Constraints::new().size_limit(size_limit);
+        Self {
+            inner: multer::Multipart::with_constraints(stream, boundary, constraints),
+            limits,
+            parts: 0,
+        }
+    }
+
+    /// Read the boundary from `Content-Type` of request and wrap the request body
+    pub fn from_request<B>(req: Request<B>, limits: MultipartLimits) -> Result<Self, MultipartError>
+    where
+        B: Body + Send + 'r,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let boundary =
+            multer::parse_boundary(content_type).map_err(MultipartError::NotMultipart)?;
+        let body = Box::pin(req.into_body());
+        let stream = stream::unfold(body, |mut body| async move {
+            let data = body.data().await?;
+            Some((
+                data.map(|mut data| data.copy_to_bytes(data.remaining())),
+                body,
+            ))
+        });
+        Ok(Self::new(stream, boundary, limits))
+    }
+
+    /// Read the next part, the previous part must be dropped before that.
+    pub async fn next_part(&mut self) -> Result<Option<GuardedPart<'r>>, MultipartError> {
+        let field = match self.inner.next_field().await? {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+        self.parts += 1;
+        if self.parts > self.limits.max_part_count {
+            return Err(MultipartError::TooManyParts {
+                limit: self.limits.max_part_count,
+            });
+        }
+        Ok(Some(GuardedPart { inner: field }))
+    }
+}
+
+pub struct GuardedPart<'r> {
+    inner: multer::Field<'r>,
+}
+
+impl<'r> GuardedPart<'r> {
+    pub fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.inner.file_name()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.inner.content_type().map(|mime| mime.essence_str())
+    }
+
+    pub fn headers(&self) -> &http::HeaderMap {
+        self.inner.headers()
+    }
+
+    /// Read the next chunk of content
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, MultipartError> {
+        Ok(self.inner.chunk().await?)
+    }
+
+    /// Read the whole content, it is bounded by the part size limit.
+    pub async fn bytes(mut self) -> Result<Bytes, MultipartError> {
+        let mut buf = bytes::BytesMut::new();
+        while let Some(chunk) = self.chunk().await? {
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(buf.freeze())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::multipart::{GuardedMultipart, MultipartError, MultipartLimits};
+    use bytes::Bytes;
+    use http::Request;
+    use http_body::Full;
+
+    const BOUNDARY: &str = "X-BOUNDARY";
+
+    fn multipart(parts: &[(&str, usize)], limits: MultipartLimits) -> GuardedMultipart<'static> {
+        let mut body = String::new();
+        for (name, size) in parts {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY,
+                name,
+                "a".repeat(*size)
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+        let req = Request::post("/upload")
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+        GuardedMultipart::from_request(req, limits).unwrap()
+    }
+
+    async fn drain(mut multipart: GuardedMultipart<'_>) -> Result<u64, MultipartError> {
+        let mut size = 0;
+        while let Some(part) = multipart.next_part().await? {
+            size += part.bytes().await?.len() as u64;
+        }
+        Ok(size)
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        // the whole upload of parts `a` (8 bytes) and `b` (4 bytes) is 148 bytes
+        let limits = MultipartLimits::new()
+            .max_part_size(8)
+            .max_total_size(148)
+            .max_part_count(2);
+
+        let size = drain(multipart(&[("a", 8), ("b", 4)], limits)).await;
+        assert_eq!(size.unwrap(), 12);
+
+        let err = drain(multipart(&[("a", 9)], limits)).await.unwrap_err();
+        assert!(
+            matches!(err, MultipartError::PartTooLarge { name: Some(name), limit: 8 } if name == "a")
+        );
+
+        let err = drain(multipart(&[("a", 8), ("b", 5)], limits))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, MultipartError::TotalTooLarge { limit: 148 }));
+
+        // parts skipped by the handler are limited as well
+        let mut skipped = multipart(&[("a", 9), ("b", 1)], limits);
+        let err = async {
+            while skipped.next_part().await?.is_some() {}
+            Ok::<_, MultipartError>(())
+        }
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, MultipartError::PartTooLarge { name: Some(name), limit: 8 } if name == "a")
+        );
+
+        let limits = limits.max_total_size(1 << 10);
+        let err = drain(multipart(&[("a", 1), ("b", 1), ("c", 1)], limits))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, MultipartError::TooManyParts { limit: 2 }));
+
+        let req = Request::post("/upload")
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert!(matches!(
+            GuardedMultipart::from_request(req, limits),
+            Err(MultipartError::NotMultipart(_))
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+/// Offset and cursor pagination for list endpoints.
+///
+/// ```rust
+/// use common::utils::pagination::{Cursor, CursorPage, Page};
+///
+/// let query = CursorPage::<u64> { after: Some(Cursor(10)), size: 2 }.validate(100).unwrap();
+/// // fetch `query.limit()` rows (one more than size) after id 10
+/// let rows: Vec<u64> = vec![11, 12, 13];
+/// let page = Page::from_cursor(rows, &query, |id| *id);
+/// assert!(page.has_next);
+/// assert_eq!(page.next_cursor, Some(Cursor(12).encode()));
+/// ```
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 20;
+
+fn default_page() -> u32 {
+    1
+}
+
+fn default_size() -> u32 {
+    DEFAULT_PAGE_SIZE
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PaginationError {
+    #[error("page starts from 1")]
+    InvalidPage,
+    #[error("page size must be in [1, {max}]")]
+    InvalidSize { max: u32 },
+    #[error("invalid cursor")]
+    InvalidCursor,
+}
+
+/// Page number (starts from 1) and page size, e.p. `?page=2&size=20`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OffsetPage {
+    #[serde(default = "default_page")]
+    pub page: u32,
+    #[serde(default = "default_size")]
+    pub size: u32,
+}
+
+impl Default for OffsetPage {
+    fn default() -> Self {
+        Self::new(default_page(), default_size())
+    }
+}
+
+impl OffsetPage {
+    pub fn new(page: u32, size: u32) -> Self {
+        Self { page, size }
+    }
+
+    /// Check the page is not zero and the size is in `[1, max_size]`
+    pub fn validate(self, max_size: u32) -> Result<Self, PaginationError> {
+        if self.page == 0 {
+            return Err(PaginationError::InvalidPage);
+        }
+        validate_size(self.size, max_size)?;
+        Ok(self)
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.page.saturating_sub(1) as u64 * self.size as u64
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.size as u64
+    }
+}
+
+fn validate_size(size: u32, max_size: u32) -> Result<(), PaginationError> {
+    if size == 0 || size > max_size {
+        return Err(PaginationError::InvalidSize { max: max_size });
+    }
+    Ok(())
+}
+
+/// An opaque cursor wrapping the sort key of the last item, it is
+/// (de)serialized as URL safe base64 of the JSON key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor<K>(pub K);
+
+impl<K: Serialize> Cursor<K> {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(&self.0).expect("cursor key cannot be serialized");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+}
+
+impl<K: DeserializeOwned> Cursor<K> {
+    pub fn decode(cursor: &str) -> Result<Self, PaginationError> {
+        let json = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| PaginationError::InvalidCursor)?;
+        serde_json::from_slice(&json)
+            .map(Cursor)
+            .map_err(|_| PaginationError::InvalidCursor)
+    }
+}
+
+impl<K: Serialize> Serialize for Cursor<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.encode())
+    }
+}
+
+impl<'de, K: DeserializeOwned> Deserialize<'de> for Cursor<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cursor = String::deserialize(deserializer)?;
+        Cursor::decode(&cursor).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Items after the cursor (from the beginning if it is `None`), e.p. `?after=MTA&size=20`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(serialize = "K: Serialize", deserialize = "K: DeserializeOwned"))]
+pub struct CursorPage<K> {
+    #[serde(default = "Option::default")]
+    pub after: Option<Cursor<K>>,
+    #[serde(default = "default_size")]
+    pub size: u32,
+}
+
+impl<K> CursorPage<K> {
+    /// Check the size is in `[1, max_size]`
+    pub fn validate(self, max_size: u32) -> Result<Self, PaginationError> {
+        validate_size(self.size, max_size)?;
+        Ok(self)
+    }
+
+    /// Fetch one more item than size to know if there is a next page
+    pub fn limit(&self) -> u64 {
+        self.size as u64 + 1
+    }
+}
+
+/// Response envelope of a page
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    pub has_next: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// Items of the offset page and the total count of all items
+    pub fn from_offset(items: Vec<T>, total: u64, page: &OffsetPage) -> Self {
+        let has_next = page.offset() + (items.len() as u64) < total;
+        Self {
+            items,
+            total: Some(total),
+            has_next,
+            next_cursor: None,
+        }
+    }
+
+    /// Items fetched with [CursorPage::limit] and the sort key of item
+    pub fn from_cursor<K: Serialize>(
+        mut items: Vec<T>,
+        page: &CursorPage<K>,
+        key: impl FnOnce(&T) -> K,
+    ) -> Self {
+        let has_next = items.len() > page.size as usize;
+        items.truncate(page.size as usize);
+        let next_cursor = items
+            .last()
+            .filter(|_| has_next)
+            .map(|last| Cursor(key(last)).encode());
+        Self {
+            items,
+            total: None,
+            has_next,
+            next_cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::pagination::{Cursor, CursorPage, OffsetPage, Page, PaginationError};
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor((1680000000u64, "book-42".to_string()));
+        let encoded = cursor.encode();
+        assert!(!encoded.contains(['+', '/', '=']));
+        assert_eq!(Cursor::decode(&encoded), Ok(cursor.clone()));
+
+        let query: CursorPage<(u64, String)> =
+            serde_json::from_str(&format!(r#"{{"after": "{}"}}"#, encoded)).unwrap();
+        assert_eq!(query.after, Some(cursor));
+        assert_eq!(query.size, 20);
+
+        assert_eq!(
+            Cursor::<u64>::decode("not a cursor"),
+            Err(PaginationError::InvalidCursor)
+        );
+        assert_eq!(
+            Cursor::<u64>::decode(&Cursor("str").encode()),
+            Err(PaginationError::InvalidCursor)
+        );
+    }
+
+    #[test]
+    fn test_offset_page() {
+        assert_eq!(
+            OffsetPage::new(0, 20).validate(100),
+            Err(PaginationError::InvalidPage)
+        );
+        assert_eq!(
+            OffsetPage::new(1, 101).validate(100),
+            Err(PaginationError::InvalidSize { max: 100 })
+        );
+        let page = OffsetPage::new(3, 10).validate(100).unwrap();
+        assert_eq!(page.offset(), 20);
+
+        assert!(Page::from_offset(vec![0; 10], 31, &page).has_next);
+        assert!(!Page::from_offset(vec![0; 10], 30, &page).has_next);
+    }
+
+    #[test]
+    fn test_cursor_page() {
+        let query = CursorPage::<u32> {
+            after: None,
+            size: 2,
+        };
+        let page = Page::from_cursor(vec![1, 2, 3], &query, |id| *id);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.next_cursor, Some(Cursor(2).encode()));
+
+        let page = Page::from_cursor(vec![1, 2], &query, |id| *id);
+        assert!(!page.has_next);
+        assert_eq!(page.next_cursor, None);
+    }
+}
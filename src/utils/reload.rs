@@ -0,0 +1,390 @@
+/// Hot reload the configuration stored in etcd or consul KV store, the store is
+/// selected by `CONFIG_TYPE=etcd|consul`.
+///
+/// The key is environment `CONFIG_KEY` (`config/{domain}.{target}` by default) and
+/// the content format is `CONFIG_FILETYPE` (`yml` by default). Changes of etcd are
+/// pushed by a watch stream, and changes of consul are pulled by blocking queries.
+/// A new configuration is only swapped in after it is parsed and validated, the
+/// old one is kept on a bad update.
+///
+/// With `CONFIG_CACHE_PATH`, the initial load falls back to the last-known-good
+/// configuration when the store is unreachable, see [cache](crate::utils::cache).
+///
+/// ```rust,no_run
+/// # use common::infra::Resolver;
+/// use common::utils::reload::ConfigWatcher;
+///
+/// # async fn run<R: Resolver>() where R::Config: Send + Sync + 'static {
+/// let (conf, _handle) = ConfigWatcher::<R::Config>::new()
+///     .validate(|_conf| Ok(()))
+///     .watch::<R>()
+///     .await
+///     .unwrap();
+/// // always the latest configuration
+/// let current = conf.borrow().clone();
+/// # }
+/// ```
+use crate::config::env::optional;
+use crate::config::ConfigType;
+use crate::infra::Resolver;
+use crate::middleware::consul::{kv_value, Consul, ConsulConf};
+use crate::middleware::etcd::{Etcd, EtcdConf};
+use crate::middleware::Middleware;
+use crate::utils::cache::{fetch_or_cached, ConfigCache};
+use crate::utils::deserialize::{deserialize_str, format_of};
+use crate::utils::diff::{config_diff, log_diff, FieldChange};
+use crate::utils::ConfigFormat;
+use consul::kv::KV;
+use consul::QueryOptions;
+use etcd_client::{EventType, WatchOptions};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn, Instrument};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+type Validator<T> = Box<dyn Fn(&T) -> Result<(), Error> + Send + Sync>;
+
+type Differ<T> = Box<dyn Fn(&T, &T) -> Vec<FieldChange> + Send + Sync>;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The interval of the next retry, doubled after each failure
+fn backoff(retry: &mut Duration) -> Duration {
+    let current = *retry;
+    *retry = (current * 2).min(MAX_RETRY_INTERVAL);
+    current
+}
+
+/// The key of configuration in KV store
+pub(crate) fn config_key<R: Resolver>() -> String {
+    optional("CONFIG_KEY", format!("config/{}.{}", R::DOMAIN, R::TARGET))
+}
+
+/// The format of the content in KV store, `CONFIG_FILETYPE`
+fn content_format() -> ConfigFormat {
+    format_of(&optional("CONFIG_FILETYPE", "yml"))
+}
+
+/// Parse the configuration content with the format `CONFIG_FILETYPE`,
+/// `origin` is where the content is from, e.p. `etcd config/sys.grpc`
+fn parse_content<T: ConfigType>(content: &str, origin: &str) -> Result<T, Error> {
+    Ok(deserialize_str(content, content_format(), origin)?)
+}
+
+/// Load from etcd, fall back to the last-known-good cache if configured
+pub(crate) async fn load_etcd<T: ConfigType>(key: &str) -> Result<T, Error> {
+    let fetch = async {
+        let mut client = Etcd::new(EtcdConf::default()).make_client().await?;
+        let (content, _) = etcd_get(&mut client, key).await?;
+        Ok(content)
+    };
+    let cache = ConfigCache::from_env();
+    let origin = format!("etcd {}", key);
+    fetch_or_cached(cache.as_ref(), &origin, content_format(), fetch).await
+}
+
+/// Load from consul, fall back to the last-known-good cache if configured
+pub(crate) async fn load_consul<T: ConfigType>(key: &str) -> Result<T, Error> {
+    let fetch = async {
+        let client = Consul::new(ConsulConf::default()).make_client().await?;
+        let (content, _) = consul_get(&client, key, None, Duration::ZERO).await?;
+        content.ok_or_else(|| format!("configuration key {} is not found in consul", key).into())
+    };
+    let cache = ConfigCache::from_env();
+    let origin = format!("consul {}", key);
+    fetch_or_cached(cache.as_ref(), &origin, content_format(), fetch).await
+}
+
+/// Get the value and the revision of key
+async fn etcd_get(client: &mut etcd_client::Client, key: &str) -> Result<(String, i64), Error> {
+    let resp = client.get(key, None).await?;
+    let kv = resp
+        .kvs()
+        .first()
+        .ok_or_else(|| format!("configuration key {} is not found in etcd", key))?;
+    let revision = resp.header().map(|header| header.revision()).unwrap_or(0);
+    Ok((kv.value_str()?.to_string(), revision))
+}
+
+/// Get the value of key, block until the index of key is changed from `index`
+async fn consul_get(
+    client: &consul::Client,
+    key: &str,
+    index: Option<u64>,
+    wait: Duration,
+) -> Result<(Option<String>, Option<u64>), Error> {
+    let options = QueryOptions {
+        wait_index: index,
+        wait_time: index.map(|_| wait),
+        ..Default::default()
+    };
+    let (pair, meta) = client.get(key, Some(&options)).await?;
+    let content = pair.map(|pair| String::from_utf8(kv_value(&pair)).unwrap_or(pair.Value));
+    Ok((content, meta.last_index))
+}
+
+pub struct ConfigWatcher<T> {
+    validator: Option<Validator<T>>,
+    differ: Option<Differ<T>>,
+    shutdown: CancellationToken,
+    consul_wait: Duration,
+}
+
+impl<T: ConfigType + Send + Sync + 'static> Default for ConfigWatcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ConfigType + Send + Sync + 'static> ConfigWatcher<T> {
+    pub fn new() -> Self {
+        Self {
+            validator: None,
+            differ: None,
+            shutdown: CancellationToken::new(),
+            consul_wait: Duration::from_secs(300),
+        }
+    }
+
+    /// Validate the new configuration before it is swapped in
+    pub fn validate(
+        mut self,
+        validator: impl Fn(&T) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Log the changed fields on each reload, see [config_diff]
+    pub fn audit(mut self) -> Self
+    where
+        T: Serialize,
+    {
+        self.differ = Some(Box::new(|old, new| config_diff(old, new)));
+        self
+    }
+
+    /// Stop watching once `shutdown` is cancelled
+    pub fn shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// The max duration of a consul blocking query, 5 minutes by default
+    pub fn consul_wait(mut self, wait: Duration) -> Self {
+        self.consul_wait = wait;
+        self
+    }
+
+    /// Load the configuration and watch its changes, the receiver always holds
+    /// the latest valid configuration.
+    pub async fn watch<R: Resolver<Config = T>>(
+        self,
+    ) -> Result<(watch::Receiver<Arc<T>>, JoinHandle<()>), Error> {
+        let key = config_key::<R>();
+        match optional("CONFIG_TYPE", "file").to_lowercase().as_str() {
+            "etcd" => self.watch_etcd(key).await,
+            "consul" => self.watch_consul(key).await,
+            typ => Err(format!("configuration type {} cannot be watched", typ).into()),
+        }
+    }
+
+    fn parse(&self, content: &str, origin: &str) -> Result<T, Error> {
+        let config = parse_content(content, origin)?;
+        if let Some(ref validator) = self.validator {
+            validator(&config)?;
+        }
+        Ok(config)
+    }
+
+    /// Parse and validate the content, then swap it in
+    fn swap(&self, tx: &watch::Sender<Arc<T>>, content: &str, origin: &str) {
+        match self.parse(content, origin) {
+            Ok(config) => {
+                let old = tx.send_replace(Arc::new(config));
+                info!("configuration is reloaded");
+                if let Some(ref differ) = self.differ {
+                    log_diff(&differ(&old, &tx.borrow()));
+                }
+            }
+            Err(err) => warn!("keep the old configuration, invalid update: {}", err),
+        }
+    }
+
+    async fn watch_etcd(
+        self,
+        key: String,
+    ) -> Result<(watch::Receiver<Arc<T>>, JoinHandle<()>), Error> {
+        let mut client = Etcd::new(EtcdConf::default()).make_client().await?;
+        let (content, mut revision) = etcd_get(&mut client, &key).await?;
+        let origin = format!("etcd {}", key);
+        let (tx, rx) = watch::channel(Arc::new(self.parse(&content, &origin)?));
+
+        let watch_loop = async move {
+            let mut retry = RETRY_INTERVAL;
+            'watch: loop {
+                let options = WatchOptions::new().with_start_revision(revision + 1);
+                let (mut watcher, mut stream) =
+                    match client.watch(key.as_str(), Some(options)).await {
+                        Ok(watch) => watch,
+                        Err(err) => {
+                            warn!("cannot watch configuration in etcd, err: {}", err);
+                            tokio::select! {
+                                _ = self.shutdown.cancelled() => break 'watch,
+                                _ = tokio::time::sleep(backoff(&mut retry)) => continue 'watch,
+                            }
+                        }
+                    };
+                loop {
+                    let message = tokio::select! {
+                        _ = self.shutdown.cancelled() => {
+                            let _ = watcher.cancel().await;
+                            break 'watch;
+                        }
+                        message = stream.message() => message,
+                    };
+                    let resp = match message {
+                        Ok(Some(resp)) => resp,
+                        Ok(None) => {
+                            warn!("configuration watch stream is closed, rewatch");
+                            continue 'watch;
+                        }
+                        Err(err) => {
+                            warn!("configuration watch stream error: {}, rewatch", err);
+                            continue 'watch;
+                        }
+                    };
+                    if resp.canceled() {
+                        warn!(
+                            "configuration watch is canceled by etcd: {}, rewatch",
+                            resp.cancel_reason()
+                        );
+                        // the revisions are compacted, catch up with the current value
+                        if resp.compact_revision() > 0 {
+                            match etcd_get(&mut client, &key).await {
+                                Ok((content, current)) => {
+                                    revision = current;
+                                    self.swap(&tx, &content, &origin);
+                                }
+                                Err(err) => warn!("cannot get configuration, err: {}", err),
+                            }
+                        }
+                        tokio::select! {
+                            _ = self.shutdown.cancelled() => break 'watch,
+                            _ = tokio::time::sleep(backoff(&mut retry)) => continue 'watch,
+                        }
+                    }
+                    retry = RETRY_INTERVAL;
+                    for event in resp.events() {
+                        if let Some(kv) = event.kv() {
+                            revision = revision.max(kv.mod_revision());
+                            match event.event_type() {
+                                EventType::Put => match kv.value_str() {
+                                    Ok(content) => self.swap(&tx, content, &origin),
+                                    Err(err) => warn!("configuration is not utf-8, err: {}", err),
+                                },
+                                EventType::Delete => warn!(
+                                    "configuration key is deleted, keep the old configuration"
+                                ),
+                            }
+                        }
+                    }
+                }
+            }
+            trace!("configuration watcher exited");
+        }
+        .in_current_span();
+        Ok((rx, tokio::spawn(watch_loop)))
+    }
+
+    async fn watch_consul(
+        self,
+        key: String,
+    ) -> Result<(watch::Receiver<Arc<T>>, JoinHandle<()>), Error> {
+        let client = Consul::new(ConsulConf::default()).make_client().await?;
+        let (content, mut index) = consul_get(&client, &key, None, self.consul_wait).await?;
+        let content =
+            content.ok_or_else(|| format!("configuration key {} is not found in consul", key))?;
+        let origin = format!("consul {}", key);
+        let (tx, rx) = watch::channel(Arc::new(self.parse(&content, &origin)?));
+
+        let watch_loop = async move {
+            let mut retry = RETRY_INTERVAL;
+            loop {
+                let res = tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    res = consul_get(&client, &key, index, self.consul_wait) => res,
+                };
+                match res {
+                    Ok((content, last_index)) => {
+                        retry = RETRY_INTERVAL;
+                        // the index goes backwards when consul is restored, reset it
+                        let changed = last_index != index;
+                        index =
+                            last_index.filter(|last| !matches!(index, Some(idx) if *last < idx));
+                        match content {
+                            Some(content) if changed => self.swap(&tx, &content, &origin),
+                            None => {
+                                warn!("configuration key is deleted, keep the old configuration")
+                            }
+                            _ => trace!("configuration is not changed"),
+                        }
+                    }
+                    Err(err) => {
+                        let interval = backoff(&mut retry);
+                        warn!(
+                            "cannot query configuration in consul, retry in {:?}, err: {}",
+                            interval, err
+                        );
+                        tokio::select! {
+                            _ = self.shutdown.cancelled() => break,
+                            _ = tokio::time::sleep(interval) => {},
+                        }
+                    }
+                }
+            }
+            trace!("configuration watcher exited");
+        }
+        .in_current_span();
+        Ok((rx, tokio::spawn(watch_loop)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::reload::ConfigWatcher;
+    use serde::Deserialize;
+    use std::sync::Arc;
+    use tokio::sync::watch;
+
+    #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+    struct Conf {
+        port: u16,
+    }
+
+    #[test]
+    fn test_swap() {
+        let watcher = ConfigWatcher::<Conf>::new().validate(|conf| {
+            if conf.port == 0 {
+                return Err("port must not be 0".into());
+            }
+            Ok(())
+        });
+        let (tx, rx) = watch::channel(Arc::new(Conf { port: 8080 }));
+
+        watcher.swap(&tx, "port: 9090", "etcd config/sys");
+        assert_eq!(rx.borrow().port, 9090);
+
+        // keep the old one
+        watcher.swap(&tx, "port: 0", "etcd config/sys");
+        assert_eq!(rx.borrow().port, 9090);
+        watcher.swap(&tx, "port: [", "etcd config/sys");
+        assert_eq!(rx.borrow().port, 9090);
+    }
+}
@@ -0,0 +1,170 @@
+/// Extract the API version of a request for version-based routing, e.p. per-version
+/// enforcement or response handling. The sources are tried in order, by default
+/// the `Accept` media type (`application/vnd.myapi.v2+json`) and then the path
+/// prefix (`/v2/book`).
+///
+/// ```rust
+/// use common::utils::routing::{ApiVersion, VersionExtractor, VersionSource};
+/// use http::Request;
+///
+/// let extractor = VersionExtractor::new()
+///     .sources(vec![VersionSource::Header("x-api-version".parse().unwrap())])
+///     .default_version(1);
+/// let req = Request::get("/book").header("x-api-version", "v2").body(()).unwrap();
+/// assert_eq!(extractor.extract(&req), Some(ApiVersion(2)));
+/// ```
+use http::header::{HeaderName, ACCEPT};
+use http::Request;
+use std::fmt::{Display, Formatter};
+
+/// The normalized major version, displayed as `v2`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiVersion(pub u32);
+
+impl Display for ApiVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Where the version is carried
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VersionSource {
+    /// The vendor media type of `Accept`, e.p. `application/vnd.myapi.v2+json`
+    Accept,
+    /// A custom header, the value is `2` or `v2`
+    Header(HeaderName),
+    /// The first path segment, e.p. `/v2/book`
+    PathPrefix,
+}
+
+#[derive(Clone, Debug)]
+pub struct VersionExtractor {
+    sources: Vec<VersionSource>,
+    default: Option<ApiVersion>,
+}
+
+impl Default for VersionExtractor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VersionExtractor {
+    /// `Accept` then the path prefix, no default version
+    pub fn new() -> Self {
+        Self {
+            sources: vec![VersionSource::Accept, VersionSource::PathPrefix],
+            default: None,
+        }
+    }
+
+    /// The sources tried in order
+    pub fn sources(mut self, sources: Vec<VersionSource>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// The version of requests without any version
+    pub fn default_version(mut self, version: u32) -> Self {
+        self.default = Some(ApiVersion(version));
+        self
+    }
+
+    /// The version of the first source carrying one, otherwise the default
+    pub fn extract<B>(&self, req: &Request<B>) -> Option<ApiVersion> {
+        self.sources
+            .iter()
+            .find_map(|source| match source {
+                VersionSource::Accept => req
+                    .headers()
+                    .get_all(ACCEPT)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .find_map(accept_version),
+                VersionSource::Header(name) => req
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| parse_version(value.trim())),
+                VersionSource::PathPrefix => req
+                    .uri()
+                    .path()
+                    .trim_start_matches('/')
+                    .split('/')
+                    .next()
+                    .and_then(|segment| segment.strip_prefix(['v', 'V']))
+                    .and_then(|version| version.parse().ok())
+                    .map(ApiVersion),
+            })
+            .or(self.default)
+    }
+}
+
+/// `2`, `v2` or `V2`
+fn parse_version(value: &str) -> Option<ApiVersion> {
+    value
+        .strip_prefix(['v', 'V'])
+        .unwrap_or(value)
+        .parse()
+        .ok()
+        .map(ApiVersion)
+}
+
+/// The version of a vendor media type, e.p. `application/vnd.myapi.v2+json; q=0.9`
+fn accept_version(media_type: &str) -> Option<ApiVersion> {
+    let essence = media_type.split(';').next()?.trim();
+    let subtype = essence.split_once('/')?.1;
+    let subtype = subtype.split('+').next()?;
+    if !subtype.starts_with("vnd.") {
+        return None;
+    }
+    subtype
+        .split('.')
+        .rev()
+        .find_map(|part| part.strip_prefix(['v', 'V'])?.parse().ok())
+        .map(ApiVersion)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::routing::{ApiVersion, VersionExtractor, VersionSource};
+    use http::Request;
+
+    #[test]
+    fn test_extract_version() {
+        let extractor = VersionExtractor::new();
+        let req = Request::get("/book")
+            .header("accept", "text/html, application/vnd.myapi.v2+json; q=0.9")
+            .body(())
+            .unwrap();
+        assert_eq!(extractor.extract(&req), Some(ApiVersion(2)));
+
+        let req = Request::get("/v3/book").body(()).unwrap();
+        assert_eq!(extractor.extract(&req), Some(ApiVersion(3)));
+        assert_eq!(ApiVersion(3).to_string(), "v3");
+
+        let header = VersionExtractor::new().sources(vec![VersionSource::Header(
+            "x-api-version".parse().unwrap(),
+        )]);
+        let req = Request::get("/v3/book")
+            .header("x-api-version", "4")
+            .body(())
+            .unwrap();
+        assert_eq!(header.extract(&req), Some(ApiVersion(4)));
+
+        // no version
+        let req = Request::get("/book")
+            .header("accept", "application/json")
+            .body(())
+            .unwrap();
+        assert_eq!(extractor.extract(&req), None);
+        assert_eq!(
+            extractor.default_version(1).extract(&req),
+            Some(ApiVersion(1))
+        );
+        let req = Request::get("/video/book").body(()).unwrap();
+        assert_eq!(VersionExtractor::new().extract(&req), None);
+    }
+}
@@ -0,0 +1,182 @@
+/// Diagnostics of what the process decided at boot, call [startup_report] once
+/// everything is set up to print it as a banner and emit it as tracing fields.
+///
+/// Following are collected:
+/// config source => where the configuration is loaded from, see [parse_config]
+/// env fallbacks => environments not found and the default values used instead
+/// connections => middleware connection results, see [connect]
+/// service keys => the service keys registered
+/// degraded => components started in a degraded mode, e.p. see [EnforcerLoader]
+///
+/// [parse_config]: crate::utils::parse_config
+/// [EnforcerLoader]: crate::layer::EnforcerLoader
+use crate::middleware::Middleware;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+use tracing::info;
+
+static REPORT: Lazy<Mutex<StartupReport>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct StartupReport {
+    config_source: Option<String>,
+    // env => default
+    env_fallbacks: BTreeMap<String, String>,
+    // middleware => error if failed
+    connections: BTreeMap<String, Option<String>>,
+    service_keys: Vec<String>,
+    // component => reason
+    degraded: BTreeMap<String, String>,
+}
+
+pub(crate) fn record_config_source(source: &str) {
+    REPORT.lock().unwrap().config_source = Some(source.to_string());
+}
+
+pub(crate) fn record_env_fallback(env_key: &str, default: &str) {
+    REPORT
+        .lock()
+        .unwrap()
+        .env_fallbacks
+        .insert(env_key.to_string(), default.to_string());
+}
+
+pub(crate) fn record_service_key(service_key: &str) {
+    let mut report = REPORT.lock().unwrap();
+    if !report.service_keys.iter().any(|key| key == service_key) {
+        report.service_keys.push(service_key.to_string());
+    }
+}
+
+pub(crate) fn record_degraded(component: &str, reason: &str) {
+    REPORT
+        .lock()
+        .unwrap()
+        .degraded
+        .insert(component.to_string(), reason.to_string());
+}
+
+/// Record the connection result of a middleware
+pub fn record_connection<E: Display>(name: &str, res: Result<(), E>) {
+    REPORT
+        .lock()
+        .unwrap()
+        .connections
+        .insert(name.to_string(), res.err().map(|err| err.to_string()));
+}
+
+/// Make the client of middleware and record the result, e.p.
+/// `connect("redis", &Redis::new(conf)).await?`
+pub async fn connect<M: Middleware>(name: &str, middleware: &M) -> Result<M::Client, M::Error>
+where
+    M::Error: Display,
+{
+    let res = middleware.make_client().await;
+    record_connection(name, res.as_ref().map(|_| ()));
+    res
+}
+
+impl StartupReport {
+    fn render(&self) -> String {
+        let mut lines = vec![format!(
+            "config source: {}",
+            self.config_source.as_deref().unwrap_or("unknown")
+        )];
+        lines.push(format!("env fallbacks: {}", self.env_fallbacks.len()));
+        for (env, default) in &self.env_fallbacks {
+            lines.push(format!("  {} => '{}'", env, default));
+        }
+        lines.push(format!("connections: {}", self.connections.len()));
+        for (name, err) in &self.connections {
+            match err {
+                None => lines.push(format!("  {} => ok", name)),
+                Some(err) => lines.push(format!("  {} => failed, {}", name, err)),
+            }
+        }
+        lines.push(format!("service keys: {}", self.service_keys.join(", ")));
+        if !self.degraded.is_empty() {
+            lines.push(format!("DEGRADED: {}", self.degraded.len()));
+            for (component, reason) in &self.degraded {
+                lines.push(format!("  {} => {}", component, reason));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// Print the startup report as a banner and emit it as structured tracing fields
+pub fn startup_report() {
+    let report = REPORT.lock().unwrap();
+    super::print_banner(&report.render(), "That is your startup report");
+    let env_fallbacks = report
+        .env_fallbacks
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(",");
+    let failed = report
+        .connections
+        .iter()
+        .filter(|(_, err)| err.is_some())
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let connected = report
+        .connections
+        .iter()
+        .filter(|(_, err)| err.is_none())
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let degraded = report
+        .degraded
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(",");
+    info!(
+        config_source = report.config_source.as_deref().unwrap_or("unknown"),
+        env_fallbacks = %env_fallbacks,
+        connected = %connected,
+        failed = %failed,
+        service_keys = %report.service_keys.join(","),
+        degraded = %degraded,
+        "startup report"
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::StartupReport;
+
+    #[test]
+    fn test_render() {
+        let mut report = StartupReport {
+            config_source: Some("nacos".to_string()),
+            ..Default::default()
+        };
+        report.env_fallbacks.insert(
+            "REDIS_ENDPOINT".to_string(),
+            "redis://127.0.0.1/".to_string(),
+        );
+        report.connections.insert("redis".to_string(), None);
+        report
+            .connections
+            .insert("etcd".to_string(), Some("connection refused".to_string()));
+        report.service_keys.push("sys-grpc".to_string());
+        report.degraded.insert(
+            "casbin enforcer".to_string(),
+            "deny-all fallback".to_string(),
+        );
+
+        let rendered = report.render();
+        assert!(rendered.contains("config source: nacos"));
+        assert!(rendered.contains("  REDIS_ENDPOINT => 'redis://127.0.0.1/'"));
+        assert!(rendered.contains("  etcd => failed, connection refused"));
+        assert!(rendered.contains("  redis => ok"));
+        assert!(rendered.contains("service keys: sys-grpc"));
+        assert!(rendered.contains("DEGRADED: 1\n  casbin enforcer => deny-all fallback"));
+    }
+}